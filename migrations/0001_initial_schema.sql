@@ -0,0 +1,162 @@
+-- the schema as it was when it was still set up by hand from commands.sql.
+-- everything is "if not exists" so databases made that way are taken as is
+create table if not exists users (
+    id serial primary key,
+    email text not null,
+    username text not null,
+    password text not null,
+    disabled boolean not null default false,
+    -- tokens issued before this are no longer accepted
+    tokens_valid_after timestamptz,
+    -- can look at and replay dead lettered mail
+    admin boolean not null default false,
+    UNIQUE (email),
+    UNIQUE (username)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS id_idx ON users (id);
+-- a users table from commands.sql is kept by the create above and has none of these
+alter table users add column if not exists disabled boolean not null default false;
+alter table users add column if not exists tokens_valid_after timestamptz;
+alter table users add column if not exists admin boolean not null default false;
+
+create table if not exists user_identities (
+    id serial primary key,
+    user_id integer not null references users (id) on delete cascade,
+    provider text not null,
+    subject text not null,
+    email text not null,
+    created_at timestamptz not null default now(),
+    UNIQUE (provider, subject)
+);
+
+create table if not exists oauth_logins (
+    state text primary key,
+    provider text not null,
+    nonce text not null,
+    code_verifier text not null,
+    created_at timestamptz not null default now()
+);
+
+create table if not exists oauth_clients (
+    id serial primary key,
+    client_id text not null unique,
+    -- null for public clients, which have to use pkce
+    secret_hash text,
+    name text not null,
+    redirect_uris text[] not null,
+    scopes text[] not null,
+    first_party boolean not null default false,
+    owner_id integer references users (id) on delete cascade,
+    created_at timestamptz not null default now()
+);
+
+create table if not exists oauth_consents (
+    user_id integer not null references users (id) on delete cascade,
+    client_id text not null references oauth_clients (client_id) on delete cascade,
+    scopes text[] not null,
+    created_at timestamptz not null default now(),
+    primary key (user_id, client_id)
+);
+
+create table if not exists oauth_codes (
+    code_hash text primary key,
+    client_id text not null references oauth_clients (client_id) on delete cascade,
+    user_id integer not null references users (id) on delete cascade,
+    redirect_uri text not null,
+    scope text not null,
+    code_challenge text,
+    nonce text,
+    -- unix time the user signed in, for the id token
+    auth_time bigint not null,
+    expires_at timestamptz not null
+);
+
+create table if not exists oauth_refresh_tokens (
+    token_hash text primary key,
+    client_id text not null references oauth_clients (client_id) on delete cascade,
+    user_id integer not null references users (id) on delete cascade,
+    scope text not null,
+    auth_time bigint not null,
+    expires_at timestamptz not null,
+    revoked_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create table if not exists service_accounts (
+    id serial primary key,
+    client_id text not null unique,
+    name text not null,
+    -- one of these is set, depending on how the account authenticates
+    secret_hash text,
+    jwks text,
+    scopes text[] not null,
+    owner_id integer references users (id) on delete cascade,
+    created_at timestamptz not null default now()
+);
+
+create table if not exists api_keys (
+    id serial primary key,
+    user_id integer not null references users (id) on delete cascade,
+    name text not null,
+    -- lets us find a key without knowing its hash
+    prefix text not null unique,
+    key_hash text not null,
+    scopes text[] not null,
+    created_at timestamptz not null default now(),
+    last_used_at timestamptz,
+    revoked_at timestamptz
+);
+
+create table if not exists revoked_tokens (
+    jti text primary key,
+    -- when the token would have expired, after which the row can go
+    expires_at timestamptz not null
+);
+
+create table if not exists email_outbox (
+    id serial primary key,
+    to_address text not null,
+    subject text not null,
+    html text not null,
+    text text not null,
+    -- pending, sent or dead
+    status text not null default 'pending',
+    -- failed attempts so far
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    last_error text,
+    created_at timestamptz not null default now(),
+    sent_at timestamptz
+);
+CREATE INDEX IF NOT EXISTS email_outbox_due_idx ON email_outbox (next_attempt_at) WHERE status = 'pending';
+
+-- addresses that bounced, dropped or reported spam, which we don't mail anymore
+create table if not exists email_suppressions (
+    email text primary key,
+    event text not null,
+    reason text,
+    updated_at timestamptz not null default now()
+);
+
+-- devices users signed in from, so a new one can be pointed out to them
+create table if not exists known_devices (
+    user_id integer not null references users (id) on delete cascade,
+    -- hash of the ip and user agent
+    device_hash text not null,
+    created_at timestamptz not null default now(),
+    last_seen_at timestamptz not null default now(),
+    PRIMARY KEY (user_id, device_hash)
+);
+
+-- the "this wasn't me" links in security notices
+create table if not exists not_me_tokens (
+    token_hash text primary key,
+    user_id integer not null references users (id) on delete cascade,
+    expires_at timestamptz not null
+);
+
+CREATE OR REPLACE PROCEDURE add_user(e text, u text, p text)
+LANGUAGE SQL
+AS $$
+INSERT INTO users (email, username, password) VALUES (e, u, p)
+$$;
@@ -0,0 +1,4 @@
+-- when each account was made, and whether we know the email is theirs, for
+-- the user directory. accounts that already exist are dated from now
+alter table users add column if not exists created_at timestamptz not null default now();
+alter table users add column if not exists email_verified boolean not null default false;
@@ -0,0 +1,7 @@
+-- the profile users see and edit at /me, and when they last signed in
+alter table users add column if not exists display_name text;
+alter table users add column if not exists avatar_url text;
+alter table users add column if not exists locale text;
+alter table users add column if not exists timezone text;
+alter table users add column if not exists updated_at timestamptz;
+alter table users add column if not exists last_login_at timestamptz;
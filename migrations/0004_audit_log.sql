@@ -0,0 +1,26 @@
+-- the security audit log. it's append only: rows can be added but not
+-- changed or removed, and they outlive the users they're about
+create table if not exists audit_events (
+    id bigserial primary key,
+    occurred_at timestamptz not null default now(),
+    action text not null,
+    outcome text not null,
+    actor text,
+    subject text,
+    ip text,
+    user_agent text,
+    detail text
+);
+
+create index if not exists audit_events_subject on audit_events (subject, id);
+create index if not exists audit_events_occurred_at on audit_events (occurred_at);
+
+create or replace function audit_events_append_only() returns trigger as $$
+begin
+    raise exception 'audit events are append only';
+end;
+$$ language plpgsql;
+
+drop trigger if exists audit_events_append_only on audit_events;
+create trigger audit_events_append_only before update or delete on audit_events
+    for each row execute procedure audit_events_append_only();
@@ -0,0 +1,2 @@
+-- lets an operator stop a service account without deleting it
+alter table service_accounts add column if not exists disabled boolean not null default false;
@@ -0,0 +1,2 @@
+-- keys can be made to stop working on their own after a while
+alter table api_keys add column if not exists expires_at timestamptz;
@@ -0,0 +1,136 @@
+-- the same tables as the postgres schema. times are unix seconds and
+-- lists of scopes or redirect uris are json arrays
+create table users (
+    id integer primary key autoincrement,
+    email text not null unique,
+    username text not null unique,
+    password text not null,
+    disabled integer not null default 0,
+    tokens_valid_after integer,
+    admin integer not null default 0
+);
+
+create table user_identities (
+    id integer primary key autoincrement,
+    user_id integer not null references users (id) on delete cascade,
+    provider text not null,
+    subject text not null,
+    email text not null,
+    created_at integer not null default (unixepoch()),
+    unique (provider, subject)
+);
+
+create table oauth_logins (
+    state text primary key,
+    provider text not null,
+    nonce text not null,
+    code_verifier text not null,
+    created_at integer not null default (unixepoch())
+);
+
+create table oauth_clients (
+    id integer primary key autoincrement,
+    client_id text not null unique,
+    secret_hash text,
+    name text not null,
+    redirect_uris text not null,
+    scopes text not null,
+    first_party integer not null default 0,
+    owner_id integer references users (id) on delete cascade,
+    created_at integer not null default (unixepoch())
+);
+
+create table oauth_consents (
+    user_id integer not null references users (id) on delete cascade,
+    client_id text not null references oauth_clients (client_id) on delete cascade,
+    scopes text not null,
+    created_at integer not null default (unixepoch()),
+    primary key (user_id, client_id)
+);
+
+create table oauth_codes (
+    code_hash text primary key,
+    client_id text not null references oauth_clients (client_id) on delete cascade,
+    user_id integer not null references users (id) on delete cascade,
+    redirect_uri text not null,
+    scope text not null,
+    code_challenge text,
+    nonce text,
+    auth_time integer not null,
+    expires_at integer not null
+);
+
+create table oauth_refresh_tokens (
+    token_hash text primary key,
+    client_id text not null references oauth_clients (client_id) on delete cascade,
+    user_id integer not null references users (id) on delete cascade,
+    scope text not null,
+    auth_time integer not null,
+    expires_at integer not null,
+    revoked_at integer,
+    created_at integer not null default (unixepoch())
+);
+
+create table service_accounts (
+    id integer primary key autoincrement,
+    client_id text not null unique,
+    name text not null,
+    secret_hash text,
+    jwks text,
+    scopes text not null,
+    owner_id integer references users (id) on delete cascade,
+    created_at integer not null default (unixepoch())
+);
+
+create table api_keys (
+    id integer primary key autoincrement,
+    user_id integer not null references users (id) on delete cascade,
+    name text not null,
+    prefix text not null unique,
+    key_hash text not null,
+    scopes text not null,
+    created_at integer not null default (unixepoch()),
+    last_used_at integer,
+    revoked_at integer
+);
+
+create table revoked_tokens (
+    jti text primary key,
+    expires_at integer not null
+);
+
+create table email_outbox (
+    id integer primary key autoincrement,
+    to_address text not null,
+    subject text not null,
+    html text not null,
+    text text not null,
+    status text not null default 'pending',
+    attempts integer not null default 0,
+    next_attempt_at integer not null default (unixepoch()),
+    last_error text,
+    created_at integer not null default (unixepoch()),
+    sent_at integer
+);
+create index email_outbox_due_idx on email_outbox (next_attempt_at) where status = 'pending';
+
+create table email_suppressions (
+    email text primary key,
+    event text not null,
+    reason text,
+    updated_at integer not null default (unixepoch())
+);
+
+create table known_devices (
+    user_id integer not null references users (id) on delete cascade,
+    device_hash text not null,
+    created_at integer not null default (unixepoch()),
+    last_seen_at integer not null default (unixepoch()),
+    primary key (user_id, device_hash)
+);
+
+create table not_me_tokens (
+    token_hash text primary key,
+    user_id integer not null references users (id) on delete cascade,
+    expires_at integer not null
+);
@@ -0,0 +1,10 @@
+-- sqlite won't give an added column a default that isn't constant, so new
+-- users are dated by a trigger instead
+alter table users add column created_at integer not null default 0;
+alter table users add column email_verified integer not null default 0;
+update users set created_at = unixepoch();
+
+create trigger users_created_at after insert on users when new.created_at = 0
+begin
+    update users set created_at = unixepoch() where id = new.id;
+end;
@@ -0,0 +1,7 @@
+-- the profile users see and edit at /me, and when they last signed in
+alter table users add column display_name text;
+alter table users add column avatar_url text;
+alter table users add column locale text;
+alter table users add column timezone text;
+alter table users add column updated_at integer;
+alter table users add column last_login_at integer;
@@ -0,0 +1,26 @@
+-- the security audit log. it's append only: rows can be added but not
+-- changed or removed, and they outlive the users they're about
+create table audit_events (
+    id integer primary key autoincrement,
+    occurred_at integer not null default (unixepoch()),
+    action text not null,
+    outcome text not null,
+    actor text,
+    subject text,
+    ip text,
+    user_agent text,
+    detail text
+);
+
+create index audit_events_subject on audit_events (subject, id);
+create index audit_events_occurred_at on audit_events (occurred_at);
+
+create trigger audit_events_no_update before update on audit_events
+begin
+    select raise(abort, 'audit events are append only');
+end;
+
+create trigger audit_events_no_delete before delete on audit_events
+begin
+    select raise(abort, 'audit events are append only');
+end;
@@ -0,0 +1,5 @@
+-- mail with links that stop working is dropped once they have, instead of
+-- going out late or being replayed
+alter table email_outbox add column expires_at integer;
+-- sent mail keeps no bodies, the links in them are still live
+update email_outbox set html = '', text = '' where status = 'sent';
@@ -12,7 +12,9 @@ import SignIn from './pages/SignIn';
 import Users from './pages/Users';
 import Home from './pages/Home';
 import ForgotPassword from './pages/ForgotPassword';
+import NotMe from './pages/NotMe';
 import ResetPassword from './pages/ResetPassword';
+import Authorize from './pages/Authorize';
 
 import 'normalize.css';
 import './App.css';
@@ -77,6 +79,12 @@ function App() {
         <Route path="/reset-password">
           <ResetPassword authenticatedSet={authenticatedSet} />
         </Route>
+        <Route path="/authorize">
+          <Authorize />
+        </Route>
+        <Route path="/not-me">
+          <NotMe />
+        </Route>
       </Switch>
     </div>
   );
@@ -0,0 +1,83 @@
+import React, { useState, useEffect, useCallback } from 'react';
+import { Link, useLocation } from "react-router-dom";
+
+// the consent page oauth and openid connect clients send users to
+function Authorize() {
+    let location = useLocation();
+    const [prompt, promptSet] = useState(null);
+    const [error, errorSet] = useState("");
+
+    const decide = useCallback(async (approve) => {
+        let token = localStorage.getItem("authapp");
+        let request = Object.fromEntries(new URLSearchParams(location.search));
+
+        let res = await fetch(`/oauth/authorize`, {
+            method: 'POST',
+            body: JSON.stringify({ ...request, approve }),
+            headers: {
+                'Authorization': `Bearer ${token}`,
+                'Content-Type': 'application/json'
+            }
+        });
+        let json = await res.json();
+
+        if (json && json.type === "success") {
+            window.location.assign(json.data);
+        } else {
+            errorSet(json.data);
+        }
+    }, [location.search]);
+
+    useEffect(() => {
+        const getPrompt = async () => {
+            let token = localStorage.getItem("authapp");
+            if (!token) return;
+
+            let res = await fetch(`/oauth/authorize${location.search}`, {
+                method: 'GET',
+                headers: {
+                    'Authorization': `Bearer ${token}`
+                },
+            });
+            let json = await res.json();
+
+            if (json && json.type === "success") {
+                // no need to ask again for scopes the user already agreed to
+                if (!json.data.consent_required) {
+                    decide(true);
+                    return;
+                }
+                promptSet(json.data);
+            } else {
+                errorSet(json.data);
+            }
+        }
+        getPrompt();
+    }, [location.search, decide]);
+
+    if (!localStorage.getItem("authapp")) {
+        return (
+            <main id="authorize">
+                <p>Please <Link to="/sign-in">sign in</Link> first, then come back to this page.</p>
+            </main>
+        )
+    }
+
+    return (
+        <main id="authorize">
+            {error && <p className="error">{error}</p>}
+            {prompt &&
+                <div>
+                    <h1>{prompt.client_name} would like to:</h1>
+                    {prompt.scopes.map((scope, i) => {
+                        return <p key={`${i}`}><span>{scope}</span></p>
+                    })}
+                    <button onClick={() => decide(true)}>Allow</button>
+                    <button onClick={() => decide(false)}>Deny</button>
+                </div>
+            }
+        </main>
+    )
+}
+
+export default Authorize;
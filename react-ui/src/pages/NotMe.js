@@ -0,0 +1,51 @@
+import React, { useState, useEffect } from 'react';
+
+// the "this wasn't me" link from a security notice email
+function NotMe() {
+    const [done, doneSet] = useState(false);
+    const [error, errorSet] = useState("");
+
+    useEffect(() => {
+        let params = new URLSearchParams(window.location.search);
+        let token = params.get("token");
+        if (!token) {
+            errorSet("This link is missing its token.");
+            return;
+        }
+
+        const notMe = async () => {
+            let res = await fetch(`/auth/not-me`, {
+                method: 'POST',
+                body: JSON.stringify({ token }),
+                headers: {
+                    'Content-Type': 'application/json'
+                }
+            });
+
+            let json = await res.json();
+            if (json && json.type === "success") {
+                localStorage.removeItem('authapp');
+                doneSet(true);
+            } else if (json) {
+                errorSet(json.data);
+            }
+        }
+        notMe();
+    }, []);
+
+    return (
+        <main>
+            {done && <section>
+                <h1>You've been signed out everywhere.</h1>
+                <p>We've sent you an email with a link to reset your password.</p>
+            </section>}
+
+            {error && <section>
+                <h1>Something went wrong.</h1>
+                <p className="error">{error}</p>
+            </section>}
+        </main>
+    )
+}
+
+export default NotMe;
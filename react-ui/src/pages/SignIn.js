@@ -117,6 +117,19 @@ function SignIn(props) {
         }
     }, [authenticatedSet]);
 
+    useEffect(() => {
+        // server side oauth logins come back with the result in the url fragment
+        if (!window.location.hash) return;
+        let params = new URLSearchParams(window.location.hash.substring(1));
+        window.history.replaceState(null, "", window.location.pathname);
+
+        if (params.get("token")) {
+            checkJson({ type: "success", context: "oauth", data: params.get("token") });
+        } else if (params.get("error")) {
+            checkJson({ type: "error", context: "general", data: params.get("error") });
+        }
+    }, [checkJson]);
+
     useEffect(() => {
         const onGoogleSignIn = async (googleUser) => {
             let data = {
@@ -18,7 +18,7 @@ function Users() {
             let json = await res.json();
 
             if (json && json.type === "success") {
-                setUsers(json.data);
+                setUsers(json.data.users);
             } else {
                 history.push("/sign-in");
             }
@@ -29,8 +29,8 @@ function Users() {
     return (
         <main id="users">
             <h1>Users</h1>
-            {users.map((item, i) => {
-                return <p key={`${i}`}><span>{item}</span></p>
+            {users.map((item) => {
+                return <p key={`${item.id}`}><span>{item.username}</span></p>
             })}
         </main>
     )
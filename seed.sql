@@ -0,0 +1,10 @@
+-- users for local development, load them after migrating with
+-- psql "$TSDB_URL" -f seed.sql
+insert into users (email, username, password, admin) values
+('a', 'a', '$argon2i$v=19$m=4096,t=3,p=1$cmFuZG9tK3NhbHQ$5gYGvSfsiNtuQ1hjAQMf1xlU9rjfFSuLGcb/eB95xjg', true)
+on conflict do nothing;
+
+insert into users (email, username, password) values
+('c@c.com', 'c', 'c'),
+('d@d.dom', 'd', 'd')
+on conflict do nothing;
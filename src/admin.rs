@@ -0,0 +1,282 @@
+use crate::audit::{self, AuditAction, AuditEvent, OPERATOR};
+use crate::auth::{Auth, User};
+use crate::crypto;
+use crate::error::AuthError;
+use crate::oidc_issuer::UserInfo;
+use crate::outbox;
+use crate::store::Store;
+use crate::templates::{Templates, DEFAULT_LOCALE};
+use serde::Serialize;
+
+// roles that can be granted to a user
+pub static ROLES: [&str; 1] = ["admin"];
+
+// everything an operator wants to know about an account
+#[derive(Serialize)]
+pub struct Account {
+    pub id: i32,
+    pub email: String,
+    pub username: String,
+    pub disabled: bool,
+    pub admin: bool,
+    pub tokens_valid_after: Option<i64>,
+    // providers of the linked external identities
+    pub identities: Vec<String>,
+}
+
+fn no_such_user(username: &str) -> AuthError {
+    AuthError::new_general(
+        "That user doesn't exist.",
+        &format!("user {} doesn't exist", username),
+        404,
+    )
+}
+
+async fn get_user(db: &Store, username: &str) -> Result<UserInfo, AuthError> {
+    db.get_user_info(username)
+        .await?
+        .ok_or_else(|| no_such_user(username))
+}
+
+// makes sure a statement that targets one user found them
+fn expect_user(num: u64, username: &str) -> Result<(), AuthError> {
+    match num {
+        0 => Err(no_such_user(username)),
+        _ => Ok(()),
+    }
+}
+
+// creates a user the same way signing up does
+pub async fn create_user(
+    db: &Store,
+    auth: &Auth,
+    email: &str,
+    username: &str,
+    password: &str,
+) -> Result<(), AuthError> {
+    let mut user = User::new(email, username, password);
+    user.is_valid_signup()?;
+    let hashed_password = auth.hash_password(&user.password).await?;
+    user.set_password(&hashed_password);
+    db.add_user(&user).await?;
+    audit::record(
+        db,
+        AuditEvent::new(AuditAction::SignUp, username).by(OPERATOR),
+    )
+    .await;
+    Ok(())
+}
+
+pub async fn get_account(db: &Store, username: &str) -> Result<Account, AuthError> {
+    db.get_account(username)
+        .await?
+        .ok_or_else(|| no_such_user(username))
+}
+
+// a disabled user can't sign in, and the tokens they have stop working
+pub async fn set_disabled(db: &Store, username: &str, disabled: bool) -> Result<(), AuthError> {
+    expect_user(db.set_user_disabled(username, disabled).await?, username)?;
+    let action = if disabled {
+        AuditAction::AccountDisabled
+    } else {
+        AuditAction::AccountEnabled
+    };
+    audit::record(db, AuditEvent::new(action, username).by(OPERATOR)).await;
+    Ok(())
+}
+
+// a disabled service account can't get tokens, and the ones it has stop working
+pub async fn set_service_account_disabled(
+    db: &Store,
+    client_id: &str,
+    disabled: bool,
+) -> Result<(), AuthError> {
+    match db.set_service_account_disabled(client_id, disabled).await? {
+        0 => Err(AuthError::new_general(
+            "That service account doesn't exist.",
+            &format!("service account {} doesn't exist", client_id),
+            404,
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub async fn set_role(
+    db: &Store,
+    username: &str,
+    role: &str,
+    granted: bool,
+) -> Result<(), AuthError> {
+    match role {
+        "admin" => expect_user(db.set_user_admin(username, granted).await?, username)?,
+        _ => {
+            return Err(AuthError::new_general(
+                "That role doesn't exist.",
+                &format!(
+                    "{} isn't a role, it should be one of {}",
+                    role,
+                    ROLES.join(", ")
+                ),
+                400,
+            ))
+        }
+    }
+
+    let action = if granted {
+        AuditAction::RoleGranted
+    } else {
+        AuditAction::RoleRevoked
+    };
+    let event = AuditEvent::new(action, username).by(OPERATOR).detail(role);
+    audit::record(db, event).await;
+    Ok(())
+}
+
+pub async fn revoke_sessions(db: &Store, username: &str) -> Result<(), AuthError> {
+    expect_user(db.revoke_sessions(username).await?, username)?;
+    let event = AuditEvent::new(AuditAction::SessionsRevoked, username).by(OPERATOR);
+    audit::record(db, event).await;
+    Ok(())
+}
+
+// for an account that may be compromised: the old password stops working,
+// the user is signed out everywhere and sent a link to pick a new one
+pub async fn force_password_reset(
+    db: &Store,
+    auth: &Auth,
+    templates: &Templates,
+    username: &str,
+) -> Result<(), AuthError> {
+    let user = get_user(db, username).await?;
+
+    // a password nobody knows
+    let unusable = auth.hash_password(&crypto::random_token(32)).await?;
+    db.update_user_password(username, &unusable).await?;
+    db.revoke_sessions(username).await?;
+    let event = AuditEvent::new(AuditAction::SessionsRevoked, username)
+        .by(OPERATOR)
+        .detail("password reset forced");
+    audit::record(db, event).await;
+
+    let token = auth.create_token(username, auth.lifetimes.reset)?;
+    let email = templates.reset_password(DEFAULT_LOCALE, &user.email, username, &token)?;
+    let expires_at = outbox::expires_in(auth.lifetimes.reset.seconds());
+    outbox::enqueue(db, &email, expires_at).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+
+    fn auth() -> Auth {
+        Auth::new(
+            "secretsecretsecret".to_owned(),
+            "0123456789abcdef".to_owned(),
+        )
+    }
+
+    // the actions recorded for username, oldest first, all of which auth-admin did
+    async fn operator_actions(db: &Store, username: &str) -> Vec<String> {
+        let activity = audit::recent_activity(db, username).await.unwrap();
+        assert!(activity
+            .iter()
+            .all(|event| event.actor.as_deref() == Some(OPERATOR)));
+        activity
+            .into_iter()
+            .rev()
+            .map(|event| event.action)
+            .collect()
+    }
+
+    #[test]
+    fn created_users_can_be_disabled_and_enabled() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            let auth = auth();
+            create_user(&db, &auth, "ann@example.com", "ann", "a long password")
+                .await
+                .unwrap();
+            assert!(
+                create_user(&db, &auth, "ann@example.com", "ann", "a long password")
+                    .await
+                    .is_err()
+            );
+
+            let account = get_account(&db, "ann").await.unwrap();
+            assert_eq!(account.email, "ann@example.com");
+            assert!(!account.disabled && !account.admin);
+
+            set_disabled(&db, "ann", true).await.unwrap();
+            assert!(get_account(&db, "ann").await.unwrap().disabled);
+            set_disabled(&db, "ann", false).await.unwrap();
+            assert!(!get_account(&db, "ann").await.unwrap().disabled);
+
+            let err = set_disabled(&db, "nobody", true).await.unwrap_err();
+            assert_eq!(err.status(), 404);
+            assert_eq!(
+                operator_actions(&db, "ann").await,
+                vec!["sign_up", "account_disabled", "account_enabled"]
+            );
+            assert!(operator_actions(&db, "nobody").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn roles_are_granted_and_revoked() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            db.add_user(&User::new("ann@example.com", "ann", "hash"))
+                .await
+                .unwrap();
+
+            set_role(&db, "ann", "admin", true).await.unwrap();
+            assert!(db.is_admin("ann").await.unwrap());
+            set_role(&db, "ann", "admin", false).await.unwrap();
+            assert!(!db.is_admin("ann").await.unwrap());
+
+            let err = set_role(&db, "ann", "owner", true).await.unwrap_err();
+            assert_eq!(err.status(), 400);
+            let err = set_role(&db, "nobody", "admin", true).await.unwrap_err();
+            assert_eq!(err.status(), 404);
+
+            assert_eq!(
+                operator_actions(&db, "ann").await,
+                vec!["role_granted", "role_revoked"]
+            );
+            let activity = audit::recent_activity(&db, "ann").await.unwrap();
+            assert!(activity
+                .iter()
+                .all(|event| event.detail.as_deref() == Some("admin")));
+        });
+    }
+
+    #[test]
+    fn revoking_sessions_ends_the_tokens_a_user_has() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            db.add_user(&User::new("ann@example.com", "ann", "hash"))
+                .await
+                .unwrap();
+            assert!(get_account(&db, "ann")
+                .await
+                .unwrap()
+                .tokens_valid_after
+                .is_none());
+
+            revoke_sessions(&db, "ann").await.unwrap();
+            assert!(get_account(&db, "ann")
+                .await
+                .unwrap()
+                .tokens_valid_after
+                .is_some());
+
+            let err = revoke_sessions(&db, "nobody").await.unwrap_err();
+            assert_eq!(err.status(), 404);
+            assert_eq!(operator_actions(&db, "ann").await, vec!["sessions_revoked"]);
+        });
+    }
+}
@@ -0,0 +1,284 @@
+use crate::auth::Claims;
+use crate::crypto;
+use crate::error::AuthError;
+use crate::store::Store;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// scopes that protect our own api, the others only mean something to oauth clients
+pub static API_KEY_SCOPES: [&str; 1] = ["users:read"];
+
+// keys look like ak_<prefix>_<secret>, the prefix finds the key without its hash
+static KEY_PREFIX: &str = "ak_";
+
+// the longest a key that expires can be made to last
+static MAX_DAYS: i64 = 365;
+
+#[derive(Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    // left out for a key that works until it's revoked
+    #[serde(default)]
+    pub expires_in_days: Option<i64>,
+}
+
+// a key as it's listed, without anything that would let it be used
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub id: i32,
+    // only ever shown here, we just keep its hash
+    pub key: String,
+}
+
+// the user and scopes a key was used for
+pub struct ApiKeyGrant {
+    pub username: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(KEY_PREFIX)
+}
+
+fn prefix_of(key: &str) -> Option<&str> {
+    key.get(KEY_PREFIX.len()..)?.split('_').next()
+}
+
+pub async fn create(db: &Store, user_id: i32, key: &NewApiKey) -> Result<CreatedApiKey, AuthError> {
+    if key.name.trim().is_empty() {
+        return Err(AuthError::new_general(
+            "Please give the key a name.",
+            "",
+            400,
+        ));
+    }
+    for scope in &key.scopes {
+        if !API_KEY_SCOPES.contains(&scope.as_str()) {
+            return Err(AuthError::new_general(
+                &format!("{} isn't a scope api keys can have.", scope),
+                "",
+                400,
+            ));
+        }
+    }
+
+    let expires_at = match key.expires_in_days {
+        Some(days) if !(1..=MAX_DAYS).contains(&days) => {
+            return Err(AuthError::new_general(
+                &format!("Keys can last between 1 and {} days.", MAX_DAYS),
+                "",
+                400,
+            ))
+        }
+        Some(days) => Some(Utc::now().timestamp() + days * 24 * 60 * 60),
+        None => None,
+    };
+
+    // base64url can contain underscores, which would break up the prefix
+    let prefix = crypto::random_token(6).replace('_', "-");
+    let secret = format!("{}{}_{}", KEY_PREFIX, prefix, crypto::random_token(32));
+    let id = db
+        .add_api_key(
+            user_id,
+            key.name.trim(),
+            &prefix,
+            &crypto::hash_token(&secret),
+            &key.scopes,
+            expires_at,
+        )
+        .await?;
+
+    Ok(CreatedApiKey { id, key: secret })
+}
+
+// the claims a key stands in for. they're never first party,
+// so a key can't manage keys, clients or the user's password
+pub async fn authenticate(db: &Store, key: &str) -> Result<Claims, AuthError> {
+    let invalid = || {
+        AuthError::new(
+            "auth",
+            "Please log in or sign up to access this resource.",
+            "api key is invalid or revoked",
+            401,
+        )
+    };
+
+    let prefix = prefix_of(key).ok_or_else(invalid)?;
+    let grant = db
+        .use_api_key(prefix, &crypto::hash_token(key))
+        .await?
+        .ok_or_else(invalid)?;
+    Ok(Claims::for_api_key(
+        &grant.username,
+        &grant.prefix,
+        &grant.scopes.join(" "),
+        grant.created_at,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Auth, User};
+    use crate::runtime::Runtime;
+    use crate::session;
+    use crate::sqlite_store::SqliteStore;
+
+    #[test]
+    fn prefix_of_finds_lookup_prefix() {
+        assert_eq!(
+            prefix_of("ak_abc-12_secret_with_underscores"),
+            Some("abc-12")
+        );
+        assert!(is_api_key("ak_abc-12_secret"));
+        assert!(!is_api_key("eyJhbGciOiJIUzI1NiJ9.e30.x"));
+    }
+
+    fn new_key(expires_in_days: Option<i64>) -> NewApiKey {
+        NewApiKey {
+            name: "ci".to_owned(),
+            scopes: vec!["users:read".to_owned()],
+            expires_in_days,
+        }
+    }
+
+    async fn ann(db: &Store) -> i32 {
+        db.add_user(&User::new("ann@example.com", "ann", "hash"))
+            .await
+            .unwrap();
+        db.get_user_id("ann").await.unwrap()
+    }
+
+    #[test]
+    fn keys_authenticate_as_their_user_until_revoked() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            let user_id = ann(&db).await;
+            let created = create(&db, user_id, &new_key(Some(30))).await.unwrap();
+
+            let claims = authenticate(&db, &created.key).await.unwrap();
+            assert_eq!(claims.sub, "ann");
+            assert!(claims.has_scope("users:read"));
+            assert!(!claims.is_first_party());
+
+            // the right prefix with another secret is someone guessing
+            let prefix = prefix_of(&created.key).unwrap();
+            let guessed = format!("{}{}_{}", KEY_PREFIX, prefix, crypto::random_token(32));
+            assert_eq!(authenticate(&db, &guessed).await.unwrap_err().status(), 401);
+
+            // only the hash is kept, the key itself doesn't find anything
+            assert!(db
+                .use_api_key(prefix, &created.key)
+                .await
+                .unwrap()
+                .is_none());
+            assert!(db
+                .use_api_key(prefix, &crypto::hash_token(&created.key))
+                .await
+                .unwrap()
+                .is_some());
+            let listed = db.get_api_keys(user_id).await.unwrap();
+            assert!(listed[0].last_used_at.is_some());
+            assert!(listed[0].expires_at.is_some());
+
+            db.revoke_api_key(user_id, created.id).await.unwrap();
+            assert_eq!(
+                authenticate(&db, &created.key).await.unwrap_err().status(),
+                401
+            );
+        });
+    }
+
+    #[test]
+    fn expired_keys_stop_working() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            let user_id = ann(&db).await;
+            assert!(create(&db, user_id, &new_key(Some(0))).await.is_err());
+
+            let key = format!("{}abcdef_{}", KEY_PREFIX, crypto::random_token(32));
+            let yesterday = Utc::now().timestamp() - 24 * 60 * 60;
+            db.add_api_key(
+                user_id,
+                "old",
+                "abcdef",
+                &crypto::hash_token(&key),
+                &["users:read".to_owned()],
+                Some(yesterday),
+            )
+            .await
+            .unwrap();
+            assert_eq!(authenticate(&db, &key).await.unwrap_err().status(), 401);
+        });
+    }
+
+    #[test]
+    fn signing_out_everywhere_ends_keys_made_before() {
+        let path = std::env::temp_dir().join("auth-app-api-key-sign-out.sqlite");
+        let path = path.to_str().unwrap();
+        // sqlite keeps the wal next to the database
+        let files = [
+            path.to_owned(),
+            format!("{}-wal", path),
+            format!("{}-shm", path),
+        ];
+        let remove_files = || {
+            for file in &files {
+                let _ = std::fs::remove_file(file);
+            }
+        };
+        remove_files();
+
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let auth = Auth::new(
+                "secretsecretsecret".to_owned(),
+                "0123456789abcdef".to_owned(),
+            );
+            let db = Store::new(SqliteStore::open(path).unwrap());
+            let user_id = ann(&db).await;
+            let created = create(&db, user_id, &new_key(None)).await.unwrap();
+
+            // the key was made a minute ago
+            let conn = rusqlite::Connection::open(path).unwrap();
+            conn.execute("UPDATE api_keys SET created_at = unixepoch() - 60", [])
+                .unwrap();
+            let claims = session::authenticate(&auth, &db, &created.key)
+                .await
+                .unwrap();
+            assert_eq!(claims.sub, "ann");
+
+            db.revoke_sessions("ann").await.unwrap();
+            assert_eq!(
+                session::authenticate(&auth, &db, &created.key)
+                    .await
+                    .unwrap_err()
+                    .status(),
+                401
+            );
+
+            // a key made afterwards works
+            let created = create(&db, user_id, &new_key(None)).await.unwrap();
+            assert!(session::authenticate(&auth, &db, &created.key)
+                .await
+                .is_ok());
+        });
+        remove_files();
+    }
+}
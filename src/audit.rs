@@ -0,0 +1,423 @@
+use crate::directory::{where_clause, SqlParam};
+use crate::error::AuthError;
+use crate::logging;
+use crate::store::Store;
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+// the security audit log: who signed up, signed in or failed to, reset a
+// password, changed a role or ended sessions, from where and whether it
+// worked. it's only ever added to, the tables refuse updates and deletes.
+// admins search all of it, users see what happened to their own account
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+// how much of their own activity a user is shown
+const ACTIVITY_LIMIT: i64 = 20;
+
+// the actor for changes made with auth-admin, by whoever can run it on the server
+pub const OPERATOR: &str = "auth-admin";
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    SignUp,
+    SignIn,
+    // google, an openid connect provider or an oauth one. the detail says which
+    ExternalSignIn,
+    PasswordResetRequested,
+    PasswordReset,
+    RoleGranted,
+    RoleRevoked,
+    // one token was signed out
+    SessionRevoked,
+    // every token the user had was
+    SessionsRevoked,
+    AccountDisabled,
+    AccountEnabled,
+}
+
+static ACTIONS: &[AuditAction] = &[
+    AuditAction::SignUp,
+    AuditAction::SignIn,
+    AuditAction::ExternalSignIn,
+    AuditAction::PasswordResetRequested,
+    AuditAction::PasswordReset,
+    AuditAction::RoleGranted,
+    AuditAction::RoleRevoked,
+    AuditAction::SessionRevoked,
+    AuditAction::SessionsRevoked,
+    AuditAction::AccountDisabled,
+    AuditAction::AccountEnabled,
+];
+
+impl AuditAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::SignUp => "sign_up",
+            AuditAction::SignIn => "sign_in",
+            AuditAction::ExternalSignIn => "external_sign_in",
+            AuditAction::PasswordResetRequested => "password_reset_requested",
+            AuditAction::PasswordReset => "password_reset",
+            AuditAction::RoleGranted => "role_granted",
+            AuditAction::RoleRevoked => "role_revoked",
+            AuditAction::SessionRevoked => "session_revoked",
+            AuditAction::SessionsRevoked => "sessions_revoked",
+            AuditAction::AccountDisabled => "account_disabled",
+            AuditAction::AccountEnabled => "account_enabled",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<AuditAction> {
+        ACTIONS.iter().copied().find(|a| a.as_str() == action)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+        }
+    }
+
+    pub fn parse(outcome: &str) -> Option<Outcome> {
+        match outcome {
+            "success" => Some(Outcome::Success),
+            "failure" => Some(Outcome::Failure),
+            _ => None,
+        }
+    }
+}
+
+// something that happened, as it's written to the log
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    pub action: AuditAction,
+    pub outcome: Outcome,
+    // who did it, none when they weren't signed in
+    pub actor: Option<String>,
+    // the account it happened to. the email that was tried when no account has it
+    pub subject: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    // the provider, the role, or why it failed
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    // a success that happened to subject, done by nobody in particular
+    pub fn new(action: AuditAction, subject: &str) -> AuditEvent {
+        AuditEvent {
+            action,
+            outcome: Outcome::Success,
+            actor: None,
+            subject: non_empty(subject),
+            ip: None,
+            user_agent: None,
+            detail: None,
+        }
+    }
+
+    pub fn by(mut self, actor: &str) -> AuditEvent {
+        self.actor = non_empty(actor);
+        self
+    }
+
+    pub fn from(mut self, ip: &str, user_agent: &str) -> AuditEvent {
+        self.ip = non_empty(ip);
+        self.user_agent = non_empty(user_agent);
+        self
+    }
+
+    pub fn detail(mut self, detail: &str) -> AuditEvent {
+        self.detail = non_empty(detail);
+        self
+    }
+
+    pub fn failed(mut self, reason: &str) -> AuditEvent {
+        self.outcome = Outcome::Failure;
+        self.detail(reason)
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    match value {
+        "" => None,
+        value => Some(value.to_owned()),
+    }
+}
+
+// an event once it's in the log
+#[derive(Serialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub id: i64,
+    // unix seconds
+    pub occurred_at: i64,
+    // as the action and outcome serialize
+    pub action: String,
+    pub outcome: String,
+    pub actor: Option<String>,
+    pub subject: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub detail: Option<String>,
+}
+
+// the query string of the admin search, as sent
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct AuditParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    // success or failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    // rfc 3339 times
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i64>,
+    // the id the last page ended at, newer events come first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<i64>,
+}
+
+// what the stores filter on, every field has to match
+#[derive(Clone, Default, Debug)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub outcome: Option<Outcome>,
+    pub actor: Option<String>,
+    pub subject: Option<String>,
+    pub ip: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub before: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditRecord>,
+    // the url of the next page, when there is one
+    pub next: Option<String>,
+}
+
+fn bad_param(name: &str, problem: &str) -> AuthError {
+    AuthError::new(
+        "audit",
+        &format!("The {} parameter {}.", name, problem),
+        "",
+        400,
+    )
+}
+
+fn time(name: &str, value: &Option<String>) -> Result<Option<i64>, AuthError> {
+    match value {
+        Some(value) => match DateTime::parse_from_rfc3339(value) {
+            Ok(time) => Ok(Some(time.timestamp())),
+            Err(_) => Err(bad_param(name, "should be an rfc 3339 time")),
+        },
+        None => Ok(None),
+    }
+}
+
+impl AuditQuery {
+    pub fn parse(params: &AuditParams) -> Result<(AuditQuery, i64), AuthError> {
+        let action = match params.action {
+            Some(ref action) => Some(
+                AuditAction::parse(action).ok_or_else(|| bad_param("action", "isn't an action"))?,
+            ),
+            None => None,
+        };
+        let outcome = match params.outcome {
+            Some(ref outcome) => Some(
+                Outcome::parse(outcome)
+                    .ok_or_else(|| bad_param("outcome", "should be success or failure"))?,
+            ),
+            None => None,
+        };
+        let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(bad_param(
+                "limit",
+                &format!("should be between 1 and {}", MAX_LIMIT),
+            ));
+        }
+
+        let query = AuditQuery {
+            action,
+            outcome,
+            actor: params.actor.clone(),
+            subject: params.subject.clone(),
+            ip: params.ip.clone(),
+            since: time("since", &params.since)?,
+            until: time("until", &params.until)?,
+            before: params.before,
+        };
+        Ok((query, limit))
+    }
+
+    // just the events that happened to this user
+    pub fn subject(username: &str) -> AuditQuery {
+        AuditQuery {
+            subject: Some(username.to_owned()),
+            ..AuditQuery::default()
+        }
+    }
+
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        let same = |want: &Option<String>, got: &Option<String>| {
+            want.is_none() || want.as_deref() == got.as_deref()
+        };
+        self.action
+            .is_none_or(|action| action.as_str() == record.action)
+            && self
+                .outcome
+                .is_none_or(|outcome| outcome.as_str() == record.outcome)
+            && same(&self.actor, &record.actor)
+            && same(&self.subject, &record.subject)
+            && same(&self.ip, &record.ip)
+            && self.since.is_none_or(|at| record.occurred_at >= at)
+            && self.until.is_none_or(|at| record.occurred_at < at)
+            && self.before.is_none_or(|id| record.id < id)
+    }
+
+    // the where clause the postgres and sqlite stores share, newest first.
+    // occurred is how the backend writes occurred_at as unix seconds and
+    // placeholder how it writes the nth parameter
+    pub fn to_sql(
+        &self,
+        occurred: &str,
+        placeholder: fn(usize) -> String,
+    ) -> (String, Vec<SqlParam>) {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<SqlParam> = Vec::new();
+        let mut push = |column: &str, op: &str, param: SqlParam| {
+            params.push(param);
+            conditions.push(format!("{} {} {}", column, op, placeholder(params.len())));
+        };
+
+        if let Some(action) = self.action {
+            push("action", "=", SqlParam::Text(action.as_str().to_owned()));
+        }
+        if let Some(outcome) = self.outcome {
+            push("outcome", "=", SqlParam::Text(outcome.as_str().to_owned()));
+        }
+        for (column, value) in &[
+            ("actor", &self.actor),
+            ("subject", &self.subject),
+            ("ip", &self.ip),
+        ] {
+            if let Some(value) = value {
+                push(column, "=", SqlParam::Text(value.clone()));
+            }
+        }
+        if let Some(at) = self.since {
+            push(occurred, ">=", SqlParam::Int(at));
+        }
+        if let Some(at) = self.until {
+            push(occurred, "<", SqlParam::Int(at));
+        }
+        if let Some(id) = self.before {
+            push("id", "<", SqlParam::Int(id));
+        }
+        (where_clause(&conditions), params)
+    }
+}
+
+// an event that can't be written shouldn't fail what it's about, but it
+// shouldn't go unnoticed either
+pub async fn record(db: &Store, event: AuditEvent) {
+    if let Err(err) = db.add_audit_event(&event).await {
+        tracing::error!(
+            action = event.action.as_str(),
+            subject = %logging::redact(event.subject.as_deref().unwrap_or("")),
+            error = %logging::redact(&err.to_string()),
+            "couldn't write an audit event"
+        );
+    }
+}
+
+pub async fn search(db: &Store, params: &AuditParams, path: &str) -> Result<AuditPage, AuthError> {
+    let (query, limit) = AuditQuery::parse(params)?;
+    // one more than asked for says whether there's another page
+    let mut events = db.list_audit_events(&query, limit + 1).await?;
+
+    let mut next = None;
+    if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        next = events.last().map(|last| {
+            let mut params = params.clone();
+            params.before = Some(last.id);
+            let query = serde_urlencoded::to_string(&params).unwrap_or_default();
+            format!("{}?{}", path, query)
+        });
+    }
+    Ok(AuditPage { events, next })
+}
+
+// the latest events that happened to the user's account
+pub async fn recent_activity(db: &Store, username: &str) -> Result<Vec<AuditRecord>, AuthError> {
+    db.list_audit_events(&AuditQuery::subject(username), ACTIVITY_LIMIT)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(query: &str) -> AuditParams {
+        serde_urlencoded::from_str(query).unwrap()
+    }
+
+    #[test]
+    fn bad_params_are_rejected() {
+        assert!(AuditQuery::parse(&params("action=delete_everything")).is_err());
+        assert!(AuditQuery::parse(&params("outcome=maybe")).is_err());
+        assert!(AuditQuery::parse(&params("limit=500")).is_err());
+        assert!(AuditQuery::parse(&params("since=yesterday")).is_err());
+
+        let (query, limit) =
+            AuditQuery::parse(&params("action=sign_in&outcome=failure&before=9")).unwrap();
+        assert_eq!(query.action, Some(AuditAction::SignIn));
+        assert_eq!(query.outcome, Some(Outcome::Failure));
+        assert_eq!(limit, DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn every_action_round_trips() {
+        for action in ACTIONS {
+            assert_eq!(AuditAction::parse(action.as_str()), Some(*action));
+            assert_eq!(serde_json::to_value(action).unwrap(), action.as_str());
+        }
+    }
+
+    #[test]
+    fn sql_numbers_the_filters_in_order() {
+        let (query, _) = AuditQuery::parse(&params(
+            "subject=ann&outcome=failure&since=2020-01-01T00:00:00Z&before=7",
+        ))
+        .unwrap();
+        let (filter, params) = query.to_sql("occurred", |n| format!("${}", n));
+        assert_eq!(
+            filter,
+            "WHERE outcome = $1 AND subject = $2 AND occurred >= $3 AND id < $4"
+        );
+        assert_eq!(params.len(), 4);
+    }
+}
@@ -1,11 +1,13 @@
+use crate::crypto;
 use crate::error::AuthError;
+use crate::hash_pool::HashPool;
 use argon2::{self, Config};
 use chrono::{Duration, Utc};
 use jsonwebtoken as jwt;
 use jwt::{decode, encode, Header, Validation};
 use serde::{Deserialize, Serialize};
 
-pub static AUTH_APP: &'static str = "Auth App";
+pub static AUTH_APP: &str = "Auth App";
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct User {
@@ -30,7 +32,7 @@ impl User {
     }
 
     pub fn is_valid_email(&self, context: &str) -> Result<(), AuthError> {
-        if self.email == "" {
+        if self.email.is_empty() {
             return Err(AuthError::new(
                 &format!("{}{}", context, "Email"),
                 "Please enter your email.",
@@ -42,7 +44,7 @@ impl User {
     }
 
     pub fn is_valid_username(&self) -> Result<(), AuthError> {
-        if self.username == "" {
+        if self.username.is_empty() {
             return Err(AuthError::new(
                 "username",
                 "Please enter a username.",
@@ -54,7 +56,7 @@ impl User {
     }
 
     pub fn is_valid_password(&self, context: &str) -> Result<(), AuthError> {
-        if self.password == "" {
+        if self.password.is_empty() {
             return Err(AuthError::new(
                 &format!("{}{}", context, "Password"),
                 "Please enter a password.",
@@ -77,33 +79,151 @@ impl User {
     }
 }
 
+// an identity asserted by an outside provider (google, an oidc provider, ...)
+// that gets linked to one of our users
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub username: String,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    pub locale: Option<String>,
+}
+
+// who an external identity signed in as
+pub struct ExternalSignIn {
+    pub username: String,
+    // set when the identity was just linked to an account that already existed
+    pub linked_existing: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // subject
     iss: String,     // issuer
     exp: usize,      // expiration (time)
     nbf: usize,      // not before (time)
+    // lets a single token be revoked before it expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+    // set on tokens issued to oauth clients, which are limited to their scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    // set when the subject is a service account rather than a user
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub service: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum TokenDuration {
     Weeks2,
     Hours24,
+    Hours1,
     Minutes5,
+    Seconds(i64),
+}
+
+impl TokenDuration {
+    pub fn seconds(&self) -> i64 {
+        match self {
+            TokenDuration::Weeks2 => Duration::weeks(2).num_seconds(),
+            TokenDuration::Hours24 => Duration::hours(24).num_seconds(),
+            TokenDuration::Hours1 => Duration::hours(1).num_seconds(),
+            TokenDuration::Minutes5 => Duration::minutes(5).num_seconds(),
+            TokenDuration::Seconds(seconds) => *seconds,
+        }
+    }
+}
+
+// how long each kind of token we issue lasts
+#[derive(Clone, Copy, Debug)]
+pub struct TokenLifetimes {
+    // what signing in gives you
+    pub session: TokenDuration,
+    // the links in password reset emails
+    pub reset: TokenDuration,
+    // access tokens for oauth clients and service accounts
+    pub access: TokenDuration,
+}
+
+impl Default for TokenLifetimes {
+    fn default() -> TokenLifetimes {
+        TokenLifetimes {
+            session: TokenDuration::Weeks2,
+            reset: TokenDuration::Minutes5,
+            access: TokenDuration::Hours1,
+        }
+    }
 }
 
 impl Claims {
     pub fn new(username: String, duration: TokenDuration) -> Claims {
-        let exp = match duration {
-            TokenDuration::Weeks2 => (Utc::now() + Duration::weeks(2)).timestamp() as usize,
-            TokenDuration::Hours24 => (Utc::now() + Duration::hours(24)).timestamp() as usize,
-            TokenDuration::Minutes5 => (Utc::now() + Duration::minutes(5)).timestamp() as usize,
-        };
+        let exp = (Utc::now().timestamp() + duration.seconds()) as usize;
 
         Claims {
             sub: username,
             iss: AUTH_APP.to_owned(),
             nbf: Utc::now().timestamp() as usize,
             exp,
+            jti: Some(crypto::random_token(16)),
+            client_id: None,
+            scope: None,
+            service: false,
+        }
+    }
+
+    // when the token was issued, i.e. when the user signed in for session tokens
+    pub fn issued_at(&self) -> i64 {
+        self.nbf as i64
+    }
+
+    pub fn expires_at(&self) -> i64 {
+        self.exp as i64
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.iss
+    }
+
+    // tokens issued before jtis were added don't have one
+    pub fn jti(&self) -> Option<&str> {
+        self.jti.as_deref()
+    }
+
+    // stands in for a token when a request is made with an api key,
+    // which is limited to its scopes like an oauth client. it counts as
+    // issued when the key was made, so signing out everywhere ends it too
+    pub fn for_api_key(username: &str, prefix: &str, scope: &str, created_at: i64) -> Claims {
+        let mut claims = Claims::new(username.to_owned(), TokenDuration::Minutes5);
+        claims.nbf = created_at as usize;
+        claims.jti = None;
+        claims.client_id = Some(format!("api-key:{}", prefix));
+        claims.scope = Some(scope.to_owned());
+        claims
+    }
+
+    // tokens from our own sign in have no client and can do anything the user can
+    pub fn is_first_party(&self) -> bool {
+        self.client_id.is_none()
+    }
+
+    pub fn is_service(&self) -> bool {
+        self.service
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.scope {
+            Some(scopes) => scopes.split(' ').any(|s| s == scope),
+            None => self.is_first_party(),
         }
     }
 }
@@ -112,11 +232,47 @@ impl Claims {
 pub struct Auth {
     jwt_secret: String,
     salt: String,
+    pub lifetimes: TokenLifetimes,
+    hash_pool: HashPool,
 }
 
 impl Auth {
     pub fn new(jwt_secret: String, salt: String) -> Auth {
-        Auth { jwt_secret, salt }
+        Auth {
+            jwt_secret,
+            salt,
+            lifetimes: TokenLifetimes::default(),
+            hash_pool: HashPool::default(),
+        }
+    }
+
+    pub fn with_lifetimes(mut self, lifetimes: TokenLifetimes) -> Auth {
+        self.lifetimes = lifetimes;
+        self
+    }
+
+    pub fn with_hash_pool(mut self, hash_pool: HashPool) -> Auth {
+        self.hash_pool = hash_pool;
+        self
+    }
+
+    pub fn hash_pool(&self) -> &HashPool {
+        &self.hash_pool
+    }
+
+    // hashes on the hash pool, a 503 when it's too busy
+    pub async fn hash_password(&self, password: &str) -> Result<String, AuthError> {
+        let hasher = self.clone();
+        let password = password.to_owned();
+        self.hash_pool
+            .run(move || hasher.create_hash(&password))
+            .await
+    }
+
+    pub async fn verify_password(&self, hash: String, password: String) -> Result<bool, AuthError> {
+        self.hash_pool
+            .run(move || Ok(Auth::verify_hash(hash, password)))
+            .await
     }
 
     pub fn create_hash(&self, password: &str) -> Result<String, AuthError> {
@@ -128,10 +284,7 @@ impl Auth {
     }
 
     pub fn verify_hash(hash: String, password: String) -> bool {
-        match argon2::verify_encoded(&hash, password.as_bytes()) {
-            Ok(value) => value,
-            Err(_) => false,
-        }
+        argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or_default()
     }
 
     pub fn create_token(
@@ -147,12 +300,47 @@ impl Auth {
         }
     }
 
+    pub fn create_client_token(
+        &self,
+        username: &str,
+        client_id: &str,
+        scope: &str,
+        duration: TokenDuration,
+    ) -> Result<String, AuthError> {
+        let mut claims = Claims::new(username.to_owned(), duration);
+        claims.client_id = Some(client_id.to_owned());
+        claims.scope = Some(scope.to_owned());
+
+        match encode(&Header::default(), &claims, self.jwt_secret.as_bytes()) {
+            Ok(token) => Ok(token),
+            Err(err) => Err(AuthError::internal_error(&err.to_string())),
+        }
+    }
+
+    // tokens for service accounts, whose client id is also the subject
+    pub fn create_service_token(
+        &self,
+        client_id: &str,
+        scope: &str,
+        duration: TokenDuration,
+    ) -> Result<String, AuthError> {
+        let mut claims = Claims::new(client_id.to_owned(), duration);
+        claims.client_id = Some(client_id.to_owned());
+        claims.scope = Some(scope.to_owned());
+        claims.service = true;
+
+        match encode(&Header::default(), &claims, self.jwt_secret.as_bytes()) {
+            Ok(token) => Ok(token),
+            Err(err) => Err(AuthError::internal_error(&err.to_string())),
+        }
+    }
+
     pub fn decode_token(&self, token: &str) -> Result<Claims, AuthError> {
         let validation = Validation {
             iss: Some(AUTH_APP.to_owned()),
             ..Default::default()
         };
-        match decode::<Claims>(&token, self.jwt_secret.as_bytes(), &validation) {
+        match decode::<Claims>(token, self.jwt_secret.as_bytes(), &validation) {
             Ok(token_data) => Ok(token_data.claims),
             Err(err) => Err(AuthError::new(
                 "auth",
@@ -1,100 +1,80 @@
-use chrono::{DateTime, Utc};
+use crate::auth::ExternalIdentity;
+use crate::http::HttpClient;
+use crate::jwks::KeyCache;
 use failure;
 use jsonwebtoken as jwt;
 use jwt::{Algorithm, Validation};
-use reqwest;
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+
+static GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+
+// google signs id tokens with either form of its issuer
+static GOOGLE_ISSUERS: [&str; 2] = ["accounts.google.com", "https://accounts.google.com"];
 
 #[derive(Clone)]
 pub struct GoogleSignin {
-    client: Arc<reqwest::Client>,
-    certs: Arc<Mutex<Certs>>,
-    cert_expiration: Arc<Mutex<i64>>,
+    keys: KeyCache,
+    // our oauth client ids (web, ios, android, ...)
+    client_ids: Vec<String>,
+    // g suite domains allowed to sign in, any account is allowed when empty
+    hosted_domains: Vec<String>,
 }
 
 impl GoogleSignin {
-    pub fn new() -> GoogleSignin {
+    pub fn new(
+        client: Arc<dyn HttpClient>,
+        client_ids: Vec<String>,
+        hosted_domains: Vec<String>,
+    ) -> GoogleSignin {
         GoogleSignin {
-            client: Arc::new(reqwest::Client::new()),
-            certs: Arc::new(Mutex::new(Certs::new())),
-            cert_expiration: Arc::new(Mutex::new(0)),
+            keys: KeyCache::new(client),
+            client_ids,
+            hosted_domains,
         }
     }
 
-    fn get_new_certs(&self) -> Result<(i64, Certs), failure::Error> {
-        let url = "https://www.googleapis.com/oauth2/v3/certs";
-        let mut res = self.client.get(url).send()?;
-        let time = match res.headers().get("expires") {
-            Some(expires) => expires.to_str().unwrap_or(""),
-            None => "",
-        };
-
-        let new_expiration = match DateTime::parse_from_rfc2822(time) {
-            Ok(dt) => dt.timestamp(),
-            Err(_) => 0,
-        };
-
-        Ok((new_expiration, res.json()?))
-    }
-
-    fn get_cached_certs(&self) -> Result<(i64, Certs), failure::Error> {
-        let mut current_certs = match self.certs.lock() {
-            Ok(cc) => cc,
-            Err(_) => return self.get_new_certs(),
-        };
-
-        let mut current_expiration = match self.cert_expiration.lock() {
-            Ok(ce) => ce,
-            Err(_) => return self.get_new_certs(),
-        };
-
-        // if certs are expired or empty, get new ones
-        if *current_expiration < Utc::now().timestamp() || current_certs.keys.is_empty() {
-            let (new_expiration, new_certs) = self.get_new_certs()?;
-            *current_expiration = new_expiration;
-            *current_certs = new_certs;
-        }
-
-        Ok((current_expiration.clone(), current_certs.clone()))
+    // keeps google's keys on disk, so we can still sign people in after
+    // a restart while google can't be reached
+    pub fn with_key_snapshot(mut self, path: &str) -> GoogleSignin {
+        self.keys = self.keys.with_snapshot(path);
+        self
     }
 
     pub fn decode_token(&self, token: &str) -> Result<GooglePayload, failure::Error> {
-        let certs: Certs = self.get_cached_certs()?.1;
-
-        let claimed_kid = jwt::decode_header(&token)?.kid.unwrap_or_default();
-        let mut e = "";
-        let mut n = "";
-
-        let mut b_match = false;
-        for key in &certs.keys {
-            if key.kid == claimed_kid {
-                b_match = true;
-                n = &key.n;
-                e = &key.e;
-                break;
-            }
-        }
-
-        if !b_match {
-            return Err(failure::err_msg(
-                "google decode_token: kid does not match any google kid".to_string(),
-            ));
-        }
+        let claimed_kid = jwt::decode_header(token)?.kid.unwrap_or_default();
+        let key = self.keys.get(GOOGLE_CERTS_URL, &claimed_kid)?;
 
+        // the issuer is checked below since google uses two
         let mut validation = Validation {
             leeway: 10,
-            iss: Some("accounts.google.com".to_owned()),
             algorithms: vec![Algorithm::RS256],
             ..Validation::default()
         };
-        validation.set_audience(&[
-            "709178405751-3gehnuuoka3ccht41qs4uo175vc6vg3f.apps.googleusercontent.com",
-        ]);
+        validation.set_audience(&self.client_ids);
+
+        let token_data =
+            jwt::decode_rsa_components::<GooglePayload>(token, &key.n, &key.e, &validation)?;
+
+        if !GOOGLE_ISSUERS.contains(&token_data.claims.iss.as_str()) {
+            return Err(failure::err_msg(format!(
+                "google decode_token: unexpected issuer {}",
+                token_data.claims.iss
+            )));
+        }
 
-        let token_data = jwt::decode_rsa_components::<GooglePayload>(&token, &n, &e, &validation)?;
         Ok(token_data.claims)
     }
+
+    pub fn is_allowed_domain(&self, payload: &GooglePayload) -> bool {
+        if self.hosted_domains.is_empty() {
+            return true;
+        }
+        match &payload.hd {
+            Some(hd) => self.hosted_domains.iter().any(|domain| domain == hd),
+            None => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -102,61 +82,128 @@ pub struct GoogleToken {
     pub id_token: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct JWK {
-    alg: String,
-    n: String,
-    kid: String,
-    e: String,
-    kty: String,
-    r#use: String,
-}
-
-#[derive(Deserialize, Debug, Clone)]
-struct Certs {
-    keys: Vec<JWK>,
-}
-
-impl Certs {
-    fn new() -> Certs {
-        Certs { keys: Vec::new() }
-    }
-}
-
-#[derive(Deserialize, Debug)]
-struct Header {
-    alg: String,
-    kid: String,
-    typ: String,
-}
-
+// only the claims google always sends are required,
+// the rest depend on the requested scopes and the account type
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GooglePayload {
     pub iss: String,
-    pub azp: String,
     pub aud: String,
     pub sub: String,
-    pub email: String,
+    pub iat: i64,
+    pub exp: i64,
+    #[serde(default)]
+    pub azp: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
     pub email_verified: bool,
-    pub at_hash: String,
-    pub name: String,
-    pub picture: String,
-    pub given_name: String,
-    pub family_name: String,
-    pub locale: String,
-    pub iat: i32,
-    pub exp: i32,
-    pub jti: String,
+    #[serde(default)]
+    pub at_hash: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub picture: Option<String>,
+    #[serde(default)]
+    pub given_name: Option<String>,
+    #[serde(default)]
+    pub family_name: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub hd: Option<String>,
+    #[serde(default)]
+    pub jti: Option<String>,
 }
 
+impl GooglePayload {
+    pub fn to_identity(&self) -> ExternalIdentity {
+        ExternalIdentity {
+            provider: "google".to_owned(),
+            subject: self.sub.clone(),
+            email: self.email.clone().unwrap_or_default(),
+            email_verified: self.email_verified,
+            username: self.given_name.clone().unwrap_or_default(),
+            name: self.name.clone(),
+            picture: self.picture.clone(),
+            locale: self.locale.clone(),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http::{FakeHttpClient, HttpResponse};
+    use chrono::Utc;
+    use jwt::Header;
+    use reqwest::Method;
+    use serde_json::json;
+
+    // google, as far as GoogleSignin can tell, signing with the test key
+    fn fake_google() -> Arc<FakeHttpClient> {
+        let http = Arc::new(FakeHttpClient::new());
+        http.respond(
+            Method::GET,
+            GOOGLE_CERTS_URL,
+            HttpResponse::new(200, include_str!("../test_data/jwks.json"))
+                .with_header("cache-control", "public, max-age=3600"),
+        );
+        http
+    }
+
     #[test]
     fn get_cached_certs_works_with_two_immediate_calls() {
-        let gsi = GoogleSignin::new();
-        assert!(gsi.get_cached_certs().is_ok());
-        assert!(gsi.get_cached_certs().is_ok());
+        let http = fake_google();
+        let gsi = GoogleSignin::new(http.clone(), Vec::new(), Vec::new());
+        assert!(gsi.keys.keys(GOOGLE_CERTS_URL).is_ok());
+        assert!(gsi.keys.keys(GOOGLE_CERTS_URL).is_ok());
+        assert_eq!(http.requests().len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn decode_token_accepts_both_issuers() {
+        let gsi = GoogleSignin::new(fake_google(), vec!["client".to_owned()], Vec::new());
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key-1".to_owned());
+
+        for iss in &GOOGLE_ISSUERS {
+            let now = Utc::now().timestamp();
+            let token = jwt::encode(
+                &header,
+                &json!({
+                    "iss": iss,
+                    "aud": "client",
+                    "sub": "1234",
+                    "iat": now,
+                    "exp": now + 60,
+                    "email": "a@a.com",
+                    "email_verified": true,
+                }),
+                include_bytes!("../test_data/rsa_private.pem"),
+            )
+            .unwrap();
+
+            let identity = gsi.decode_token(&token).unwrap().to_identity();
+            assert_eq!(identity.subject, "1234");
+            assert!(identity.email_verified);
+        }
+    }
+
+    #[test]
+    fn is_allowed_domain_checks_hosted_domain() {
+        let payload: GooglePayload = serde_json::from_value(json!({
+            "iss": "https://accounts.google.com",
+            "aud": "client",
+            "sub": "1",
+            "iat": 0,
+            "exp": 0,
+            "hd": "example.com",
+        }))
+        .unwrap();
+        let gsi = |domains: Vec<String>| GoogleSignin::new(fake_google(), Vec::new(), domains);
+
+        assert!(gsi(Vec::new()).is_allowed_domain(&payload));
+        assert!(gsi(vec!["example.com".to_owned()]).is_allowed_domain(&payload));
+        assert!(!gsi(vec!["other.com".to_owned()]).is_allowed_domain(&payload));
+    }
+}
@@ -0,0 +1,255 @@
+use auth_app::auth::Auth;
+use auth_app::error::AuthError;
+use auth_app::migrations::{self, MigrationState};
+use auth_app::oidc_issuer::SigningKey;
+use auth_app::runtime::Runtime;
+use auth_app::*;
+use chrono::{TimeZone, Utc};
+use std::env;
+use std::io::{self, BufRead};
+use std::process;
+
+static USAGE: &str = "usage: auth-admin <command>
+
+  create-user <email> <username> [--admin]
+                            the password is read from stdin
+  show <username>
+  disable <username>
+  enable <username>
+  disable-service-account <client-id>
+  enable-service-account <client-id>
+  force-password-reset <username>
+                            replaces the password and emails a reset link
+  grant-role <username> <role>
+  revoke-role <username> <role>
+  revoke-sessions <username>
+  rotate-signing-key <path> writes a new oidc signing key to path
+  migrate up
+  migrate status
+
+configuration is read the same way the server reads it";
+
+// reads the password from the first line of stdin, so it stays
+// out of the shell history and the process list
+fn read_password() -> Result<String, AuthError> {
+    eprint!("password: ");
+    let mut password = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|err| AuthError::internal_error(&err.to_string()))?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+async fn create_user(
+    db: &store::Store,
+    auth: &Auth,
+    email: &str,
+    username: &str,
+    admin: bool,
+) -> Result<(), AuthError> {
+    let password = read_password()?;
+    admin::create_user(db, auth, email, username, &password).await?;
+    if admin {
+        admin::set_role(db, username, "admin", true).await?;
+    }
+    println!("created {}", username);
+    Ok(())
+}
+
+async fn show(db: &store::Store, username: &str) -> Result<(), AuthError> {
+    let account = admin::get_account(db, username).await?;
+    let json = serde_json::to_string_pretty(&account)
+        .map_err(|err| AuthError::internal_error(&err.to_string()))?;
+    println!("{}", json);
+    Ok(())
+}
+
+async fn force_password_reset(
+    db: &store::Store,
+    auth: &Auth,
+    config: &config::Config,
+    username: &str,
+) -> Result<(), AuthError> {
+    let templates = templates::Templates::load(&config.mail.templates, &config.server.public_url)
+        .map_err(|err| AuthError::internal_error(&err.to_string()))?;
+    admin::force_password_reset(db, auth, &templates, username).await?;
+    println!(
+        "{} has to reset their password, a link is on its way",
+        username
+    );
+    Ok(())
+}
+
+// the new key signs from the next restart, the old ones stay published
+// until the id tokens they signed have expired, an hour at most
+fn rotate_signing_key(config: &config::Config, path: &str) -> Result<(), AuthError> {
+    let key =
+        SigningKey::generate(path).map_err(|err| AuthError::internal_error(&err.to_string()))?;
+    let mut keys = vec![path.to_owned()];
+    keys.extend(config.oidc.signing_keys.iter().cloned());
+
+    println!("wrote signing key {} to {}", key.kid(), path);
+    println!("sign with it by putting it first in the signing keys and restarting:");
+    println!("  AUTH_OIDC_SIGNING_KEYS={}", keys.join(","));
+    println!("drop the old keys from the list an hour after that");
+    Ok(())
+}
+
+async fn migrate_up(db: &db::Db) -> Result<(), AuthError> {
+    let applied = migrations::up(db).await?;
+    for migration in &applied {
+        println!("applied migration {} {}", migration.version, migration.name);
+    }
+    if applied.is_empty() {
+        println!("the database is up to date");
+    }
+    Ok(())
+}
+
+async fn migrate_status(db: &db::Db) -> Result<(), AuthError> {
+    let applied_at = |at: i64| Utc.timestamp(at, 0).to_rfc3339();
+    for status in migrations::status(db).await? {
+        let state = match status.state {
+            MigrationState::Pending => "pending".to_owned(),
+            MigrationState::Applied { applied_at: at } => format!("applied {}", applied_at(at)),
+            MigrationState::Changed { applied_at: at } => {
+                format!("applied {}, but changed since", applied_at(at))
+            }
+            MigrationState::Unknown { applied_at: at } => {
+                format!("applied {}, by a newer version", applied_at(at))
+            }
+        };
+        println!("{:>4} {:<24} {}", status.version, status.name, state);
+    }
+    Ok(())
+}
+
+enum Command<'a> {
+    CreateUser {
+        email: &'a str,
+        username: &'a str,
+        admin: bool,
+    },
+    Show(&'a str),
+    SetDisabled(&'a str, bool),
+    SetServiceAccountDisabled(&'a str, bool),
+    ForcePasswordReset(&'a str),
+    SetRole(&'a str, &'a str, bool),
+    RevokeSessions(&'a str),
+    RotateSigningKey(&'a str),
+    MigrateUp,
+    MigrateStatus,
+}
+
+impl<'a> Command<'a> {
+    fn parse(args: &[&'a str]) -> Option<Command<'a>> {
+        let command = match *args {
+            ["create-user", email, username] => Command::CreateUser {
+                email,
+                username,
+                admin: false,
+            },
+            ["create-user", email, username, "--admin"] => Command::CreateUser {
+                email,
+                username,
+                admin: true,
+            },
+            ["show", username] => Command::Show(username),
+            ["disable", username] => Command::SetDisabled(username, true),
+            ["enable", username] => Command::SetDisabled(username, false),
+            ["disable-service-account", client_id] => {
+                Command::SetServiceAccountDisabled(client_id, true)
+            }
+            ["enable-service-account", client_id] => {
+                Command::SetServiceAccountDisabled(client_id, false)
+            }
+            ["force-password-reset", username] => Command::ForcePasswordReset(username),
+            ["grant-role", username, role] => Command::SetRole(username, role, true),
+            ["revoke-role", username, role] => Command::SetRole(username, role, false),
+            ["revoke-sessions", username] => Command::RevokeSessions(username),
+            ["rotate-signing-key", path] => Command::RotateSigningKey(path),
+            ["migrate", "up"] => Command::MigrateUp,
+            ["migrate", "status"] => Command::MigrateStatus,
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+// migrations are only run by hand for postgres, sqlite runs its own when it's opened
+fn postgres(config: &config::Config) -> Result<db::Db, AuthError> {
+    match config.database.backend {
+        config::DatabaseBackend::Postgres => postgres_db(config),
+        _ => Err(AuthError::internal_error(
+            "migrate only works with the postgres backend",
+        )),
+    }
+}
+
+fn postgres_db(config: &config::Config) -> Result<db::Db, AuthError> {
+    db::Db::new(&config.database)
+        .map_err(|err| AuthError::internal_error(&format!("couldn't set up the database: {}", err)))
+}
+
+fn open_store(config: &config::Config) -> Result<store::Store, AuthError> {
+    Ok(match config.database.backend {
+        config::DatabaseBackend::Postgres => store::Store::new(postgres_db(config)?),
+        config::DatabaseBackend::Sqlite => {
+            store::Store::new(sqlite_store::SqliteStore::open(&config.database.path)?)
+        }
+        config::DatabaseBackend::Memory => store::Store::memory(),
+    })
+}
+
+async fn run(config: &config::Config, command: Command<'_>) -> Result<(), AuthError> {
+    let db = open_store(config)?;
+    let auth = Auth::new(config.auth.jwt_secret.clone(), config.auth.salt.clone())
+        .with_lifetimes(config.tokens.lifetimes());
+
+    match command {
+        Command::CreateUser {
+            email,
+            username,
+            admin,
+        } => create_user(&db, &auth, email, username, admin).await,
+        Command::Show(username) => show(&db, username).await,
+        Command::SetDisabled(username, disabled) => {
+            admin::set_disabled(&db, username, disabled).await
+        }
+        Command::SetServiceAccountDisabled(client_id, disabled) => {
+            admin::set_service_account_disabled(&db, client_id, disabled).await
+        }
+        Command::ForcePasswordReset(username) => {
+            force_password_reset(&db, &auth, config, username).await
+        }
+        Command::SetRole(username, role, granted) => {
+            admin::set_role(&db, username, role, granted).await
+        }
+        Command::RevokeSessions(username) => admin::revoke_sessions(&db, username).await,
+        Command::RotateSigningKey(path) => rotate_signing_key(config, path),
+        Command::MigrateUp => migrate_up(&postgres(config)?).await,
+        Command::MigrateStatus => migrate_status(&postgres(config)?).await,
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let command = Command::parse(&args).unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        process::exit(2)
+    });
+
+    let config = config::Config::from_env().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init_cli();
+
+    let runtime = Runtime::new();
+    if let Err(err) = runtime.block_on(run(&config, command)) {
+        eprintln!("{}", err.server_message());
+        process::exit(1);
+    }
+}
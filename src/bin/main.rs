@@ -1,19 +1,25 @@
 use actix_files as fs;
-use actix_web::{guard, http, middleware, web, App, HttpRequest, HttpResponse, HttpServer};
-use auth_app::auth::{self, Auth, TokenDuration};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{guard, http, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
+use auth_app::audit::{AuditAction, AuditEvent};
+use auth_app::auth::{self, Auth};
 use auth_app::error::AuthError;
+use auth_app::http::{HttpClient, ReqwestClient};
+use auth_app::mailer::{self, Mailer};
 use auth_app::*;
 use futures::Future;
-use serde;
 use serde_json::{self, json};
-use std::env;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing_futures::Instrument;
 
 fn check_username(
-    db: web::Data<db::Db>,
+    db: web::Data<store::Store>,
     user: web::Json<auth::User>,
+    rt: web::Data<runtime::Runtime>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    actix_web::web::block(move || {
-        let exists = db.user_exists(&user.username)?;
+    rt.run(async move {
+        let exists = db.user_exists(&user.username).await?;
         match exists {
             true => Err(AuthError::new(
                 "username",
@@ -24,10 +30,7 @@ fn check_username(
             false => Ok(()),
         }
     })
-    .map_err(|err| {
-        println!("check_username: {}", err);
-        actix_web::Error::from(AuthError::from(err))
-    })
+    .map_err(error_response)
     .and_then(|_| {
         HttpResponse::Ok()
             .content_type("application/json")
@@ -37,40 +40,50 @@ fn check_username(
 
 fn get_users(
     req: HttpRequest,
-    db: web::Data<db::Db>,
+    params: web::Query<directory::UserListParams>,
+    db: web::Data<store::Store>,
     auth: web::Data<auth::Auth>,
+    rt: web::Data<runtime::Runtime>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
     let token_string = get_authorization_header(req.headers());
+    let path = req.path().to_owned();
 
-    actix_web::web::block(move || {
-        auth.decode_token(&token_string)?;
-        db.get_all_users()
-    })
-    .map_err(|err| {
-        println!("get_users: {}", err);
-        actix_web::Error::from(AuthError::from(err))
+    rt.run(async move {
+        let claims = session::authenticate(&auth, &db, &token_string).await?;
+        if !claims.has_scope("users:read") {
+            return Err(missing_scope("users:read"));
+        }
+        directory::browse(&db, &claims.sub, &params, &path).await
     })
-    .and_then(|res| {
-        HttpResponse::Ok()
-            .content_type("application/json")
-            .body(make_success_json("users", res))
+    .map_err(error_response)
+    .and_then(|listing| {
+        let mut res = HttpResponse::Ok();
+        if let Some(next) = listing.next() {
+            res.header("Link", format!("<{}>; rel=\"next\"", next));
+        }
+        res.content_type("application/json")
+            .body(make_success_json("users", json!(listing)))
     })
 }
 
 fn verify_user(
+    req: HttpRequest,
     user: web::Json<auth::User>,
-    db: web::Data<db::Db>,
+    db: web::Data<store::Store>,
     auth: web::Data<auth::Auth>,
+    notifier: web::Data<notifications::Notifier>,
+    rt: web::Data<runtime::Runtime>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    actix_web::web::block(move || {
+    let origin = request_origin(&req);
+
+    rt.run(async move {
         user.is_valid_signin()?;
-        let username = db.verify_user(&user)?;
-        auth.create_token(&username, TokenDuration::Weeks2)
-    })
-    .map_err(|err| {
-        println!("verify_user: {}", err);
-        actix_web::Error::from(AuthError::from(err))
+        let username =
+            session::verify_user(&auth, &db, &user, &origin.ip, &origin.user_agent).await?;
+        after_sign_in(&notifier, &db, &username, None, &origin).await;
+        auth.create_token(&username, auth.lifetimes.session)
     })
+    .map_err(error_response)
     .and_then(|token| {
         HttpResponse::Ok()
             .content_type("application/json")
@@ -79,22 +92,27 @@ fn verify_user(
 }
 
 fn add_user(
+    req: HttpRequest,
     user: web::Json<auth::User>,
-    db: web::Data<db::Db>,
+    db: web::Data<store::Store>,
     auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    actix_web::web::block(move || {
+    let origin = request_origin(&req);
+
+    rt.run(async move {
         user.is_valid_signup()?;
-        let hashed_password = auth.create_hash(&user.password)?;
+        let hashed_password = auth.hash_password(&user.password).await?;
         let mut user = user.clone();
         user.set_password(&hashed_password);
-        db.add_user(&user)?;
-        auth.create_token(&user.username, TokenDuration::Weeks2)
-    })
-    .map_err(|err| {
-        println!("add_user: {}", err);
-        actix_web::Error::from(AuthError::from(err))
+        db.add_user(&user).await?;
+        let event = AuditEvent::new(AuditAction::SignUp, &user.username)
+            .by(&user.username)
+            .from(&origin.ip, &origin.user_agent);
+        audit::record(&db, event).await;
+        auth.create_token(&user.username, auth.lifetimes.session)
     })
+    .map_err(error_response)
     .and_then(|token| {
         HttpResponse::Ok()
             .content_type("application/json")
@@ -103,35 +121,41 @@ fn add_user(
 }
 
 fn google(
+    req: HttpRequest,
     token: web::Json<auth_google::GoogleToken>,
-    db: web::Data<db::Db>,
+    db: web::Data<store::Store>,
     ggl: web::Data<auth_google::GoogleSignin>,
     auth: web::Data<Auth>,
+    notifier: web::Data<notifications::Notifier>,
+    rt: web::Data<runtime::Runtime>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    actix_web::web::block(move || {
-        // decode the google token or throw an error
-        let token_data = match ggl.decode_token(&token.id_token) {
-            Ok(td) => td,
-            Err(err) => return Err(AuthError::internal_error(&err.to_string())),
-        };
-
-        // create User from token data
-        let user = auth::User::new(&token_data.email, &token_data.given_name, "");
+    let origin = request_origin(&req);
 
-        // check if user exists in our db
-        let exists = db.user_exists(&user.username)?;
+    rt.run(async move {
+        let (identity, sign_in) = match google_sign_in(&ggl, &db, &token.id_token).await {
+            Ok(signed_in) => signed_in,
+            Err(err) => {
+                let reason = format!("google: {}", err.server_message());
+                let event = AuditEvent::new(AuditAction::ExternalSignIn, "")
+                    .from(&origin.ip, &origin.user_agent)
+                    .failed(&reason);
+                audit::record(&db, event).await;
+                return Err(err);
+            }
+        };
+        after_sign_in(
+            &notifier,
+            &db,
+            &sign_in.username,
+            Some((&identity, &sign_in)),
+            &origin,
+        )
+        .await;
 
-        if !exists {
-            // if user doesn't exist, create a new user
-            db.add_user(&user)?;
-        }
         // todo: prevent google users from changing their username
-        auth.create_token(&user.username, TokenDuration::Weeks2)
-    })
-    .map_err(|err| {
-        println!("auth_google: {}", err);
-        actix_web::Error::from(AuthError::from(err))
+        auth.create_token(&sign_in.username, auth.lifetimes.session)
     })
+    .map_err(error_response)
     .and_then(|token| {
         HttpResponse::Ok()
             .content_type("application/json")
@@ -139,34 +163,217 @@ fn google(
     })
 }
 
+// checks the token google gave the browser and finds or makes the user it's for
+async fn google_sign_in(
+    ggl: &auth_google::GoogleSignin,
+    db: &store::Store,
+    id_token: &str,
+) -> Result<(auth::ExternalIdentity, auth::ExternalSignIn), AuthError> {
+    // decode the google token or throw an error, fetching
+    // google's keys blocks so it's done off the runtime
+    let google = ggl.clone();
+    let id_token = id_token.to_owned();
+    let token_data = runtime::blocking(move || match google.decode_token(&id_token) {
+        Ok(td) => Ok(td),
+        Err(err) => Err(AuthError::new_general(
+            "Couldn't sign you in with Google.",
+            &err.to_string(),
+            401,
+        )),
+    })
+    .await?;
+
+    if !ggl.is_allowed_domain(&token_data) {
+        return Err(AuthError::new_general(
+            "Please sign in with your organization's Google account.",
+            &format!("google hosted domain {:?} is not allowed", token_data.hd),
+            403,
+        ));
+    }
+
+    // find the user linked to this google account, or link/create one
+    let identity = token_data.to_identity();
+    let sign_in = db.sign_in_external(&identity).await?;
+    Ok((identity, sign_in))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn oidc(
+    req: HttpRequest,
+    provider: web::Path<String>,
+    token: web::Json<oidc::IdTokenRequest>,
+    db: web::Data<store::Store>,
+    providers: web::Data<oidc::OidcProviders>,
+    auth: web::Data<Auth>,
+    notifier: web::Data<notifications::Notifier>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let origin = request_origin(&req);
+
+    rt.run(async move {
+        // the provider's keys may have to be fetched, which blocks
+        let identity = runtime::blocking(move || {
+            let provider = match providers.get(&provider) {
+                Some(p) => p,
+                None => {
+                    return Err(AuthError::new_general(
+                        "This sign in provider isn't supported.",
+                        "",
+                        404,
+                    ))
+                }
+            };
+
+            match provider.decode_token(&token.id_token) {
+                Ok(identity) => Ok(identity),
+                Err(err) => Err(AuthError::new_general(
+                    "Couldn't sign you in with this provider.",
+                    &err.to_string(),
+                    401,
+                )),
+            }
+        })
+        .await?;
+
+        let sign_in = db.sign_in_external(&identity).await?;
+        after_sign_in(
+            &notifier,
+            &db,
+            &sign_in.username,
+            Some((&identity, &sign_in)),
+            &origin,
+        )
+        .await;
+        auth.create_token(&sign_in.username, auth.lifetimes.session)
+    })
+    .map_err(error_response)
+    .and_then(|token| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("oidc", token))
+    })
+}
+
+fn oauth_start(
+    provider: web::Path<String>,
+    db: web::Data<store::Store>,
+    providers: web::Data<oauth_client::OAuthProviders>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let complete = providers.clone();
+
+    rt.run(async move { oauth_client::start(&db, &providers, &provider).await })
+        .then(move |res: Result<_, AuthError>| match res {
+            Ok(started) => Ok(HttpResponse::Found()
+                .header(http::header::LOCATION, started.url)
+                .header(http::header::SET_COOKIE, started.cookie)
+                .finish()),
+            Err(err) => {
+                logging::log_error(&err);
+                redirect(&complete.complete_url("error", err.client_message()))
+            }
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn oauth_callback(
+    req: HttpRequest,
+    provider: web::Path<String>,
+    query: web::Query<oauth_client::OAuthCallback>,
+    db: web::Data<store::Store>,
+    providers: web::Data<oauth_client::OAuthProviders>,
+    auth: web::Data<Auth>,
+    notifier: web::Data<notifications::Notifier>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let origin = request_origin(&req);
+    let cookie_state = req
+        .cookie(oauth_client::STATE_COOKIE)
+        .map(|cookie| cookie.value().to_owned());
+    // the state cookie has done its job whatever happens
+    let clear_cookie = providers
+        .get(&provider)
+        .map(|provider| provider.clear_state_cookie());
+    let complete = providers.clone();
+
+    rt.run(async move {
+        let identity =
+            oauth_client::finish(&db, &providers, &provider, &query, cookie_state.as_deref())
+                .await?;
+
+        let sign_in = db.sign_in_external(&identity).await?;
+        after_sign_in(
+            &notifier,
+            &db,
+            &sign_in.username,
+            Some((&identity, &sign_in)),
+            &origin,
+        )
+        .await;
+        auth.create_token(&sign_in.username, auth.lifetimes.session)
+    })
+    .then(move |res: Result<_, AuthError>| {
+        let url = match res {
+            Ok(token) => complete.complete_url("token", &token),
+            Err(err) => {
+                logging::log_error(&err);
+                complete.complete_url("error", err.client_message())
+            }
+        };
+        let mut res = HttpResponse::Found();
+        res.header(http::header::LOCATION, url);
+        if let Some(cookie) = clear_cookie {
+            res.header(http::header::SET_COOKIE, cookie);
+        }
+        Ok(res.finish())
+    })
+}
+
 fn forgot_password(
-    db: web::Data<db::Db>,
-    send_grid: web::Data<send_grid::SendGrid>,
+    req: HttpRequest,
+    db: web::Data<store::Store>,
+    templates: web::Data<templates::Templates>,
     user: web::Json<auth::User>,
     auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    actix_web::web::block(move || {
+    // the email goes out in the language the browser asked for
+    let origin = request_origin(&req);
+
+    rt.run(async move {
         // check if the email is valid
         user.is_valid_email("email")?;
 
         // if the email exists in the database,
         // the username is returned
-        let username = db.get_user_by_email(&user.email)?;
+        let username = db.get_user_by_email(&user.email).await?;
+        // the attempt is logged against the email when no account has it
+        let subject = if username.is_empty() {
+            &user.email
+        } else {
+            &username
+        };
+        let event = AuditEvent::new(AuditAction::PasswordResetRequested, subject)
+            .from(&origin.ip, &origin.user_agent);
 
-        // if username is not empty, send a password reset email
-        if !username.is_empty() {
-            let token = auth.create_token(&username, TokenDuration::Minutes5)?;
-            send_grid.send_forgot_email(&user.email, &token, &username)?;
+        // send a password reset email when there's an account
+        if username.is_empty() {
+            audit::record(&db, event.failed("no account has that email")).await;
+        } else {
+            audit::record(&db, event).await;
+            let token = auth.create_token(&username, auth.lifetimes.reset)?;
+            // queued rather than sent, so the response doesn't wait on the
+            // mail provider or take longer when the account exists
+            let email = templates.reset_password(&origin.locale, &user.email, &username, &token)?;
+            let expires_at = outbox::expires_in(auth.lifetimes.reset.seconds());
+            outbox::enqueue(&db, &email, expires_at).await?;
         }
 
         // return ok even if the username is empty
         // security through obscurity
         Ok("Email sent!")
     })
-    .map_err(|err| {
-        println!("forgot_password: {}", err);
-        actix_web::Error::from(AuthError::from(err))
-    })
+    .map_err(error_response)
     .and_then(|res| {
         HttpResponse::Ok()
             .content_type("application/json")
@@ -174,31 +381,78 @@ fn forgot_password(
     })
 }
 
+// the "this wasn't me" link from a security notice
+fn not_me(
+    req: HttpRequest,
+    body: web::Json<notifications::NotMeRequest>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    templates: web::Data<templates::Templates>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let locale = request_origin(&req).locale;
+
+    rt.run(async move { notifications::not_me(&db, &auth, &templates, &body.token, &locale).await })
+        .map_err(error_response)
+        .and_then(|_| {
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(make_success_json("notMe", true))
+        })
+}
+
+// revokes the token the request was made with
+fn sign_out(
+    req: HttpRequest,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+    let origin = request_origin(&req);
+
+    rt.run(async move {
+        let claims = session::authenticate(&auth, &db, &token_string).await?;
+        session::sign_out(&db, &claims, &origin.ip, &origin.user_agent).await
+    })
+    .map_err(error_response)
+    .and_then(|_| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("signOut", true))
+    })
+}
+
 fn reset_password(
     req: HttpRequest,
-    db: web::Data<db::Db>,
+    db: web::Data<store::Store>,
     user: web::Json<auth::User>,
     auth: web::Data<Auth>,
+    notifier: web::Data<notifications::Notifier>,
+    rt: web::Data<runtime::Runtime>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
     let token_string = get_authorization_header(req.headers());
+    let origin = request_origin(&req);
 
-    actix_web::web::block(move || {
-        user.is_valid_password("resetPassword")?;
-        let claims = auth.decode_token(&token_string)?;
-        let hashed_password = auth.create_hash(&user.password)?;
-        let num = db.update_user_password(&claims.sub, &hashed_password)?;
-        if num != 0 {
-            auth.create_token(&user.username, TokenDuration::Weeks2)
-        } else {
-            Err(AuthError::internal_error(
-                "No rows modified for updating password.",
-            ))
-        }
-    })
-    .map_err(|err| {
-        println!("forgot_password: {}", err);
-        actix_web::Error::from(AuthError::from(err))
+    rt.run(async move {
+        let reset = session::reset_password(
+            &auth,
+            &db,
+            &token_string,
+            &user,
+            &origin.ip,
+            &origin.user_agent,
+        )
+        .await?;
+        let event = notifications::SecurityEvent::PasswordChanged;
+        log_notice_error(
+            notifier
+                .notify(&db, &reset.username, &origin.locale, &event)
+                .await,
+        );
+        Ok(reset.token)
     })
+    .map_err(error_response)
     .and_then(|res| {
         HttpResponse::Ok()
             .content_type("application/json")
@@ -206,24 +460,551 @@ fn reset_password(
     })
 }
 
+fn create_api_key(
+    req: HttpRequest,
+    key: web::Json<api_key::NewApiKey>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let user_id = get_first_party_user(&auth, &db, &token_string).await?;
+        api_key::create(&db, user_id, &key).await
+    })
+    .map_err(error_response)
+    .and_then(|key| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("apiKey", json!(key)))
+    })
+}
+
+fn get_api_keys(
+    req: HttpRequest,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let user_id = get_first_party_user(&auth, &db, &token_string).await?;
+        db.get_api_keys(user_id).await
+    })
+    .map_err(error_response)
+    .and_then(|keys| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("apiKeys", json!(keys)))
+    })
+}
+
+fn get_me(
+    req: HttpRequest,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let claims = session::authenticate(&auth, &db, &token_string).await?;
+        if !claims.has_scope("profile") {
+            return Err(missing_scope("profile"));
+        }
+        profile::get(&db, &claims.sub).await
+    })
+    .map_err(error_response)
+    .and_then(|profile| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("me", json!(profile)))
+    })
+}
+
+// only the user themselves can change their profile, not apps acting for them
+fn update_me(
+    req: HttpRequest,
+    update: web::Json<profile::ProfileUpdate>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let claims = session::authenticate(&auth, &db, &token_string).await?;
+        if !claims.is_first_party() {
+            return Err(missing_scope("profile"));
+        }
+        profile::update(&db, &claims.sub, update.into_inner()).await
+    })
+    .map_err(error_response)
+    .and_then(|profile| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("me", json!(profile)))
+    })
+}
+
+// what happened to the signed in user's account lately, only for our own sign in
+fn get_my_activity(
+    req: HttpRequest,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let claims = get_first_party_claims(&auth, &db, &token_string).await?;
+        audit::recent_activity(&db, &claims.sub).await
+    })
+    .map_err(error_response)
+    .and_then(|events| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("activity", json!(events)))
+    })
+}
+
+fn revoke_api_key(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let user_id = get_first_party_user(&auth, &db, &token_string).await?;
+        match db.revoke_api_key(user_id, *id).await? {
+            0 => Err(AuthError::new_general(
+                "This api key doesn't exist.",
+                "",
+                404,
+            )),
+            _ => Ok(()),
+        }
+    })
+    .map_err(error_response)
+    .and_then(|_| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("apiKeyRevoked", true))
+    })
+}
+
+fn get_dead_emails(
+    req: HttpRequest,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        get_admin_claims(&auth, &db, &token_string).await?;
+        db.get_dead_emails().await
+    })
+    .map_err(error_response)
+    .and_then(|messages| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("deadEmails", json!(messages)))
+    })
+}
+
+fn replay_email(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        get_admin_claims(&auth, &db, &token_string).await?;
+        outbox::replay(&db, *id).await
+    })
+    .map_err(error_response)
+    .and_then(|_| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("emailReplayed", true))
+    })
+}
+
+fn get_audit_events(
+    req: HttpRequest,
+    params: web::Query<audit::AuditParams>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+    let path = req.path().to_owned();
+
+    rt.run(async move {
+        get_admin_claims(&auth, &db, &token_string).await?;
+        audit::search(&db, &params, &path).await
+    })
+    .map_err(error_response)
+    .and_then(|page| {
+        let mut res = HttpResponse::Ok();
+        if let Some(ref next) = page.next {
+            res.header("Link", format!("<{}>; rel=\"next\"", next));
+        }
+        res.content_type("application/json")
+            .body(make_success_json("auditEvents", json!(page)))
+    })
+}
+
+fn send_grid_events(
+    req: HttpRequest,
+    body: web::Bytes,
+    db: web::Data<store::Store>,
+    webhook: web::Data<Option<send_grid::EventWebhook>>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_owned()
+    };
+    let signature = header(send_grid::SIGNATURE_HEADER);
+    let timestamp = header(send_grid::TIMESTAMP_HEADER);
+
+    rt.run(async move {
+        match webhook.get_ref() {
+            Some(webhook) => webhook.receive(&db, &signature, &timestamp, &body).await,
+            None => Err(AuthError::new(
+                "webhook",
+                "Not found.",
+                "send grid webhook key isn't configured",
+                404,
+            )),
+        }
+    })
+    .map_err(error_response)
+    .and_then(|marked| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("suppressed", marked))
+    })
+}
+
+fn register_oauth_client(
+    req: HttpRequest,
+    client: web::Json<oauth_server::NewClient>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    oauth: web::Data<config::OAuthConfig>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let user_id = get_first_party_user(&auth, &db, &token_string).await?;
+        oauth_server::register_client(&db, user_id, &client, &oauth.native_schemes).await
+    })
+    .map_err(error_response)
+    .and_then(|client| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("oauthClient", json!(client)))
+    })
+}
+
+fn register_service_account(
+    req: HttpRequest,
+    account: web::Json<service_account::NewServiceAccount>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let user_id = get_first_party_user(&auth, &db, &token_string).await?;
+        service_account::register(&db, user_id, &account).await
+    })
+    .map_err(error_response)
+    .and_then(|account| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("serviceAccount", json!(account)))
+    })
+}
+
+// what the consent page needs to ask the user
+fn oauth_authorize_prompt(
+    req: HttpRequest,
+    query: web::Query<oauth_server::AuthorizeRequest>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let user_id = get_first_party_user(&auth, &db, &token_string).await?;
+        oauth_server::authorize_prompt(&db, user_id, &query).await
+    })
+    .map_err(error_response)
+    .and_then(|prompt| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("oauthAuthorize", json!(prompt)))
+    })
+}
+
+// the user's answer on the consent page, the response says where to redirect
+fn oauth_authorize(
+    req: HttpRequest,
+    decision: web::Json<oauth_server::AuthorizeDecision>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move {
+        let claims = get_first_party_claims(&auth, &db, &token_string).await?;
+        let user_id = db.get_user_id(&claims.sub).await?;
+        oauth_server::authorize(&db, user_id, claims.issued_at(), &decision).await
+    })
+    .map_err(error_response)
+    .and_then(|redirect_to| {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(make_success_json("oauthRedirect", redirect_to))
+    })
+}
+
+fn oauth_token(
+    req: HttpRequest,
+    form: web::Form<oauth_server::TokenRequest>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    issuer: web::Data<oidc_issuer::OidcIssuer>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let basic = get_basic_auth(req.headers());
+
+    rt.run(async move { oauth_server::token(&db, &auth, &issuer, &form, basic).await })
+        .map_err(|err: oauth_server::OAuthError| actix_web::Error::from(err))
+        .and_then(|res| {
+            HttpResponse::Ok()
+                .header("Cache-Control", "no-store")
+                .json(res)
+        })
+}
+
+fn oauth_introspect(
+    req: HttpRequest,
+    form: web::Form<oauth_server::IntrospectRequest>,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let basic = get_basic_auth(req.headers());
+
+    rt.run(async move { oauth_server::introspect(&db, &auth, &form, basic).await })
+        .map_err(|err: oauth_server::OAuthError| actix_web::Error::from(err))
+        .and_then(|res| {
+            HttpResponse::Ok()
+                .header("Cache-Control", "no-store")
+                .json(res)
+        })
+}
+
+fn oidc_discovery(issuer: web::Data<oidc_issuer::OidcIssuer>) -> HttpResponse {
+    if !issuer.is_enabled() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().json(issuer.discovery())
+}
+
+fn oidc_jwks(issuer: web::Data<oidc_issuer::OidcIssuer>) -> HttpResponse {
+    if !issuer.is_enabled() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok()
+        .header("Cache-Control", "public, max-age=3600")
+        .json(issuer.jwks())
+}
+
+// for prometheus to scrape
+fn metrics(auth: web::Data<Auth>) -> HttpResponse {
+    let mut out = String::new();
+    auth.hash_pool().render_metrics(&mut out);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
+}
+
+fn userinfo(
+    req: HttpRequest,
+    db: web::Data<store::Store>,
+    auth: web::Data<Auth>,
+    issuer: web::Data<oidc_issuer::OidcIssuer>,
+    rt: web::Data<runtime::Runtime>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let token_string = get_authorization_header(req.headers());
+
+    rt.run(async move { oidc_issuer::userinfo(&db, &auth, &issuer, &token_string).await })
+        .map_err(|err: oauth_server::OAuthError| actix_web::Error::from(err))
+        .and_then(|res| {
+            HttpResponse::Ok()
+                .header("Cache-Control", "no-store")
+                .json(res)
+        })
+}
+
 fn main() {
-    let jwt_secret = env::var("AUTH_JWT_SECRET").expect("auth jwt secret not found");
-    let salt = env::var("AUTH_SALT").expect("auth salt not found");
-    let database_url = env::var("TSDB_URL").expect("tsdb url not found");
-    let send_grid_key = env::var("AUTH_SEND_GRID_KEY").expect("send grid key not found");
+    // everything is checked before anything starts, and every problem is reported at once
+    let config = config::Config::from_env().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    });
+    if let Err(err) = logging::init(&config.log) {
+        eprintln!("couldn't start logging: {}", err);
+        std::process::exit(1)
+    }
+
+    let rt = runtime::Runtime::new();
+    let db = match config.database.backend {
+        config::DatabaseBackend::Postgres => {
+            let db = db::Db::new(&config.database).unwrap_or_else(|err| exit_on(err));
+
+            if config.database.migrate_on_start {
+                match rt.block_on(migrations::up(&db)) {
+                    Ok(applied) => {
+                        for migration in applied {
+                            tracing::info!(
+                                version = migration.version,
+                                name = migration.name,
+                                "applied migration"
+                            );
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!(error = %err, "couldn't apply the migrations");
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                match rt.block_on(migrations::pending(&db)) {
+                    Ok(0) => {}
+                    Ok(pending) => tracing::warn!(
+                        pending,
+                        "migrations haven't been applied, run `auth-admin migrate up`"
+                    ),
+                    Err(err) => tracing::warn!(error = %err, "couldn't check the migrations"),
+                }
+            }
+
+            store::Store::new(db)
+        }
+        // sqlite brings its schema up to date when it's opened
+        config::DatabaseBackend::Sqlite => {
+            match sqlite_store::SqliteStore::open(&config.database.path) {
+                Ok(db) => store::Store::new(db),
+                Err(err) => {
+                    tracing::error!(error = %err, "couldn't open the sqlite database");
+                    std::process::exit(1);
+                }
+            }
+        }
+        config::DatabaseBackend::Memory => {
+            tracing::warn!("keeping everything in memory, it will be gone when the server stops");
+            store::Store::memory()
+        }
+    };
+
+    // every outgoing request goes through this
+    let http_client: Arc<dyn HttpClient> = Arc::new(ReqwestClient::new());
+
+    // optional json file listing generic openid connect providers
+    let oidc_providers = match config.oidc.providers {
+        Some(ref path) => oidc::OidcProviders::from_file(http_client.clone(), path)
+            .unwrap_or_else(|err| exit_on(err)),
+        None => oidc::OidcProviders::new(http_client.clone(), Vec::new()),
+    };
 
-    let auth = Auth::new(jwt_secret, salt);
-    let google_signin = auth_google::GoogleSignin::new();
-    let send_grid = send_grid::SendGrid::new(&send_grid_key);
-    let db = db::Db::new(&database_url);
+    // where the react app is served, users are sent back there after social logins
+    let public_url = config.server.public_url.clone();
+    let oauth_complete_url = format!("{}/sign-in", public_url.trim_end_matches('/'));
+
+    // optional json file listing providers for the server side oauth flow
+    let oauth_providers = match config.oauth.providers {
+        Some(ref path) => {
+            oauth_client::OAuthProviders::from_file(http_client.clone(), path, &oauth_complete_url)
+                .unwrap_or_else(|err| exit_on(err))
+        }
+        None => {
+            oauth_client::OAuthProviders::new(http_client.clone(), Vec::new(), &oauth_complete_url)
+        }
+    };
+
+    // without any signing keys we don't act as an openid connect provider
+    let signing_keys = config
+        .oidc
+        .signing_keys
+        .iter()
+        .map(|path| oidc_issuer::SigningKey::from_file(path).unwrap_or_else(|err| exit_on(err)))
+        .collect();
+    let oidc_issuer =
+        oidc_issuer::OidcIssuer::new(config.server.issuer_url(), &public_url, signing_keys);
+
+    let auth = Auth::new(config.auth.jwt_secret.clone(), config.auth.salt.clone())
+        .with_lifetimes(config.tokens.lifetimes())
+        .with_hash_pool(config.auth.hash_pool());
+    let google_signin = auth_google::GoogleSignin::new(
+        http_client.clone(),
+        config.google.client_ids.clone(),
+        config.google.hosted_domains.clone(),
+    );
+    let google_signin = match config.google.key_snapshot {
+        Some(ref path) => google_signin.with_key_snapshot(path),
+        None => google_signin,
+    };
+    let mailer = get_mailer(&config.mail, http_client.clone());
+    let send_grid_webhook = config
+        .mail
+        .send_grid_webhook_key
+        .as_ref()
+        .map(|key| send_grid::EventWebhook::new(key).unwrap_or_else(|err| exit_on(err)));
+    // links in emails point at the public url too
+    let templates = templates::Templates::load(&config.mail.templates, &public_url)
+        .unwrap_or_else(|err| exit_on(err));
+    let notifier =
+        notifications::Notifier::new(templates.clone(), config.notifications.events.clone())
+            .unwrap_or_else(|err| exit_on(err));
+    outbox::start_worker(&rt, db.clone(), mailer);
+    let oauth_config = config.oauth.clone();
 
     HttpServer::new(move || {
         App::new()
+            .data(rt.clone())
             .data(db.clone())
             .data(google_signin.clone())
             .data(auth.clone())
-            .data(send_grid.clone())
-            .wrap(middleware::Logger::default())
+            .data(templates.clone())
+            .data(notifier.clone())
+            .data(send_grid_webhook.clone())
+            .data(oidc_providers.clone())
+            .data(oauth_providers.clone())
+            .data(oidc_issuer.clone())
+            .data(oauth_config.clone())
+            .wrap_fn(trace_request)
             .service(
                 web::scope("/auth")
                     // .default_service(web::get().to_async(unsplash_get))
@@ -231,10 +1012,57 @@ fn main() {
                     .route("/verify-user", web::post().to_async(verify_user))
                     .route("/check-username", web::post().to_async(check_username))
                     .route("/forgot-password", web::post().to_async(forgot_password))
+                    .route("/not-me", web::post().to_async(not_me))
                     .route("/reset-password", web::post().to_async(reset_password))
-                    .route("/google", web::post().to_async(google)),
+                    .route("/sign-out", web::post().to_async(sign_out))
+                    .route("/google", web::post().to_async(google))
+                    .route("/oidc/{provider}", web::post().to_async(oidc))
+                    .route("/oauth/{provider}/start", web::get().to_async(oauth_start))
+                    .route(
+                        "/oauth/{provider}/callback",
+                        web::get().to_async(oauth_callback),
+                    ),
             )
             .service(web::scope("/protected").route("/users", web::get().to_async(get_users)))
+            .service(
+                web::scope("/api-keys")
+                    .route("", web::post().to_async(create_api_key))
+                    .route("", web::get().to_async(get_api_keys))
+                    .route("/{id}", web::delete().to_async(revoke_api_key)),
+            )
+            .service(
+                web::resource("/me")
+                    .route(web::get().to_async(get_me))
+                    .route(web::patch().to_async(update_me)),
+            )
+            .route("/me/activity", web::get().to_async(get_my_activity))
+            .route("/webhooks/sendgrid", web::post().to_async(send_grid_events))
+            .service(
+                web::scope("/admin")
+                    .route("/audit", web::get().to_async(get_audit_events))
+                    .route("/outbox/dead", web::get().to_async(get_dead_emails))
+                    .route("/outbox/{id}/replay", web::post().to_async(replay_email)),
+            )
+            .service(
+                web::scope("/oauth")
+                    .route("/clients", web::post().to_async(register_oauth_client))
+                    .route(
+                        "/service-accounts",
+                        web::post().to_async(register_service_account),
+                    )
+                    .route("/authorize", web::get().to_async(oauth_authorize_prompt))
+                    .route("/authorize", web::post().to_async(oauth_authorize))
+                    .route("/token", web::post().to_async(oauth_token))
+                    .route("/introspect", web::post().to_async(oauth_introspect)),
+            )
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(oidc_discovery),
+            )
+            .route("/.well-known/jwks.json", web::get().to(oidc_jwks))
+            .route("/metrics", web::get().to(metrics))
+            .route("/userinfo", web::get().to_async(userinfo))
+            .route("/userinfo", web::post().to_async(userinfo))
             .service(fs::Files::new("/", "static/build").index_file("index.html"))
             .default_service(
                 // 404 for GET request
@@ -248,30 +1076,247 @@ fn main() {
                     ),
             )
     })
-    .bind(("0.0.0.0", get_server_port()))
+    .bind((config.server.host.as_str(), config.server.port))
     .unwrap()
     .run()
     .unwrap();
 }
 
+// every request gets an id, sent back as X-Request-Id and in the body of
+// errors, and a span that everything logged while handling it is in
+fn trace_request<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Item = ServiceResponse<B>, Error = actix_web::Error>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let sent = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok());
+    let request_id = logging::request_id(sent);
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path()
+    );
+
+    let started = Instant::now();
+    // the handler is only called once actix polls, so the span has to be
+    // entered on every poll, not just around call
+    let in_span = span.clone();
+    srv.call(req).instrument(in_span).map(move |res| {
+        let _span = span.enter();
+        // handlers return an AuthError, which is sent again with the id in it.
+        // anything else is actix turning the request away, like a bad body
+        let auth_error = res
+            .response()
+            .error()
+            .map(|err| (err.as_error::<AuthError>().cloned(), err.to_string()));
+        let mut res = match auth_error {
+            Some((Some(err), _)) => {
+                logging::log_error(&err);
+                let err = err.with_request_id(&request_id);
+                res.error_response(err)
+            }
+            Some((None, err)) => {
+                tracing::info!(error = %logging::redact(&err), "request rejected");
+                res
+            }
+            None => res,
+        };
+
+        tracing::info!(
+            status = res.status().as_u16(),
+            elapsed_ms = logging::elapsed_ms(started),
+            "request finished"
+        );
+        if let Ok(value) = http::HeaderValue::from_str(&request_id) {
+            res.headers_mut()
+                .insert(http::HeaderName::from_static("x-request-id"), value);
+        }
+        res
+    })
+}
+
+// handlers leave the logging to trace_request, which knows the request it was in
+fn error_response(err: AuthError) -> actix_web::Error {
+    actix_web::Error::from(err)
+}
+
 fn p404() -> Result<fs::NamedFile, actix_web::Error> {
     Ok(fs::NamedFile::open("static/404.html")?.set_status_code(http::StatusCode::NOT_FOUND))
 }
 
-fn get_server_port() -> u16 {
-    env::var("PORT")
-        .unwrap_or(5000.to_string())
-        .parse()
-        .expect("PORT must be a number")
+fn redirect(url: &str) -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Found()
+        .header(http::header::LOCATION, url)
+        .finish())
+}
+
+// picks how mail goes out from the mail config
+// Config::load has already tried all of this, so it only fails if a file changed since
+fn exit_on(err: failure::Error) -> ! {
+    tracing::error!(error = %err, "couldn't start");
+    std::process::exit(1)
+}
+
+fn get_mailer(config: &config::MailConfig, http_client: Arc<dyn HttpClient>) -> Arc<dyn Mailer> {
+    match config.backend() {
+        config::MailBackend::SendGrid => {
+            let key = config.send_grid_key.as_deref().unwrap_or("");
+            Arc::new(send_grid::SendGrid::new(http_client, key, &config.from))
+        }
+        config::MailBackend::Smtp => Arc::new(
+            mailer::SmtpMailer::new(config.smtp_config()).unwrap_or_else(|err| exit_on(err)),
+        ),
+        config::MailBackend::File => Arc::new(
+            mailer::FileMailer::new(&config.dir, &config.from).unwrap_or_else(|err| exit_on(err)),
+        ),
+        config::MailBackend::Console => Arc::new(mailer::ConsoleMailer),
+    }
+}
+
+// where a request came from, for emails and security notices
+struct RequestOrigin {
+    locale: String,
+    ip: String,
+    user_agent: String,
+}
+
+fn request_origin(req: &HttpRequest) -> RequestOrigin {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_owned()
+    };
+    // the peer's port changes with every connection, only the address says anything
+    let remote = req.connection_info().remote().unwrap_or("").to_owned();
+    let ip = match remote.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => remote,
+    };
+
+    RequestOrigin {
+        locale: templates::locale_from_accept_language(&header("Accept-Language")),
+        ip,
+        user_agent: header("User-Agent"),
+    }
+}
+
+// a notice that can't be sent shouldn't fail what it's about
+fn log_notice_error(res: Result<(), AuthError>) {
+    if let Err(err) = res {
+        tracing::warn!(error = %logging::redact(&err.to_string()), "couldn't send a security notice");
+    }
 }
 
+// records the sign in, audits external ones and tells the user about a new
+// device or a newly linked identity. none of it is worth failing the sign in over
+async fn after_sign_in(
+    notifier: &notifications::Notifier,
+    db: &store::Store,
+    username: &str,
+    external: Option<(&auth::ExternalIdentity, &auth::ExternalSignIn)>,
+    origin: &RequestOrigin,
+) {
+    if let Err(err) = db.record_sign_in(username).await {
+        tracing::error!(error = %logging::redact(&err.to_string()), "couldn't record a sign in");
+    }
+    if let Some((identity, sign_in)) = external {
+        let event = AuditEvent::new(AuditAction::ExternalSignIn, username)
+            .by(username)
+            .from(&origin.ip, &origin.user_agent)
+            .detail(&identity.provider);
+        audit::record(db, event).await;
+        if sign_in.linked_existing {
+            let event = notifications::SecurityEvent::IdentityLinked {
+                provider: &identity.provider,
+            };
+            log_notice_error(notifier.notify(db, username, &origin.locale, &event).await);
+        }
+    }
+    log_notice_error(
+        notifier
+            .sign_in(db, username, &origin.locale, &origin.ip, &origin.user_agent)
+            .await,
+    );
+}
+
+// the bearer token, or an empty one that won't authenticate
 fn get_authorization_header(header_map: &actix_web::http::header::HeaderMap) -> String {
-    if let Some(header) = header_map.get("Authorization") {
-        let mut value = header.to_str().unwrap_or("").to_string();
-        value.drain(0..7); // remove "Bearer " from value
-        return value;
+    header_map
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("")
+        .to_owned()
+}
+
+// the credentials of an oauth client using http basic auth
+fn get_basic_auth(header_map: &actix_web::http::header::HeaderMap) -> Option<(String, String)> {
+    let value = header_map.get("Authorization")?.to_str().ok()?;
+    if !value.starts_with("Basic ") {
+        return None;
     }
-    "".to_owned()
+    let decoded = base64::decode(&value[6..]).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+}
+
+// the id of the signed in user, only for tokens from our own sign in.
+// tokens issued to oauth clients can't act as the user here
+async fn get_first_party_user(
+    auth: &Auth,
+    db: &store::Store,
+    token: &str,
+) -> Result<i32, AuthError> {
+    let claims = get_first_party_claims(auth, db, token).await?;
+    db.get_user_id(&claims.sub).await
+}
+
+async fn get_first_party_claims(
+    auth: &Auth,
+    db: &store::Store,
+    token: &str,
+) -> Result<auth::Claims, AuthError> {
+    let claims = session::authenticate(auth, db, token).await?;
+    if !claims.is_first_party() {
+        return Err(missing_scope("session"));
+    }
+    Ok(claims)
+}
+
+// admins sign in like everyone else, but only with a first party session
+async fn get_admin_claims(
+    auth: &Auth,
+    db: &store::Store,
+    token: &str,
+) -> Result<auth::Claims, AuthError> {
+    let claims = get_first_party_claims(auth, db, token).await?;
+    if !db.is_admin(&claims.sub).await? {
+        return Err(AuthError::new(
+            "auth",
+            "You don't have access to this resource.",
+            &format!("{} isn't an admin", claims.sub),
+            403,
+        ));
+    }
+    Ok(claims)
+}
+
+fn missing_scope(scope: &str) -> AuthError {
+    AuthError::new(
+        "auth",
+        "You don't have access to this resource.",
+        &format!("token is missing the {} scope", scope),
+        403,
+    )
 }
 
 fn make_success_json<T>(context: &str, data: T) -> serde_json::value::Value
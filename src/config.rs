@@ -0,0 +1,1021 @@
+use crate::auth::{TokenDuration, TokenLifetimes};
+use crate::db::Db;
+use crate::hash_pool::HashPool;
+use crate::mailer::{FileMailer, SmtpConfig, SmtpMailer, SmtpSecurity};
+use crate::notifications::{self, Notifier};
+use crate::oauth_client::OAuthProviders;
+use crate::oidc::OidcProviders;
+use crate::oidc_issuer::SigningKey;
+use crate::send_grid;
+use crate::templates::Templates;
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use tracing_subscriber::EnvFilter;
+
+// every setting, from the toml file named by AUTH_CONFIG and then the environment.
+// the env vars are the ones we've always read, so existing deployments keep working
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub tokens: TokenConfig,
+    pub google: GoogleConfig,
+    pub oidc: OidcConfig,
+    pub oauth: OAuthConfig,
+    pub mail: MailConfig,
+    pub notifications: NotificationsConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    // where the react app is served. links in emails and redirects after social logins point here
+    pub public_url: String,
+    // the iss of id tokens we sign, the public url when it isn't set
+    pub issuer_url: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            host: "0.0.0.0".to_owned(),
+            port: 5000,
+            public_url: "http://localhost:3000".to_owned(),
+            issuer_url: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn issuer_url(&self) -> &str {
+        self.issuer_url.as_deref().unwrap_or(&self.public_url)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseTls {
+    None,
+    Prefer,
+    Require,
+}
+
+// where users, sessions and everything else are kept
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    Postgres,
+    // a single file, for small deployments
+    Sqlite,
+    // lost on restart, for trying things out locally
+    Memory,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: DatabaseBackend,
+    // the sqlite database file
+    pub path: String,
+    pub url: String,
+    pub url_file: Option<String>,
+    pub pool_size: u32,
+    // how long a request waits for a free connection
+    pub pool_timeout_ms: u64,
+    // queries running longer than this are cancelled
+    pub statement_timeout_ms: u64,
+    pub tls: DatabaseTls,
+    // apply pending migrations before serving, instead of with `migrate up`
+    pub migrate_on_start: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> DatabaseConfig {
+        DatabaseConfig {
+            backend: DatabaseBackend::Postgres,
+            path: "auth.db".to_owned(),
+            url: String::new(),
+            url_file: None,
+            pool_size: 16,
+            pool_timeout_ms: 5_000,
+            statement_timeout_ms: 10_000,
+            tls: DatabaseTls::None,
+            migrate_on_start: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub jwt_secret: String,
+    pub jwt_secret_file: Option<String>,
+    pub salt: String,
+    pub salt_file: Option<String>,
+    // threads hashing passwords, 0 for one per cpu
+    pub hash_threads: usize,
+    // hashes that can wait for a thread before sign ins are turned away with a 503
+    pub hash_queue: usize,
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            jwt_secret: String::new(),
+            jwt_secret_file: None,
+            salt: String::new(),
+            salt_file: None,
+            hash_threads: 0,
+            hash_queue: 64,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn hash_pool(&self) -> HashPool {
+        HashPool::new(self.hash_threads, self.hash_queue)
+    }
+}
+
+// how long tokens last, in seconds
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    // what signing in gives you
+    pub session_seconds: i64,
+    // the links in password reset emails
+    pub reset_seconds: i64,
+    // access tokens for oauth clients and service accounts
+    pub access_seconds: i64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> TokenConfig {
+        let lifetimes = TokenLifetimes::default();
+        TokenConfig {
+            session_seconds: lifetimes.session.seconds(),
+            reset_seconds: lifetimes.reset.seconds(),
+            access_seconds: lifetimes.access.seconds(),
+        }
+    }
+}
+
+impl TokenConfig {
+    pub fn lifetimes(&self) -> TokenLifetimes {
+        TokenLifetimes {
+            session: TokenDuration::Seconds(self.session_seconds),
+            reset: TokenDuration::Seconds(self.reset_seconds),
+            access: TokenDuration::Seconds(self.access_seconds),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GoogleConfig {
+    pub client_ids: Vec<String>,
+    pub hosted_domains: Vec<String>,
+    pub key_snapshot: Option<String>,
+}
+
+impl Default for GoogleConfig {
+    fn default() -> GoogleConfig {
+        GoogleConfig {
+            client_ids: vec![
+                "709178405751-3gehnuuoka3ccht41qs4uo175vc6vg3f.apps.googleusercontent.com"
+                    .to_owned(),
+            ],
+            hosted_domains: Vec::new(),
+            key_snapshot: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    // json file listing the openid connect providers users can sign in with
+    pub providers: Option<String>,
+    // pem files of the keys id tokens are signed with, the first one signs
+    pub signing_keys: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthConfig {
+    // json file listing providers for the server side oauth flow
+    pub providers: Option<String>,
+    // custom uri schemes, like com.example.app, that native apps registering
+    // with us may use for their redirect uris
+    pub native_schemes: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    SendGrid,
+    Smtp,
+    File,
+    Console,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    // sendgrid when there's a key and console otherwise, unless this says
+    pub backend: Option<MailBackend>,
+    pub from: String,
+    pub templates: String,
+    // where the file backend writes messages
+    pub dir: String,
+    pub send_grid_key: Option<String>,
+    pub send_grid_key_file: Option<String>,
+    // the verification key from sendgrid's signed event webhook settings
+    pub send_grid_webhook_key: Option<String>,
+    pub smtp: SmtpSettings,
+}
+
+impl Default for MailConfig {
+    fn default() -> MailConfig {
+        MailConfig {
+            backend: None,
+            from: crate::mailer::FROM_ADDRESS.to_owned(),
+            templates: "templates/email".to_owned(),
+            dir: "mail".to_owned(),
+            send_grid_key: None,
+            send_grid_key_file: None,
+            send_grid_webhook_key: None,
+            smtp: SmtpSettings::default(),
+        }
+    }
+}
+
+impl MailConfig {
+    pub fn backend(&self) -> MailBackend {
+        match (self.backend, &self.send_grid_key) {
+            (Some(backend), _) => backend,
+            (None, Some(_)) => MailBackend::SendGrid,
+            (None, None) => MailBackend::Console,
+        }
+    }
+
+    pub fn smtp_config(&self) -> SmtpConfig {
+        SmtpConfig {
+            host: self.smtp.host.clone().unwrap_or_default(),
+            port: self.smtp.port(),
+            security: self.smtp.security,
+            username: self.smtp.username.clone(),
+            password: self.smtp.password.clone(),
+            from: self.from.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpSettings {
+    pub host: Option<String>,
+    // 465 for tls, 587 for starttls and 25 otherwise when it isn't set
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+}
+
+impl Default for SmtpSettings {
+    fn default() -> SmtpSettings {
+        SmtpSettings {
+            host: None,
+            port: None,
+            security: SmtpSecurity::StartTls,
+            username: None,
+            password: None,
+            password_file: None,
+        }
+    }
+}
+
+impl SmtpSettings {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.security {
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::None => 25,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    // which security notifications go out
+    pub events: Vec<String>,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> NotificationsConfig {
+        NotificationsConfig {
+            events: notifications::EVENTS
+                .iter()
+                .map(|event| (*event).to_owned())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // a json object a line, for log collectors
+    Json,
+    // for reading in a terminal
+    Text,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // a level like info, or per module filters like info,auth_app::db=debug
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_owned(),
+            format: LogFormat::Json,
+        }
+    }
+}
+
+// everything that's wrong with the configuration, so it can all be fixed at once
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// a comma separated list
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// a value the way it would be written in the toml file
+fn parse<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    let deserializer: serde::de::value::StrDeserializer<serde::de::value::Error> =
+        value.into_deserializer();
+    T::deserialize(deserializer).map_err(|err| err.to_string())
+}
+
+// applies env vars over the values from the file, noting the ones that don't parse
+struct Overrides<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    problems: Vec<String>,
+}
+
+impl<'a> Overrides<'a> {
+    fn string(&mut self, name: &str, field: &mut String) {
+        if let Some(value) = (self.env)(name) {
+            *field = value;
+        }
+    }
+
+    fn optional(&mut self, name: &str, field: &mut Option<String>) {
+        if let Some(value) = (self.env)(name) {
+            *field = Some(value);
+        }
+    }
+
+    fn list(&mut self, name: &str, field: &mut Vec<String>) {
+        if let Some(value) = (self.env)(name) {
+            *field = list(&value);
+        }
+    }
+
+    fn parsed<T: DeserializeOwned>(&mut self, name: &str, field: &mut T) {
+        if let Some(value) = (self.env)(name) {
+            match parse(&value) {
+                Ok(value) => *field = value,
+                Err(err) => self.problems.push(format!(
+                    "{} has an invalid value {:?}: {}",
+                    name, value, err
+                )),
+            }
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, name: &str, field: &mut T) {
+        if let Some(value) = (self.env)(name) {
+            match value.trim().parse() {
+                Ok(value) => *field = value,
+                Err(_) => self
+                    .problems
+                    .push(format!("{} should be a number, not {:?}", name, value)),
+            }
+        }
+    }
+
+    fn flag(&mut self, name: &str, field: &mut bool) {
+        if let Some(value) = (self.env)(name) {
+            match value.trim().to_lowercase().as_str() {
+                "true" | "1" => *field = true,
+                "false" | "0" => *field = false,
+                _ => self
+                    .problems
+                    .push(format!("{} should be true or false, not {:?}", name, value)),
+            }
+        }
+    }
+
+    // a secret can be given directly or as a file it's read from, like the
+    // ones docker and kubernetes mount. whichever the env sets wins
+    fn secret(&mut self, name: &str, value: &mut String, file: &mut Option<String>) {
+        if let Some(secret) = (self.env)(name) {
+            *value = secret;
+            *file = None;
+        }
+        if let Some(path) = (self.env)(&format!("{}_FILE", name)) {
+            value.clear();
+            *file = Some(path);
+        }
+    }
+
+    fn optional_secret(
+        &mut self,
+        name: &str,
+        value: &mut Option<String>,
+        file: &mut Option<String>,
+    ) {
+        let mut secret = value.take().unwrap_or_default();
+        self.secret(name, &mut secret, file);
+        if !secret.is_empty() {
+            *value = Some(secret);
+        }
+    }
+}
+
+// reads the file a secret points at, without the newline editors leave at the end
+fn read_secret(field: &str, value: &mut String, file: &Option<String>, problems: &mut Vec<String>) {
+    if let Some(path) = file {
+        if !value.is_empty() {
+            problems.push(format!("{} and {}_file are both set", field, field));
+            return;
+        }
+        match fs::read_to_string(path) {
+            Ok(secret) => *value = secret.trim_end_matches(&['\r', '\n'][..]).to_owned(),
+            Err(err) => problems.push(format!("{}_file {} can't be read: {}", field, path, err)),
+        }
+    }
+}
+
+fn read_optional_secret(
+    field: &str,
+    value: &mut Option<String>,
+    file: &Option<String>,
+    problems: &mut Vec<String>,
+) {
+    let mut secret = value.take().unwrap_or_default();
+    read_secret(field, &mut secret, file, problems);
+    if !secret.is_empty() {
+        *value = Some(secret);
+    }
+}
+
+fn is_url(value: &str) -> bool {
+    value.starts_with("http://") || value.starts_with("https://")
+}
+
+fn is_native_scheme(scheme: &str) -> bool {
+    scheme.contains('.')
+        && scheme.starts_with(|c: char| c.is_ascii_lowercase())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c))
+}
+
+impl Config {
+    // the toml file named by AUTH_CONFIG, if there is one, then the environment
+    pub fn from_env() -> Result<Config, ConfigError> {
+        let toml = match env::var("AUTH_CONFIG") {
+            Ok(path) => Some(fs::read_to_string(&path).map_err(|err| ConfigError {
+                problems: vec![format!("AUTH_CONFIG {} can't be read: {}", path, err)],
+            })?),
+            Err(_) => None,
+        };
+        Config::load(toml.as_deref(), &|name| env::var(name).ok())
+    }
+
+    pub fn load(
+        toml: Option<&str>,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let mut config: Config = match toml {
+            Some(toml) => toml::from_str(toml).map_err(|err| ConfigError {
+                problems: vec![format!("the config file is invalid: {}", err)],
+            })?,
+            None => Config::default(),
+        };
+
+        let mut env = Overrides {
+            env,
+            problems: Vec::new(),
+        };
+        config.apply_env(&mut env);
+        let mut problems = env.problems;
+
+        config.read_secrets(&mut problems);
+        config.validate(&mut problems);
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+    fn apply_env(&mut self, env: &mut Overrides) {
+        let server = &mut self.server;
+        env.string("AUTH_HOST", &mut server.host);
+        env.number("PORT", &mut server.port);
+        env.string("AUTH_PUBLIC_URL", &mut server.public_url);
+        env.optional("AUTH_ISSUER_URL", &mut server.issuer_url);
+
+        let database = &mut self.database;
+        env.parsed("AUTH_DB_BACKEND", &mut database.backend);
+        env.string("AUTH_DB_PATH", &mut database.path);
+        env.secret("TSDB_URL", &mut database.url, &mut database.url_file);
+        env.number("AUTH_DB_POOL_SIZE", &mut database.pool_size);
+        env.number("AUTH_DB_POOL_TIMEOUT_MS", &mut database.pool_timeout_ms);
+        env.number(
+            "AUTH_DB_STATEMENT_TIMEOUT_MS",
+            &mut database.statement_timeout_ms,
+        );
+        env.parsed("AUTH_DB_TLS", &mut database.tls);
+        env.flag("AUTH_DB_MIGRATE_ON_START", &mut database.migrate_on_start);
+
+        let auth = &mut self.auth;
+        env.secret(
+            "AUTH_JWT_SECRET",
+            &mut auth.jwt_secret,
+            &mut auth.jwt_secret_file,
+        );
+        env.secret("AUTH_SALT", &mut auth.salt, &mut auth.salt_file);
+        env.number("AUTH_HASH_THREADS", &mut auth.hash_threads);
+        env.number("AUTH_HASH_QUEUE", &mut auth.hash_queue);
+
+        let tokens = &mut self.tokens;
+        env.number("AUTH_SESSION_SECONDS", &mut tokens.session_seconds);
+        env.number("AUTH_RESET_TOKEN_SECONDS", &mut tokens.reset_seconds);
+        env.number("AUTH_ACCESS_TOKEN_SECONDS", &mut tokens.access_seconds);
+
+        let google = &mut self.google;
+        env.list("AUTH_GOOGLE_CLIENT_IDS", &mut google.client_ids);
+        env.list("AUTH_GOOGLE_HOSTED_DOMAINS", &mut google.hosted_domains);
+        env.optional("AUTH_GOOGLE_KEY_SNAPSHOT", &mut google.key_snapshot);
+
+        env.optional("AUTH_OIDC_PROVIDERS", &mut self.oidc.providers);
+        env.list("AUTH_OIDC_SIGNING_KEYS", &mut self.oidc.signing_keys);
+        env.optional("AUTH_OAUTH_PROVIDERS", &mut self.oauth.providers);
+        env.list("AUTH_OAUTH_NATIVE_SCHEMES", &mut self.oauth.native_schemes);
+
+        let mail = &mut self.mail;
+        if let Some(value) = (env.env)("AUTH_MAILER") {
+            match parse(&value) {
+                Ok(backend) => mail.backend = Some(backend),
+                Err(err) => env.problems.push(format!(
+                    "AUTH_MAILER has an invalid value {:?}: {}",
+                    value, err
+                )),
+            }
+        }
+        env.string("AUTH_MAIL_FROM", &mut mail.from);
+        env.string("AUTH_EMAIL_TEMPLATES", &mut mail.templates);
+        env.string("AUTH_MAIL_DIR", &mut mail.dir);
+        env.optional_secret(
+            "AUTH_SEND_GRID_KEY",
+            &mut mail.send_grid_key,
+            &mut mail.send_grid_key_file,
+        );
+        env.optional(
+            "AUTH_SEND_GRID_WEBHOOK_KEY",
+            &mut mail.send_grid_webhook_key,
+        );
+
+        let smtp = &mut mail.smtp;
+        env.optional("AUTH_SMTP_HOST", &mut smtp.host);
+        if let Some(value) = (env.env)("AUTH_SMTP_PORT") {
+            match value.trim().parse() {
+                Ok(port) => smtp.port = Some(port),
+                Err(_) => env.problems.push(format!(
+                    "AUTH_SMTP_PORT should be a number, not {:?}",
+                    value
+                )),
+            }
+        }
+        env.parsed("AUTH_SMTP_SECURITY", &mut smtp.security);
+        env.optional("AUTH_SMTP_USERNAME", &mut smtp.username);
+        env.optional_secret(
+            "AUTH_SMTP_PASSWORD",
+            &mut smtp.password,
+            &mut smtp.password_file,
+        );
+
+        env.list(
+            "AUTH_SECURITY_NOTIFICATIONS",
+            &mut self.notifications.events,
+        );
+
+        env.string("AUTH_LOG_LEVEL", &mut self.log.level);
+        env.parsed("AUTH_LOG_FORMAT", &mut self.log.format);
+    }
+
+    fn read_secrets(&mut self, problems: &mut Vec<String>) {
+        let database = &mut self.database;
+        read_secret(
+            "database.url",
+            &mut database.url,
+            &database.url_file,
+            problems,
+        );
+        let auth = &mut self.auth;
+        read_secret(
+            "auth.jwt_secret",
+            &mut auth.jwt_secret,
+            &auth.jwt_secret_file,
+            problems,
+        );
+        read_secret("auth.salt", &mut auth.salt, &auth.salt_file, problems);
+        let mail = &mut self.mail;
+        read_optional_secret(
+            "mail.send_grid_key",
+            &mut mail.send_grid_key,
+            &mail.send_grid_key_file,
+            problems,
+        );
+        let smtp = &mut mail.smtp;
+        read_optional_secret(
+            "mail.smtp.password",
+            &mut smtp.password,
+            &smtp.password_file,
+            problems,
+        );
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_owned());
+            }
+        };
+
+        check(
+            is_url(&self.server.public_url),
+            "server.public_url (AUTH_PUBLIC_URL) should be an http or https url",
+        );
+        check(
+            self.server.issuer_url.as_deref().is_none_or(is_url),
+            "server.issuer_url (AUTH_ISSUER_URL) should be an http or https url",
+        );
+
+        let postgres = self.database.backend == DatabaseBackend::Postgres;
+        check(
+            !postgres || !self.database.url.is_empty(),
+            "database.url (TSDB_URL) is required",
+        );
+        check(
+            self.database.backend != DatabaseBackend::Sqlite || !self.database.path.is_empty(),
+            "database.path (AUTH_DB_PATH) is required for sqlite",
+        );
+        check(
+            self.database.url.is_empty()
+                || self.database.url.parse::<tokio_postgres::Config>().is_ok(),
+            "database.url (TSDB_URL) isn't a valid postgres url",
+        );
+        check(
+            self.database.pool_size > 0,
+            "database.pool_size (AUTH_DB_POOL_SIZE) should be at least 1",
+        );
+        check(
+            self.database.pool_timeout_ms > 0 && self.database.statement_timeout_ms > 0,
+            "database.pool_timeout_ms and statement_timeout_ms should be more than 0",
+        );
+
+        check(
+            !self.auth.jwt_secret.is_empty(),
+            "auth.jwt_secret (AUTH_JWT_SECRET) is required",
+        );
+        // argon2 won't hash with a shorter salt
+        check(
+            self.auth.salt.len() >= 8,
+            "auth.salt (AUTH_SALT) should be at least 8 bytes",
+        );
+
+        check(
+            self.tokens.session_seconds > 0
+                && self.tokens.reset_seconds > 0
+                && self.tokens.access_seconds > 0,
+            "token lifetimes should be more than 0 seconds",
+        );
+
+        check(
+            !self.google.client_ids.is_empty(),
+            "google.client_ids (AUTH_GOOGLE_CLIENT_IDS) should list at least one client id",
+        );
+
+        // reverse domain names only, so nothing like javascript or data slips in
+        check(
+            self.oauth.native_schemes.iter().all(|scheme| is_native_scheme(scheme)),
+            "oauth.native_schemes (AUTH_OAUTH_NATIVE_SCHEMES) should be reverse domain names like com.example.app",
+        );
+
+        // postgres isn't connected to yet, but the pool and tls are set up as main will
+        if postgres && self.database.url.parse::<tokio_postgres::Config>().is_ok() {
+            if let Err(err) = Db::new(&self.database) {
+                problems.push(format!("database couldn't be set up: {}", err));
+            }
+        }
+
+        // everything main reads from a file is loaded here too, so a bad one is reported
+        // alongside everything else instead of stopping the server on its own
+        let mut load = |field: &str, path: &str, result: Result<(), failure::Error>| {
+            if !Path::new(path).exists() {
+                problems.push(format!("{} points at {}, which doesn't exist", field, path));
+            } else if let Err(err) = result {
+                problems.push(format!("{} couldn't load {}: {}", field, path, err));
+            }
+        };
+        if let Some(ref path) = self.oidc.providers {
+            load(
+                "oidc.providers (AUTH_OIDC_PROVIDERS)",
+                path,
+                OidcProviders::read_file(path).map(drop),
+            );
+        }
+        if let Some(ref path) = self.oauth.providers {
+            load(
+                "oauth.providers (AUTH_OAUTH_PROVIDERS)",
+                path,
+                OAuthProviders::read_file(path).map(drop),
+            );
+        }
+        for path in &self.oidc.signing_keys {
+            load(
+                "oidc.signing_keys (AUTH_OIDC_SIGNING_KEYS)",
+                path,
+                SigningKey::from_file(path).map(drop),
+            );
+        }
+        // unknown events are reported below, the notifier only needs checking for missing notices
+        let events: Vec<String> = self
+            .notifications
+            .events
+            .iter()
+            .filter(|event| notifications::EVENTS.contains(&event.as_str()))
+            .cloned()
+            .collect();
+        load(
+            "mail.templates (AUTH_EMAIL_TEMPLATES)",
+            &self.mail.templates,
+            Templates::load(&self.mail.templates, &self.server.public_url)
+                .and_then(|templates| Notifier::new(templates, events))
+                .map(drop),
+        );
+
+        match self.mail.backend() {
+            MailBackend::SendGrid if self.mail.send_grid_key.is_none() => problems.push(
+                "mail.send_grid_key (AUTH_SEND_GRID_KEY) is required to send with sendgrid"
+                    .to_owned(),
+            ),
+            MailBackend::Smtp if self.mail.smtp.host.is_none() => problems
+                .push("mail.smtp.host (AUTH_SMTP_HOST) is required to send with smtp".to_owned()),
+            MailBackend::Smtp => {
+                if let Err(err) = SmtpMailer::new(self.mail.smtp_config()) {
+                    problems.push(format!(
+                        "mail.smtp (AUTH_SMTP_HOST) couldn't be set up: {}",
+                        err
+                    ));
+                }
+            }
+            // the directory is made if it isn't there, so this is where that fails
+            MailBackend::File => {
+                if let Err(err) = FileMailer::new(&self.mail.dir, &self.mail.from) {
+                    problems.push(format!(
+                        "mail.dir (AUTH_MAIL_DIR) {} couldn't be made: {}",
+                        self.mail.dir, err
+                    ));
+                }
+            }
+            MailBackend::SendGrid | MailBackend::Console => {}
+        }
+        if let Some(ref key) = self.mail.send_grid_webhook_key {
+            if let Err(err) = send_grid::EventWebhook::new(key) {
+                problems.push(format!(
+                    "mail.send_grid_webhook_key (AUTH_SEND_GRID_WEBHOOK_KEY) is invalid: {}",
+                    err
+                ));
+            }
+        }
+
+        for event in &self.notifications.events {
+            if !notifications::EVENTS.contains(&event.as_str()) {
+                problems.push(format!(
+                    "notifications.events (AUTH_SECURITY_NOTIFICATIONS) has {}, which should be one of {}",
+                    event,
+                    notifications::EVENTS.join(", ")
+                ));
+            }
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.log.level) {
+            problems.push(format!(
+                "log.level (AUTH_LOG_LEVEL) {:?} isn't a level or filter: {}",
+                self.log.level, err
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(toml: &str, env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+            .collect();
+        Config::load(Some(toml), &|name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn env_overrides_file_and_secrets_come_from_files() {
+        let secret_path = env::temp_dir().join("auth-app-config-test-jwt-secret");
+        fs::write(&secret_path, "from a file\n").unwrap();
+
+        let config = load(
+            r#"
+            [server]
+            port = 8080
+            public_url = "https://auth.example"
+
+            [database]
+            url = "postgres://file"
+            pool_size = 10
+            tls = "require"
+
+            [auth]
+            jwt_secret = "from toml"
+            salt = "0123456789"
+            "#,
+            &[
+                ("TSDB_URL", "postgres://env"),
+                ("AUTH_DB_MIGRATE_ON_START", "true"),
+                ("AUTH_JWT_SECRET_FILE", secret_path.to_str().unwrap()),
+                (
+                    "AUTH_SECURITY_NOTIFICATIONS",
+                    "new_sign_in, password_changed",
+                ),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.issuer_url(), "https://auth.example");
+        assert_eq!(config.database.url, "postgres://env");
+        assert_eq!(config.database.pool_size, 10);
+        assert_eq!(config.database.tls, DatabaseTls::Require);
+        assert!(config.database.migrate_on_start);
+        assert_eq!(config.auth.jwt_secret, "from a file");
+        assert_eq!(config.notifications.events.len(), 2);
+        assert_eq!(config.mail.backend(), MailBackend::Console);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let err = load(
+            "[database]\npool_size = 0\n",
+            &[
+                ("PORT", "eighty"),
+                ("AUTH_MAILER", "pigeon"),
+                ("AUTH_SALT", "short"),
+                ("AUTH_SECURITY_NOTIFICATIONS", "unknown"),
+                ("AUTH_LOG_LEVEL", "info,[[["),
+                ("AUTH_OAUTH_NATIVE_SCHEMES", "com.example.app,javascript"),
+            ],
+        )
+        .unwrap_err();
+
+        let problems = err.problems.join("\n");
+        for expected in &[
+            "PORT",
+            "AUTH_MAILER",
+            "TSDB_URL",
+            "pool_size",
+            "AUTH_JWT_SECRET",
+            "AUTH_SALT",
+            "unknown",
+            "AUTH_LOG_LEVEL",
+            "AUTH_OAUTH_NATIVE_SCHEMES",
+        ] {
+            assert!(
+                problems.contains(expected),
+                "{} missing from {}",
+                expected,
+                problems
+            );
+        }
+
+        assert!(load("[server]\nprot = 1\n", &[]).is_err());
+    }
+
+    #[test]
+    fn files_that_wont_load_are_reported_with_everything_else() {
+        let dir = env::temp_dir().join("auth-app-config-test-files");
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        let not_json = dir.join("providers.json");
+        fs::write(&not_json, "not json").unwrap();
+        let not_a_key = dir.join("key.pem");
+        fs::write(&not_a_key, "not a key").unwrap();
+        let path = |path: &std::path::PathBuf| path.to_str().unwrap().to_owned();
+
+        let err = load(
+            "",
+            &[
+                ("AUTH_JWT_SECRET", "secretsecretsecret"),
+                ("AUTH_SALT", "0123456789abcdef"),
+                ("AUTH_DB_BACKEND", "memory"),
+                ("AUTH_OIDC_PROVIDERS", &path(&not_json)),
+                ("AUTH_OAUTH_PROVIDERS", &path(&not_json)),
+                ("AUTH_OIDC_SIGNING_KEYS", &path(&not_a_key)),
+                ("AUTH_EMAIL_TEMPLATES", &path(&dir.join("templates"))),
+                ("AUTH_MAILER", "file"),
+                // a file is in the way of the directory
+                ("AUTH_MAIL_DIR", &path(&not_json.join("mail"))),
+            ],
+        )
+        .unwrap_err();
+
+        let problems = err.problems.join("\n");
+        for expected in &[
+            "AUTH_OIDC_PROVIDERS",
+            "AUTH_OAUTH_PROVIDERS",
+            "AUTH_OIDC_SIGNING_KEYS",
+            "AUTH_EMAIL_TEMPLATES",
+            "AUTH_MAIL_DIR",
+        ] {
+            assert!(
+                problems.contains(expected),
+                "{} missing from {}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn sqlite_and_memory_need_no_postgres_url() {
+        let secrets = [
+            ("AUTH_JWT_SECRET", "secretsecretsecret"),
+            ("AUTH_SALT", "0123456789abcdef"),
+        ];
+        let config = load("[database]\nbackend = \"sqlite\"\n", &secrets).unwrap();
+        assert_eq!(config.database.backend, DatabaseBackend::Sqlite);
+        assert_eq!(config.database.path, "auth.db");
+
+        let mut env = secrets.to_vec();
+        env.push(("AUTH_DB_BACKEND", "memory"));
+        let config = load("", &env).unwrap();
+        assert_eq!(config.database.backend, DatabaseBackend::Memory);
+
+        let err = load("[database]\nbackend = \"sqlite\"\npath = \"\"\n", &secrets).unwrap_err();
+        assert!(err.problems.join("\n").contains("AUTH_DB_PATH"));
+        assert!(load("", &secrets).is_err());
+    }
+}
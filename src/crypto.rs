@@ -0,0 +1,34 @@
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// a url safe random string made from `bytes` random bytes
+pub fn random_token(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    base64::encode_config(&buf, base64::URL_SAFE_NO_PAD)
+}
+
+// tokens we hand out are stored as hashes so a leaked table can't be replayed.
+// they're long and random, so a plain sha256 is enough here, unlike passwords
+pub fn hash_token(token: &str) -> String {
+    base64::encode_config(&Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+// the S256 pkce code challenge for a code verifier
+pub fn pkce_challenge(verifier: &str) -> String {
+    hash_token(verifier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkce_challenge_matches_rfc_7636_example() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+}
@@ -1,56 +1,245 @@
-use crate::auth::{Auth, User};
+use crate::admin::Account;
+use crate::api_key::{ApiKey, ApiKeyGrant};
+use crate::audit::{AuditEvent, AuditQuery, AuditRecord};
+use crate::auth::{ExternalIdentity, ExternalSignIn, User};
+use crate::config::{DatabaseConfig, DatabaseTls};
+use crate::directory::{DirectoryUser, SqlParam, UserQuery};
 use crate::error::AuthError;
-use r2d2_postgres::r2d2;
-use r2d2_postgres::PostgresConnectionManager;
+use crate::logging;
+use crate::mailer::Email;
+use crate::migrations::{AppliedMigration, Migration};
+use crate::oauth_client::OAuthLogin;
+use crate::oauth_server::{AuthorizationCode, AuthorizeRequest, Grant, OAuthClient};
+use crate::oidc_issuer::UserInfo;
+use crate::outbox::{DeadMessage, OutboxMessage};
+use crate::profile::{ExternalProfile, Profile, ProfileUpdate};
+use crate::service_account::ServiceAccount;
+use crate::session::{Credentials, UserStatus};
+use crate::store::{
+    self, AuditStore, ClientStore, OutboxStore, SessionStore, TokenStore, UserStore,
+};
+use async_trait::async_trait;
+use deadpool_postgres::{GenericClient, Manager, ManagerConfig, Pool, RecyclingMethod};
+use postgres_native_tls::MakeTlsConnector;
+use std::time::{Duration, Instant};
+use tokio_postgres::config::SslMode;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{NoTls, Row};
+use tracing::{Instrument, Span};
+
+// the advisory lock taken while migrating, any number that's ours alone
+static MIGRATION_LOCK: i64 = 7_262_022;
+
+// the statement on one line, for the span it runs in. the values are
+// parameters, so there's nothing in it to redact
+fn db_span(sql: &str) -> Span {
+    let statement = sql.split_whitespace().collect::<Vec<&str>>().join(" ");
+    tracing::debug_span!("db", %statement)
+}
+
+// statements go through the connection's cache, so each one
+// is only prepared once per connection
+async fn query<C: GenericClient>(
+    client: &C,
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<Vec<Row>, tokio_postgres::Error> {
+    async {
+        let started = Instant::now();
+        let statement = client.prepare_cached(sql).await?;
+        let rows = client.query(&statement, params).await?;
+        tracing::debug!(
+            elapsed_ms = logging::elapsed_ms(started),
+            rows = rows.len(),
+            "query finished"
+        );
+        Ok(rows)
+    }
+    .instrument(db_span(sql))
+    .await
+}
+
+async fn execute<C: GenericClient>(
+    client: &C,
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<u64, tokio_postgres::Error> {
+    async {
+        let started = Instant::now();
+        let statement = client.prepare_cached(sql).await?;
+        let num = client.execute(&statement, params).await?;
+        tracing::debug!(
+            elapsed_ms = logging::elapsed_ms(started),
+            rows = num,
+            "statement finished"
+        );
+        Ok(num)
+    }
+    .instrument(db_span(sql))
+    .await
+}
 
 #[derive(Clone)]
 pub struct Db {
-    pub pool: r2d2::Pool<r2d2_postgres::PostgresConnectionManager>,
+    pool: Pool,
 }
 
 impl Db {
-    pub fn new(url: &str) -> Db {
-        let manager = PostgresConnectionManager::new(url, r2d2_postgres::TlsMode::None)
-            .expect("Couldn't make a new postgres connection manager.");
-        let pool = r2d2::Pool::builder()
-            .max_size(3)
-            .build(manager)
-            .expect("Couldn't make the connection pool.");
-        Db { pool }
-    }
-
-    pub fn add_user(&self, user: &User) -> Result<u64, AuthError> {
-        let conn = self.pool.get()?;
-        match conn.execute("CALL add_user($1, $2, $3);",
+    // connections are made as they're needed, so this doesn't touch the database
+    // and only fails when the config is wrong
+    pub fn new(config: &DatabaseConfig) -> Result<Db, failure::Error> {
+        let mut pg_config = config.url.parse::<tokio_postgres::Config>()?;
+        // a query that runs longer than this is cancelled by the server
+        pg_config.options(format!(
+            "-c statement_timeout={}",
+            config.statement_timeout_ms
+        ));
+
+        let manager_config = ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        };
+        let manager = match config.tls {
+            DatabaseTls::None => Manager::from_config(pg_config, NoTls, manager_config),
+            DatabaseTls::Prefer | DatabaseTls::Require => {
+                pg_config.ssl_mode(if config.tls == DatabaseTls::Require {
+                    SslMode::Require
+                } else {
+                    SslMode::Prefer
+                });
+                let connector = native_tls::TlsConnector::new()?;
+                Manager::from_config(pg_config, MakeTlsConnector::new(connector), manager_config)
+            }
+        };
+
+        // waiting for a connection, or for a new one to be made,
+        // gives up after the pool timeout
+        let timeout = Some(Duration::from_millis(config.pool_timeout_ms));
+        let pool = Pool::builder(manager)
+            .max_size(config.pool_size as usize)
+            .runtime(deadpool_postgres::Runtime::Tokio1)
+            .wait_timeout(timeout)
+            .create_timeout(timeout)
+            .build()?;
+        Ok(Db { pool })
+    }
+
+    async fn query(
+        &self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, AuthError> {
+        let conn = self.pool.get().await?;
+        Ok(query(&conn, sql, params).await?)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, AuthError> {
+        let conn = self.pool.get().await?;
+        Ok(execute(&conn, sql, params).await?)
+    }
+
+    // nothing has been applied to a database without the migrations table,
+    // which is left for apply_migration to make
+    pub async fn get_applied_migrations(&self) -> Result<Vec<AppliedMigration>, AuthError> {
+        let rows = self
+            .query("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+            .await?;
+        if !rows[0].get::<_, bool>(0) {
+            return Ok(Vec::new());
+        }
+
+        let rows = self
+            .query(
+                "SELECT version, name, checksum, extract(epoch from applied_at)::bigint
+            FROM schema_migrations ORDER BY version",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| AppliedMigration {
+                version: row.get(0),
+                name: row.get(1),
+                checksum: row.get(2),
+                applied_at: row.get(3),
+            })
+            .collect())
+    }
+
+    // applies a migration unless it already has been, true when it was applied here.
+    // the lock holds off anyone else migrating until this commits
+    pub async fn apply_migration(&self, migration: &Migration) -> Result<bool, AuthError> {
+        let mut conn = self.pool.get().await?;
+        let trans = conn.transaction().await?;
+        // migrations, and waiting for someone else's, can take as long as they need
+        trans
+            .batch_execute("SET LOCAL statement_timeout = 0")
+            .await?;
+        execute(
+            &trans,
+            "SELECT pg_advisory_xact_lock($1)",
+            &[&MIGRATION_LOCK],
+        )
+        .await?;
+        trans
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version integer primary key,
+                name text not null,
+                checksum text not null,
+                applied_at timestamptz not null default now()
+            )",
+            )
+            .await?;
+
+        let rows = query(
+            &trans,
+            "SELECT 1 FROM schema_migrations WHERE version=$1",
+            &[&migration.version],
+        )
+        .await?;
+        if !rows.is_empty() {
+            return Ok(false);
+        }
+
+        trans.batch_execute(migration.sql).await.map_err(|err| {
+            AuthError::internal_error(&format!(
+                "migration {} ({}) failed: {}",
+                migration.version, migration.name, err
+            ))
+        })?;
+        execute(
+            &trans,
+            "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+            &[&migration.version, &migration.name, &migration.checksum()],
+        )
+        .await?;
+        trans.commit().await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl UserStore for Db {
+    async fn add_user(&self, user: &User) -> Result<u64, AuthError> {
+        let conn = self.pool.get().await?;
+        match execute(
+            &conn,
+            "CALL add_user($1, $2, $3);",
             &[&user.email, &user.username, &user.password],
-        ) {
+        )
+        .await
+        {
             Ok(modified_rows) => Ok(modified_rows),
             Err(err) => {
-                if let Some(dberr) = err.as_db() {
-                    println!("some dberr");
-                    // 23505 = unique violation
-                    if !(dberr.code.code() == "23505") {
-                        println!("code doesn't equal 23505");
+                if let Some(dberr) = err.as_db_error() {
+                    if *dberr.code() != SqlState::UNIQUE_VIOLATION {
                         return Err(AuthError::internal_error(&err.to_string()));
                     }
-                    if let Some(constraint) = &dberr.constraint {
-                        match constraint.as_ref() {
-                            "users_email_key" => {
-                                return Err(AuthError::new(
-                                    "signupEmail",
-                                    "This email has already been registered.",
-                                    "",
-                                    500,
-                                ))
-                            }
-                            "users_username_key" => {
-                                return Err(AuthError::new(
-                                    "username",
-                                    "This username has already been taken.",
-                                    "",
-                                    500,
-                                ))
-                            }
+                    if let Some(constraint) = dberr.constraint() {
+                        match constraint {
+                            "users_email_key" => return Err(store::email_taken()),
+                            "users_username_key" => return Err(store::username_taken()),
                             _ => return Err(AuthError::internal_error(&err.to_string())),
                         }
                     }
@@ -60,15 +249,13 @@ impl Db {
         }
     }
 
-    pub fn user_exists(&self, username: &str) -> Result<bool, AuthError> {
-        let conn = self.pool.get()?;
-        let rows = match conn.query(
-            "SELECT username FROM users WHERE username=$1 OR email=$1",
-            &[&username],
-        ) {
-            Ok(r) => r,
-            Err(err) => return Err(AuthError::internal_error(&err.to_string())),
-        };
+    async fn user_exists(&self, username: &str) -> Result<bool, AuthError> {
+        let rows = self
+            .query(
+                "SELECT username FROM users WHERE username=$1 OR email=$1",
+                &[&username],
+            )
+            .await?;
 
         if rows.is_empty() {
             return Ok(false);
@@ -76,82 +263,1077 @@ impl Db {
         Ok(true)
     }
 
-    pub fn get_all_users(&self) -> Result<Vec<String>, AuthError> {
-        let mut users: Vec<String> = Vec::new();
-        let conn = self.pool.get()?;
+    async fn list_users(
+        &self,
+        query: &UserQuery,
+        limit: i64,
+    ) -> Result<(Vec<DirectoryUser>, i64), AuthError> {
+        let created = "floor(extract(epoch from created_at))::bigint";
+        let sql = query.to_sql(created, |n| format!("${}", n));
+        let mut params: Vec<&(dyn ToSql + Sync)> = sql
+            .params
+            .iter()
+            .map(|param| match param {
+                SqlParam::Text(value) => value as &(dyn ToSql + Sync),
+                SqlParam::Int(value) => value,
+                SqlParam::Bool(value) => value,
+                SqlParam::Id(value) => value,
+            })
+            .collect();
 
-        let rows = match conn.query("SELECT username FROM users", &[]) {
-            Ok(r) => r,
-            Err(err) => return Err(AuthError::internal_error(&err.to_string())),
-        };
+        let rows = self
+            .query(
+                &format!("SELECT count(*) FROM users {}", sql.filter),
+                &params[..sql.filter_params],
+            )
+            .await?;
+        let total: i64 = rows[0].get(0);
+
+        params.push(&limit);
+        let rows = self
+            .query(
+                &format!(
+                    "SELECT id, username, email, admin, disabled, email_verified, {}
+                FROM users {} {} LIMIT ${}",
+                    created,
+                    sql.page,
+                    sql.order,
+                    params.len()
+                ),
+                &params,
+            )
+            .await?;
+
+        let users = rows
+            .iter()
+            .map(|row| DirectoryUser {
+                id: row.get(0),
+                username: row.get(1),
+                email: row.get(2),
+                admin: row.get(3),
+                disabled: row.get(4),
+                email_verified: row.get(5),
+                created_at: row.get(6),
+            })
+            .collect();
+        Ok((users, total))
+    }
+
+    // what signing in with a password is checked against
+    async fn get_credentials(&self, email: &str) -> Result<Option<Credentials>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT username, password, disabled FROM users WHERE email=$1",
+                &[&email],
+            )
+            .await?;
+
+        Ok(rows.first().map(|row| Credentials {
+            username: row.get(0),
+            password_hash: row.get(1),
+            disabled: row.get(2),
+        }))
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<String, AuthError> {
+        let rows = self
+            .query("SELECT username FROM users WHERE email=$1", &[&email])
+            .await?;
 
-        for row in &rows {
-            let username: String = row.get(0);
-            users.push(username);
+        if rows.is_empty() {
+            // return ok even if user isn't found
+            // ecurity through obscurity
+            return Ok("".to_owned());
         }
 
-        Ok(users)
+        Ok(rows[0].get(0))
     }
 
-    pub fn verify_user(&self, user: &User) -> Result<String, AuthError> {
-        let conn = self.pool.get()?;
+    async fn update_user_password(&self, username: &str, password: &str) -> Result<u64, AuthError> {
+        self.execute(
+            // changing the password signs the user out everywhere
+            "UPDATE users SET password = $1, tokens_valid_after = now() WHERE username=$2",
+            &[&password, &username],
+        )
+        .await
+    }
 
-        let rows = match conn.query(
-            "SELECT username, password FROM users WHERE email=$1",
-            &[&user.email],
-        ) {
-            Ok(r) => r,
-            Err(err) => return Err(AuthError::internal_error(&err.to_string())),
-        };
+    // finds the user linked to an external identity, linking or creating one if needed.
+    // returns the username to issue a token for
+    async fn sign_in_external(
+        &self,
+        identity: &ExternalIdentity,
+    ) -> Result<ExternalSignIn, AuthError> {
+        let mut conn = self.pool.get().await?;
+        let trans = conn.transaction().await?;
 
-        let mut hashed_password = "".to_owned();
-        let mut username = "".to_owned();
-        for row in &rows {
-            username = row.get(0);
-            hashed_password = row.get(1);
-            break;
+        let rows = query(
+            &trans,
+            "SELECT u.username, u.disabled FROM user_identities i JOIN users u ON u.id = i.user_id
+            WHERE i.provider=$1 AND i.subject=$2",
+            &[&identity.provider, &identity.subject],
+        )
+        .await?;
+        if !rows.is_empty() {
+            let username: String = rows[0].get(0);
+            if rows[0].get(1) {
+                return Err(store::account_disabled(&username));
+            }
+            return Ok(ExternalSignIn {
+                username,
+                linked_existing: false,
+            });
         }
-        if Auth::verify_hash(hashed_password, user.password.clone()) {
-            Ok(username)
+
+        store::check_external_email(identity)?;
+
+        let rows = query(
+            &trans,
+            "SELECT id, username, disabled FROM users WHERE email=$1",
+            &[&identity.email],
+        )
+        .await?;
+
+        let profile = ExternalProfile::from_identity(identity);
+        let linked_existing = !rows.is_empty();
+        let (user_id, username): (i32, String) = if linked_existing {
+            store::check_external_link(identity)?;
+            let row = &rows[0];
+            if row.get(2) {
+                return Err(store::account_disabled(row.get(1)));
+            }
+            // the provider vouched for the email, and fills in what the profile is missing
+            execute(
+                &trans,
+                "UPDATE users SET email_verified=true, display_name=coalesce(display_name, $2),
+                avatar_url=coalesce(avatar_url, $3), locale=coalesce(locale, $4) WHERE id=$1",
+                &[
+                    &row.get::<_, i32>(0),
+                    &profile.display_name,
+                    &profile.avatar_url,
+                    &profile.locale,
+                ],
+            )
+            .await?;
+            (row.get(0), row.get(1))
         } else {
-            return Err(AuthError::new(
-                "signin",
-                "Email and password combo not found.",
-                "Token hash wasn't verified.",
-                400,
-            ));
+            let mut created = None;
+            for candidate in store::external_usernames(identity) {
+                let rows = query(&trans,
+                    "INSERT INTO users (email, username, password, email_verified, display_name, avatar_url, locale)
+                    VALUES ($1, $2, '', $3, $4, $5, $6)
+                    ON CONFLICT (username) DO NOTHING RETURNING id",
+                    &[&identity.email, &candidate, &identity.email_verified,
+                    &profile.display_name, &profile.avatar_url, &profile.locale],
+                ).await?;
+                if !rows.is_empty() {
+                    created = Some((rows[0].get(0), candidate));
+                    break;
+                }
+            }
+
+            match created {
+                Some(created) => created,
+                None => return Err(store::no_free_username(identity)),
+            }
+        };
+
+        execute(&trans,
+            "INSERT INTO user_identities (user_id, provider, subject, email) VALUES ($1, $2, $3, $4)",
+            &[&user_id, &identity.provider, &identity.subject, &identity.email],
+        ).await?;
+        trans.commit().await?;
+
+        Ok(ExternalSignIn {
+            username,
+            linked_existing,
+        })
+    }
+
+    async fn get_user_id(&self, username: &str) -> Result<i32, AuthError> {
+        let rows = self
+            .query("SELECT id FROM users WHERE username=$1", &[&username])
+            .await?;
+
+        if rows.is_empty() {
+            return Err(store::unknown_user(username));
         }
+        Ok(rows[0].get(0))
     }
 
-    pub fn get_user_by_email(&self, email: &str) -> Result<String, AuthError> {
-        let conn = self.pool.get()?;
-        let rows = match conn.query("SELECT username FROM users WHERE email=$1", &[&email]) {
-            Ok(r) => r,
-            Err(err) => return Err(AuthError::internal_error(&err.to_string())),
-        };
+    async fn get_user_info(&self, username: &str) -> Result<Option<UserInfo>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT id, email, username FROM users WHERE username=$1",
+                &[&username],
+            )
+            .await?;
 
         if rows.is_empty() {
-            // return ok even if user isn't found
-            // ecurity through obscurity
-            return Ok("".to_owned());
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        Ok(Some(UserInfo {
+            id: row.get(0),
+            email: row.get(1),
+            username: row.get(2),
+        }))
+    }
+
+    async fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT disabled, floor(extract(epoch from tokens_valid_after))::bigint
+            FROM users WHERE username=$1",
+                &[&username],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        Ok(Some(UserStatus {
+            disabled: row.get(0),
+            tokens_valid_after: row.get(1),
+        }))
+    }
+
+    async fn is_admin(&self, username: &str) -> Result<bool, AuthError> {
+        let rows = self
+            .query(
+                "SELECT 1 FROM users WHERE username=$1 AND admin AND NOT disabled",
+                &[&username],
+            )
+            .await?;
+        Ok(!rows.is_empty())
+    }
+
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, AuthError> {
+        let rows = self.query(
+            "SELECT id, email, username, disabled, admin,
+                floor(extract(epoch from tokens_valid_after))::bigint,
+                array(SELECT provider FROM user_identities WHERE user_id = users.id ORDER BY provider)
+            FROM users WHERE username=$1",
+            &[&username],
+        ).await?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        Ok(Some(Account {
+            id: row.get(0),
+            email: row.get(1),
+            username: row.get(2),
+            disabled: row.get(3),
+            admin: row.get(4),
+            tokens_valid_after: row.get(5),
+            identities: row.get(6),
+        }))
+    }
+
+    async fn set_user_disabled(&self, username: &str, disabled: bool) -> Result<u64, AuthError> {
+        let num = self
+            .execute(
+                "UPDATE users SET disabled=$2 WHERE username=$1",
+                &[&username, &disabled],
+            )
+            .await?;
+        Ok(num)
+    }
+
+    async fn set_user_admin(&self, username: &str, admin: bool) -> Result<u64, AuthError> {
+        let num = self
+            .execute(
+                "UPDATE users SET admin=$2 WHERE username=$1",
+                &[&username, &admin],
+            )
+            .await?;
+        Ok(num)
+    }
+
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT username, email, display_name, avatar_url, locale, timezone,
+            floor(extract(epoch from created_at))::bigint,
+            floor(extract(epoch from coalesce(updated_at, created_at)))::bigint,
+            floor(extract(epoch from last_login_at))::bigint
+            FROM users WHERE username=$1",
+                &[&username],
+            )
+            .await?;
+
+        Ok(rows.first().map(|row| Profile {
+            username: row.get(0),
+            email: row.get(1),
+            display_name: row.get(2),
+            avatar_url: row.get(3),
+            locale: row.get(4),
+            timezone: row.get(5),
+            created_at: row.get(6),
+            updated_at: row.get(7),
+            last_login_at: row.get(8),
+        }))
+    }
+
+    async fn update_profile(
+        &self,
+        username: &str,
+        update: &ProfileUpdate,
+    ) -> Result<u64, AuthError> {
+        let mut sets = vec!["updated_at=now()".to_owned()];
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&username];
+        for (column, value) in [
+            ("display_name", &update.display_name),
+            ("avatar_url", &update.avatar_url),
+            ("locale", &update.locale),
+            ("timezone", &update.timezone),
+        ] {
+            if let Some(value) = value {
+                params.push(value);
+                sets.push(format!("{}=${}", column, params.len()));
+            }
+        }
+
+        self.execute(
+            &format!("UPDATE users SET {} WHERE username=$1", sets.join(", ")),
+            &params,
+        )
+        .await
+    }
+
+    async fn record_sign_in(&self, username: &str) -> Result<(), AuthError> {
+        self.execute(
+            "UPDATE users SET last_login_at=now() WHERE username=$1",
+            &[&username],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for Db {
+    async fn revoke_token(&self, jti: &str, expires_at: i64) -> Result<bool, AuthError> {
+        self.execute("DELETE FROM revoked_tokens WHERE expires_at < now()", &[])
+            .await?;
+        let num = self
+            .execute(
+                "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, to_timestamp($2))
+            ON CONFLICT (jti) DO NOTHING",
+                &[&jti, &(expires_at as f64)],
+            )
+            .await?;
+        Ok(num == 1)
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        let rows = self
+            .query("SELECT 1 FROM revoked_tokens WHERE jti=$1", &[&jti])
+            .await?;
+        Ok(!rows.is_empty())
+    }
+
+    // signs the user out everywhere, tokens issued before now stop working
+    async fn revoke_sessions(&self, username: &str) -> Result<u64, AuthError> {
+        let num = self
+            .execute(
+                "UPDATE users SET tokens_valid_after = now() WHERE username=$1",
+                &[&username],
+            )
+            .await?;
+        Ok(num)
+    }
+
+    // records that the user signed in from a device. true when the device is new
+    // and they had signed in from somewhere else before
+    async fn remember_device(&self, user_id: i32, device_hash: &str) -> Result<bool, AuthError> {
+        let num = self
+            .execute(
+                "UPDATE known_devices SET last_seen_at = now() WHERE user_id=$1 AND device_hash=$2",
+                &[&user_id, &device_hash],
+            )
+            .await?;
+        if num != 0 {
+            return Ok(false);
         }
 
-        let mut username = "".to_owned();
-        for row in &rows {
-            username = row.get(0);
-            break;
+        let rows = self
+            .query(
+                "SELECT 1 FROM known_devices WHERE user_id=$1 LIMIT 1",
+                &[&user_id],
+            )
+            .await?;
+        self.execute(
+            "INSERT INTO known_devices (user_id, device_hash) VALUES ($1, $2)
+            ON CONFLICT (user_id, device_hash) DO NOTHING",
+            &[&user_id, &device_hash],
+        )
+        .await?;
+        Ok(!rows.is_empty())
+    }
+
+    async fn add_oauth_login(&self, login: &OAuthLogin) -> Result<(), AuthError> {
+        // logins that were never finished are cleaned up as new ones start
+        self.execute(
+            "DELETE FROM oauth_logins WHERE created_at < now() - interval '1 hour'",
+            &[],
+        )
+        .await?;
+        self.execute(
+            "INSERT INTO oauth_logins (state, provider, nonce, code_verifier) VALUES ($1, $2, $3, $4)",
+            &[&login.state, &login.provider, &login.nonce, &login.code_verifier],
+        ).await?;
+        Ok(())
+    }
+
+    // a login can only be finished once, and only within 10 minutes of starting it
+    async fn take_oauth_login(&self, state: &str) -> Result<Option<OAuthLogin>, AuthError> {
+        let rows = self.query(
+            "DELETE FROM oauth_logins WHERE state=$1 AND created_at > now() - interval '10 minutes'
+            RETURNING provider, nonce, code_verifier",
+            &[&state],
+        ).await?;
+
+        if rows.is_empty() {
+            return Ok(None);
         }
-        Ok(username)
+
+        let row = &rows[0];
+        Ok(Some(OAuthLogin {
+            state: state.to_owned(),
+            provider: row.get(0),
+            nonce: row.get(1),
+            code_verifier: row.get(2),
+        }))
     }
+}
 
-    pub fn update_user_password(&self, email: &str, password: &str) -> Result<u64, AuthError> {
-        let conn = self.pool.get()?;
-        match conn.execute(
-            "UPDATE users SET password = $1 WHERE username=$2",
-            &[&password, &email],
-        ) {
-            Ok(num) => Ok(num),
-            Err(err) => Err(AuthError::internal_error(&err.to_string())),
+#[async_trait]
+impl TokenStore for Db {
+    async fn add_api_key(
+        &self,
+        user_id: i32,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        expires_at: Option<i64>,
+    ) -> Result<i32, AuthError> {
+        let rows = self
+            .query(
+                "INSERT INTO api_keys (user_id, name, prefix, key_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, to_timestamp($6)) RETURNING id",
+                &[
+                    &user_id,
+                    &name,
+                    &prefix,
+                    &key_hash,
+                    &scopes,
+                    &expires_at.map(|at| at as f64),
+                ],
+            )
+            .await?;
+        Ok(rows[0].get(0))
+    }
+
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT id, name, prefix, scopes,
+                extract(epoch from created_at)::bigint, extract(epoch from last_used_at)::bigint,
+                extract(epoch from expires_at)::bigint
+            FROM api_keys WHERE user_id=$1 AND revoked_at IS NULL ORDER BY id",
+                &[&user_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| ApiKey {
+                id: row.get(0),
+                name: row.get(1),
+                prefix: row.get(2),
+                scopes: row.get(3),
+                created_at: row.get(4),
+                last_used_at: row.get(5),
+                expires_at: row.get(6),
+            })
+            .collect())
+    }
+
+    async fn revoke_api_key(&self, user_id: i32, id: i32) -> Result<u64, AuthError> {
+        let num = self
+            .execute(
+                "UPDATE api_keys SET revoked_at = now()
+            WHERE id=$1 AND user_id=$2 AND revoked_at IS NULL",
+                &[&id, &user_id],
+            )
+            .await?;
+        Ok(num)
+    }
+
+    // looks up a key by its prefix and hash and records that it was used
+    async fn use_api_key(
+        &self,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<Option<ApiKeyGrant>, AuthError> {
+        let rows = self
+            .query(
+                "WITH key AS (
+                UPDATE api_keys SET last_used_at = now()
+                WHERE prefix=$1 AND key_hash=$2 AND revoked_at IS NULL
+                    AND (expires_at IS NULL OR expires_at > now())
+                RETURNING user_id, prefix, scopes, created_at
+            )
+            SELECT users.username, key.prefix, key.scopes,
+                floor(extract(epoch from key.created_at))::bigint
+            FROM key JOIN users ON users.id = key.user_id",
+                &[&prefix, &key_hash],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(None);
         }
+
+        let row = &rows[0];
+        Ok(Some(ApiKeyGrant {
+            username: row.get(0),
+            prefix: row.get(1),
+            scopes: row.get(2),
+            created_at: row.get(3),
+        }))
+    }
+
+    async fn add_oauth_code(
+        &self,
+        code_hash: &str,
+        user_id: i32,
+        req: &AuthorizeRequest,
+        scope: &str,
+        auth_time: i64,
+    ) -> Result<(), AuthError> {
+        self.execute("DELETE FROM oauth_codes WHERE expires_at < now()", &[])
+            .await?;
+        self.execute(
+            "INSERT INTO oauth_codes (code_hash, client_id, user_id, redirect_uri, scope, code_challenge, nonce, auth_time, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, now() + interval '10 minutes')",
+            &[
+                &code_hash,
+                &req.client_id,
+                &user_id,
+                &req.redirect_uri,
+                &scope,
+                &req.code_challenge,
+                &req.nonce,
+                &auth_time,
+            ],
+        ).await?;
+        Ok(())
+    }
+
+    // codes can only be exchanged once
+    async fn take_oauth_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<AuthorizationCode>, AuthError> {
+        let rows = self
+            .query(
+                "WITH code AS (
+                DELETE FROM oauth_codes WHERE code_hash=$1 AND expires_at > now()
+                RETURNING client_id, user_id, redirect_uri, scope, code_challenge, nonce, auth_time
+            )
+            SELECT code.client_id, code.user_id, users.username, code.scope, code.auth_time,
+                code.redirect_uri, code.code_challenge, code.nonce
+            FROM code JOIN users ON users.id = code.user_id",
+                &[&code_hash],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        Ok(Some(AuthorizationCode {
+            grant: Grant {
+                client_id: row.get(0),
+                user_id: row.get(1),
+                username: row.get(2),
+                scope: row.get(3),
+                auth_time: row.get(4),
+            },
+            redirect_uri: row.get(5),
+            code_challenge: row.get(6),
+            nonce: row.get(7),
+        }))
+    }
+
+    async fn add_refresh_token(&self, token_hash: &str, grant: &Grant) -> Result<(), AuthError> {
+        self.execute(
+            "INSERT INTO oauth_refresh_tokens (token_hash, client_id, user_id, scope, auth_time, expires_at)
+            VALUES ($1, $2, $3, $4, $5, now() + interval '30 days')",
+            &[
+                &token_hash,
+                &grant.client_id,
+                &grant.user_id,
+                &grant.scope,
+                &grant.auth_time,
+            ],
+        ).await?;
+        Ok(())
+    }
+
+    // marks the refresh token used and returns what it was granted for
+    async fn take_refresh_token(&self, token_hash: &str) -> Result<Option<Grant>, AuthError> {
+        let rows = self
+            .query(
+                "WITH token AS (
+                UPDATE oauth_refresh_tokens SET revoked_at = now()
+                WHERE token_hash=$1 AND revoked_at IS NULL AND expires_at > now()
+                RETURNING client_id, user_id, scope, auth_time
+            )
+            SELECT token.client_id, token.user_id, users.username, token.scope, token.auth_time
+            FROM token JOIN users ON users.id = token.user_id",
+                &[&token_hash],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        Ok(Some(Grant {
+            client_id: row.get(0),
+            user_id: row.get(1),
+            username: row.get(2),
+            scope: row.get(3),
+            auth_time: row.get(4),
+        }))
+    }
+
+    async fn add_not_me_token(
+        &self,
+        token_hash: &str,
+        user_id: i32,
+        days: i64,
+    ) -> Result<(), AuthError> {
+        self.execute("DELETE FROM not_me_tokens WHERE expires_at < now()", &[])
+            .await?;
+        self.execute(
+            "INSERT INTO not_me_tokens (token_hash, user_id, expires_at)
+            VALUES ($1, $2, now() + $3 * interval '1 day')",
+            &[&token_hash, &user_id, &(days as f64)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    // uses up a "this wasn't me" token, returning the user it was for
+    async fn take_not_me_token(&self, token_hash: &str) -> Result<Option<UserInfo>, AuthError> {
+        let rows = self
+            .query(
+                "WITH token AS (
+                DELETE FROM not_me_tokens WHERE token_hash=$1 AND expires_at > now()
+                RETURNING user_id
+            )
+            SELECT users.id, users.email, users.username
+            FROM token JOIN users ON users.id = token.user_id",
+                &[&token_hash],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        Ok(Some(UserInfo {
+            id: row.get(0),
+            email: row.get(1),
+            username: row.get(2),
+        }))
+    }
+}
+
+#[async_trait]
+impl ClientStore for Db {
+    async fn add_oauth_client(&self, client: &OAuthClient, owner_id: i32) -> Result<(), AuthError> {
+        self.execute(
+            "INSERT INTO oauth_clients (client_id, secret_hash, name, redirect_uris, scopes, first_party, owner_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &client.client_id,
+                &client.secret_hash,
+                &client.name,
+                &client.redirect_uris,
+                &client.scopes,
+                &client.first_party,
+                &owner_id,
+            ],
+        ).await?;
+        Ok(())
+    }
+
+    async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT client_id, secret_hash, name, redirect_uris, scopes, first_party
+            FROM oauth_clients WHERE client_id=$1",
+                &[&client_id],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        Ok(Some(OAuthClient {
+            client_id: row.get(0),
+            secret_hash: row.get(1),
+            name: row.get(2),
+            redirect_uris: row.get(3),
+            scopes: row.get(4),
+            first_party: row.get(5),
+        }))
+    }
+
+    // the scopes a user has already agreed to give a client
+    async fn get_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+    ) -> Result<Vec<String>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT scopes FROM oauth_consents WHERE user_id=$1 AND client_id=$2",
+                &[&user_id, &client_id],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(rows[0].get(0))
+    }
+
+    async fn add_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+        scopes: &[String],
+    ) -> Result<(), AuthError> {
+        self.execute(
+            "INSERT INTO oauth_consents (user_id, client_id, scopes) VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, client_id) DO UPDATE
+            SET scopes = ARRAY(SELECT DISTINCT unnest(oauth_consents.scopes || EXCLUDED.scopes))",
+            &[&user_id, &client_id, &scopes],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn add_service_account(
+        &self,
+        account: &ServiceAccount,
+        owner_id: i32,
+    ) -> Result<(), AuthError> {
+        let jwks = match account.jwks {
+            Some(ref jwks) => Some(
+                serde_json::to_string(jwks)
+                    .map_err(|err| AuthError::internal_error(&err.to_string()))?,
+            ),
+            None => None,
+        };
+
+        self.execute(
+            "INSERT INTO service_accounts (client_id, name, secret_hash, jwks, scopes, owner_id)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &account.client_id,
+                &account.name,
+                &account.secret_hash,
+                &jwks,
+                &account.scopes,
+                &owner_id,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_service_account(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<ServiceAccount>, AuthError> {
+        let rows = self
+            .query(
+                "SELECT client_id, name, secret_hash, jwks, scopes, disabled
+            FROM service_accounts WHERE client_id=$1",
+                &[&client_id],
+            )
+            .await?;
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let row = &rows[0];
+        let jwks: Option<String> = row.get(3);
+        let jwks = match jwks {
+            Some(jwks) => Some(
+                serde_json::from_str(&jwks)
+                    .map_err(|err| AuthError::internal_error(&err.to_string()))?,
+            ),
+            None => None,
+        };
+
+        Ok(Some(ServiceAccount {
+            client_id: row.get(0),
+            name: row.get(1),
+            secret_hash: row.get(2),
+            jwks,
+            scopes: row.get(4),
+            disabled: row.get(5),
+        }))
+    }
+
+    async fn set_service_account_disabled(
+        &self,
+        client_id: &str,
+        disabled: bool,
+    ) -> Result<u64, AuthError> {
+        self.execute(
+            "UPDATE service_accounts SET disabled=$2 WHERE client_id=$1",
+            &[&client_id, &disabled],
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl OutboxStore for Db {
+    async fn enqueue_email(&self, email: &Email, expires_at: Option<i64>) -> Result<(), AuthError> {
+        self.execute(
+            "INSERT INTO email_outbox (to_address, subject, html, text, expires_at)
+            VALUES ($1, $2, $3, $4, to_timestamp($5))",
+            &[
+                &email.to,
+                &email.subject,
+                &email.html,
+                &email.text,
+                &expires_at.map(|at| at as f64),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn drop_expired_emails(&self) -> Result<u64, AuthError> {
+        self.execute(
+            "DELETE FROM email_outbox WHERE status <> 'sent' AND expires_at <= now()",
+            &[],
+        )
+        .await
+    }
+
+    // takes the messages that are due and pushes them back by lease_seconds,
+    // so other workers skip them while they're being sent
+    async fn claim_emails(
+        &self,
+        limit: i64,
+        lease_seconds: i64,
+    ) -> Result<Vec<OutboxMessage>, AuthError> {
+        let rows = self
+            .query(
+                "UPDATE email_outbox SET next_attempt_at = now() + $2 * interval '1 second'
+            WHERE id IN (
+                SELECT id FROM email_outbox
+                WHERE status = 'pending' AND next_attempt_at <= now()
+                    AND (expires_at IS NULL OR expires_at > now())
+                ORDER BY next_attempt_at LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, to_address, subject, html, text, attempts",
+                &[&limit, &(lease_seconds as f64)],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| OutboxMessage {
+                id: row.get(0),
+                email: Email {
+                    to: row.get(1),
+                    subject: row.get(2),
+                    html: row.get(3),
+                    text: row.get(4),
+                },
+                attempts: row.get(5),
+            })
+            .collect())
+    }
+
+    async fn mark_email_sent(&self, id: i32) -> Result<(), AuthError> {
+        self.execute(
+            "UPDATE email_outbox SET status = 'sent', sent_at = now(), html = '', text = ''
+            WHERE id=$1",
+            &[&id],
+        )
+        .await?;
+        Ok(())
+    }
+
+    // without retry_in the message is dead lettered
+    async fn mark_email_failed(
+        &self,
+        id: i32,
+        error: &str,
+        retry_in: Option<i64>,
+    ) -> Result<(), AuthError> {
+        self.execute(
+            "UPDATE email_outbox SET attempts = attempts + 1, last_error = $2,
+                status = CASE WHEN $3::float8 IS NULL THEN 'dead' ELSE 'pending' END,
+                next_attempt_at = now() + coalesce($3::float8, 0) * interval '1 second'
+            WHERE id=$1",
+            &[&id, &error, &retry_in.map(|seconds| seconds as f64)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_dead_emails(&self) -> Result<Vec<DeadMessage>, AuthError> {
+        let rows = self.query(
+            "SELECT id, to_address, subject, attempts, last_error, extract(epoch from created_at)::bigint
+            FROM email_outbox WHERE status = 'dead' ORDER BY id",
+            &[],
+        ).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| DeadMessage {
+                id: row.get(0),
+                to: row.get(1),
+                subject: row.get(2),
+                attempts: row.get(3),
+                last_error: row.get(4),
+                created_at: row.get(5),
+            })
+            .collect())
+    }
+
+    async fn replay_email(&self, id: i32) -> Result<u64, AuthError> {
+        let num = self
+            .execute(
+                "UPDATE email_outbox SET status = 'pending', attempts = 0, next_attempt_at = now()
+            WHERE id=$1 AND status = 'dead' AND (expires_at IS NULL OR expires_at > now())",
+                &[&id],
+            )
+            .await?;
+        Ok(num)
+    }
+
+    // stops mail going to an address, event is what sendgrid told us about it
+    async fn suppress_email(
+        &self,
+        email: &str,
+        event: &str,
+        reason: Option<&str>,
+    ) -> Result<(), AuthError> {
+        self.execute(
+            "INSERT INTO email_suppressions (email, event, reason) VALUES (lower($1), $2, $3)
+            ON CONFLICT (email) DO UPDATE SET event = $2, reason = $3, updated_at = now()",
+            &[&email, &event, &reason],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn is_email_suppressed(&self, email: &str) -> Result<bool, AuthError> {
+        let rows = self
+            .query(
+                "SELECT 1 FROM email_suppressions WHERE email = lower($1)",
+                &[&email],
+            )
+            .await?;
+        Ok(!rows.is_empty())
+    }
+}
+
+#[async_trait]
+impl AuditStore for Db {
+    async fn add_audit_event(&self, event: &AuditEvent) -> Result<(), AuthError> {
+        self.execute(
+            "INSERT INTO audit_events (action, outcome, actor, subject, ip, user_agent, detail)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &event.action.as_str(),
+                &event.outcome.as_str(),
+                &event.actor,
+                &event.subject,
+                &event.ip,
+                &event.user_agent,
+                &event.detail,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    // up to limit events matching the query, newest first
+    async fn list_audit_events(
+        &self,
+        query: &AuditQuery,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, AuthError> {
+        let occurred = "floor(extract(epoch from occurred_at))::bigint";
+        let (filter, sql_params) = query.to_sql(occurred, |n| format!("${}", n));
+        let mut params: Vec<&(dyn ToSql + Sync)> = sql_params
+            .iter()
+            .map(|param| match param {
+                SqlParam::Text(value) => value as &(dyn ToSql + Sync),
+                SqlParam::Int(value) => value,
+                SqlParam::Bool(value) => value,
+                SqlParam::Id(value) => value,
+            })
+            .collect();
+        params.push(&limit);
+
+        let rows = self
+            .query(
+                &format!(
+                    "SELECT id, {}, action, outcome, actor, subject, ip, user_agent, detail
+                FROM audit_events {} ORDER BY id DESC LIMIT ${}",
+                    occurred,
+                    filter,
+                    params.len()
+                ),
+                &params,
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| AuditRecord {
+                id: row.get(0),
+                occurred_at: row.get(1),
+                action: row.get(2),
+                outcome: row.get(3),
+                actor: row.get(4),
+                subject: row.get(5),
+                ip: row.get(6),
+                user_agent: row.get(7),
+                detail: row.get(8),
+            })
+            .collect())
     }
 }
@@ -0,0 +1,578 @@
+use crate::admin::ROLES;
+use crate::error::AuthError;
+use crate::store::Store;
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+// the user directory behind /protected/users: filtered, sorted and handed
+// out a page at a time. pages are keyset paginated, the cursor is where the
+// last page ended, so users signing up while someone pages through don't
+// shift what they see
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+// the query string, as sent
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct UserListParams {
+    // matches the start of the username or email, ignoring case
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    // active or disabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verified: Option<bool>,
+    // rfc 3339 times
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_after: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_before: Option<String>,
+    // username, email or created_at, with a leading - for descending
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserSort {
+    Username,
+    Email,
+    CreatedAt,
+}
+
+// the sort value of the last user on a page, and their id to break ties
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortValue {
+    Text(String),
+    Time(i64),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cursor {
+    sort: UserSort,
+    descending: bool,
+    pub value: SortValue,
+    pub id: i32,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("Couldn't serialize a cursor.");
+        base64::encode_config(&json, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(cursor: &str) -> Option<Cursor> {
+        let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+// what the store is asked for
+#[derive(Clone, Debug)]
+pub struct UserQuery {
+    // lowercased
+    pub prefix: Option<String>,
+    // whether the prefix matches emails too, only admins can search them
+    pub emails: bool,
+    pub admin: Option<bool>,
+    pub disabled: Option<bool>,
+    pub verified: Option<bool>,
+    // unix seconds
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    pub sort: UserSort,
+    pub descending: bool,
+    // start after this user
+    pub after: Option<Cursor>,
+    pub limit: i64,
+}
+
+// a user as the directory lists them
+#[derive(Serialize, Clone, Debug)]
+pub struct DirectoryUser {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub admin: bool,
+    pub disabled: bool,
+    pub email_verified: bool,
+    pub created_at: i64,
+}
+
+// all of a user that someone who isn't an admin sees
+#[derive(Serialize, Clone, Debug)]
+pub struct Username {
+    pub username: String,
+}
+
+impl DirectoryUser {
+    fn sort_value(&self, sort: UserSort) -> SortValue {
+        match sort {
+            UserSort::Username => SortValue::Text(self.username.clone()),
+            UserSort::Email => SortValue::Text(self.email.clone()),
+            UserSort::CreatedAt => SortValue::Time(self.created_at),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserPage<T = DirectoryUser> {
+    pub users: Vec<T>,
+    // every user matching the filters, not just this page
+    pub total: i64,
+    pub next_cursor: Option<String>,
+    // the url of the next page, when there is one
+    pub next: Option<String>,
+}
+
+fn bad_param(name: &str, problem: &str) -> AuthError {
+    AuthError::new(
+        "users",
+        &format!("The {} parameter {}.", name, problem),
+        "",
+        400,
+    )
+}
+
+fn time(name: &str, value: &Option<String>) -> Result<Option<i64>, AuthError> {
+    match value {
+        Some(value) => match DateTime::parse_from_rfc3339(value) {
+            Ok(time) => Ok(Some(time.timestamp())),
+            Err(_) => Err(bad_param(name, "should be an rfc 3339 time")),
+        },
+        None => Ok(None),
+    }
+}
+
+// what an admin and everyone else get back
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Listing {
+    Users(UserPage),
+    Usernames(UserPage<Username>),
+}
+
+impl Listing {
+    pub fn next(&self) -> Option<&String> {
+        match self {
+            Listing::Users(page) => page.next.as_ref(),
+            Listing::Usernames(page) => page.next.as_ref(),
+        }
+    }
+}
+
+fn admin_only(name: &str) -> AuthError {
+    AuthError::new(
+        "users",
+        &format!("Only admins can use the {} parameter.", name),
+        "",
+        403,
+    )
+}
+
+impl UserQuery {
+    pub fn parse(params: &UserListParams) -> Result<UserQuery, AuthError> {
+        let (descending, sort) = match params.sort.as_deref() {
+            Some(sort) if sort.starts_with('-') => (true, &sort[1..]),
+            Some(sort) => (false, sort),
+            None => (false, "username"),
+        };
+        let sort = match sort {
+            "username" => UserSort::Username,
+            "email" => UserSort::Email,
+            "created_at" => UserSort::CreatedAt,
+            _ => return Err(bad_param("sort", "should be username, email or created_at")),
+        };
+
+        let admin = match params.role.as_deref() {
+            Some("user") => Some(false),
+            Some(role) if ROLES.contains(&role) => Some(true),
+            Some(_) => {
+                return Err(bad_param(
+                    "role",
+                    &format!("should be user or {}", ROLES.join(", ")),
+                ))
+            }
+            None => None,
+        };
+        let disabled = match params.status.as_deref() {
+            Some("active") => Some(false),
+            Some("disabled") => Some(true),
+            Some(_) => return Err(bad_param("status", "should be active or disabled")),
+            None => None,
+        };
+
+        let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(bad_param(
+                "limit",
+                &format!("should be between 1 and {}", MAX_LIMIT),
+            ));
+        }
+
+        // a cursor only makes sense for the order it was made in
+        let after = match params.cursor {
+            Some(ref cursor) => match Cursor::decode(cursor) {
+                Some(cursor) if cursor.sort == sort && cursor.descending == descending => {
+                    Some(cursor)
+                }
+                _ => return Err(bad_param("cursor", "isn't one this query gave out")),
+            },
+            None => None,
+        };
+
+        Ok(UserQuery {
+            prefix: params
+                .q
+                .as_ref()
+                .map(|q| q.trim().to_lowercase())
+                .filter(|q| !q.is_empty()),
+            emails: true,
+            admin,
+            disabled,
+            verified: params.verified,
+            created_after: time("created_after", &params.created_after)?,
+            created_before: time("created_before", &params.created_before)?,
+            sort,
+            descending,
+            after,
+            limit,
+        })
+    }
+
+    // usernames in username order is all someone who isn't an admin can ask for
+    pub fn parse_usernames(params: &UserListParams) -> Result<UserQuery, AuthError> {
+        let filters = [
+            ("role", params.role.is_some()),
+            ("status", params.status.is_some()),
+            ("verified", params.verified.is_some()),
+            ("created_after", params.created_after.is_some()),
+            ("created_before", params.created_before.is_some()),
+        ];
+        if let Some((name, _)) = filters.iter().find(|(_, set)| *set) {
+            return Err(admin_only(name));
+        }
+
+        let query = UserQuery::parse(params)?;
+        if query.sort != UserSort::Username {
+            return Err(admin_only("sort"));
+        }
+        Ok(UserQuery {
+            emails: false,
+            ..query
+        })
+    }
+
+    // whether a user passes the filters, for stores that don't speak sql
+    pub fn matches(&self, user: &DirectoryUser) -> bool {
+        let prefix = |value: &str| {
+            self.prefix
+                .as_ref()
+                .is_none_or(|prefix| value.to_lowercase().starts_with(prefix.as_str()))
+        };
+        (prefix(&user.username) || (self.emails && prefix(&user.email)))
+            && self.admin.is_none_or(|admin| user.admin == admin)
+            && self
+                .disabled
+                .is_none_or(|disabled| user.disabled == disabled)
+            && self
+                .verified
+                .is_none_or(|verified| user.email_verified == verified)
+            && self.created_after.is_none_or(|at| user.created_at >= at)
+            && self.created_before.is_none_or(|at| user.created_at < at)
+    }
+}
+
+// a parameter of a sql query built below
+pub enum SqlParam {
+    Text(String),
+    Int(i64),
+    Bool(bool),
+    Id(i32),
+}
+
+// the sql the postgres and sqlite stores share. the count is run with just
+// the filter params, the page with all of them
+pub struct SqlQuery {
+    pub filter: String,
+    pub page: String,
+    pub order: String,
+    pub filter_params: usize,
+    pub params: Vec<SqlParam>,
+}
+
+impl UserQuery {
+    // created is how the backend writes created_at as unix seconds and
+    // placeholder how it writes the nth parameter
+    pub fn to_sql(&self, created: &str, placeholder: fn(usize) -> String) -> SqlQuery {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<SqlParam> = Vec::new();
+        let push = |params: &mut Vec<SqlParam>, param: SqlParam| {
+            params.push(param);
+            placeholder(params.len())
+        };
+
+        if let Some(ref prefix) = self.prefix {
+            let pattern = format!("{}%", escape_like(prefix));
+            let p = push(&mut params, SqlParam::Text(pattern));
+            conditions.push(if self.emails {
+                format!(
+                    "(lower(username) LIKE {p} ESCAPE '\\' OR lower(email) LIKE {p} ESCAPE '\\')",
+                    p = p
+                )
+            } else {
+                format!("lower(username) LIKE {} ESCAPE '\\'", p)
+            });
+        }
+        if let Some(admin) = self.admin {
+            let p = push(&mut params, SqlParam::Bool(admin));
+            conditions.push(format!("admin = {}", p));
+        }
+        if let Some(disabled) = self.disabled {
+            let p = push(&mut params, SqlParam::Bool(disabled));
+            conditions.push(format!("disabled = {}", p));
+        }
+        if let Some(verified) = self.verified {
+            let p = push(&mut params, SqlParam::Bool(verified));
+            conditions.push(format!("email_verified = {}", p));
+        }
+        if let Some(at) = self.created_after {
+            let p = push(&mut params, SqlParam::Int(at));
+            conditions.push(format!("{} >= {}", created, p));
+        }
+        if let Some(at) = self.created_before {
+            let p = push(&mut params, SqlParam::Int(at));
+            conditions.push(format!("{} < {}", created, p));
+        }
+        let filter = where_clause(&conditions);
+        let filter_params = params.len();
+
+        let column = match self.sort {
+            UserSort::Username => "username",
+            UserSort::Email => "email",
+            UserSort::CreatedAt => created,
+        };
+        let direction = if self.descending { "DESC" } else { "ASC" };
+        if let Some(ref after) = self.after {
+            let value = match after.value {
+                SortValue::Text(ref text) => SqlParam::Text(text.clone()),
+                SortValue::Time(at) => SqlParam::Int(at),
+            };
+            let value = push(&mut params, value);
+            let id = push(&mut params, SqlParam::Id(after.id));
+            let op = if self.descending { "<" } else { ">" };
+            conditions.push(format!("({}, id) {} ({}, {})", column, op, value, id));
+        }
+
+        SqlQuery {
+            filter,
+            page: where_clause(&conditions),
+            order: format!(
+                "ORDER BY {col} {dir}, id {dir}",
+                col = column,
+                dir = direction
+            ),
+            filter_params,
+            params,
+        }
+    }
+}
+
+pub fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// sorts users the way the sql stores do, for stores that don't speak sql
+pub fn sort_users(users: &mut [DirectoryUser], query: &UserQuery) {
+    users.sort_by(|a, b| {
+        let order = a
+            .sort_value(query.sort)
+            .cmp(&b.sort_value(query.sort))
+            .then(a.id.cmp(&b.id));
+        if query.descending {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+}
+
+// whether a user comes after the cursor in the query's order
+pub fn after_cursor(user: &DirectoryUser, query: &UserQuery) -> bool {
+    match query.after {
+        Some(ref after) => {
+            let order = user
+                .sort_value(query.sort)
+                .cmp(&after.value)
+                .then(user.id.cmp(&after.id));
+            if query.descending {
+                order.is_lt()
+            } else {
+                order.is_gt()
+            }
+        }
+        None => true,
+    }
+}
+
+// the directory as viewer may see it: admins get everything, anyone else
+// just usernames
+pub async fn browse(
+    db: &Store,
+    viewer: &str,
+    params: &UserListParams,
+    path: &str,
+) -> Result<Listing, AuthError> {
+    if db.is_admin(viewer).await? {
+        return Ok(Listing::Users(list_users(db, params, path).await?));
+    }
+
+    let page = page(db, params, UserQuery::parse_usernames(params)?, path).await?;
+    Ok(Listing::Usernames(UserPage {
+        users: page
+            .users
+            .into_iter()
+            .map(|user| Username {
+                username: user.username,
+            })
+            .collect(),
+        total: page.total,
+        next_cursor: page.next_cursor,
+        next: page.next,
+    }))
+}
+
+// a page of users, with a link to the next one. path is where the
+// directory is served, for the link
+pub async fn list_users(
+    db: &Store,
+    params: &UserListParams,
+    path: &str,
+) -> Result<UserPage, AuthError> {
+    page(db, params, UserQuery::parse(params)?, path).await
+}
+
+async fn page(
+    db: &Store,
+    params: &UserListParams,
+    query: UserQuery,
+    path: &str,
+) -> Result<UserPage, AuthError> {
+    // one more than asked for says whether there's another page
+    let (mut users, total) = db.list_users(&query, query.limit + 1).await?;
+
+    let mut next_cursor = None;
+    if users.len() as i64 > query.limit {
+        users.truncate(query.limit as usize);
+        next_cursor = users.last().map(|last| {
+            Cursor {
+                sort: query.sort,
+                descending: query.descending,
+                value: last.sort_value(query.sort),
+                id: last.id,
+            }
+            .encode()
+        });
+    }
+    let next = next_cursor.as_ref().map(|cursor| {
+        let mut params = params.clone();
+        params.cursor = Some(cursor.clone());
+        let query = serde_urlencoded::to_string(&params).unwrap_or_default();
+        format!("{}?{}", path, query)
+    });
+
+    Ok(UserPage {
+        users,
+        total,
+        next_cursor,
+        next,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(query: &str) -> UserListParams {
+        serde_urlencoded::from_str(query).unwrap()
+    }
+
+    #[test]
+    fn bad_params_are_rejected() {
+        assert!(UserQuery::parse(&params("sort=password")).is_err());
+        assert!(UserQuery::parse(&params("role=owner")).is_err());
+        assert!(UserQuery::parse(&params("status=gone")).is_err());
+        assert!(UserQuery::parse(&params("limit=0")).is_err());
+        assert!(UserQuery::parse(&params("limit=1000")).is_err());
+        assert!(UserQuery::parse(&params("created_after=yesterday")).is_err());
+        assert!(UserQuery::parse(&params("cursor=nonsense")).is_err());
+    }
+
+    #[test]
+    fn usernames_are_searched_without_emails() {
+        let query = UserQuery::parse_usernames(&params("q=ann&sort=-username")).unwrap();
+        let sql = query.to_sql("created", |n| format!("${}", n));
+        assert_eq!(sql.filter, "WHERE lower(username) LIKE $1 ESCAPE '\\'");
+        assert!(UserQuery::parse_usernames(&params("created_after=2020-01-01T00:00:00Z")).is_err());
+    }
+
+    #[test]
+    fn cursors_only_work_for_their_order() {
+        let cursor = Cursor {
+            sort: UserSort::Email,
+            descending: true,
+            value: SortValue::Text("a@example.com".to_owned()),
+            id: 3,
+        }
+        .encode();
+        let query = UserQuery::parse(&params(&format!("sort=-email&cursor={}", cursor))).unwrap();
+        assert_eq!(query.after.unwrap().id, 3);
+        assert!(UserQuery::parse(&params(&format!("sort=email&cursor={}", cursor))).is_err());
+    }
+
+    #[test]
+    fn sql_numbers_the_filters_before_the_cursor() {
+        let cursor = Cursor {
+            sort: UserSort::CreatedAt,
+            descending: false,
+            value: SortValue::Time(100),
+            id: 3,
+        }
+        .encode();
+        let query = UserQuery::parse(&params(&format!(
+            "q=An_n&role=admin&sort=created_at&cursor={}",
+            cursor
+        )))
+        .unwrap();
+        let sql = query.to_sql("created", |n| format!("${}", n));
+
+        assert_eq!(sql.filter_params, 2);
+        assert_eq!(sql.params.len(), 4);
+        assert!(sql.filter.contains("admin = $2"));
+        assert!(sql.page.ends_with("(created, id) > ($3, $4)"));
+        assert_eq!(sql.order, "ORDER BY created ASC, id ASC");
+        match sql.params[0] {
+            SqlParam::Text(ref pattern) => assert_eq!(pattern, "an\\_n%"),
+            _ => panic!("the prefix should come first"),
+        }
+    }
+}
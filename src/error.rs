@@ -1,18 +1,24 @@
 use actix_web::error::BlockingError;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
-use r2d2_postgres::r2d2;
+use deadpool_postgres::PoolError;
 use reqwest;
 use serde::Serialize;
-use serde_json::{json, to_string_pretty};
+use serde_json::json;
 use std::fmt::{self, Formatter, Result as FmtResult};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct AuthError {
     client_message: String,
     server_message: String,
     context: String,
     status: u16,
+    // seconds to wait before trying again, sent as Retry-After
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+    // the request it happened in, so a user can quote it to support
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 impl AuthError {
@@ -22,19 +28,19 @@ impl AuthError {
         server_message: &str,
         status: u16,
     ) -> AuthError {
-        let error: &str;
-
-        if server_message == "" {
-            error = client_message;
+        let error = if server_message.is_empty() {
+            client_message
         } else {
-            error = server_message;
-        }
+            server_message
+        };
 
         AuthError {
             context: context.to_owned(),
             client_message: client_message.to_owned(),
             server_message: error.to_owned(),
             status,
+            retry_after: None,
+            request_id: None,
         }
     }
 
@@ -42,14 +48,46 @@ impl AuthError {
         AuthError::new("general", client_message, server_message, status)
     }
 
+    pub fn client_message(&self) -> &str {
+        &self.client_message
+    }
+
+    pub fn server_message(&self) -> &str {
+        &self.server_message
+    }
+
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
     pub fn internal_error(error: &str) -> AuthError {
         AuthError {
             context: "general".to_owned(),
             client_message: "Something went wrong. Please try again later.".to_owned(),
             server_message: error.to_owned(),
             status: 500,
+            retry_after: None,
+            request_id: None,
         }
     }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> AuthError {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: &str) -> AuthError {
+        self.request_id = Some(request_id.to_owned());
+        self
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
 }
 
 impl From<BlockingError<AuthError>> for AuthError {
@@ -61,9 +99,30 @@ impl From<BlockingError<AuthError>> for AuthError {
     }
 }
 
-impl From<r2d2::Error> for AuthError {
-    fn from(error: r2d2::Error) -> Self {
-        AuthError::new("general", "Internal Error.", &error.to_string(), 500)
+impl From<PoolError> for AuthError {
+    fn from(error: PoolError) -> Self {
+        match error {
+            // every connection is busy, which won't last
+            PoolError::Timeout(_) => AuthError::new(
+                "general",
+                "The server is busy. Please try again in a moment.",
+                &error.to_string(),
+                503,
+            ),
+            _ => AuthError::new("general", "Internal Error.", &error.to_string(), 500),
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for AuthError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        AuthError::internal_error(&error.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for AuthError {
+    fn from(error: rusqlite::Error) -> Self {
+        AuthError::internal_error(&error.to_string())
     }
 }
 
@@ -79,25 +138,35 @@ impl From<reqwest::Error> for AuthError {
 //     }
 // }
 
+// one line for logs and the cli. what the user was told isn't repeated,
+// the server message says more
 impl fmt::Display for AuthError {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(
             f,
-            "{}",
-            to_string_pretty(self).expect("Couldn't format AuthError for display.")
+            "{} {}: {}",
+            self.status, self.context, self.server_message
         )
     }
 }
 
 impl ResponseError for AuthError {
     fn render_response(&self) -> HttpResponse {
-        let err_json = json!({
+        let mut err_json = json!({
             "type": "error",
             "context": self.context,
             "data": self.client_message
         });
-        HttpResponse::build(StatusCode::from_u16(self.status).expect("Invalid status code given."))
-            .json(err_json)
+        if let Some(ref request_id) = self.request_id {
+            err_json["request_id"] = json!(request_id);
+        }
+        let mut res = HttpResponse::build(
+            StatusCode::from_u16(self.status).expect("Invalid status code given."),
+        );
+        if let Some(seconds) = self.retry_after {
+            res.header("Retry-After", seconds.to_string());
+        }
+        res.json(err_json)
     }
 }
 
@@ -0,0 +1,187 @@
+use crate::error::AuthError;
+use crate::logging;
+use crate::metrics::{self, Histogram};
+use futures03::channel::oneshot;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+// argon2 is slow on purpose, so it gets threads of its own. a burst of sign
+// ins can only fill the queue, it can't take threads other work needs, and
+// once the queue is full callers are told to come back later instead of
+// waiting behind everyone else
+
+// how long a caller turned away is asked to wait
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone)]
+pub struct HashPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    jobs: SyncSender<Job>,
+    // jobs waiting for a thread
+    queued: AtomicU64,
+    rejected: AtomicU64,
+    duration: Histogram,
+}
+
+impl HashPool {
+    // threads hash at once, one per cpu when it's 0, and up to
+    // queue_size more wait for one of them
+    pub fn new(threads: usize, queue_size: usize) -> HashPool {
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        };
+        let (jobs, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("auth-hash-{}", i))
+                .spawn(move || work(&receiver))
+                .expect("Couldn't start a hashing thread.");
+        }
+
+        HashPool {
+            inner: Arc::new(Inner {
+                jobs,
+                queued: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+                duration: Histogram::new(DURATION_BUCKETS),
+            }),
+        }
+    }
+
+    pub async fn run<F, T>(&self, f: F) -> Result<T, AuthError>
+    where
+        F: FnOnce() -> Result<T, AuthError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let inner = self.inner.clone();
+        // the worker hashes in a span under the caller's
+        let span = tracing::debug_span!("hash");
+        let job: Job = Box::new(move || {
+            let _span = span.enter();
+            inner.queued.fetch_sub(1, Ordering::Relaxed);
+            let started = Instant::now();
+            let res = f();
+            inner.duration.observe(started.elapsed());
+            tracing::debug!(
+                elapsed_ms = logging::elapsed_ms(started),
+                "hashing finished"
+            );
+            let _ = tx.send(res);
+        });
+
+        self.inner.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = self.inner.jobs.try_send(job) {
+            self.inner.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(match err {
+                TrySendError::Full(_) => {
+                    self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!("hashing queue is full, turning a request away");
+                    AuthError::new_general(
+                        "The server is busy. Please try again in a moment.",
+                        "the hashing queue is full",
+                        503,
+                    )
+                    .with_retry_after(RETRY_AFTER_SECONDS)
+                }
+                TrySendError::Disconnected(_) => {
+                    AuthError::internal_error("the hashing threads have stopped")
+                }
+            });
+        }
+
+        match rx.await {
+            Ok(res) => res,
+            Err(_) => Err(AuthError::internal_error("a hashing thread panicked")),
+        }
+    }
+
+    pub fn render_metrics(&self, out: &mut String) {
+        let inner = &self.inner;
+        metrics::gauge(
+            out,
+            "auth_hash_queue_depth",
+            "Password hashes waiting for a thread.",
+            inner.queued.load(Ordering::Relaxed),
+        );
+        metrics::counter(
+            out,
+            "auth_hash_rejected_total",
+            "Password hashes turned away because the queue was full.",
+            inner.rejected.load(Ordering::Relaxed),
+        );
+        inner.duration.render(
+            out,
+            "auth_hash_duration_seconds",
+            "How long hashing or verifying a password took.",
+        );
+    }
+}
+
+impl Default for HashPool {
+    fn default() -> HashPool {
+        HashPool::new(0, 64)
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // the lock is only held while waiting, so the others can take the next job
+        let job = receiver.lock().expect("hash pool lock poisoned").recv();
+        match job {
+            Ok(job) => job(),
+            // the pool was dropped
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures03::executor::block_on;
+    use futures03::FutureExt;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn a_full_queue_is_turned_away() {
+        let pool = HashPool::new(1, 1);
+
+        // hold the only thread until the queue has filled up. polling once
+        // is enough to queue a job, it runs whether or not anyone waits for it
+        let (started, has_started) = channel();
+        let (release, wait) = channel::<()>();
+        let wait = Mutex::new(wait);
+        let holding = pool.run(move || {
+            let _ = started.send(());
+            let _ = wait.lock().unwrap().recv();
+            Ok(())
+        });
+        assert!(holding.now_or_never().is_none());
+        has_started.recv().unwrap();
+        assert!(pool.run(|| Ok(())).now_or_never().is_none());
+
+        let err = pool.run(|| Ok(())).now_or_never().unwrap().unwrap_err();
+        assert_eq!(err.status(), 503);
+        let mut out = String::new();
+        pool.render_metrics(&mut out);
+        assert!(out.contains("auth_hash_queue_depth 1\n"));
+        assert!(out.contains("auth_hash_rejected_total 1\n"));
+
+        release.send(()).unwrap();
+        assert_eq!(block_on(pool.run(|| Ok(2))).unwrap(), 2);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::logging;
+use failure;
+use reqwest;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// an outgoing request. everything that talks to google, sendgrid or
+// another provider goes through an HttpClient, so tests can swap it out
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new(method: Method, url: &str) -> HttpRequest {
+        HttpRequest {
+            method,
+            url: url.to_owned(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(url: &str) -> HttpRequest {
+        HttpRequest::new(Method::GET, url)
+    }
+
+    pub fn post(url: &str) -> HttpRequest {
+        HttpRequest::new(Method::POST, url)
+    }
+
+    pub fn header<K, V>(mut self, name: K, value: V) -> Result<HttpRequest, failure::Error>
+    where
+        K: Into<HeaderName>,
+        V: AsRef<str>,
+    {
+        self.headers
+            .insert(name.into(), HeaderValue::from_str(value.as_ref())?);
+        Ok(self)
+    }
+
+    pub fn bearer_auth(self, token: &str) -> Result<HttpRequest, failure::Error> {
+        self.header(AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    pub fn json<T: Serialize>(mut self, body: &T) -> Result<HttpRequest, failure::Error> {
+        self.body = serde_json::to_vec(body)?;
+        self.header(CONTENT_TYPE, "application/json")
+    }
+
+    pub fn form<T: Serialize>(mut self, body: &T) -> Result<HttpRequest, failure::Error> {
+        self.body = serde_urlencoded::to_string(body)?.into_bytes();
+        self.header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: &'static str) -> HttpResponse {
+        self.headers.insert(name, HeaderValue::from_static(value));
+        self
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    pub fn error_for_status(self) -> Result<HttpResponse, failure::Error> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            Err(failure::err_msg(format!(
+                "http request failed with status {}: {}",
+                self.status,
+                self.text()
+            )))
+        }
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, failure::Error> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+pub trait HttpClient: Send + Sync {
+    fn send(&self, req: HttpRequest) -> Result<HttpResponse, failure::Error>;
+}
+
+// the real thing
+pub struct ReqwestClient {
+    client: reqwest::Client,
+}
+
+impl ReqwestClient {
+    pub fn new() -> ReqwestClient {
+        ReqwestClient {
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Default for ReqwestClient {
+    fn default() -> ReqwestClient {
+        ReqwestClient::new()
+    }
+}
+
+impl HttpClient for ReqwestClient {
+    fn send(&self, req: HttpRequest) -> Result<HttpResponse, failure::Error> {
+        // the query string is left out, it can carry codes and keys
+        let url = req.url.split('?').next().unwrap_or("");
+        let _span = tracing::debug_span!("http", method = %req.method, url).entered();
+        let started = Instant::now();
+
+        let mut res = self
+            .client
+            .request(req.method, &req.url)
+            .headers(req.headers)
+            .body(req.body)
+            .send()?;
+
+        let mut body = Vec::new();
+        res.copy_to(&mut body)?;
+        tracing::debug!(
+            status = res.status().as_u16(),
+            elapsed_ms = logging::elapsed_ms(started),
+            "http request finished"
+        );
+        Ok(HttpResponse {
+            status: res.status().as_u16(),
+            headers: res.headers().clone(),
+            body,
+        })
+    }
+}
+
+// answers requests with canned responses and remembers what was sent,
+// for tests and for running without network. unknown urls get a 404
+#[derive(Default)]
+pub struct FakeHttpClient {
+    responses: Mutex<HashMap<(Method, String), HttpResponse>>,
+    requests: Mutex<Vec<HttpRequest>>,
+}
+
+impl FakeHttpClient {
+    pub fn new() -> FakeHttpClient {
+        FakeHttpClient::default()
+    }
+
+    // replaces whatever was set for the same method and url
+    pub fn respond(&self, method: Method, url: &str, res: HttpResponse) {
+        if let Ok(mut responses) = self.responses.lock() {
+            responses.insert((method, url.to_owned()), res);
+        }
+    }
+
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        match self.requests.lock() {
+            Ok(requests) => requests.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl HttpClient for FakeHttpClient {
+    fn send(&self, req: HttpRequest) -> Result<HttpResponse, failure::Error> {
+        let key = (req.method.clone(), req.url.clone());
+        self.requests
+            .lock()
+            .map_err(|_| failure::err_msg("fake http client lock poisoned"))?
+            .push(req);
+
+        let responses = self
+            .responses
+            .lock()
+            .map_err(|_| failure::err_msg("fake http client lock poisoned"))?;
+        Ok(responses
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| HttpResponse::new(404, "")))
+    }
+}
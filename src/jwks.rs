@@ -0,0 +1,313 @@
+use crate::http::{HttpClient, HttpRequest};
+use chrono::{DateTime, Utc};
+use failure;
+use reqwest::header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL, EXPIRES};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+
+// how long keys are kept when the response doesn't say
+static DEFAULT_MAX_AGE: i64 = 5 * 60;
+// keys are refetched in the background this long before they expire
+static REFRESH_AHEAD: i64 = 60;
+// an unknown kid refetches the keys at most this often,
+// so tokens with made up kids can't make us hammer the provider
+static MIN_REFETCH_INTERVAL: i64 = 30;
+// expired keys are still used for this long while the provider can't be reached
+static MAX_STALE: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Jwk {
+    pub kid: String,
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#use: Option<String>,
+    #[serde(default)]
+    pub n: String,
+    #[serde(default)]
+    pub e: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+impl JwkSet {
+    // only rsa keys can be used with decode_rsa_components,
+    // so anything else is skipped even if the kid matches
+    pub fn find(&self, kid: &str) -> Option<&Jwk> {
+        self.keys
+            .iter()
+            .find(|key| key.kid == kid && key.kty == "RSA")
+    }
+}
+
+// when a response can be cached until, from its cache-control
+// max-age or else its expires header
+pub fn expiration(headers: &HeaderMap, now: i64) -> i64 {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .unwrap_or("")
+    };
+
+    let mut max_age = None;
+    for directive in header(CACHE_CONTROL).split(',') {
+        let directive = directive.trim().to_lowercase();
+        if directive == "no-cache" || directive == "no-store" {
+            return now;
+        }
+        if let Some(seconds) = directive.strip_prefix("max-age=") {
+            max_age = seconds.parse::<i64>().ok();
+        }
+    }
+
+    if let Some(max_age) = max_age {
+        // the response may have sat in a shared cache for a while already
+        let age = header(AGE).parse::<i64>().unwrap_or(0);
+        return now + (max_age - age).max(0);
+    }
+
+    match DateTime::parse_from_rfc2822(header(EXPIRES)) {
+        Ok(dt) => dt.timestamp(),
+        Err(_) => now + DEFAULT_MAX_AGE,
+    }
+}
+
+// fetches a jwk set and returns it with its expiration timestamp
+pub fn fetch(client: &dyn HttpClient, url: &str) -> Result<(i64, JwkSet), failure::Error> {
+    let res = client.send(HttpRequest::get(url))?.error_for_status()?;
+    let expiration = expiration(&res.headers, Utc::now().timestamp());
+    Ok((expiration, res.json()?))
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct CachedKeys {
+    url: String,
+    expires_at: i64,
+    keys: JwkSet,
+    // when we last tried to fetch, whether or not it worked
+    #[serde(skip)]
+    attempted_at: i64,
+    // bumped on every fetch attempt
+    #[serde(skip)]
+    generation: u64,
+    #[serde(skip)]
+    refreshing: bool,
+}
+
+// a provider's signing keys, shared by every request that needs them.
+// the lock on the keys is never held during a fetch, so a slow provider
+// only holds up the requests that really have nothing to go on
+#[derive(Clone)]
+pub struct KeyCache {
+    client: Arc<dyn HttpClient>,
+    cached: Arc<Mutex<CachedKeys>>,
+    // held while fetching, so only one fetch goes out at a time
+    fetching: Arc<Mutex<()>>,
+    // where the last keys are kept, so a restart without network can still validate
+    snapshot: Option<String>,
+}
+
+fn poisoned() -> failure::Error {
+    failure::err_msg("jwks cache lock poisoned")
+}
+
+impl KeyCache {
+    pub fn new(client: Arc<dyn HttpClient>) -> KeyCache {
+        KeyCache {
+            client,
+            cached: Arc::new(Mutex::new(CachedKeys::default())),
+            fetching: Arc::new(Mutex::new(())),
+            snapshot: None,
+        }
+    }
+
+    // starts from the snapshot at path if there is one, and keeps it up to date
+    pub fn with_snapshot(mut self, path: &str) -> KeyCache {
+        let snapshot = fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str::<CachedKeys>(&json).ok());
+        if let (Some(snapshot), Ok(mut cached)) = (snapshot, self.cached.lock()) {
+            *cached = snapshot;
+        }
+        self.snapshot = Some(path.to_owned());
+        self
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, CachedKeys>, failure::Error> {
+        self.cached.lock().map_err(|_| poisoned())
+    }
+
+    fn current(&self) -> Result<CachedKeys, failure::Error> {
+        Ok(self.lock()?.clone())
+    }
+
+    pub fn get(&self, url: &str, kid: &str) -> Result<Jwk, failure::Error> {
+        match self.keys_for(url, Some(kid))?.find(kid) {
+            Some(key) => Ok(key.clone()),
+            None => Err(failure::err_msg(format!(
+                "jwks: kid {} isn't one of the keys from {}",
+                kid, url
+            ))),
+        }
+    }
+
+    pub fn keys(&self, url: &str) -> Result<JwkSet, failure::Error> {
+        self.keys_for(url, None)
+    }
+
+    fn keys_for(&self, url: &str, kid: Option<&str>) -> Result<JwkSet, failure::Error> {
+        let now = Utc::now().timestamp();
+        let cached = self.current()?;
+        let has_key = |keys: &JwkSet| match kid {
+            Some(kid) => keys.find(kid).is_some(),
+            None => !keys.keys.is_empty(),
+        };
+        let same_url = cached.url == url;
+
+        if same_url && cached.expires_at > now {
+            if has_key(&cached.keys) {
+                if cached.expires_at - now < REFRESH_AHEAD {
+                    self.refresh_in_background(url)?;
+                }
+                return Ok(cached.keys);
+            }
+            // the provider may have rotated its keys, unless we just asked
+            if now - cached.attempted_at < MIN_REFETCH_INTERVAL {
+                return Ok(cached.keys);
+            }
+        }
+
+        let stale_ok = same_url && has_key(&cached.keys) && now - cached.expires_at < MAX_STALE;
+        let _fetching = match self.fetching.try_lock() {
+            Ok(guard) => guard,
+            // someone else is already fetching, the old keys will do until they're done
+            Err(TryLockError::WouldBlock) if stale_ok => return Ok(cached.keys),
+            Err(TryLockError::WouldBlock) => self.fetching.lock().map_err(|_| poisoned())?,
+            Err(TryLockError::Poisoned(_)) => return Err(poisoned()),
+        };
+
+        // whoever we waited on has already fetched, whether or not it worked
+        let latest = self.current()?;
+        if latest.generation != cached.generation && latest.url == url {
+            return Ok(latest.keys);
+        }
+
+        match self.fetch_locked(url) {
+            Ok(keys) => Ok(keys),
+            Err(err) if stale_ok => {
+                tracing::warn!(url, error = %err, "using expired signing keys");
+                Ok(cached.keys)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // fetches and stores new keys, with the fetching lock held
+    fn fetch_locked(&self, url: &str) -> Result<JwkSet, failure::Error> {
+        let result = fetch(self.client.as_ref(), url);
+
+        let mut cached = self.lock()?;
+        cached.attempted_at = Utc::now().timestamp();
+        cached.generation += 1;
+        let (expires_at, keys) = result?;
+        cached.url = url.to_owned();
+        cached.expires_at = expires_at;
+        cached.keys = keys.clone();
+
+        if let Some(ref path) = self.snapshot {
+            let written = serde_json::to_string(&*cached)
+                .map_err(failure::Error::from)
+                .and_then(|json| fs::write(path, json).map_err(failure::Error::from));
+            if let Err(err) = written {
+                tracing::warn!(path = %path, error = %err, "couldn't write the signing key snapshot");
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn refresh_in_background(&self, url: &str) -> Result<(), failure::Error> {
+        {
+            let mut cached = self.lock()?;
+            if cached.refreshing {
+                return Ok(());
+            }
+            cached.refreshing = true;
+        }
+
+        let cache = self.clone();
+        let url = url.to_owned();
+        thread::spawn(move || {
+            if let Ok(_fetching) = cache.fetching.try_lock() {
+                if let Err(err) = cache.fetch_locked(&url) {
+                    tracing::warn!(url = %url, error = %err, "refreshing signing keys failed");
+                }
+            }
+            if let Ok(mut cached) = cache.cached.lock() {
+                cached.refreshing = false;
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{FakeHttpClient, HttpResponse};
+    use reqwest::Method;
+
+    #[test]
+    fn expiration_prefers_max_age() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(expiration(&headers, 1000), 1000 + DEFAULT_MAX_AGE);
+
+        headers.insert(
+            EXPIRES,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:30:00 GMT"),
+        );
+        assert_eq!(expiration(&headers, 1000), 1800);
+
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=600, must-revalidate"),
+        );
+        headers.insert(AGE, HeaderValue::from_static("100"));
+        assert_eq!(expiration(&headers, 1000), 1500);
+
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        assert_eq!(expiration(&headers, 1000), 1000);
+    }
+
+    #[test]
+    fn key_cache_refetches_unknown_kids_and_serves_stale_keys() {
+        let url = "https://keys.example/jwks";
+        let http = Arc::new(FakeHttpClient::new());
+        http.respond(
+            Method::GET,
+            url,
+            HttpResponse::new(200, include_str!("../test_data/jwks.json"))
+                .with_header("cache-control", "max-age=0"),
+        );
+        let cache = KeyCache::new(http.clone());
+
+        assert!(cache.get(url, "test-key-1").is_ok());
+        assert_eq!(http.requests().len(), 1);
+
+        // max-age=0, so the keys are already expired, but they're
+        // still used while the provider is down
+        http.respond(Method::GET, url, HttpResponse::new(503, ""));
+        assert!(cache.get(url, "test-key-1").is_ok());
+        assert_eq!(http.requests().len(), 2);
+
+        // an unknown kid can't be served stale
+        assert!(cache.get(url, "unknown").is_err());
+    }
+}
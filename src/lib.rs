@@ -1,5 +1,32 @@
+pub mod admin;
+pub mod api_key;
+pub mod audit;
 pub mod auth;
 pub mod auth_google;
+pub mod config;
+pub mod crypto;
 pub mod db;
+pub mod directory;
 pub mod error;
+pub mod hash_pool;
+pub mod http;
+pub mod jwks;
+pub mod logging;
+pub mod mailer;
+pub mod memory_store;
+pub mod metrics;
+pub mod migrations;
+pub mod notifications;
+pub mod oauth_client;
+pub mod oauth_server;
+pub mod oidc;
+pub mod oidc_issuer;
+pub mod outbox;
+pub mod profile;
+pub mod runtime;
 pub mod send_grid;
+pub mod service_account;
+pub mod session;
+pub mod sqlite_store;
+pub mod store;
+pub mod templates;
@@ -0,0 +1,200 @@
+use crate::error::AuthError;
+use failure;
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, SmtpTransport, Transport};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub static FROM_ADDRESS: &str = "support@authapp.com";
+
+// a message ready to go out, with an html body and a plain text one
+// for clients that don't show html
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), AuthError>;
+}
+
+fn mail_error<E: std::fmt::Display>(err: E) -> AuthError {
+    AuthError::internal_error(&format!("mailer: {}", err))
+}
+
+// the multipart message as it goes out over smtp or into a file
+pub fn build_message(from: &str, email: &Email) -> Result<lettre_email::Email, AuthError> {
+    EmailBuilder::new()
+        .to(email.to.as_str())
+        .from(from)
+        .subject(email.subject.as_str())
+        .alternative(email.html.as_str(), email.text.as_str())
+        .build()
+        .map_err(mail_error)
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // plain connection, only for a relay on localhost
+    None,
+    // upgrade with STARTTLS, usually on port 587
+    StartTls,
+    // tls from the start, usually on port 465
+    Tls,
+}
+
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+}
+
+pub struct SmtpMailer {
+    from: String,
+    // the transport needs &mut to send and keeps its connection between messages
+    transport: Mutex<SmtpTransport>,
+}
+
+impl SmtpMailer {
+    pub fn new(config: SmtpConfig) -> Result<SmtpMailer, failure::Error> {
+        let security = match config.security {
+            SmtpSecurity::None => ClientSecurity::None,
+            SmtpSecurity::StartTls | SmtpSecurity::Tls => {
+                let tls = ClientTlsParameters::new(config.host.clone(), TlsConnector::new()?);
+                if config.security == SmtpSecurity::Tls {
+                    ClientSecurity::Wrapper(tls)
+                } else {
+                    ClientSecurity::Required(tls)
+                }
+            }
+        };
+
+        let mut client = SmtpClient::new((config.host.as_str(), config.port), security)?;
+        if let (Some(username), Some(password)) = (config.username, config.password) {
+            client = client.credentials(Credentials::new(username, password));
+        }
+
+        Ok(SmtpMailer {
+            from: config.from,
+            transport: Mutex::new(client.transport()),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), AuthError> {
+        let message = build_message(&self.from, email)?;
+        let mut transport = self
+            .transport
+            .lock()
+            .map_err(|_| mail_error("smtp transport lock poisoned"))?;
+        transport.send(message.into()).map_err(mail_error)?;
+        Ok(())
+    }
+}
+
+// writes every message to its own .eml file, for development
+// or to hand mail to something that picks it up from a directory
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str, from: &str) -> Result<FileMailer, failure::Error> {
+        fs::create_dir_all(dir)?;
+        Ok(FileMailer {
+            dir: PathBuf::from(dir),
+            from: from.to_owned(),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), AuthError> {
+        let message: lettre::SendableEmail = build_message(&self.from, email)?.into();
+        let path = self.dir.join(format!("{}.eml", message.message_id()));
+        let contents = message.message_to_string().map_err(mail_error)?;
+        fs::write(&path, contents).map_err(mail_error)
+    }
+}
+
+// just prints messages, so nothing needs setting up to see reset links locally
+pub struct ConsoleMailer;
+
+impl Mailer for ConsoleMailer {
+    fn send(&self, email: &Email) -> Result<(), AuthError> {
+        println!(
+            "mailer: to {}\nsubject: {}\n\n{}",
+            email.to, email.subject, email.text
+        );
+        Ok(())
+    }
+}
+
+// keeps messages in memory, so tests can read what would have been sent
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailer {
+    pub fn new() -> MemoryMailer {
+        MemoryMailer::default()
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        match self.sent.lock() {
+            Ok(sent) => sent.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, email: &Email) -> Result<(), AuthError> {
+        self.sent
+            .lock()
+            .map_err(|_| mail_error("memory mailer lock poisoned"))?
+            .push(email.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_message_has_html_and_text_parts() {
+        let mailer = MemoryMailer::new();
+        let email = Email {
+            to: "a@a.com".to_owned(),
+            subject: "Subject".to_owned(),
+            html: "<p>reset-token</p>".to_owned(),
+            text: "reset-token".to_owned(),
+        };
+        mailer.send(&email).unwrap();
+
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "a@a.com");
+
+        let message: lettre::SendableEmail = build_message(FROM_ADDRESS, &sent[0]).unwrap().into();
+        let message = message.message_to_string().unwrap();
+        assert!(message.contains("multipart/alternative"));
+        assert!(message.contains("text/plain"));
+        assert!(message.contains("text/html"));
+    }
+}
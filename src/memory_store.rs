@@ -0,0 +1,1022 @@
+use crate::admin::Account;
+use crate::api_key::{ApiKey, ApiKeyGrant};
+use crate::audit::{AuditEvent, AuditQuery, AuditRecord};
+use crate::auth::{ExternalIdentity, ExternalSignIn, User};
+use crate::directory::{self, DirectoryUser, UserQuery};
+use crate::error::AuthError;
+use crate::mailer::Email;
+use crate::oauth_client::OAuthLogin;
+use crate::oauth_server::{AuthorizationCode, AuthorizeRequest, Grant, OAuthClient};
+use crate::oidc_issuer::UserInfo;
+use crate::outbox::{DeadMessage, OutboxMessage};
+use crate::profile::{ExternalProfile, Profile, ProfileUpdate};
+use crate::service_account::ServiceAccount;
+use crate::session::{Credentials, UserStatus};
+use crate::store::{
+    self, AuditStore, ClientStore, OutboxStore, SessionStore, TokenStore, UserStore,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+// keeps everything in memory, for tests and for running the server locally
+// without a database. it's all lost when the process exits
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: i32,
+    users: Vec<UserRow>,
+    identities: Vec<IdentityRow>,
+    oauth_logins: HashMap<String, (OAuthLogin, i64)>,
+    revoked_tokens: HashMap<String, i64>,
+    known_devices: HashSet<(i32, String)>,
+    api_keys: Vec<ApiKeyRow>,
+    oauth_codes: HashMap<String, CodeRow>,
+    refresh_tokens: HashMap<String, RefreshTokenRow>,
+    not_me_tokens: HashMap<String, (i32, i64)>,
+    oauth_clients: Vec<OAuthClient>,
+    oauth_consents: HashMap<(i32, String), Vec<String>>,
+    service_accounts: Vec<ServiceAccount>,
+    outbox: Vec<OutboxRow>,
+    suppressions: HashMap<String, (String, Option<String>)>,
+    audit_events: Vec<AuditRecord>,
+}
+
+struct UserRow {
+    id: i32,
+    email: String,
+    username: String,
+    password: String,
+    disabled: bool,
+    admin: bool,
+    tokens_valid_after: Option<i64>,
+    email_verified: bool,
+    created_at: i64,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    updated_at: Option<i64>,
+    last_login_at: Option<i64>,
+}
+
+impl UserRow {
+    fn new(id: i32, email: &str, username: &str, password: &str) -> UserRow {
+        UserRow {
+            id,
+            email: email.to_owned(),
+            username: username.to_owned(),
+            password: password.to_owned(),
+            disabled: false,
+            admin: false,
+            tokens_valid_after: None,
+            email_verified: false,
+            created_at: now(),
+            display_name: None,
+            avatar_url: None,
+            locale: None,
+            timezone: None,
+            updated_at: None,
+            last_login_at: None,
+        }
+    }
+}
+
+struct IdentityRow {
+    user_id: i32,
+    provider: String,
+    subject: String,
+}
+
+struct ApiKeyRow {
+    id: i32,
+    user_id: i32,
+    name: String,
+    prefix: String,
+    key_hash: String,
+    scopes: Vec<String>,
+    created_at: i64,
+    last_used_at: Option<i64>,
+    expires_at: Option<i64>,
+    revoked: bool,
+}
+
+struct CodeRow {
+    client_id: String,
+    user_id: i32,
+    redirect_uri: String,
+    scope: String,
+    code_challenge: Option<String>,
+    nonce: Option<String>,
+    auth_time: i64,
+    expires_at: i64,
+}
+
+struct RefreshTokenRow {
+    grant: Grant,
+    expires_at: i64,
+    revoked: bool,
+}
+
+struct OutboxRow {
+    id: i32,
+    email: Email,
+    status: &'static str,
+    attempts: i32,
+    next_attempt_at: i64,
+    last_error: Option<String>,
+    created_at: i64,
+    expires_at: Option<i64>,
+}
+
+impl OutboxRow {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+impl State {
+    // ids are unique across every table, which is all anything needs
+    fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn user(&self, username: &str) -> Option<&UserRow> {
+        self.users.iter().find(|user| user.username == username)
+    }
+
+    fn user_mut(&mut self, username: &str) -> Option<&mut UserRow> {
+        self.users.iter_mut().find(|user| user.username == username)
+    }
+
+    fn user_by_id(&self, id: i32) -> Option<&UserRow> {
+        self.users.iter().find(|user| user.id == id)
+    }
+
+    fn user_info(&self, id: i32) -> Option<UserInfo> {
+        self.user_by_id(id).map(|user| UserInfo {
+            id: user.id,
+            email: user.email.clone(),
+            username: user.username.clone(),
+        })
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // a panic while the lock was held leaves nothing half written worth refusing
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn add_user(&self, user: &User) -> Result<u64, AuthError> {
+        let mut state = self.state();
+        if state.users.iter().any(|row| row.email == user.email) {
+            return Err(store::email_taken());
+        }
+        if state.users.iter().any(|row| row.username == user.username) {
+            return Err(store::username_taken());
+        }
+        let id = state.next_id();
+        state.users.push(UserRow::new(
+            id,
+            &user.email,
+            &user.username,
+            &user.password,
+        ));
+        Ok(1)
+    }
+
+    async fn user_exists(&self, username: &str) -> Result<bool, AuthError> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .any(|user| user.username == username || user.email == username))
+    }
+
+    async fn list_users(
+        &self,
+        query: &UserQuery,
+        limit: i64,
+    ) -> Result<(Vec<DirectoryUser>, i64), AuthError> {
+        let mut users: Vec<DirectoryUser> = self
+            .state()
+            .users
+            .iter()
+            .map(|user| DirectoryUser {
+                id: user.id,
+                username: user.username.clone(),
+                email: user.email.clone(),
+                admin: user.admin,
+                disabled: user.disabled,
+                email_verified: user.email_verified,
+                created_at: user.created_at,
+            })
+            .filter(|user| query.matches(user))
+            .collect();
+        let total = users.len() as i64;
+
+        users.retain(|user| directory::after_cursor(user, query));
+        directory::sort_users(&mut users, query);
+        users.truncate(limit as usize);
+        Ok((users, total))
+    }
+
+    async fn get_credentials(&self, email: &str) -> Result<Option<Credentials>, AuthError> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.email == email)
+            .map(|user| Credentials {
+                username: user.username.clone(),
+                password_hash: user.password.clone(),
+                disabled: user.disabled,
+            }))
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<String, AuthError> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.email == email)
+            .map(|user| user.username.clone())
+            .unwrap_or_default())
+    }
+
+    async fn update_user_password(&self, username: &str, password: &str) -> Result<u64, AuthError> {
+        match self.state().user_mut(username) {
+            Some(user) => {
+                user.password = password.to_owned();
+                user.tokens_valid_after = Some(now());
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn sign_in_external(
+        &self,
+        identity: &ExternalIdentity,
+    ) -> Result<ExternalSignIn, AuthError> {
+        let mut state = self.state();
+
+        let linked = state
+            .identities
+            .iter()
+            .find(|row| row.provider == identity.provider && row.subject == identity.subject)
+            .map(|row| row.user_id);
+        if let Some(user_id) = linked {
+            if let Some(user) = state.user_by_id(user_id) {
+                if user.disabled {
+                    return Err(store::account_disabled(&user.username));
+                }
+                return Ok(ExternalSignIn {
+                    username: user.username.clone(),
+                    linked_existing: false,
+                });
+            }
+        }
+
+        store::check_external_email(identity)?;
+
+        let existing = state
+            .users
+            .iter_mut()
+            .find(|user| user.email == identity.email);
+        let profile = ExternalProfile::from_identity(identity);
+        let linked_existing = existing.is_some();
+        let (user_id, username) = match existing {
+            Some(user) => {
+                store::check_external_link(identity)?;
+                if user.disabled {
+                    return Err(store::account_disabled(&user.username));
+                }
+                // the provider vouched for the email, and fills in what the profile is missing
+                user.email_verified = true;
+                user.display_name = user.display_name.take().or(profile.display_name);
+                user.avatar_url = user.avatar_url.take().or(profile.avatar_url);
+                user.locale = user.locale.take().or(profile.locale);
+                (user.id, user.username.clone())
+            }
+            None => {
+                let username = store::external_usernames(identity)
+                    .into_iter()
+                    .find(|candidate| state.user(candidate).is_none())
+                    .ok_or_else(|| store::no_free_username(identity))?;
+                let id = state.next_id();
+                let mut user = UserRow::new(id, &identity.email, &username, "");
+                user.email_verified = identity.email_verified;
+                user.display_name = profile.display_name;
+                user.avatar_url = profile.avatar_url;
+                user.locale = profile.locale;
+                state.users.push(user);
+                (id, username)
+            }
+        };
+
+        state.identities.push(IdentityRow {
+            user_id,
+            provider: identity.provider.clone(),
+            subject: identity.subject.clone(),
+        });
+        Ok(ExternalSignIn {
+            username,
+            linked_existing,
+        })
+    }
+
+    async fn get_user_id(&self, username: &str) -> Result<i32, AuthError> {
+        match self.state().user(username) {
+            Some(user) => Ok(user.id),
+            None => Err(store::unknown_user(username)),
+        }
+    }
+
+    async fn get_user_info(&self, username: &str) -> Result<Option<UserInfo>, AuthError> {
+        let state = self.state();
+        Ok(state
+            .user(username)
+            .and_then(|user| state.user_info(user.id)))
+    }
+
+    async fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>, AuthError> {
+        Ok(self.state().user(username).map(|user| UserStatus {
+            disabled: user.disabled,
+            tokens_valid_after: user.tokens_valid_after,
+        }))
+    }
+
+    async fn is_admin(&self, username: &str) -> Result<bool, AuthError> {
+        Ok(self
+            .state()
+            .user(username)
+            .is_some_and(|user| user.admin && !user.disabled))
+    }
+
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, AuthError> {
+        let state = self.state();
+        let user = match state.user(username) {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let mut identities: Vec<String> = state
+            .identities
+            .iter()
+            .filter(|row| row.user_id == user.id)
+            .map(|row| row.provider.clone())
+            .collect();
+        identities.sort();
+
+        Ok(Some(Account {
+            id: user.id,
+            email: user.email.clone(),
+            username: user.username.clone(),
+            disabled: user.disabled,
+            admin: user.admin,
+            tokens_valid_after: user.tokens_valid_after,
+            identities,
+        }))
+    }
+
+    async fn set_user_disabled(&self, username: &str, disabled: bool) -> Result<u64, AuthError> {
+        match self.state().user_mut(username) {
+            Some(user) => {
+                user.disabled = disabled;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn set_user_admin(&self, username: &str, admin: bool) -> Result<u64, AuthError> {
+        match self.state().user_mut(username) {
+            Some(user) => {
+                user.admin = admin;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, AuthError> {
+        Ok(self.state().user(username).map(|user| Profile {
+            username: user.username.clone(),
+            email: user.email.clone(),
+            display_name: user.display_name.clone(),
+            avatar_url: user.avatar_url.clone(),
+            locale: user.locale.clone(),
+            timezone: user.timezone.clone(),
+            created_at: user.created_at,
+            updated_at: user.updated_at.unwrap_or(user.created_at),
+            last_login_at: user.last_login_at,
+        }))
+    }
+
+    async fn update_profile(
+        &self,
+        username: &str,
+        update: &ProfileUpdate,
+    ) -> Result<u64, AuthError> {
+        match self.state().user_mut(username) {
+            Some(user) => {
+                let fields = [
+                    (&mut user.display_name, &update.display_name),
+                    (&mut user.avatar_url, &update.avatar_url),
+                    (&mut user.locale, &update.locale),
+                    (&mut user.timezone, &update.timezone),
+                ];
+                for (field, value) in fields {
+                    if let Some(value) = value {
+                        *field = value.clone();
+                    }
+                }
+                user.updated_at = Some(now());
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn record_sign_in(&self, username: &str) -> Result<(), AuthError> {
+        if let Some(user) = self.state().user_mut(username) {
+            user.last_login_at = Some(now());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn revoke_token(&self, jti: &str, expires_at: i64) -> Result<bool, AuthError> {
+        let mut state = self.state();
+        let now = now();
+        state
+            .revoked_tokens
+            .retain(|_, expires_at| *expires_at >= now);
+        if state.revoked_tokens.contains_key(jti) {
+            return Ok(false);
+        }
+        state.revoked_tokens.insert(jti.to_owned(), expires_at);
+        Ok(true)
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        Ok(self.state().revoked_tokens.contains_key(jti))
+    }
+
+    async fn revoke_sessions(&self, username: &str) -> Result<u64, AuthError> {
+        match self.state().user_mut(username) {
+            Some(user) => {
+                user.tokens_valid_after = Some(now());
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn remember_device(&self, user_id: i32, device_hash: &str) -> Result<bool, AuthError> {
+        let mut state = self.state();
+        let device = (user_id, device_hash.to_owned());
+        if state.known_devices.contains(&device) {
+            return Ok(false);
+        }
+        let seen_before = state.known_devices.iter().any(|(id, _)| *id == user_id);
+        state.known_devices.insert(device);
+        Ok(seen_before)
+    }
+
+    async fn add_oauth_login(&self, login: &OAuthLogin) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let now = now();
+        state
+            .oauth_logins
+            .retain(|_, (_, created_at)| *created_at >= now - 60 * 60);
+        state
+            .oauth_logins
+            .insert(login.state.clone(), (login.clone(), now));
+        Ok(())
+    }
+
+    async fn take_oauth_login(&self, state: &str) -> Result<Option<OAuthLogin>, AuthError> {
+        Ok(match self.state().oauth_logins.remove(state) {
+            Some((login, created_at)) if created_at > now() - 10 * 60 => Some(login),
+            _ => None,
+        })
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryStore {
+    async fn add_api_key(
+        &self,
+        user_id: i32,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        expires_at: Option<i64>,
+    ) -> Result<i32, AuthError> {
+        let mut state = self.state();
+        let id = state.next_id();
+        state.api_keys.push(ApiKeyRow {
+            id,
+            user_id,
+            name: name.to_owned(),
+            prefix: prefix.to_owned(),
+            key_hash: key_hash.to_owned(),
+            scopes: scopes.to_vec(),
+            created_at: now(),
+            last_used_at: None,
+            expires_at,
+            revoked: false,
+        });
+        Ok(id)
+    }
+
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, AuthError> {
+        Ok(self
+            .state()
+            .api_keys
+            .iter()
+            .filter(|key| key.user_id == user_id && !key.revoked)
+            .map(|key| ApiKey {
+                id: key.id,
+                name: key.name.clone(),
+                prefix: key.prefix.clone(),
+                scopes: key.scopes.clone(),
+                created_at: key.created_at,
+                last_used_at: key.last_used_at,
+                expires_at: key.expires_at,
+            })
+            .collect())
+    }
+
+    async fn revoke_api_key(&self, user_id: i32, id: i32) -> Result<u64, AuthError> {
+        let mut state = self.state();
+        let key = state
+            .api_keys
+            .iter_mut()
+            .find(|key| key.id == id && key.user_id == user_id && !key.revoked);
+        match key {
+            Some(key) => {
+                key.revoked = true;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn use_api_key(
+        &self,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<Option<ApiKeyGrant>, AuthError> {
+        let mut state = self.state();
+        let now = now();
+        let key = state.api_keys.iter_mut().find(|key| {
+            key.prefix == prefix
+                && key.key_hash == key_hash
+                && !key.revoked
+                && key.expires_at.is_none_or(|at| at > now)
+        });
+        let (user_id, scopes, created_at) = match key {
+            Some(key) => {
+                key.last_used_at = Some(now);
+                (key.user_id, key.scopes.clone(), key.created_at)
+            }
+            None => return Ok(None),
+        };
+        Ok(state.user_by_id(user_id).map(|user| ApiKeyGrant {
+            username: user.username.clone(),
+            prefix: prefix.to_owned(),
+            scopes,
+            created_at,
+        }))
+    }
+
+    async fn add_oauth_code(
+        &self,
+        code_hash: &str,
+        user_id: i32,
+        req: &AuthorizeRequest,
+        scope: &str,
+        auth_time: i64,
+    ) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let now = now();
+        state.oauth_codes.retain(|_, code| code.expires_at >= now);
+        state.oauth_codes.insert(
+            code_hash.to_owned(),
+            CodeRow {
+                client_id: req.client_id.clone(),
+                user_id,
+                redirect_uri: req.redirect_uri.clone(),
+                scope: scope.to_owned(),
+                code_challenge: req.code_challenge.clone(),
+                nonce: req.nonce.clone(),
+                auth_time,
+                expires_at: now + 10 * 60,
+            },
+        );
+        Ok(())
+    }
+
+    async fn take_oauth_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<AuthorizationCode>, AuthError> {
+        let mut state = self.state();
+        let code = match state.oauth_codes.remove(code_hash) {
+            Some(code) if code.expires_at > now() => code,
+            _ => return Ok(None),
+        };
+        Ok(state
+            .user_by_id(code.user_id)
+            .map(|user| AuthorizationCode {
+                grant: Grant {
+                    client_id: code.client_id,
+                    user_id: code.user_id,
+                    username: user.username.clone(),
+                    scope: code.scope,
+                    auth_time: code.auth_time,
+                },
+                redirect_uri: code.redirect_uri,
+                code_challenge: code.code_challenge,
+                nonce: code.nonce,
+            }))
+    }
+
+    async fn add_refresh_token(&self, token_hash: &str, grant: &Grant) -> Result<(), AuthError> {
+        self.state().refresh_tokens.insert(
+            token_hash.to_owned(),
+            RefreshTokenRow {
+                grant: grant.clone(),
+                expires_at: now() + 30 * 24 * 60 * 60,
+                revoked: false,
+            },
+        );
+        Ok(())
+    }
+
+    async fn take_refresh_token(&self, token_hash: &str) -> Result<Option<Grant>, AuthError> {
+        let mut state = self.state();
+        let now = now();
+        let grant = match state.refresh_tokens.get_mut(token_hash) {
+            Some(token) if !token.revoked && token.expires_at > now => {
+                token.revoked = true;
+                token.grant.clone()
+            }
+            _ => return Ok(None),
+        };
+        // the username is looked up again, like the join the sql stores make
+        Ok(state.user_by_id(grant.user_id).map(|user| Grant {
+            username: user.username.clone(),
+            ..grant
+        }))
+    }
+
+    async fn add_not_me_token(
+        &self,
+        token_hash: &str,
+        user_id: i32,
+        days: i64,
+    ) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let now = now();
+        state
+            .not_me_tokens
+            .retain(|_, (_, expires_at)| *expires_at >= now);
+        state
+            .not_me_tokens
+            .insert(token_hash.to_owned(), (user_id, now + days * 24 * 60 * 60));
+        Ok(())
+    }
+
+    async fn take_not_me_token(&self, token_hash: &str) -> Result<Option<UserInfo>, AuthError> {
+        let mut state = self.state();
+        match state.not_me_tokens.remove(token_hash) {
+            Some((user_id, expires_at)) if expires_at > now() => Ok(state.user_info(user_id)),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientStore for MemoryStore {
+    async fn add_oauth_client(
+        &self,
+        client: &OAuthClient,
+        _owner_id: i32,
+    ) -> Result<(), AuthError> {
+        let mut state = self.state();
+        if state
+            .oauth_clients
+            .iter()
+            .any(|row| row.client_id == client.client_id)
+        {
+            return Err(AuthError::internal_error(&format!(
+                "oauth client {} already exists",
+                client.client_id
+            )));
+        }
+        state.oauth_clients.push(client.clone());
+        Ok(())
+    }
+
+    async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>, AuthError> {
+        Ok(self
+            .state()
+            .oauth_clients
+            .iter()
+            .find(|client| client.client_id == client_id)
+            .cloned())
+    }
+
+    async fn get_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+    ) -> Result<Vec<String>, AuthError> {
+        Ok(self
+            .state()
+            .oauth_consents
+            .get(&(user_id, client_id.to_owned()))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn add_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+        scopes: &[String],
+    ) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let granted = state
+            .oauth_consents
+            .entry((user_id, client_id.to_owned()))
+            .or_default();
+        for scope in scopes {
+            if !granted.contains(scope) {
+                granted.push(scope.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_service_account(
+        &self,
+        account: &ServiceAccount,
+        _owner_id: i32,
+    ) -> Result<(), AuthError> {
+        let mut state = self.state();
+        if state
+            .service_accounts
+            .iter()
+            .any(|row| row.client_id == account.client_id)
+        {
+            return Err(AuthError::internal_error(&format!(
+                "service account {} already exists",
+                account.client_id
+            )));
+        }
+        state.service_accounts.push(account.clone());
+        Ok(())
+    }
+
+    async fn get_service_account(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<ServiceAccount>, AuthError> {
+        Ok(self
+            .state()
+            .service_accounts
+            .iter()
+            .find(|account| account.client_id == client_id)
+            .cloned())
+    }
+
+    async fn set_service_account_disabled(
+        &self,
+        client_id: &str,
+        disabled: bool,
+    ) -> Result<u64, AuthError> {
+        let mut state = self.state();
+        match state
+            .service_accounts
+            .iter_mut()
+            .find(|account| account.client_id == client_id)
+        {
+            Some(account) => {
+                account.disabled = disabled;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+#[async_trait]
+impl OutboxStore for MemoryStore {
+    async fn enqueue_email(&self, email: &Email, expires_at: Option<i64>) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let id = state.next_id();
+        let now = now();
+        state.outbox.push(OutboxRow {
+            id,
+            email: email.clone(),
+            status: "pending",
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+            expires_at,
+        });
+        Ok(())
+    }
+
+    async fn drop_expired_emails(&self) -> Result<u64, AuthError> {
+        let mut state = self.state();
+        let now = now();
+        let before = state.outbox.len();
+        state
+            .outbox
+            .retain(|row| row.status == "sent" || !row.is_expired(now));
+        Ok((before - state.outbox.len()) as u64)
+    }
+
+    async fn claim_emails(
+        &self,
+        limit: i64,
+        lease_seconds: i64,
+    ) -> Result<Vec<OutboxMessage>, AuthError> {
+        let mut state = self.state();
+        let now = now();
+        let mut due: Vec<&mut OutboxRow> = state
+            .outbox
+            .iter_mut()
+            .filter(|row| {
+                row.status == "pending" && row.next_attempt_at <= now && !row.is_expired(now)
+            })
+            .collect();
+        due.sort_by_key(|row| row.next_attempt_at);
+
+        Ok(due
+            .into_iter()
+            .take(limit as usize)
+            .map(|row| {
+                row.next_attempt_at = now + lease_seconds;
+                OutboxMessage {
+                    id: row.id,
+                    email: row.email.clone(),
+                    attempts: row.attempts,
+                }
+            })
+            .collect())
+    }
+
+    async fn mark_email_sent(&self, id: i32) -> Result<(), AuthError> {
+        if let Some(row) = self.state().outbox.iter_mut().find(|row| row.id == id) {
+            row.status = "sent";
+            row.email.html.clear();
+            row.email.text.clear();
+        }
+        Ok(())
+    }
+
+    async fn mark_email_failed(
+        &self,
+        id: i32,
+        error: &str,
+        retry_in: Option<i64>,
+    ) -> Result<(), AuthError> {
+        if let Some(row) = self.state().outbox.iter_mut().find(|row| row.id == id) {
+            row.attempts += 1;
+            row.last_error = Some(error.to_owned());
+            row.status = if retry_in.is_some() {
+                "pending"
+            } else {
+                "dead"
+            };
+            row.next_attempt_at = now() + retry_in.unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    async fn get_dead_emails(&self) -> Result<Vec<DeadMessage>, AuthError> {
+        Ok(self
+            .state()
+            .outbox
+            .iter()
+            .filter(|row| row.status == "dead")
+            .map(|row| DeadMessage {
+                id: row.id,
+                to: row.email.to.clone(),
+                subject: row.email.subject.clone(),
+                attempts: row.attempts,
+                last_error: row.last_error.clone(),
+                created_at: row.created_at,
+            })
+            .collect())
+    }
+
+    async fn replay_email(&self, id: i32) -> Result<u64, AuthError> {
+        let mut state = self.state();
+        let now = now();
+        let row = state
+            .outbox
+            .iter_mut()
+            .find(|row| row.id == id && row.status == "dead" && !row.is_expired(now));
+        match row {
+            Some(row) => {
+                row.status = "pending";
+                row.attempts = 0;
+                row.next_attempt_at = now;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn suppress_email(
+        &self,
+        email: &str,
+        event: &str,
+        reason: Option<&str>,
+    ) -> Result<(), AuthError> {
+        self.state().suppressions.insert(
+            email.to_lowercase(),
+            (event.to_owned(), reason.map(str::to_owned)),
+        );
+        Ok(())
+    }
+
+    async fn is_email_suppressed(&self, email: &str) -> Result<bool, AuthError> {
+        Ok(self
+            .state()
+            .suppressions
+            .contains_key(&email.to_lowercase()))
+    }
+}
+
+#[async_trait]
+impl AuditStore for MemoryStore {
+    async fn add_audit_event(&self, event: &AuditEvent) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let id = state.audit_events.len() as i64 + 1;
+        state.audit_events.push(AuditRecord {
+            id,
+            occurred_at: now(),
+            action: event.action.as_str().to_owned(),
+            outcome: event.outcome.as_str().to_owned(),
+            actor: event.actor.clone(),
+            subject: event.subject.clone(),
+            ip: event.ip.clone(),
+            user_agent: event.user_agent.clone(),
+            detail: event.detail.clone(),
+        });
+        Ok(())
+    }
+
+    async fn list_audit_events(
+        &self,
+        query: &AuditQuery,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, AuthError> {
+        Ok(self
+            .state()
+            .audit_events
+            .iter()
+            .rev()
+            .filter(|record| query.matches(record))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
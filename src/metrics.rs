@@ -0,0 +1,84 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// what /metrics serves, in the prometheus text format. the numbers are kept
+// in atomics by whatever they measure and only formatted when scraped
+
+pub fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+pub fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// how long something took, counted into buckets of seconds
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        // the first bucket it fits in, the rest are added up when rendering
+        if let Some(i) = self.buckets.iter().position(|le| seconds <= *le) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(2));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "took_seconds", "how long it took");
+        assert!(out.contains("took_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(out.contains("took_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("took_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("took_seconds_sum 2.55\n"));
+        assert!(out.contains("took_seconds_count 3\n"));
+    }
+}
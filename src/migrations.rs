@@ -0,0 +1,264 @@
+use crate::db::Db;
+use crate::error::AuthError;
+use sha2::{Digest, Sha256};
+
+// a schema change, applied once and in version order. a migration that's
+// been applied anywhere shouldn't be edited, add a new one instead
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    // recorded when it's applied, so an edit afterwards gets noticed
+    pub fn checksum(&self) -> String {
+        base64::encode_config(
+            &Sha256::digest(self.sql.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+}
+
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "user_directory",
+        sql: include_str!("../migrations/0002_user_directory.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "profiles",
+        sql: include_str!("../migrations/0003_profiles.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "audit_log",
+        sql: include_str!("../migrations/0004_audit_log.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "service_account_disabled",
+        sql: include_str!("../migrations/0005_service_account_disabled.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "api_key_expiry",
+        sql: include_str!("../migrations/0006_api_key_expiry.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "outbox_expiry",
+        sql: include_str!("../migrations/0007_outbox_expiry.sql"),
+    },
+];
+
+// the same changes for sqlite, which applies them as it opens the database.
+// versions follow the postgres ones
+pub static SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../migrations/sqlite/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "user_directory",
+        sql: include_str!("../migrations/sqlite/0002_user_directory.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "profiles",
+        sql: include_str!("../migrations/sqlite/0003_profiles.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "audit_log",
+        sql: include_str!("../migrations/sqlite/0004_audit_log.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "service_account_disabled",
+        sql: include_str!("../migrations/sqlite/0005_service_account_disabled.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "api_key_expiry",
+        sql: include_str!("../migrations/sqlite/0006_api_key_expiry.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "outbox_expiry",
+        sql: include_str!("../migrations/sqlite/0007_outbox_expiry.sql"),
+    },
+];
+
+// a row of the schema_migrations table
+pub struct AppliedMigration {
+    pub version: i32,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: i64,
+}
+
+pub enum MigrationState {
+    Pending,
+    Applied { applied_at: i64 },
+    // applied, but the sql has changed since
+    Changed { applied_at: i64 },
+    // applied by a newer version of the app
+    Unknown { applied_at: i64 },
+}
+
+pub struct MigrationStatus {
+    pub version: i32,
+    pub name: String,
+    pub state: MigrationState,
+}
+
+fn changed_error(version: i32, name: &str) -> AuthError {
+    AuthError::internal_error(&format!(
+        "migration {} ({}) was changed after it was applied",
+        version, name
+    ))
+}
+
+// every migration this build knows about and every one the database has seen
+pub async fn status(db: &Db) -> Result<Vec<MigrationStatus>, AuthError> {
+    let applied = db.get_applied_migrations().await?;
+    let mut statuses: Vec<MigrationStatus> = MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let state = match applied.iter().find(|a| a.version == migration.version) {
+                None => MigrationState::Pending,
+                Some(a) if a.checksum != migration.checksum() => MigrationState::Changed {
+                    applied_at: a.applied_at,
+                },
+                Some(a) => MigrationState::Applied {
+                    applied_at: a.applied_at,
+                },
+            };
+            MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_owned(),
+                state,
+            }
+        })
+        .collect();
+    for a in applied
+        .iter()
+        .filter(|a| MIGRATIONS.iter().all(|m| m.version != a.version))
+    {
+        statuses.push(MigrationStatus {
+            version: a.version,
+            name: a.name.clone(),
+            state: MigrationState::Unknown {
+                applied_at: a.applied_at,
+            },
+        });
+    }
+    statuses.sort_by_key(|status| status.version);
+    Ok(statuses)
+}
+
+// how many migrations haven't been applied yet
+pub async fn pending(db: &Db) -> Result<usize, AuthError> {
+    Ok(status(db)
+        .await?
+        .iter()
+        .filter(|status| matches!(status.state, MigrationState::Pending))
+        .count())
+}
+
+// applies every pending migration, each in its own transaction, and
+// returns the ones that were applied. several servers starting at once
+// is fine, the database only lets one of them apply a given migration
+pub async fn up(db: &Db) -> Result<Vec<&'static Migration>, AuthError> {
+    for status in status(db).await? {
+        if let MigrationState::Changed { .. } = status.state {
+            return Err(changed_error(status.version, &status.name));
+        }
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        if db.apply_migration(migration).await? {
+            applied.push(migration);
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::runtime::Runtime;
+    use crate::store::UserStore;
+    use tokio_postgres::NoTls;
+
+    // the users table as commands.sql used to set it up, before there were migrations
+    static BASELINE: &str = "
+        drop schema public cascade;
+        create schema public;
+        create table users (
+            id serial primary key,
+            email text not null,
+            username text not null,
+            password text not null,
+            UNIQUE (email),
+            UNIQUE (username)
+        );
+        CREATE UNIQUE INDEX id_idx ON users (id);
+        insert into users (email, username, password) values ('a', 'a', 'a');
+    ";
+
+    #[test]
+    fn versions_count_up_from_one() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1, "{}", migration.name);
+        }
+        for (i, migration) in SQLITE_MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1, "{}", migration.name);
+        }
+    }
+
+    // needs a postgres database it can wipe, in MIGRATIONS_TEST_URL
+    #[test]
+    #[ignore]
+    fn migrates_a_database_made_from_commands_sql() {
+        let url = std::env::var("MIGRATIONS_TEST_URL").expect("MIGRATIONS_TEST_URL isn't set");
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let (client, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+            tokio::spawn(connection);
+            client.batch_execute(BASELINE).await.unwrap();
+
+            let db = Db::new(&DatabaseConfig {
+                url,
+                ..DatabaseConfig::default()
+            })
+            .unwrap();
+            assert_eq!(up(&db).await.unwrap().len(), MIGRATIONS.len());
+            assert!(status(&db)
+                .await
+                .unwrap()
+                .iter()
+                .all(|status| matches!(status.state, MigrationState::Applied { .. })));
+
+            // the user from before is still there, with the columns added since
+            let status = db.get_user_status("a").await.unwrap().unwrap();
+            assert!(!status.disabled);
+            assert!(status.tokens_valid_after.is_none());
+            assert!(!db.is_admin("a").await.unwrap());
+
+            // and running it again finds nothing to do
+            assert!(up(&db).await.unwrap().is_empty());
+        });
+    }
+}
@@ -0,0 +1,185 @@
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::auth::Auth;
+use crate::crypto;
+use crate::error::AuthError;
+use crate::outbox;
+use crate::store::Store;
+use crate::templates::Templates;
+use failure;
+use serde::Deserialize;
+
+pub static EVENTS: [&str; 3] = ["new_sign_in", "password_changed", "identity_linked"];
+
+// things that happen to an account that its owner should hear about
+pub enum SecurityEvent<'a> {
+    NewSignIn { ip: &'a str, device: &'a str },
+    PasswordChanged,
+    IdentityLinked { provider: &'a str },
+}
+
+impl<'a> SecurityEvent<'a> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SecurityEvent::NewSignIn { .. } => "new_sign_in",
+            SecurityEvent::PasswordChanged => "password_changed",
+            SecurityEvent::IdentityLinked { .. } => "identity_linked",
+        }
+    }
+
+    fn vars(&self) -> Vec<(&'static str, &'a str)> {
+        match *self {
+            SecurityEvent::NewSignIn { ip, device } => vec![("ip", ip), ("device", device)],
+            SecurityEvent::IdentityLinked { provider } => vec![("provider", provider)],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NotMeRequest {
+    pub token: String,
+}
+
+// "this wasn't me" links work for a week
+static NOT_ME_DAYS: i64 = 7;
+
+// sends security notices for the events that are turned on
+#[derive(Clone)]
+pub struct Notifier {
+    templates: Templates,
+    enabled: Vec<String>,
+}
+
+impl Notifier {
+    pub fn new(templates: Templates, enabled: Vec<String>) -> Result<Notifier, failure::Error> {
+        for event in &enabled {
+            if !EVENTS.contains(&event.as_str()) {
+                return Err(failure::err_msg(format!(
+                    "{} isn't a security notification, it should be one of {}",
+                    event,
+                    EVENTS.join(", ")
+                )));
+            }
+            if !templates.has_notice(event) {
+                return Err(failure::err_msg(format!(
+                    "templates: there's no notice for {}",
+                    event
+                )));
+            }
+        }
+        Ok(Notifier { templates, enabled })
+    }
+
+    pub fn is_enabled(&self, event: &SecurityEvent) -> bool {
+        self.enabled.iter().any(|name| name == event.name())
+    }
+
+    // queues a notice for username, with a link that undoes a sign in they didn't make
+    pub async fn notify(
+        &self,
+        db: &Store,
+        username: &str,
+        locale: &str,
+        event: &SecurityEvent<'_>,
+    ) -> Result<(), AuthError> {
+        if !self.is_enabled(event) {
+            return Ok(());
+        }
+        let user = match db.get_user_info(username).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+
+        let token = crypto::random_token(32);
+        db.add_not_me_token(&crypto::hash_token(&token), user.id, NOT_ME_DAYS)
+            .await?;
+
+        let notice = self.templates.notice(event.name(), locale, &event.vars())?;
+        let email =
+            self.templates
+                .security_notice(locale, &user.email, &user.username, &notice, &token)?;
+        outbox::enqueue(db, &email, outbox::expires_in(NOT_ME_DAYS * 24 * 60 * 60)).await
+    }
+
+    // remembers the device a user signed in from, and tells them when it's
+    // one we haven't seen. the very first sign in isn't worth an email
+    pub async fn sign_in(
+        &self,
+        db: &Store,
+        username: &str,
+        locale: &str,
+        ip: &str,
+        user_agent: &str,
+    ) -> Result<(), AuthError> {
+        let user_id = db.get_user_id(username).await?;
+        let device = crypto::hash_token(&format!("{}|{}", ip, user_agent));
+        if db.remember_device(user_id, &device).await? {
+            let device = if user_agent.is_empty() {
+                "unknown device"
+            } else {
+                user_agent
+            };
+            self.notify(
+                db,
+                username,
+                locale,
+                &SecurityEvent::NewSignIn { ip, device },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+// the "this wasn't me" link: signs the user out everywhere and
+// sends them a password reset
+pub async fn not_me(
+    db: &Store,
+    auth: &Auth,
+    templates: &Templates,
+    token: &str,
+    locale: &str,
+) -> Result<(), AuthError> {
+    let user = db
+        .take_not_me_token(&crypto::hash_token(token))
+        .await?
+        .ok_or_else(|| {
+            AuthError::new_general(
+                "This link has expired or was already used.",
+                "not me token is invalid",
+                400,
+            )
+        })?;
+
+    db.revoke_sessions(&user.username).await?;
+    let event = AuditEvent::new(AuditAction::SessionsRevoked, &user.username)
+        .by(&user.username)
+        .detail("reported a sign in that wasn't them");
+    audit::record(db, event).await;
+
+    let reset = auth.create_token(&user.username, auth.lifetimes.reset)?;
+    let email = templates.reset_password(locale, &user.email, &user.username, &reset)?;
+    let expires_at = outbox::expires_in(auth.lifetimes.reset.seconds());
+    outbox::enqueue(db, &email, expires_at).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_event_has_a_notice() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/templates/email");
+        let templates = Templates::load(dir, "https://auth.example").unwrap();
+        let enabled = EVENTS.iter().map(|event| (*event).to_owned()).collect();
+        assert!(Notifier::new(templates.clone(), enabled).is_ok());
+        assert!(Notifier::new(templates.clone(), vec!["unknown".to_owned()]).is_err());
+
+        let event = SecurityEvent::NewSignIn {
+            ip: "203.0.113.7",
+            device: "Firefox",
+        };
+        let notice = templates.notice(event.name(), "es", &event.vars()).unwrap();
+        assert!(notice.contains("Firefox (203.0.113.7)"));
+    }
+}
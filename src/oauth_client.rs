@@ -0,0 +1,545 @@
+use crate::auth::ExternalIdentity;
+use crate::crypto;
+use crate::error::AuthError;
+use crate::http::{HttpClient, HttpRequest};
+use crate::oidc::{ClaimMapping, OidcProvider, OidcProviderConfig};
+use crate::runtime;
+use crate::store::Store;
+use failure;
+use reqwest::header::{ACCEPT, USER_AGENT};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+// a provider we send users to for the authorization code flow.
+// openid connect providers only need a discovery_url, plain oauth2
+// providers (github, ...) need their endpoints and a userinfo endpoint
+#[derive(Clone, Debug, Deserialize)]
+pub struct OAuthProviderConfig {
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub discovery_url: Option<String>,
+    #[serde(default)]
+    pub authorization_endpoint: Option<String>,
+    #[serde(default)]
+    pub token_endpoint: Option<String>,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    pub claims: ClaimMapping,
+}
+
+// a login in progress, kept between the start and callback requests
+#[derive(Clone, Debug)]
+pub struct OAuthLogin {
+    pub state: String,
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+impl OAuthLogin {
+    pub fn new(provider: &str) -> OAuthLogin {
+        OAuthLogin {
+            state: crypto::random_token(32),
+            provider: provider.to_owned(),
+            nonce: crypto::random_token(32),
+            code_verifier: crypto::random_token(32),
+        }
+    }
+}
+
+// the cookie that ties a login to the browser that started it. without it
+// anyone could send a victim a callback url for a login they started, and
+// sign the victim in to the attacker's account
+pub static STATE_COOKIE: &str = "oauth_state";
+
+#[derive(Deserialize)]
+pub struct OAuthCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    access_token: String,
+    #[serde(default)]
+    id_token: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+struct Endpoints {
+    authorization: String,
+    token: String,
+    userinfo: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct OAuthProvider {
+    config: OAuthProviderConfig,
+    client: Arc<dyn HttpClient>,
+    oidc: Option<OidcProvider>,
+}
+
+impl OAuthProvider {
+    pub fn new(client: Arc<dyn HttpClient>, config: OAuthProviderConfig) -> OAuthProvider {
+        let oidc = config.discovery_url.as_ref().map(|url| {
+            OidcProvider::new(
+                client.clone(),
+                OidcProviderConfig {
+                    claims: config.claims.clone(),
+                    ..OidcProviderConfig::new(&config.name, url, vec![config.client_id.clone()])
+                },
+            )
+        });
+
+        OAuthProvider {
+            config,
+            client,
+            oidc,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    // the state cookie is only sent back to the callback, and only lasts as
+    // long as the login it's for
+    pub fn state_cookie(&self, state: &str) -> String {
+        self.cookie(state, 10 * 60)
+    }
+
+    pub fn clear_state_cookie(&self) -> String {
+        self.cookie("", 0)
+    }
+
+    fn cookie(&self, value: &str, max_age: i64) -> String {
+        let (path, secure) = match Url::parse(&self.config.redirect_uri) {
+            Ok(url) => (url.path().to_owned(), url.scheme() == "https"),
+            Err(_) => ("/".to_owned(), true),
+        };
+        format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            STATE_COOKIE,
+            value,
+            path,
+            max_age,
+            if secure { "; Secure" } else { "" }
+        )
+    }
+
+    // endpoints set in the config win over the discovered ones
+    fn endpoints(&self) -> Result<Endpoints, failure::Error> {
+        let config = &self.config;
+        let (mut authorization, mut token, mut userinfo) = (
+            config.authorization_endpoint.clone(),
+            config.token_endpoint.clone(),
+            config.userinfo_endpoint.clone(),
+        );
+
+        if let Some(ref oidc) = self.oidc {
+            let metadata = oidc.metadata()?;
+            authorization = authorization.or(metadata.authorization_endpoint);
+            token = token.or(metadata.token_endpoint);
+            userinfo = userinfo.or(metadata.userinfo_endpoint);
+        }
+
+        match (authorization, token) {
+            (Some(authorization), Some(token)) => Ok(Endpoints {
+                authorization,
+                token,
+                userinfo,
+            }),
+            _ => Err(failure::err_msg(format!(
+                "oauth provider {} has no authorization or token endpoint",
+                config.name
+            ))),
+        }
+    }
+
+    pub fn authorization_url(&self, login: &OAuthLogin) -> Result<String, failure::Error> {
+        let scope = self.config.scopes.join(" ");
+        let challenge = crypto::pkce_challenge(&login.code_verifier);
+        let mut params = vec![
+            ("response_type", "code"),
+            ("client_id", &self.config.client_id),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("scope", &scope),
+            ("state", &login.state),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ];
+        if self.oidc.is_some() {
+            params.push(("nonce", &login.nonce));
+        }
+
+        let url = Url::parse_with_params(&self.endpoints()?.authorization, &params)?;
+        Ok(url.into_string())
+    }
+
+    // exchanges the code from the callback and works out who the user is,
+    // from the id token for openid connect providers or the userinfo endpoint otherwise
+    pub fn sign_in(
+        &self,
+        code: &str,
+        login: &OAuthLogin,
+    ) -> Result<ExternalIdentity, failure::Error> {
+        let endpoints = self.endpoints()?;
+
+        let req = HttpRequest::post(&endpoints.token)
+            .header(ACCEPT, "application/json")?
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+                ("code_verifier", &login.code_verifier),
+            ])?;
+        let tokens: TokenResponse = self.client.send(req)?.error_for_status()?.json()?;
+
+        // some providers answer errors with a 200
+        if let Some(error) = tokens.error {
+            return Err(failure::err_msg(format!(
+                "oauth token exchange with {} failed: {}",
+                self.config.name, error
+            )));
+        }
+
+        if let (Some(oidc), Some(id_token)) = (&self.oidc, &tokens.id_token) {
+            let claims = oidc.decode_claims(id_token)?;
+            if claims.get("nonce").and_then(Value::as_str) != Some(login.nonce.as_str()) {
+                return Err(failure::err_msg(format!(
+                    "oauth id token from {} has the wrong nonce",
+                    self.config.name
+                )));
+            }
+            return self.config.claims.map_identity(&self.config.name, &claims);
+        }
+
+        let userinfo = match endpoints.userinfo {
+            Some(userinfo) => userinfo,
+            None => {
+                return Err(failure::err_msg(format!(
+                    "oauth provider {} returned no id token and has no userinfo endpoint",
+                    self.config.name
+                )))
+            }
+        };
+
+        let req = HttpRequest::get(&userinfo)
+            .bearer_auth(&tokens.access_token)?
+            .header(ACCEPT, "application/json")?
+            // github rejects requests without a user agent
+            .header(USER_AGENT, "auth-app")?;
+        let claims: Map<String, Value> = self.client.send(req)?.error_for_status()?.json()?;
+        self.config.claims.map_identity(&self.config.name, &claims)
+    }
+}
+
+#[derive(Clone)]
+pub struct OAuthProviders {
+    providers: HashMap<String, OAuthProvider>,
+    // the page users land on after the callback, with the result in the fragment
+    complete_url: String,
+}
+
+impl OAuthProviders {
+    pub fn new(
+        client: Arc<dyn HttpClient>,
+        configs: Vec<OAuthProviderConfig>,
+        complete_url: &str,
+    ) -> OAuthProviders {
+        let providers = configs
+            .into_iter()
+            .map(|config| {
+                (
+                    config.name.clone(),
+                    OAuthProvider::new(client.clone(), config),
+                )
+            })
+            .collect();
+        OAuthProviders {
+            providers,
+            complete_url: complete_url.to_owned(),
+        }
+    }
+
+    // reads a json array of provider configs
+    pub fn from_file(
+        client: Arc<dyn HttpClient>,
+        path: &str,
+        complete_url: &str,
+    ) -> Result<OAuthProviders, failure::Error> {
+        let configs = OAuthProviders::read_file(path)?;
+        Ok(OAuthProviders::new(client, configs, complete_url))
+    }
+
+    pub fn read_file(path: &str) -> Result<Vec<OAuthProviderConfig>, failure::Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OAuthProvider> {
+        self.providers.get(name)
+    }
+
+    fn find(&self, name: &str) -> Result<OAuthProvider, AuthError> {
+        match self.get(name) {
+            Some(provider) => Ok(provider.clone()),
+            None => Err(AuthError::new_general(
+                "This sign in provider isn't supported.",
+                "",
+                404,
+            )),
+        }
+    }
+
+    // the fragment keeps the token out of server logs and referer headers
+    pub fn complete_url(&self, key: &str, value: &str) -> String {
+        let mut url = match Url::parse_with_params(&self.complete_url, &[(key, value)]) {
+            Ok(url) => url,
+            Err(_) => return self.complete_url.clone(),
+        };
+        let params = url.query().map(str::to_owned);
+        url.set_query(None);
+        url.set_fragment(params.as_deref());
+        url.into_string()
+    }
+}
+
+// a login that was started: where to send the browser, and the cookie to set
+// on it so the callback knows it's the same browser
+pub struct LoginStart {
+    pub url: String,
+    pub cookie: String,
+}
+
+pub async fn start(
+    db: &Store,
+    providers: &OAuthProviders,
+    name: &str,
+) -> Result<LoginStart, AuthError> {
+    let provider = providers.find(name)?;
+    let login = OAuthLogin::new(provider.name());
+    db.add_oauth_login(&login).await?;
+
+    // the endpoints of an openid connect provider may have to be discovered first
+    runtime::blocking(move || match provider.authorization_url(&login) {
+        Ok(url) => Ok(LoginStart {
+            url,
+            cookie: provider.state_cookie(&login.state),
+        }),
+        Err(err) => Err(AuthError::internal_error(&err.to_string())),
+    })
+    .await
+}
+
+// checks the callback belongs to a login this browser started and signs the
+// user in with the code. cookie_state is the state cookie the browser sent
+pub async fn finish(
+    db: &Store,
+    providers: &OAuthProviders,
+    name: &str,
+    callback: &OAuthCallback,
+    cookie_state: Option<&str>,
+) -> Result<ExternalIdentity, AuthError> {
+    // the user declined or the provider failed before giving us a code
+    if let Some(ref error) = callback.error {
+        return Err(AuthError::new_general("Sign in was cancelled.", error, 400));
+    }
+
+    let (code, state) = match (&callback.code, &callback.state) {
+        (Some(code), Some(state)) => (code.to_owned(), state),
+        _ => {
+            return Err(AuthError::new_general(
+                "Couldn't sign you in with this provider.",
+                "oauth callback is missing the code or state",
+                400,
+            ))
+        }
+    };
+
+    let provider = providers.find(name)?;
+
+    // checked before the login is taken, so a callback opened in the wrong
+    // browser doesn't use it up
+    if cookie_state != Some(state.as_str()) {
+        return Err(AuthError::new_general(
+            "Couldn't sign you in with this provider. Please try again.",
+            "oauth state doesn't match the browser's state cookie",
+            400,
+        ));
+    }
+
+    let login = match db.take_oauth_login(state).await? {
+        Some(login) if login.provider == provider.name() => login,
+        _ => {
+            return Err(AuthError::new_general(
+                "Your sign in took too long. Please try again.",
+                "unknown or expired oauth state",
+                400,
+            ))
+        }
+    };
+
+    // exchanging the code is a blocking call to the provider
+    runtime::blocking(move || match provider.sign_in(&code, &login) {
+        Ok(identity) => Ok(identity),
+        Err(err) => Err(AuthError::new_general(
+            "Couldn't sign you in with this provider.",
+            &err.to_string(),
+            401,
+        )),
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{FakeHttpClient, HttpResponse};
+    use crate::runtime::Runtime;
+    use reqwest::Method;
+    use serde_json::json;
+
+    static BASE: &str = "https://git.example";
+
+    // a plain oauth2 provider like github, with a token and userinfo endpoint
+    fn providers() -> (OAuthProviders, Arc<FakeHttpClient>) {
+        let http = Arc::new(FakeHttpClient::new());
+        http.respond(
+            Method::POST,
+            &format!("{}/token", BASE),
+            HttpResponse::new(200, &json!({ "access_token": "access" }).to_string()),
+        );
+        http.respond(
+            Method::GET,
+            &format!("{}/user", BASE),
+            HttpResponse::new(
+                200,
+                &json!({ "sub": "42", "email": "ann@example.com", "preferred_username": "ann" })
+                    .to_string(),
+            ),
+        );
+
+        let config = OAuthProviderConfig {
+            name: "git".to_owned(),
+            client_id: "client-1".to_owned(),
+            client_secret: "secret".to_owned(),
+            redirect_uri: "https://auth.example/auth/oauth/git/callback".to_owned(),
+            scopes: vec!["user".to_owned()],
+            discovery_url: None,
+            authorization_endpoint: Some(format!("{}/authorize", BASE)),
+            token_endpoint: Some(format!("{}/token", BASE)),
+            userinfo_endpoint: Some(format!("{}/user", BASE)),
+            claims: ClaimMapping::default(),
+        };
+        let providers = OAuthProviders::new(http.clone(), vec![config], "https://app.example/done");
+        (providers, http)
+    }
+
+    fn query_param(url: &str, name: &str) -> String {
+        let url = Url::parse(url).unwrap();
+        let value = url.query_pairs().find(|(key, _)| key == name).unwrap().1;
+        value.into_owned()
+    }
+
+    fn callback(state: &str) -> OAuthCallback {
+        OAuthCallback {
+            code: Some("code-1".to_owned()),
+            state: Some(state.to_owned()),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn start_and_callback_sign_the_user_in() {
+        let rt = Runtime::new();
+        let db = Store::memory();
+        let (providers, http) = providers();
+
+        rt.block_on(async {
+            let started = start(&db, &providers, "git").await.unwrap();
+            let state = query_param(&started.url, "state");
+            assert!(started
+                .cookie
+                .starts_with(&format!("oauth_state={};", state)));
+            assert!(started.cookie.contains("Path=/auth/oauth/git/callback"));
+            assert!(started.cookie.contains("HttpOnly; SameSite=Lax; Secure"));
+
+            let identity = finish(&db, &providers, "git", &callback(&state), Some(&state))
+                .await
+                .unwrap();
+            assert_eq!(identity.provider, "git");
+            assert_eq!(identity.subject, "42");
+            assert_eq!(identity.username, "ann");
+        });
+
+        // the code was exchanged with the verifier for the challenge we sent
+        let exchange = http
+            .requests()
+            .into_iter()
+            .find(|req| req.method == Method::POST)
+            .unwrap();
+        assert!(exchange.text().contains("code=code-1"));
+        assert!(exchange.text().contains("code_verifier="));
+    }
+
+    #[test]
+    fn callbacks_need_the_browser_that_started_the_login() {
+        let rt = Runtime::new();
+        let db = Store::memory();
+        let (providers, _) = providers();
+
+        rt.block_on(async {
+            let state = query_param(&start(&db, &providers, "git").await.unwrap().url, "state");
+            let other = query_param(&start(&db, &providers, "git").await.unwrap().url, "state");
+
+            let missing = finish(&db, &providers, "git", &callback(&state), None).await;
+            assert_eq!(missing.unwrap_err().status(), 400);
+            let mismatched = finish(&db, &providers, "git", &callback(&state), Some(&other)).await;
+            assert_eq!(mismatched.unwrap_err().status(), 400);
+
+            // neither used the login up
+            assert!(
+                finish(&db, &providers, "git", &callback(&state), Some(&state))
+                    .await
+                    .is_ok()
+            );
+        });
+    }
+
+    #[test]
+    fn a_login_can_only_be_finished_once() {
+        let rt = Runtime::new();
+        let db = Store::memory();
+        let (providers, _) = providers();
+
+        rt.block_on(async {
+            let state = query_param(&start(&db, &providers, "git").await.unwrap().url, "state");
+            assert!(
+                finish(&db, &providers, "git", &callback(&state), Some(&state))
+                    .await
+                    .is_ok()
+            );
+
+            let replayed = finish(&db, &providers, "git", &callback(&state), Some(&state)).await;
+            assert_eq!(
+                replayed.unwrap_err().server_message(),
+                "unknown or expired oauth state"
+            );
+        });
+    }
+}
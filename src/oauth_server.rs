@@ -0,0 +1,1070 @@
+use crate::api_key;
+use crate::auth::Auth;
+use crate::crypto;
+use crate::error::AuthError;
+use crate::logging;
+use crate::oidc_issuer::OidcIssuer;
+use crate::service_account::{self, JWT_BEARER};
+use crate::session;
+use crate::store::Store;
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt::{self, Formatter, Result as FmtResult};
+use std::net::Ipv4Addr;
+
+// every scope a client can be allowed to request
+pub static SCOPES: [&str; 5] = ["openid", "profile", "email", "offline_access", "users:read"];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OAuthClient {
+    pub client_id: String,
+    #[serde(skip)]
+    pub secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    // our own apps, which don't ask users for consent
+    pub first_party: bool,
+}
+
+impl OAuthClient {
+    // clients without a secret (spas, mobile apps) have to use pkce
+    pub fn is_public(&self) -> bool {
+        self.secret_hash.is_none()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewClient {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub public: bool,
+}
+
+#[derive(Serialize)]
+pub struct RegisteredClient {
+    pub client_id: String,
+    // only ever shown here, we just keep its hash
+    pub client_secret: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthorizeRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: String,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub code_challenge: Option<String>,
+    #[serde(default)]
+    pub code_challenge_method: Option<String>,
+    // echoed back in the id token so the client can tie it to this request
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizeDecision {
+    #[serde(flatten)]
+    pub request: AuthorizeRequest,
+    pub approve: bool,
+}
+
+// what the consent page shows the user
+#[derive(Serialize)]
+pub struct AuthorizePrompt {
+    pub client_name: String,
+    pub scopes: Vec<String>,
+    pub consent_required: bool,
+}
+
+// what a user granted a client, carried by codes and refresh tokens
+#[derive(Clone)]
+pub struct Grant {
+    pub client_id: String,
+    pub user_id: i32,
+    pub username: String,
+    pub scope: String,
+    // when the user signed in, for the id token's auth_time
+    pub auth_time: i64,
+}
+
+// an issued authorization code, waiting to be exchanged
+pub struct AuthorizationCode {
+    pub grant: Grant,
+    pub redirect_uri: String,
+    pub code_challenge: Option<String>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    // the signed jwt for the jwt bearer grant
+    pub assertion: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct IntrospectRequest {
+    pub token: String,
+    // we can tell the kinds of token apart, so the hint is ignored
+    #[serde(default)]
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+// the token endpoint answers errors the way rfc 6749 says to,
+// instead of with the json our own ui expects
+#[derive(Debug)]
+pub struct OAuthError {
+    error: &'static str,
+    description: String,
+    status: u16,
+}
+
+impl OAuthError {
+    pub fn new(error: &'static str, description: &str) -> OAuthError {
+        let status = match error {
+            "invalid_client" | "invalid_token" => 401,
+            "insufficient_scope" => 403,
+            "server_error" => 500,
+            _ => 400,
+        };
+        OAuthError {
+            error,
+            description: description.to_owned(),
+            status,
+        }
+    }
+}
+
+impl From<AuthError> for OAuthError {
+    fn from(error: AuthError) -> Self {
+        logging::log_error(&error);
+        OAuthError::new("server_error", error.client_message())
+    }
+}
+
+impl From<BlockingError<OAuthError>> for OAuthError {
+    fn from(error: BlockingError<OAuthError>) -> Self {
+        match error {
+            BlockingError::Error(err) => err,
+            BlockingError::Canceled => OAuthError::new("server_error", "Please try again later."),
+        }
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}: {}", self.error, self.description)
+    }
+}
+
+impl ResponseError for OAuthError {
+    fn render_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(
+            StatusCode::from_u16(self.status).expect("Invalid status code given."),
+        );
+        res.header("Cache-Control", "no-store");
+        // resource servers say what was wrong with the bearer token (rfc 6750)
+        if self.error == "invalid_token" || self.error == "insufficient_scope" {
+            res.header(
+                "WWW-Authenticate",
+                format!(r#"Bearer error="{}""#, self.error),
+            );
+        }
+        res.json(json!({
+            "error": self.error,
+            "error_description": self.description,
+        }))
+    }
+}
+
+fn invalid_request(message: &str) -> AuthError {
+    AuthError::new_general(message, "", 400)
+}
+
+pub fn parse_scopes(scope: &str) -> Vec<String> {
+    let mut scopes: Vec<String> = scope.split_whitespace().map(str::to_owned).collect();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+// https anywhere, http only back to this machine, and the custom schemes of the
+// native apps we allow. the consent page sends the browser to this uri, so
+// anything else, like javascript: or data:, would run on our origin
+fn check_redirect_uri(uri: &str, native_schemes: &[String]) -> Result<(), AuthError> {
+    let invalid = || invalid_request(&format!("{} isn't a valid redirect uri.", uri));
+    let url = Url::parse(uri).map_err(|_| invalid())?;
+    if url.fragment().is_some() {
+        return Err(invalid());
+    }
+    let loopback = match url.host_str() {
+        Some("localhost") | Some("[::1]") => true,
+        Some(host) => host.parse::<Ipv4Addr>().is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    };
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if loopback => Ok(()),
+        "http" => Err(invalid_request(&format!(
+            "{} should use https, http is only for localhost.",
+            uri
+        ))),
+        scheme if native_schemes.iter().any(|allowed| allowed == scheme) => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+pub async fn register_client(
+    db: &Store,
+    owner_id: i32,
+    client: &NewClient,
+    native_schemes: &[String],
+) -> Result<RegisteredClient, AuthError> {
+    if client.name.trim().is_empty() {
+        return Err(invalid_request("Please give the client a name."));
+    }
+    if client.redirect_uris.is_empty() {
+        return Err(invalid_request("Please add at least one redirect uri."));
+    }
+    for uri in &client.redirect_uris {
+        check_redirect_uri(uri, native_schemes)?;
+    }
+    for scope in &client.scopes {
+        if !SCOPES.contains(&scope.as_str()) {
+            return Err(invalid_request(&format!("{} isn't a known scope.", scope)));
+        }
+    }
+
+    let client_secret = if client.public {
+        None
+    } else {
+        Some(crypto::random_token(32))
+    };
+
+    let registered = OAuthClient {
+        client_id: crypto::random_token(16),
+        secret_hash: client_secret
+            .as_ref()
+            .map(|secret| crypto::hash_token(secret)),
+        name: client.name.trim().to_owned(),
+        redirect_uris: client.redirect_uris.clone(),
+        scopes: client.scopes.clone(),
+        first_party: false,
+    };
+    db.add_oauth_client(&registered, owner_id).await?;
+
+    Ok(RegisteredClient {
+        client_id: registered.client_id,
+        client_secret,
+    })
+}
+
+// checks an authorization request, returning the client and requested scopes
+async fn validate(
+    db: &Store,
+    req: &AuthorizeRequest,
+) -> Result<(OAuthClient, Vec<String>), AuthError> {
+    let client = match db.get_oauth_client(&req.client_id).await? {
+        Some(client) => client,
+        None => return Err(invalid_request("This application isn't registered.")),
+    };
+
+    // redirect uris are matched exactly, anything else could leak codes
+    if !client.redirect_uris.contains(&req.redirect_uri) {
+        return Err(invalid_request(
+            "This application sent an invalid redirect uri.",
+        ));
+    }
+
+    if req.response_type != "code" {
+        return Err(invalid_request("Only the code response type is supported."));
+    }
+
+    let scopes = parse_scopes(&req.scope);
+    for scope in &scopes {
+        if !client.scopes.contains(scope) {
+            return Err(invalid_request(&format!(
+                "This application can't ask for the {} scope.",
+                scope
+            )));
+        }
+    }
+
+    match (&req.code_challenge, &req.code_challenge_method) {
+        (Some(_), Some(method)) if method == "S256" => {}
+        (Some(_), _) => {
+            return Err(invalid_request(
+                "Only the S256 code challenge method is supported.",
+            ))
+        }
+        (None, _) if client.is_public() => {
+            return Err(invalid_request("This application has to use pkce."))
+        }
+        (None, _) => {}
+    }
+
+    Ok((client, scopes))
+}
+
+pub async fn authorize_prompt(
+    db: &Store,
+    user_id: i32,
+    req: &AuthorizeRequest,
+) -> Result<AuthorizePrompt, AuthError> {
+    let (client, scopes) = validate(db, req).await?;
+
+    let consent_required = if client.first_party {
+        false
+    } else {
+        let granted = db.get_oauth_consent(user_id, &client.client_id).await?;
+        !scopes.iter().all(|scope| granted.contains(scope))
+    };
+
+    Ok(AuthorizePrompt {
+        client_name: client.name,
+        scopes,
+        consent_required,
+    })
+}
+
+// records the user's decision and returns where to send the browser
+pub async fn authorize(
+    db: &Store,
+    user_id: i32,
+    auth_time: i64,
+    decision: &AuthorizeDecision,
+) -> Result<String, AuthError> {
+    let req = &decision.request;
+    let (client, scopes) = validate(db, req).await?;
+
+    let mut params = Vec::new();
+    if decision.approve {
+        if !client.first_party {
+            db.add_oauth_consent(user_id, &client.client_id, &scopes)
+                .await?;
+        }
+
+        let code = crypto::random_token(32);
+        db.add_oauth_code(
+            &crypto::hash_token(&code),
+            user_id,
+            req,
+            &scopes.join(" "),
+            auth_time,
+        )
+        .await?;
+        params.push(("code", code));
+    } else {
+        params.push(("error", "access_denied".to_owned()));
+    }
+    if let Some(ref state) = req.state {
+        params.push(("state", state.clone()));
+    }
+
+    match Url::parse_with_params(&req.redirect_uri, &params) {
+        Ok(url) => Ok(url.into_string()),
+        Err(err) => Err(AuthError::internal_error(&err.to_string())),
+    }
+}
+
+// client credentials can come from basic auth or the form body
+pub fn client_credentials(
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+    basic: Option<(String, String)>,
+) -> Option<(String, Option<String>)> {
+    match basic {
+        Some((id, secret)) => Some((id, Some(secret))),
+        None => client_id.map(|id| (id.to_owned(), client_secret.map(str::to_owned))),
+    }
+}
+
+fn invalid_client() -> OAuthError {
+    OAuthError::new("invalid_client", "Client authentication failed.")
+}
+
+async fn authenticate_client(
+    db: &Store,
+    client_id: &str,
+    secret: Option<&str>,
+) -> Result<OAuthClient, OAuthError> {
+    let client = db
+        .get_oauth_client(client_id)
+        .await?
+        .ok_or_else(invalid_client)?;
+
+    if let Some(ref hash) = client.secret_hash {
+        match secret {
+            Some(secret) if crypto::hash_token(secret) == *hash => {}
+            _ => return Err(invalid_client()),
+        }
+    }
+
+    Ok(client)
+}
+
+pub async fn token(
+    db: &Store,
+    auth: &Auth,
+    issuer: &OidcIssuer,
+    req: &TokenRequest,
+    basic: Option<(String, String)>,
+) -> Result<TokenResponse, OAuthError> {
+    // service accounts aren't oauth clients and authenticate themselves
+    match req.grant_type.as_ref() {
+        "client_credentials" => {
+            return service_account::client_credentials(db, auth, req, basic).await
+        }
+        grant_type if grant_type == JWT_BEARER => {
+            return service_account::jwt_bearer(db, auth, issuer, req).await
+        }
+        _ => {}
+    }
+
+    let (client_id, secret) = client_credentials(
+        req.client_id.as_deref(),
+        req.client_secret.as_deref(),
+        basic,
+    )
+    .ok_or_else(invalid_client)?;
+    let client = authenticate_client(db, &client_id, secret.as_deref()).await?;
+
+    match req.grant_type.as_ref() {
+        "authorization_code" => exchange_code(db, auth, issuer, &client, req).await,
+        "refresh_token" => refresh(db, auth, issuer, &client, req).await,
+        _ => Err(OAuthError::new(
+            "unsupported_grant_type",
+            "This grant type isn't supported.",
+        )),
+    }
+}
+
+async fn exchange_code(
+    db: &Store,
+    auth: &Auth,
+    issuer: &OidcIssuer,
+    client: &OAuthClient,
+    req: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let invalid_grant = || OAuthError::new("invalid_grant", "The code is invalid or expired.");
+
+    let code = req.code.as_ref().ok_or_else(invalid_grant)?;
+    let code = db
+        .take_oauth_code(&crypto::hash_token(code))
+        .await?
+        .ok_or_else(invalid_grant)?;
+
+    if code.grant.client_id != client.client_id
+        || req.redirect_uri.as_ref() != Some(&code.redirect_uri)
+    {
+        return Err(invalid_grant());
+    }
+
+    if let Some(ref challenge) = code.code_challenge {
+        match req.code_verifier {
+            Some(ref verifier) if crypto::pkce_challenge(verifier) == *challenge => {}
+            _ => return Err(invalid_grant()),
+        }
+    }
+
+    issue_tokens(db, auth, issuer, client, &code.grant, code.nonce.as_deref()).await
+}
+
+async fn refresh(
+    db: &Store,
+    auth: &Auth,
+    issuer: &OidcIssuer,
+    client: &OAuthClient,
+    req: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let invalid_grant =
+        || OAuthError::new("invalid_grant", "The refresh token is invalid or expired.");
+
+    let token = req.refresh_token.as_ref().ok_or_else(invalid_grant)?;
+    // refresh tokens are single use, a new one is issued with the new access token
+    let grant = db
+        .take_refresh_token(&crypto::hash_token(token))
+        .await?
+        .ok_or_else(invalid_grant)?;
+
+    if grant.client_id != client.client_id {
+        return Err(invalid_grant());
+    }
+
+    // the grant lasts as long as the sign in it came from, so signing out
+    // everywhere or being disabled ends it like it does access tokens
+    if !session::is_user_active(db, &grant.username, grant.auth_time).await? {
+        return Err(invalid_grant());
+    }
+
+    // a refresh can narrow the scope but never widen it
+    let granted = parse_scopes(&grant.scope);
+    let scope = match req.scope {
+        Some(ref scope) => {
+            let requested = parse_scopes(scope);
+            if !requested.iter().all(|s| granted.contains(s)) {
+                return Err(OAuthError::new(
+                    "invalid_scope",
+                    "The requested scope is wider than the original grant.",
+                ));
+            }
+            requested.join(" ")
+        }
+        None => grant.scope.clone(),
+    };
+
+    let grant = Grant { scope, ..grant };
+    issue_tokens(db, auth, issuer, client, &grant, None).await
+}
+
+async fn issue_tokens(
+    db: &Store,
+    auth: &Auth,
+    issuer: &OidcIssuer,
+    client: &OAuthClient,
+    grant: &Grant,
+    nonce: Option<&str>,
+) -> Result<TokenResponse, OAuthError> {
+    let duration = auth.lifetimes.access;
+    let expires_in = duration.seconds();
+    let access_token =
+        auth.create_client_token(&grant.username, &client.client_id, &grant.scope, duration)?;
+
+    let scopes = parse_scopes(&grant.scope);
+
+    // only clients that asked for offline access get to refresh
+    let refresh_token = if scopes.iter().any(|s| s == "offline_access") {
+        let token = crypto::random_token(32);
+        db.add_refresh_token(&crypto::hash_token(&token), grant)
+            .await?;
+        Some(token)
+    } else {
+        None
+    };
+
+    let id_token = if issuer.is_enabled() && scopes.iter().any(|s| s == "openid") {
+        let user = db
+            .get_user_info(&grant.username)
+            .await?
+            .ok_or_else(|| OAuthError::new("invalid_grant", "The user no longer exists."))?;
+        Some(issuer.id_token(
+            &user,
+            &client.client_id,
+            &grant.scope,
+            nonce,
+            grant.auth_time,
+        )?)
+    } else {
+        None
+    };
+
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_owned(),
+        expires_in,
+        refresh_token,
+        scope: grant.scope.clone(),
+        id_token,
+    })
+}
+
+// rfc 7662 token introspection, for services that can't check our tokens
+// themselves. only confidential clients and service accounts can ask
+pub async fn introspect(
+    db: &Store,
+    auth: &Auth,
+    req: &IntrospectRequest,
+    basic: Option<(String, String)>,
+) -> Result<Value, OAuthError> {
+    let (client_id, secret) = client_credentials(
+        req.client_id.as_deref(),
+        req.client_secret.as_deref(),
+        basic,
+    )
+    .ok_or_else(invalid_client)?;
+
+    if service_account::is_service_account(&client_id) {
+        service_account::authenticate(db, &client_id, secret.as_deref()).await?;
+    } else if authenticate_client(db, &client_id, secret.as_deref())
+        .await?
+        .is_public()
+    {
+        return Err(invalid_client());
+    }
+
+    let claims = if api_key::is_api_key(&req.token) {
+        api_key::authenticate(db, &req.token).await
+    } else {
+        auth.decode_token(&req.token)
+    };
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(_) => return Ok(json!({ "active": false })),
+    };
+    if !session::is_active(db, &claims).await? {
+        return Ok(json!({ "active": false }));
+    }
+
+    let mut res = json!({
+        "active": true,
+        "token_type": "Bearer",
+        "sub": claims.sub,
+        "iss": claims.issuer(),
+        "exp": claims.expires_at(),
+        "iat": claims.issued_at(),
+        "nbf": claims.issued_at(),
+    });
+    if let Some(ref scope) = claims.scope {
+        res["scope"] = json!(scope);
+    }
+    if let Some(ref client_id) = claims.client_id {
+        res["client_id"] = json!(client_id);
+    }
+    if let Some(jti) = claims.jti() {
+        res["jti"] = json!(jti);
+    }
+    if !claims.is_service() {
+        res["username"] = json!(claims.sub);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{TokenDuration, User};
+    use crate::runtime::Runtime;
+    use crate::sqlite_store::SqliteStore;
+    use chrono::Utc;
+
+    static REDIRECT_URI: &str = "https://app.example/callback";
+    static VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    struct Server {
+        db: Store,
+        auth: Auth,
+        issuer: OidcIssuer,
+        user_id: i32,
+        client_id: String,
+        client_secret: String,
+    }
+
+    // ann, and a confidential client she can grant offline access to
+    async fn new_server(db: Store) -> Server {
+        db.add_user(&User::new("ann@example.com", "ann", "hash"))
+            .await
+            .unwrap();
+        let user_id = db.get_user_id("ann").await.unwrap();
+        let client = NewClient {
+            name: "App".to_owned(),
+            redirect_uris: vec![REDIRECT_URI.to_owned()],
+            scopes: vec!["offline_access".to_owned(), "users:read".to_owned()],
+            public: false,
+        };
+        let registered = register_client(&db, user_id, &client, &[]).await.unwrap();
+
+        Server {
+            db,
+            auth: Auth::new(
+                "secretsecretsecret".to_owned(),
+                "0123456789abcdef".to_owned(),
+            ),
+            issuer: OidcIssuer::new("https://auth.example", "https://auth.example", Vec::new()),
+            user_id,
+            client_id: registered.client_id,
+            client_secret: registered.client_secret.unwrap(),
+        }
+    }
+
+    impl Server {
+        fn authorize_request(&self) -> AuthorizeRequest {
+            AuthorizeRequest {
+                response_type: "code".to_owned(),
+                client_id: self.client_id.clone(),
+                redirect_uri: REDIRECT_URI.to_owned(),
+                scope: "offline_access users:read".to_owned(),
+                state: Some("xyz".to_owned()),
+                code_challenge: Some(crypto::pkce_challenge(VERIFIER)),
+                code_challenge_method: Some("S256".to_owned()),
+                nonce: None,
+            }
+        }
+
+        // ann, who signed in a minute ago, approves the request
+        async fn code(&self) -> String {
+            let decision = AuthorizeDecision {
+                request: self.authorize_request(),
+                approve: true,
+            };
+            let signed_in = Utc::now().timestamp() - 60;
+            let url = authorize(&self.db, self.user_id, signed_in, &decision)
+                .await
+                .unwrap();
+            let url = Url::parse(&url).unwrap();
+            let code = url.query_pairs().find(|(key, _)| key == "code").unwrap().1;
+            code.into_owned()
+        }
+
+        fn request(&self, grant_type: &str) -> TokenRequest {
+            TokenRequest {
+                grant_type: grant_type.to_owned(),
+                code: None,
+                redirect_uri: None,
+                code_verifier: None,
+                refresh_token: None,
+                scope: None,
+                client_id: Some(self.client_id.clone()),
+                client_secret: Some(self.client_secret.clone()),
+                assertion: None,
+            }
+        }
+
+        fn exchange(&self, code: &str, verifier: &str, redirect_uri: &str) -> TokenRequest {
+            TokenRequest {
+                code: Some(code.to_owned()),
+                code_verifier: Some(verifier.to_owned()),
+                redirect_uri: Some(redirect_uri.to_owned()),
+                ..self.request("authorization_code")
+            }
+        }
+
+        fn refresh(&self, refresh_token: &str) -> TokenRequest {
+            TokenRequest {
+                refresh_token: Some(refresh_token.to_owned()),
+                ..self.request("refresh_token")
+            }
+        }
+
+        async fn token(&self, req: TokenRequest) -> Result<TokenResponse, OAuthError> {
+            token(&self.db, &self.auth, &self.issuer, &req, None).await
+        }
+
+        // asks about a token as the confidential client
+        async fn introspect(&self, token: &str) -> Value {
+            let req = introspect_request(token, &self.client_id, Some(&self.client_secret));
+            introspect(&self.db, &self.auth, &req, None)
+                .await
+                .ok()
+                .unwrap()
+        }
+    }
+
+    fn error_of(res: Result<TokenResponse, OAuthError>) -> &'static str {
+        match res {
+            Ok(_) => panic!("the token request should have failed"),
+            Err(err) => err.error,
+        }
+    }
+
+    #[test]
+    fn redirect_uris_have_to_be_safe_to_send_the_browser_to() {
+        let native = vec!["com.example.app".to_owned()];
+        for uri in &[
+            "https://app.example/callback",
+            "http://localhost:8080/callback",
+            "http://127.0.0.1/callback",
+            "http://[::1]/callback",
+            "com.example.app:/callback",
+        ] {
+            assert!(check_redirect_uri(uri, &native).is_ok(), "{}", uri);
+        }
+        for uri in &[
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "http://app.example/callback",
+            "https://app.example/callback#fragment",
+            "org.example.other:/callback",
+            "not a uri",
+        ] {
+            assert!(check_redirect_uri(uri, &native).is_err(), "{}", uri);
+        }
+        assert!(check_redirect_uri("com.example.app:/callback", &[]).is_err());
+
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            let client = NewClient {
+                name: "Evil".to_owned(),
+                redirect_uris: vec![REDIRECT_URI.to_owned(), "javascript:alert(1)".to_owned()],
+                scopes: Vec::new(),
+                public: true,
+            };
+            assert!(register_client(&db, 1, &client, &native).await.is_err());
+        });
+    }
+
+    #[test]
+    fn codes_need_the_right_verifier_and_redirect_uri() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let server = new_server(Store::memory()).await;
+
+            let wrong_verifier =
+                server.exchange(&server.code().await, "not-the-verifier", REDIRECT_URI);
+            assert_eq!(
+                error_of(server.token(wrong_verifier).await),
+                "invalid_grant"
+            );
+
+            let other_uri = "https://app.example/other";
+            let wrong_uri = server.exchange(&server.code().await, VERIFIER, other_uri);
+            assert_eq!(error_of(server.token(wrong_uri).await), "invalid_grant");
+
+            // a redirect uri that wasn't registered doesn't get a code at all
+            let mut req = server.authorize_request();
+            req.redirect_uri = other_uri.to_owned();
+            assert!(authorize_prompt(&server.db, server.user_id, &req)
+                .await
+                .is_err());
+
+            let exchange = server.exchange(&server.code().await, VERIFIER, REDIRECT_URI);
+            let tokens = server.token(exchange).await.ok().unwrap();
+            assert_eq!(tokens.scope, "offline_access users:read");
+            assert!(tokens.refresh_token.is_some());
+        });
+    }
+
+    #[test]
+    fn codes_can_only_be_exchanged_once() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let server = new_server(Store::memory()).await;
+            let code = server.code().await;
+
+            let exchange = server.exchange(&code, VERIFIER, REDIRECT_URI);
+            assert!(server.token(exchange).await.is_ok());
+            let reused = server.exchange(&code, VERIFIER, REDIRECT_URI);
+            assert_eq!(error_of(server.token(reused).await), "invalid_grant");
+        });
+    }
+
+    #[test]
+    fn expired_codes_are_refused() {
+        let path = std::env::temp_dir().join("auth-app-expired-codes.sqlite");
+        let path = path.to_str().unwrap();
+        // sqlite keeps the wal next to the database
+        let files = [
+            path.to_owned(),
+            format!("{}-wal", path),
+            format!("{}-shm", path),
+        ];
+        let remove_files = || {
+            for file in &files {
+                let _ = std::fs::remove_file(file);
+            }
+        };
+        remove_files();
+
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let server = new_server(Store::new(SqliteStore::open(path).unwrap())).await;
+            let code = server.code().await;
+
+            // ten minutes go by
+            let conn = rusqlite::Connection::open(path).unwrap();
+            conn.execute("UPDATE oauth_codes SET expires_at = unixepoch() - 1", [])
+                .unwrap();
+
+            let exchange = server.exchange(&code, VERIFIER, REDIRECT_URI);
+            assert_eq!(error_of(server.token(exchange).await), "invalid_grant");
+        });
+        remove_files();
+    }
+
+    #[test]
+    fn refresh_tokens_rotate_and_cannot_be_replayed() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let server = new_server(Store::memory()).await;
+            let exchange = server.exchange(&server.code().await, VERIFIER, REDIRECT_URI);
+            let first = server
+                .token(exchange)
+                .await
+                .ok()
+                .unwrap()
+                .refresh_token
+                .unwrap();
+
+            let second = server
+                .token(server.refresh(&first))
+                .await
+                .ok()
+                .unwrap()
+                .refresh_token
+                .unwrap();
+            assert_ne!(first, second);
+            assert_eq!(
+                error_of(server.token(server.refresh(&first)).await),
+                "invalid_grant"
+            );
+
+            // narrowing the scope is fine, widening it isn't
+            let mut narrower = server.refresh(&second);
+            narrower.scope = Some("offline_access".to_owned());
+            let third = server.token(narrower).await.ok().unwrap();
+            assert_eq!(third.scope, "offline_access");
+            let mut wider = server.refresh(third.refresh_token.as_ref().unwrap());
+            wider.scope = Some("offline_access users:read".to_owned());
+            assert_eq!(error_of(server.token(wider).await), "invalid_scope");
+        });
+    }
+
+    #[test]
+    fn refresh_stops_when_the_user_signs_out_everywhere_or_is_disabled() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let server = new_server(Store::memory()).await;
+            let exchange = server.exchange(&server.code().await, VERIFIER, REDIRECT_URI);
+            let refresh_token = server
+                .token(exchange)
+                .await
+                .ok()
+                .unwrap()
+                .refresh_token
+                .unwrap();
+            server.db.revoke_sessions("ann").await.unwrap();
+            let refresh = server.refresh(&refresh_token);
+            assert_eq!(error_of(server.token(refresh).await), "invalid_grant");
+
+            let server = new_server(Store::memory()).await;
+            let exchange = server.exchange(&server.code().await, VERIFIER, REDIRECT_URI);
+            let refresh_token = server
+                .token(exchange)
+                .await
+                .ok()
+                .unwrap()
+                .refresh_token
+                .unwrap();
+            server.db.set_user_disabled("ann", true).await.unwrap();
+            let refresh = server.refresh(&refresh_token);
+            assert_eq!(error_of(server.token(refresh).await), "invalid_grant");
+        });
+    }
+
+    fn introspect_request(token: &str, client_id: &str, secret: Option<&str>) -> IntrospectRequest {
+        IntrospectRequest {
+            token: token.to_owned(),
+            token_type_hint: None,
+            client_id: Some(client_id.to_owned()),
+            client_secret: secret.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn introspection_needs_a_confidential_client() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let server = new_server(Store::memory()).await;
+            let token = server
+                .auth
+                .create_token("ann", TokenDuration::Hours1)
+                .unwrap();
+            let refused = |res: Result<Value, OAuthError>| match res {
+                Ok(_) => panic!("the caller should have been refused"),
+                Err(err) => assert_eq!(err.error, "invalid_client"),
+            };
+
+            let mut anonymous = introspect_request(&token, "", None);
+            anonymous.client_id = None;
+            refused(introspect(&server.db, &server.auth, &anonymous, None).await);
+
+            let wrong_secret = introspect_request(&token, &server.client_id, Some("wrong"));
+            refused(introspect(&server.db, &server.auth, &wrong_secret, None).await);
+
+            let client = NewClient {
+                name: "Spa".to_owned(),
+                redirect_uris: vec![REDIRECT_URI.to_owned()],
+                scopes: Vec::new(),
+                public: true,
+            };
+            let public = register_client(&server.db, server.user_id, &client, &[])
+                .await
+                .unwrap();
+            let public = introspect_request(&token, &public.client_id, None);
+            refused(introspect(&server.db, &server.auth, &public, None).await);
+        });
+    }
+
+    #[test]
+    fn introspection_reports_whether_a_token_is_active() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let server = new_server(Store::memory()).await;
+            let token = server
+                .auth
+                .create_client_token(
+                    "ann",
+                    &server.client_id,
+                    "users:read",
+                    TokenDuration::Hours1,
+                )
+                .unwrap();
+            let claims = server.auth.decode_token(&token).unwrap();
+            let res = server.introspect(&token).await;
+            assert_eq!(res["active"], true);
+            assert_eq!(res["sub"], "ann");
+            assert_eq!(res["username"], "ann");
+            assert_eq!(res["client_id"], server.client_id.as_str());
+            assert_eq!(res["scope"], "users:read");
+            assert_eq!(res["token_type"], "Bearer");
+            assert_eq!(res["exp"], claims.expires_at());
+            assert_eq!(res["jti"], claims.jti().unwrap());
+
+            let inactive = json!({ "active": false });
+            assert_eq!(server.introspect("not a token").await, inactive);
+            let expired = server
+                .auth
+                .create_token("ann", TokenDuration::Seconds(-120))
+                .unwrap();
+            assert_eq!(server.introspect(&expired).await, inactive);
+
+            server
+                .db
+                .revoke_token(claims.jti().unwrap(), claims.expires_at())
+                .await
+                .unwrap();
+            assert_eq!(server.introspect(&token).await, inactive);
+
+            let token = server
+                .auth
+                .create_token("ann", TokenDuration::Hours1)
+                .unwrap();
+            assert_eq!(server.introspect(&token).await["active"], true);
+            server.db.set_user_disabled("ann", true).await.unwrap();
+            assert_eq!(server.introspect(&token).await, inactive);
+        });
+    }
+}
@@ -0,0 +1,372 @@
+use crate::auth::ExternalIdentity;
+use crate::http::{HttpClient, HttpRequest};
+use crate::jwks::KeyCache;
+use failure;
+use jsonwebtoken as jwt;
+use jwt::{Algorithm, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+// which claims of the id token hold the values we need.
+// the defaults follow the oidc standard claims
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ClaimMapping {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: String,
+    pub username: String,
+    pub name: String,
+    pub picture: String,
+    pub locale: String,
+}
+
+impl ClaimMapping {
+    pub fn map_identity(
+        &self,
+        provider: &str,
+        claims: &Map<String, Value>,
+    ) -> Result<ExternalIdentity, failure::Error> {
+        let subject = claim_string(claims, &self.subject);
+        if subject.is_empty() {
+            return Err(failure::err_msg(format!(
+                "{}: claim {} is missing",
+                provider, self.subject
+            )));
+        }
+
+        // some providers send email_verified as a string
+        let email_verified = match claims.get(&self.email_verified) {
+            Some(Value::Bool(verified)) => *verified,
+            Some(Value::String(verified)) => verified == "true",
+            _ => false,
+        };
+
+        let optional = |claim: &str| Some(claim_string(claims, claim)).filter(|v| !v.is_empty());
+
+        Ok(ExternalIdentity {
+            provider: provider.to_owned(),
+            subject,
+            email: claim_string(claims, &self.email),
+            email_verified,
+            username: claim_string(claims, &self.username),
+            name: optional(&self.name),
+            picture: optional(&self.picture),
+            locale: optional(&self.locale),
+        })
+    }
+}
+
+impl Default for ClaimMapping {
+    fn default() -> ClaimMapping {
+        ClaimMapping {
+            subject: "sub".to_owned(),
+            email: "email".to_owned(),
+            email_verified: "email_verified".to_owned(),
+            username: "preferred_username".to_owned(),
+            name: "name".to_owned(),
+            picture: "picture".to_owned(),
+            locale: "locale".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcProviderConfig {
+    pub name: String,
+    // the issuer url, or the full url of its openid-configuration document
+    pub discovery_url: String,
+    // client ids the id tokens must be issued to
+    pub audiences: Vec<String>,
+    // accepted "iss" values, defaults to the issuer from the discovery document
+    #[serde(default)]
+    pub issuers: Vec<String>,
+    #[serde(default = "default_algorithms")]
+    pub algorithms: Vec<Algorithm>,
+    #[serde(default)]
+    pub claims: ClaimMapping,
+    #[serde(default = "default_leeway")]
+    pub leeway: u64,
+}
+
+impl OidcProviderConfig {
+    pub fn new(name: &str, discovery_url: &str, audiences: Vec<String>) -> OidcProviderConfig {
+        OidcProviderConfig {
+            name: name.to_owned(),
+            discovery_url: discovery_url.to_owned(),
+            audiences,
+            issuers: Vec::new(),
+            algorithms: default_algorithms(),
+            claims: ClaimMapping::default(),
+            leeway: default_leeway(),
+        }
+    }
+}
+
+fn default_algorithms() -> Vec<Algorithm> {
+    vec![Algorithm::RS256]
+}
+
+fn default_leeway() -> u64 {
+    10
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub authorization_endpoint: Option<String>,
+    #[serde(default)]
+    pub token_endpoint: Option<String>,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IdTokenRequest {
+    pub id_token: String,
+}
+
+#[derive(Clone)]
+pub struct OidcProvider {
+    config: OidcProviderConfig,
+    client: Arc<dyn HttpClient>,
+    metadata: Arc<Mutex<Option<ProviderMetadata>>>,
+    keys: KeyCache,
+}
+
+impl OidcProvider {
+    pub fn new(client: Arc<dyn HttpClient>, config: OidcProviderConfig) -> OidcProvider {
+        OidcProvider {
+            config,
+            keys: KeyCache::new(client.clone()),
+            client,
+            metadata: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn discovery_url(&self) -> String {
+        let url = self.config.discovery_url.trim_end_matches('/');
+        if url.ends_with("/.well-known/openid-configuration") {
+            url.to_owned()
+        } else {
+            format!("{}/.well-known/openid-configuration", url)
+        }
+    }
+
+    // the discovery document is fetched on first use so that
+    // a provider being down doesn't keep the server from starting
+    pub fn metadata(&self) -> Result<ProviderMetadata, failure::Error> {
+        let mut metadata = self
+            .metadata
+            .lock()
+            .map_err(|_| failure::err_msg("oidc metadata lock poisoned"))?;
+
+        if let Some(ref md) = *metadata {
+            return Ok(md.clone());
+        }
+
+        let md: ProviderMetadata = self
+            .client
+            .send(HttpRequest::get(&self.discovery_url()))?
+            .error_for_status()?
+            .json()?;
+        *metadata = Some(md.clone());
+        Ok(md)
+    }
+
+    fn get_key(&self, kid: &str) -> Result<(String, String), failure::Error> {
+        let key = self.keys.get(&self.metadata()?.jwks_uri, kid)?;
+        Ok((key.n, key.e))
+    }
+
+    pub fn decode_claims(&self, token: &str) -> Result<Map<String, Value>, failure::Error> {
+        let header = jwt::decode_header(token)?;
+        if !self.config.algorithms.contains(&header.alg) {
+            return Err(failure::err_msg(format!(
+                "oidc decode_token: algorithm {:?} is not allowed",
+                header.alg
+            )));
+        }
+
+        let (n, e) = self.get_key(&header.kid.unwrap_or_default())?;
+
+        // the issuer is checked below since more than one can be allowed
+        let mut validation = Validation {
+            leeway: self.config.leeway,
+            algorithms: self.config.algorithms.clone(),
+            ..Validation::default()
+        };
+        validation.set_audience(&self.config.audiences);
+
+        let claims =
+            jwt::decode_rsa_components::<Map<String, Value>>(token, &n, &e, &validation)?.claims;
+
+        let issuers = if self.config.issuers.is_empty() {
+            vec![self.metadata()?.issuer]
+        } else {
+            self.config.issuers.clone()
+        };
+        let iss = claims.get("iss").and_then(Value::as_str).unwrap_or("");
+        if !issuers.iter().any(|allowed| allowed == iss) {
+            return Err(failure::err_msg(format!(
+                "oidc decode_token: issuer {} is not allowed",
+                iss
+            )));
+        }
+
+        Ok(claims)
+    }
+
+    pub fn decode_token(&self, token: &str) -> Result<ExternalIdentity, failure::Error> {
+        let claims = self.decode_claims(token)?;
+        self.config.claims.map_identity(&self.config.name, &claims)
+    }
+}
+
+fn claim_string(claims: &Map<String, Value>, claim: &str) -> String {
+    match claims.get(claim) {
+        Some(Value::String(value)) => value.clone(),
+        Some(Value::Number(value)) => value.to_string(),
+        _ => "".to_owned(),
+    }
+}
+
+#[derive(Clone)]
+pub struct OidcProviders {
+    providers: HashMap<String, OidcProvider>,
+}
+
+impl OidcProviders {
+    pub fn new(client: Arc<dyn HttpClient>, configs: Vec<OidcProviderConfig>) -> OidcProviders {
+        let providers = configs
+            .into_iter()
+            .map(|config| {
+                (
+                    config.name.clone(),
+                    OidcProvider::new(client.clone(), config),
+                )
+            })
+            .collect();
+        OidcProviders { providers }
+    }
+
+    // reads a json array of provider configs
+    pub fn from_file(
+        client: Arc<dyn HttpClient>,
+        path: &str,
+    ) -> Result<OidcProviders, failure::Error> {
+        Ok(OidcProviders::new(client, OidcProviders::read_file(path)?))
+    }
+
+    // the provider list on its own, so config can check it before anything starts
+    pub fn read_file(path: &str) -> Result<Vec<OidcProviderConfig>, failure::Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OidcProvider> {
+        self.providers.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{FakeHttpClient, HttpResponse};
+    use chrono::Utc;
+    use jwt::Header;
+    use reqwest::Method;
+    use serde_json::json;
+
+    static BASE: &str = "https://login.example";
+
+    // a provider serving its discovery document and the jwks for the test key
+    fn fake_provider() -> Arc<FakeHttpClient> {
+        let http = Arc::new(FakeHttpClient::new());
+        http.respond(
+            Method::GET,
+            &format!("{}/.well-known/openid-configuration", BASE),
+            HttpResponse::new(
+                200,
+                &json!({
+                    "issuer": BASE,
+                    "jwks_uri": format!("{}/jwks", BASE),
+                })
+                .to_string(),
+            ),
+        );
+        http.respond(
+            Method::GET,
+            &format!("{}/jwks", BASE),
+            HttpResponse::new(200, include_str!("../test_data/jwks.json")),
+        );
+        http
+    }
+
+    fn sign(claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key-1".to_owned());
+        jwt::encode(
+            &header,
+            claims,
+            include_bytes!("../test_data/rsa_private.pem"),
+        )
+        .unwrap()
+    }
+
+    fn provider() -> OidcProvider {
+        OidcProvider::new(
+            fake_provider(),
+            OidcProviderConfig {
+                claims: ClaimMapping {
+                    username: "nickname".to_owned(),
+                    ..ClaimMapping::default()
+                },
+                ..OidcProviderConfig::new("local", BASE, vec!["client-1".to_owned()])
+            },
+        )
+    }
+
+    #[test]
+    fn decode_token_works_against_discovered_provider() {
+        let token = sign(&json!({
+            "iss": BASE,
+            "aud": "client-1",
+            "sub": "1234",
+            "email": "a@a.com",
+            "email_verified": "true",
+            "nickname": "a",
+            "exp": Utc::now().timestamp() + 60,
+        }));
+
+        let identity = provider().decode_token(&token).unwrap();
+        assert_eq!(identity.provider, "local");
+        assert_eq!(identity.subject, "1234");
+        assert_eq!(identity.email, "a@a.com");
+        assert!(identity.email_verified);
+        assert_eq!(identity.username, "a");
+        assert!(identity.name.is_none());
+    }
+
+    #[test]
+    fn decode_token_rejects_wrong_audience_and_issuer() {
+        let provider = provider();
+        let exp = Utc::now().timestamp() + 60;
+
+        let wrong_aud = sign(&json!({ "iss": BASE, "aud": "client-2", "sub": "1", "exp": exp }));
+        assert!(provider.decode_token(&wrong_aud).is_err());
+
+        let wrong_iss = sign(
+            &json!({ "iss": "https://evil.example", "aud": "client-1", "sub": "1", "exp": exp }),
+        );
+        assert!(provider.decode_token(&wrong_iss).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use crate::auth::Auth;
+use crate::crypto;
+use crate::error::AuthError;
+use crate::oauth_server::{parse_scopes, OAuthError, SCOPES};
+use crate::service_account::JWT_BEARER;
+use crate::session;
+use crate::store::Store;
+use chrono::Utc;
+use failure;
+use jsonwebtoken as jwt;
+use jwt::{Algorithm, Header};
+use openssl::rsa::Rsa;
+use ring::signature::{KeyPair, RsaKeyPair};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+// an rsa key we sign id tokens with, published in our jwks
+#[derive(Clone)]
+pub struct SigningKey {
+    kid: String,
+    pem: Vec<u8>,
+    n: String,
+    e: String,
+}
+
+impl SigningKey {
+    pub fn from_pem(pem: &[u8]) -> Result<SigningKey, failure::Error> {
+        let text = String::from_utf8(pem.to_vec())?;
+        let der = base64::decode(
+            &text
+                .lines()
+                .filter(|line| !line.starts_with("-----"))
+                .collect::<String>(),
+        )?;
+
+        let key_pair = if text.contains("BEGIN RSA PRIVATE KEY") {
+            RsaKeyPair::from_der(&der)
+        } else {
+            RsaKeyPair::from_pkcs8(&der)
+        }
+        .map_err(|err| failure::err_msg(format!("invalid rsa signing key: {}", err)))?;
+
+        let public_key = key_pair.public_key();
+        let n = base64::encode_config(
+            public_key.modulus().big_endian_without_leading_zero(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let e = base64::encode_config(
+            public_key.exponent().big_endian_without_leading_zero(),
+            base64::URL_SAFE_NO_PAD,
+        );
+
+        // the rfc 7638 thumbprint, so the kid stays the same for the same key
+        let kid = crypto::hash_token(&format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n));
+
+        Ok(SigningKey {
+            kid,
+            pem: pem.to_vec(),
+            n,
+            e,
+        })
+    }
+
+    pub fn from_file(path: &str) -> Result<SigningKey, failure::Error> {
+        SigningKey::from_pem(&fs::read(path)?)
+    }
+
+    // makes a new 2048 bit key and saves it to path, which mustn't exist yet
+    pub fn generate(path: &str) -> Result<SigningKey, failure::Error> {
+        let pem = Rsa::generate(2048)?.private_key_to_pem()?;
+        let key = SigningKey::from_pem(&pem)?;
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(&pem)?;
+        Ok(key)
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn jwk(&self) -> Value {
+        json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": self.kid,
+            "n": self.n,
+            "e": self.e,
+        })
+    }
+}
+
+// who an id token or userinfo response is about
+pub struct UserInfo {
+    pub id: i32,
+    pub email: String,
+    pub username: String,
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims<'a> {
+    iss: &'a str,
+    sub: String,
+    aud: &'a str,
+    exp: i64,
+    iat: i64,
+    auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+    #[serde(flatten)]
+    profile: Value,
+}
+
+#[derive(Clone)]
+pub struct OidcIssuer {
+    issuer: String,
+    // the public url of the react app, which hosts the consent page
+    public_url: String,
+    // the first key signs, the others are still published while tokens they signed expire
+    keys: Vec<SigningKey>,
+}
+
+impl OidcIssuer {
+    pub fn new(issuer: &str, public_url: &str, keys: Vec<SigningKey>) -> OidcIssuer {
+        OidcIssuer {
+            issuer: issuer.trim_end_matches('/').to_owned(),
+            public_url: public_url.trim_end_matches('/').to_owned(),
+            keys,
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn token_endpoint(&self) -> String {
+        format!("{}/oauth/token", self.issuer)
+    }
+
+    // without a signing key we're a plain oauth server
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn discovery(&self) -> Value {
+        json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": format!("{}/authorize", self.public_url),
+            "token_endpoint": self.token_endpoint(),
+            "userinfo_endpoint": format!("{}/userinfo", self.issuer),
+            "introspection_endpoint": format!("{}/oauth/introspect", self.issuer),
+            "jwks_uri": format!("{}/.well-known/jwks.json", self.issuer),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code", "refresh_token", "client_credentials", JWT_BEARER],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "scopes_supported": SCOPES,
+            "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+            "code_challenge_methods_supported": ["S256"],
+            "claims_supported": ["iss", "sub", "aud", "exp", "iat", "auth_time", "nonce", "email", "email_verified", "preferred_username"],
+        })
+    }
+
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": self.keys.iter().map(SigningKey::jwk).collect::<Vec<Value>>() })
+    }
+
+    // the claims the granted scopes allow, shared by id tokens and userinfo
+    pub fn profile(&self, user: &UserInfo, scope: &str) -> Value {
+        let scopes = parse_scopes(scope);
+        let mut profile = json!({ "sub": user.id.to_string() });
+        if scopes.iter().any(|s| s == "email") {
+            profile["email"] = json!(user.email);
+            // we don't verify the emails people sign up with
+            profile["email_verified"] = json!(false);
+        }
+        if scopes.iter().any(|s| s == "profile") {
+            profile["preferred_username"] = json!(user.username);
+        }
+        profile
+    }
+
+    pub fn id_token(
+        &self,
+        user: &UserInfo,
+        client_id: &str,
+        scope: &str,
+        nonce: Option<&str>,
+        auth_time: i64,
+    ) -> Result<String, AuthError> {
+        let key = match self.keys.first() {
+            Some(key) => key,
+            None => return Err(AuthError::internal_error("no oidc signing key configured")),
+        };
+
+        let mut profile = self.profile(user, scope);
+        if let Some(profile) = profile.as_object_mut() {
+            profile.remove("sub");
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = IdTokenClaims {
+            iss: &self.issuer,
+            sub: user.id.to_string(),
+            aud: client_id,
+            exp: now + 60 * 60,
+            iat: now,
+            auth_time,
+            nonce,
+            profile,
+        };
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(key.kid.clone());
+        match jwt::encode(&header, &claims, &key.pem) {
+            Ok(token) => Ok(token),
+            Err(err) => Err(AuthError::internal_error(&err.to_string())),
+        }
+    }
+}
+
+// the userinfo endpoint, for access tokens granted the openid scope
+pub async fn userinfo(
+    db: &Store,
+    auth: &Auth,
+    issuer: &OidcIssuer,
+    token: &str,
+) -> Result<Value, OAuthError> {
+    let claims = session::authenticate(auth, db, token)
+        .await
+        .map_err(|_| OAuthError::new("invalid_token", "The access token is invalid or expired."))?;
+    if !claims.has_scope("openid") {
+        return Err(OAuthError::new(
+            "insufficient_scope",
+            "The access token wasn't granted the openid scope.",
+        ));
+    }
+
+    let user = db
+        .get_user_info(&claims.sub)
+        .await?
+        .ok_or_else(|| OAuthError::new("invalid_token", "The user no longer exists."))?;
+    Ok(issuer.profile(&user, claims.scope.as_deref().unwrap_or("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jwt::Validation;
+    use serde_json::Map;
+
+    #[test]
+    fn id_token_verifies_with_published_jwk() {
+        let key = SigningKey::from_pem(include_bytes!("../test_data/rsa_private.pem")).unwrap();
+        let issuer = OidcIssuer::new("http://localhost:5000", "http://localhost:3000", vec![key]);
+        let user = UserInfo {
+            id: 7,
+            email: "a@a.com".to_owned(),
+            username: "a".to_owned(),
+        };
+
+        let token = issuer
+            .id_token(&user, "client", "openid email", Some("n-0S6_WzA2Mj"), 100)
+            .unwrap();
+
+        let jwk = &issuer.jwks()["keys"][0];
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&["client"]);
+        let claims = jwt::decode_rsa_components::<Map<String, Value>>(
+            &token,
+            jwk["n"].as_str().unwrap(),
+            jwk["e"].as_str().unwrap(),
+            &validation,
+        )
+        .unwrap()
+        .claims;
+
+        assert_eq!(claims["sub"], "7");
+        assert_eq!(claims["nonce"], "n-0S6_WzA2Mj");
+        assert_eq!(claims["auth_time"], 100);
+        assert_eq!(claims["email"], "a@a.com");
+        assert!(claims.get("preferred_username").is_none());
+    }
+
+    #[test]
+    fn generated_key_is_saved_once() {
+        let path = std::env::temp_dir().join("auth-app-generated-signing-key.pem");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let key = SigningKey::generate(path).unwrap();
+        assert_eq!(SigningKey::from_file(path).unwrap().kid(), key.kid());
+        assert!(SigningKey::generate(path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use crate::error::AuthError;
+use crate::logging;
+use crate::mailer::{Email, Mailer};
+use crate::runtime::{self, Runtime};
+use crate::store::Store;
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+// after this many failed attempts a message is dead lettered
+pub static MAX_ATTEMPTS: i32 = 8;
+// the first retry waits this long, and every one after twice as long as the last
+static BASE_BACKOFF: i64 = 30;
+static MAX_BACKOFF: i64 = 6 * 60 * 60;
+// a claimed message is left alone this long, so a worker that dies
+// mid send doesn't lose it
+static LEASE_SECONDS: i64 = 5 * 60;
+static BATCH_SIZE: i64 = 20;
+static POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// a message claimed for delivery
+pub struct OutboxMessage {
+    pub id: i32,
+    pub email: Email,
+    // failed attempts so far
+    pub attempts: i32,
+}
+
+// a message we gave up on, as admins see it
+#[derive(Serialize)]
+pub struct DeadMessage {
+    pub id: i32,
+    pub to: String,
+    pub subject: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+}
+
+// seconds to wait before trying again after the given number of failures
+pub fn backoff(attempts: i32) -> i64 {
+    let doublings = (attempts.max(1) - 1).min(20) as u32;
+    (BASE_BACKOFF * 2i64.pow(doublings)).min(MAX_BACKOFF)
+}
+
+// when mail with a link that works for this many seconds should be dropped
+// rather than sent late
+pub fn expires_in(seconds: i64) -> Option<i64> {
+    Some(Utc::now().timestamp() + seconds)
+}
+
+// mail sent from a request goes through here, so the request doesn't wait
+// on the mail provider or fail when it's down. mail to undeliverable
+// addresses is dropped without telling the caller, and so is mail that's
+// still queued at expires_at
+pub async fn enqueue(db: &Store, email: &Email, expires_at: Option<i64>) -> Result<(), AuthError> {
+    if db.is_email_suppressed(&email.to).await? {
+        tracing::info!(
+            to = %logging::redact_email(&email.to),
+            "not sending to a suppressed address"
+        );
+        return Ok(());
+    }
+    db.enqueue_email(email, expires_at).await
+}
+
+// tries to send every message that's due, returns how many were claimed
+pub async fn deliver_batch(db: &Store, mailer: &Arc<dyn Mailer>) -> Result<usize, AuthError> {
+    let dropped = db.drop_expired_emails().await?;
+    if dropped > 0 {
+        tracing::info!(
+            dropped,
+            "dropped emails whose links expired before they were sent"
+        );
+    }
+
+    let messages = db.claim_emails(BATCH_SIZE, LEASE_SECONDS).await?;
+    for message in &messages {
+        // mailers block on the network
+        let sender = mailer.clone();
+        let email = message.email.clone();
+        match runtime::blocking(move || sender.send(&email)).await {
+            Ok(()) => db.mark_email_sent(message.id).await?,
+            Err(err) => {
+                let attempts = message.attempts + 1;
+                let retry_in = if attempts < MAX_ATTEMPTS {
+                    Some(backoff(attempts))
+                } else {
+                    None
+                };
+                tracing::warn!(
+                    message_id = message.id,
+                    attempts,
+                    error = %logging::redact(&err.to_string()),
+                    "sending an email failed"
+                );
+                db.mark_email_failed(message.id, &err.to_string(), retry_in)
+                    .await?;
+            }
+        }
+    }
+    Ok(messages.len())
+}
+
+// delivers queued mail in the background for as long as the server runs
+pub fn start_worker(runtime: &Runtime, db: Store, mailer: Arc<dyn Mailer>) {
+    runtime.spawn(async move {
+        loop {
+            match deliver_batch(&db, &mailer).await {
+                // there may be more waiting
+                Ok(claimed) if claimed as i64 == BATCH_SIZE => continue,
+                Ok(_) => {}
+                Err(err) => logging::log_error(&err),
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+// puts a dead message back in the queue with its attempts reset
+pub async fn replay(db: &Store, id: i32) -> Result<(), AuthError> {
+    match db.replay_email(id).await? {
+        0 => Err(AuthError::new_general(
+            "This message isn't dead lettered, or its links have expired.",
+            "",
+            404,
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::MemoryMailer;
+
+    struct FailingMailer;
+
+    impl Mailer for FailingMailer {
+        fn send(&self, _email: &Email) -> Result<(), AuthError> {
+            Err(AuthError::internal_error("mailer: connection refused"))
+        }
+    }
+
+    fn email() -> Email {
+        Email {
+            to: "ann@example.com".to_owned(),
+            subject: "Reset your password".to_owned(),
+            html: "<p>reset-token</p>".to_owned(),
+            text: "reset-token".to_owned(),
+        }
+    }
+
+    #[test]
+    fn failed_sends_are_retried_later() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            let failing: Arc<dyn Mailer> = Arc::new(FailingMailer);
+            enqueue(&db, &email(), None).await.unwrap();
+
+            assert_eq!(deliver_batch(&db, &failing).await.unwrap(), 1);
+            // it waits out the backoff rather than being dead lettered
+            assert!(db.get_dead_emails().await.unwrap().is_empty());
+            assert_eq!(deliver_batch(&db, &failing).await.unwrap(), 0);
+
+            assert_eq!(backoff(1), 30);
+            assert_eq!(backoff(2), 60);
+            assert_eq!(backoff(3), 120);
+            assert_eq!(backoff(100), MAX_BACKOFF);
+        });
+    }
+
+    #[test]
+    fn messages_are_dead_lettered_and_can_be_replayed() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            let failing: Arc<dyn Mailer> = Arc::new(FailingMailer);
+            let memory = Arc::new(MemoryMailer::new());
+            let working: Arc<dyn Mailer> = memory.clone();
+            enqueue(&db, &email(), None).await.unwrap();
+
+            // every attempt but the last has failed, and it's due again
+            let id = db.claim_emails(1, 0).await.unwrap()[0].id;
+            for _ in 1..MAX_ATTEMPTS {
+                db.mark_email_failed(id, "connection refused", Some(0))
+                    .await
+                    .unwrap();
+            }
+            assert!(db.get_dead_emails().await.unwrap().is_empty());
+
+            assert_eq!(deliver_batch(&db, &failing).await.unwrap(), 1);
+            let dead = db.get_dead_emails().await.unwrap();
+            assert_eq!(dead.len(), 1);
+            assert_eq!(dead[0].attempts, MAX_ATTEMPTS);
+            assert_eq!(deliver_batch(&db, &working).await.unwrap(), 0);
+
+            replay(&db, id).await.unwrap();
+            assert!(db.get_dead_emails().await.unwrap().is_empty());
+            assert_eq!(deliver_batch(&db, &working).await.unwrap(), 1);
+            assert_eq!(memory.sent().len(), 1);
+            assert_eq!(memory.sent()[0].text, "reset-token");
+
+            // only dead messages can be replayed
+            assert_eq!(replay(&db, id).await.unwrap_err().status(), 404);
+        });
+    }
+
+    #[test]
+    fn expired_messages_are_dropped_instead_of_sent() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let db = Store::memory();
+            let memory = Arc::new(MemoryMailer::new());
+            let working: Arc<dyn Mailer> = memory.clone();
+            enqueue(&db, &email(), expires_in(-1)).await.unwrap();
+            enqueue(&db, &email(), expires_in(60)).await.unwrap();
+
+            assert_eq!(deliver_batch(&db, &working).await.unwrap(), 1);
+            assert_eq!(memory.sent().len(), 1);
+            assert_eq!(db.drop_expired_emails().await.unwrap(), 0);
+        });
+    }
+}
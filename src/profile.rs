@@ -0,0 +1,273 @@
+use crate::auth::ExternalIdentity;
+use crate::error::AuthError;
+use crate::store::Store;
+use serde::{Deserialize, Deserializer, Serialize};
+
+// what a user tells us about themselves, beyond what they sign in with.
+// it starts out filled in from the provider they first signed in with
+
+const MAX_DISPLAY_NAME: usize = 100;
+const MAX_AVATAR_URL: usize = 2048;
+
+#[derive(Serialize, Debug)]
+pub struct Profile {
+    pub username: String,
+    pub email: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    // a bcp 47 language tag, like en-US
+    pub locale: Option<String>,
+    // an iana time zone, like Europe/London
+    pub timezone: Option<String>,
+    pub created_at: i64,
+    // when the profile last changed, when it was made if it never has
+    pub updated_at: i64,
+    pub last_login_at: Option<i64>,
+}
+
+// a PATCH of the profile. a field that's left out stays as it is,
+// one that's null is cleared
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileUpdate {
+    #[serde(default, deserialize_with = "present")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub avatar_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub locale: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub timezone: Option<Option<String>>,
+}
+
+// tells a null apart from a field that wasn't sent
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::<String>::deserialize(deserializer).map(Some)
+}
+
+// the fields a new account takes from the identity it was made from
+#[derive(Default)]
+pub struct ExternalProfile {
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub locale: Option<String>,
+}
+
+fn invalid(field: &str, message: &str) -> AuthError {
+    AuthError::new(field, message, "", 400)
+}
+
+fn check_display_name(name: &str) -> Result<(), AuthError> {
+    if name.trim().is_empty() || name.chars().count() > MAX_DISPLAY_NAME {
+        return Err(invalid(
+            "display_name",
+            &format!(
+                "Your name should be between 1 and {} characters.",
+                MAX_DISPLAY_NAME
+            ),
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(invalid(
+            "display_name",
+            "Your name can't contain line breaks.",
+        ));
+    }
+    Ok(())
+}
+
+fn check_avatar_url(url: &str) -> Result<(), AuthError> {
+    let host = url.strip_prefix("https://").unwrap_or("");
+    if host.is_empty()
+        || host.starts_with('/')
+        || url.len() > MAX_AVATAR_URL
+        || url.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(invalid(
+            "avatar_url",
+            "Your avatar should be an https link to an image.",
+        ));
+    }
+    Ok(())
+}
+
+// a language, then optional script, region and variant subtags
+fn check_locale(locale: &str) -> Result<(), AuthError> {
+    let mut subtags = locale.split('-');
+    let language = subtags.next().unwrap_or("");
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (2..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if !valid || locale.len() > 35 {
+        return Err(invalid(
+            "locale",
+            "Your locale should be a language tag, like en-US.",
+        ));
+    }
+    Ok(())
+}
+
+// we don't ship the time zone database, so this checks the shape of the
+// name: UTC, or an area and a location like America/New_York
+fn check_timezone(timezone: &str) -> Result<(), AuthError> {
+    let part = |part: &str| {
+        !part.is_empty()
+            && part.len() <= 30
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+    };
+    let mut parts = timezone.split('/');
+    let area = parts.next().unwrap_or("");
+    let rest: Vec<&str> = parts.collect();
+    let valid = timezone == "UTC"
+        || (area.starts_with(|c: char| c.is_ascii_uppercase())
+            && part(area)
+            && !rest.is_empty()
+            && rest.len() <= 2
+            && rest.iter().all(|p| part(p)));
+    if !valid {
+        return Err(invalid(
+            "timezone",
+            "Your time zone should be one like Europe/London.",
+        ));
+    }
+    Ok(())
+}
+
+impl ProfileUpdate {
+    // trims what was sent, empty values clear the field like null does
+    pub fn normalize(mut self) -> Result<ProfileUpdate, AuthError> {
+        for field in self.fields_mut() {
+            if let Some(Some(value)) = field {
+                let trimmed = value.trim();
+                *field = Some(if trimmed.is_empty() {
+                    None
+                } else {
+                    Some(trimmed.to_owned())
+                });
+            }
+        }
+
+        if let Some(Some(ref name)) = self.display_name {
+            check_display_name(name)?;
+        }
+        if let Some(Some(ref url)) = self.avatar_url {
+            check_avatar_url(url)?;
+        }
+        if let Some(Some(ref locale)) = self.locale {
+            check_locale(locale)?;
+        }
+        if let Some(Some(ref timezone)) = self.timezone {
+            check_timezone(timezone)?;
+        }
+        Ok(self)
+    }
+
+    fn fields_mut(&mut self) -> [&mut Option<Option<String>>; 4] {
+        [
+            &mut self.display_name,
+            &mut self.avatar_url,
+            &mut self.locale,
+            &mut self.timezone,
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.display_name.is_none()
+            && self.avatar_url.is_none()
+            && self.locale.is_none()
+            && self.timezone.is_none()
+    }
+}
+
+impl ExternalProfile {
+    // what the provider told us, leaving out anything we wouldn't accept
+    // from the user themselves
+    pub fn from_identity(identity: &ExternalIdentity) -> ExternalProfile {
+        let keep = |value: &Option<String>, check: fn(&str) -> Result<(), AuthError>| {
+            value
+                .as_ref()
+                .map(|value| value.trim().to_owned())
+                .filter(|value| check(value).is_ok())
+        };
+        ExternalProfile {
+            display_name: keep(&identity.name, check_display_name),
+            avatar_url: keep(&identity.picture, check_avatar_url),
+            locale: keep(&identity.locale, check_locale),
+        }
+    }
+}
+
+fn no_profile(username: &str) -> AuthError {
+    AuthError::new_general(
+        "That user doesn't exist.",
+        &format!("user {} has no profile", username),
+        404,
+    )
+}
+
+pub async fn get(db: &Store, username: &str) -> Result<Profile, AuthError> {
+    db.get_profile(username)
+        .await?
+        .ok_or_else(|| no_profile(username))
+}
+
+pub async fn update(
+    db: &Store,
+    username: &str,
+    update: ProfileUpdate,
+) -> Result<Profile, AuthError> {
+    let update = update.normalize()?;
+    if !update.is_empty() && db.update_profile(username, &update).await? == 0 {
+        return Err(no_profile(username));
+    }
+    get(db, username).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(json: &str) -> Result<ProfileUpdate, AuthError> {
+        serde_json::from_str::<ProfileUpdate>(json)
+            .unwrap()
+            .normalize()
+    }
+
+    #[test]
+    fn null_clears_and_missing_leaves_alone() {
+        let update =
+            update(r#"{"display_name": "  Ann  ", "locale": null, "timezone": ""}"#).unwrap();
+        assert_eq!(update.display_name, Some(Some("Ann".to_owned())));
+        assert_eq!(update.locale, Some(None));
+        assert_eq!(update.timezone, Some(None));
+        assert_eq!(update.avatar_url, None);
+    }
+
+    #[test]
+    fn each_field_is_checked() {
+        for (json, field) in &[
+            (r#"{"display_name": "a\nb"}"#, "display_name"),
+            (
+                r#"{"avatar_url": "http://example.com/a.png"}"#,
+                "avatar_url",
+            ),
+            (r#"{"avatar_url": "javascript:alert(1)"}"#, "avatar_url"),
+            (r#"{"locale": "english"}"#, "locale"),
+            (r#"{"timezone": "not a zone"}"#, "timezone"),
+            (r#"{"timezone": "europe/london"}"#, "timezone"),
+        ] {
+            let err = update(json).unwrap_err();
+            assert_eq!(err.context(), *field, "{}", json);
+        }
+
+        assert!(
+            update(r#"{"locale": "zh-Hant-TW", "timezone": "America/Argentina/Salta"}"#).is_ok()
+        );
+        assert!(update(r#"{"avatar_url": "https://example.com/me.png"}"#).is_ok());
+        assert!(serde_json::from_str::<ProfileUpdate>(r#"{"username": "x"}"#).is_err());
+    }
+}
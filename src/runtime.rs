@@ -0,0 +1,77 @@
+use crate::error::AuthError;
+use futures03::future::{FutureExt, TryFutureExt};
+use std::future::Future;
+use std::sync::Arc;
+use tracing::{Instrument, Span};
+
+// the database driver is async and runs on its own tokio runtime, next to
+// actix's. a request waiting on the database doesn't hold a thread, so
+// it no longer competes with hashing for actix's blocking pool
+#[derive(Clone)]
+pub struct Runtime {
+    runtime: Arc<tokio::runtime::Runtime>,
+}
+
+impl Runtime {
+    pub fn new() -> Runtime {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("auth-runtime")
+            .enable_all()
+            .build()
+            .expect("Couldn't start the tokio runtime.");
+        Runtime {
+            runtime: Arc::new(runtime),
+        }
+    }
+
+    // starts fut on the runtime and returns a future actix can wait on. it
+    // stays in the span it was started in, the request's for a handler
+    pub fn run<F, T, E>(&self, fut: F) -> impl futures::Future<Item = T, Error = E>
+    where
+        F: Future<Output = Result<T, E>> + Send + 'static,
+        T: Send + 'static,
+        E: From<AuthError> + Send + 'static,
+    {
+        let handle = self.runtime.spawn(fut.instrument(Span::current()));
+        async move {
+            match handle.await {
+                Ok(res) => res,
+                Err(err) => Err(E::from(AuthError::internal_error(&err.to_string()))),
+            }
+        }
+        .boxed()
+        .compat()
+    }
+
+    // for the cli and startup, which have nothing else to do while they wait
+    pub fn block_on<F: Future>(&self, fut: F) -> F::Output {
+        self.runtime.block_on(fut)
+    }
+
+    pub fn spawn<F>(&self, fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.runtime.spawn(fut);
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Runtime {
+        Runtime::new()
+    }
+}
+
+// work that would hold up the runtime's threads, like hashing
+// passwords or the blocking http client, runs on its blocking pool
+pub async fn blocking<F, T>(f: F) -> Result<T, AuthError>
+where
+    F: FnOnce() -> Result<T, AuthError> + Send + 'static,
+    T: Send + 'static,
+{
+    let span = Span::current();
+    match tokio::task::spawn_blocking(move || span.in_scope(f)).await {
+        Ok(res) => res,
+        Err(err) => Err(AuthError::internal_error(&err.to_string())),
+    }
+}
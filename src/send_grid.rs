@@ -1,93 +1,255 @@
 use crate::error::AuthError;
-use reqwest;
-use reqwest::header::*;
+use crate::http::{HttpClient, HttpRequest};
+use crate::mailer::{Email, Mailer};
+use crate::store::Store;
+use chrono::Utc;
+use failure;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use serde::Deserialize;
 use serde_json::json;
 use std::sync::Arc;
 
+pub static SEND_GRID_URL: &str = "https://api.sendgrid.com/v3/mail/send";
+
+pub static SIGNATURE_HEADER: &str = "X-Twilio-Email-Event-Webhook-Signature";
+pub static TIMESTAMP_HEADER: &str = "X-Twilio-Email-Event-Webhook-Timestamp";
+
+// events that mean mail to the address won't get through, or isn't wanted
+static UNDELIVERABLE_EVENTS: [&str; 3] = ["bounce", "dropped", "spamreport"];
+
+// signed events older than this are turned away, so a captured request can't be replayed later
+static MAX_WEBHOOK_AGE: i64 = 10 * 60;
+
+// the der SubjectPublicKeyInfo header of an uncompressed p-256 key,
+// sendgrid gives us the key in that form and ring only wants the point after it
+static P256_SPKI_PREFIX: [u8; 26] = [
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
 #[derive(Clone)]
 pub struct SendGrid {
     key: String,
-    client: Arc<reqwest::Client>,
+    from: String,
+    client: Arc<dyn HttpClient>,
 }
 
 impl SendGrid {
-    pub fn new(key: &str) -> SendGrid {
+    pub fn new(client: Arc<dyn HttpClient>, key: &str, from: &str) -> SendGrid {
         SendGrid {
             key: key.to_owned(),
-            client: Arc::new(reqwest::Client::new()),
+            from: from.to_owned(),
+            client,
         }
     }
+}
 
-    pub fn send_forgot_email(&self, to: &str, token: &str, username: &str) -> Result<(), AuthError> {
-        // let msg = format!(
-        //     "Hi, please use the following link to reset your password:
-        //     \n\rhttp://localhost:3000/reset-password?token={}
-        //     \n\rIf you did not initiate this request, you can safely ignore this email.
-        //     \n\rThanks,
-        //     \n\rAuth App Support",
-        //     token
-        // );
-
-        let href = format!("http://localhost:3000/reset-password?token={}", token);
-        let html_msg = format!(r#"
-        <div style="display: flex;">
-        <table style="font-family: sans-serif; color: #555; padding: 20px; margin: auto; border: 3px solid #ccc; border-radius: 20px;">
-            <tr>
-            <td>Hi {0}, please use the following link to reset your password:</td>
-            </tr>
-            <tr>
-            <td><a href="{1}"><h3>{1}</h3></a></td>
-            </tr>
-            <tr>
-            <td style="padding-bottom: 20px;">If you did not initiate this request, you can safely ignore this email.</td>
-            </tr>
-            <tr>
-            <td>Thanks,</td>
-            </tr>
-            <tr>
-            <td>Auth App Support</td>
-            </tr>
-        </table>
-        </div>
-        "#, username, href);
-
+impl Mailer for SendGrid {
+    fn send(&self, email: &Email) -> Result<(), AuthError> {
         let data = json!({
           "personalizations": [
             {
               "to": [
                 {
-                  "email": to
+                  "email": email.to
                 }
               ],
-              "subject": "Auth App: Password Reset"
+              "subject": email.subject
             }
           ],
           "from": {
-            "email": "support@authapp.com"
+            "email": self.from
           },
           "content": [
+            {
+              "type": "text/plain",
+              "value": email.text
+            },
             {
               "type": "text/html",
-              "value": html_msg
+              "value": email.html
             }
           ]
         });
 
-        let mut res = match self
-            .client
-            .post("https://api.sendgrid.com/v3/mail/send")
-            .header(AUTHORIZATION, format!("Bearer {}", self.key))
-            .header(CONTENT_TYPE, format!("application/json"))
-            .json(&data)
-            .send()
+        let req = match HttpRequest::post(SEND_GRID_URL)
+            .bearer_auth(&self.key)
+            .and_then(|req| req.json(&data))
         {
-            Ok(r) => r,
+            Ok(req) => req,
             Err(err) => return Err(AuthError::internal_error(&err.to_string())),
         };
 
-        match res.text() {
+        // a bad key or a rate limit comes back as a 401 or 429,
+        // which has to fail the send so it gets retried
+        match self.client.send(req).and_then(|res| res.error_for_status()) {
             Ok(_) => Ok(()),
-            Err(err) => Err(AuthError::internal_error(&err.to_string())),
+            Err(err) => Err(AuthError::internal_error(&format!("send grid: {}", err))),
+        }
+    }
+}
+
+// one event from the event webhook, we only need a few of the fields
+#[derive(Deserialize, Debug)]
+pub struct Event {
+    pub email: String,
+    pub event: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+fn webhook_error(server_msg: &str) -> AuthError {
+    AuthError::new("webhook", "Invalid webhook request.", server_msg, 401)
+}
+
+// checks the signatures on the event webhook and records what it reports
+#[derive(Clone)]
+pub struct EventWebhook {
+    // the uncompressed p-256 point of the verification key
+    public_key: Vec<u8>,
+}
+
+impl EventWebhook {
+    // key is the base64 verification key from the sendgrid mail settings
+    pub fn new(key: &str) -> Result<EventWebhook, failure::Error> {
+        let der = base64::decode(key.trim())?;
+        if der.len() != P256_SPKI_PREFIX.len() + 65
+            || der[..P256_SPKI_PREFIX.len()] != P256_SPKI_PREFIX
+        {
+            return Err(failure::err_msg(
+                "send grid webhook key isn't a p-256 public key",
+            ));
+        }
+        Ok(EventWebhook {
+            public_key: der[P256_SPKI_PREFIX.len()..].to_vec(),
+        })
+    }
+
+    // the signature covers the timestamp followed by the raw body
+    pub fn verify(
+        &self,
+        signature: &str,
+        timestamp: &str,
+        body: &[u8],
+        now: i64,
+    ) -> Result<(), AuthError> {
+        let signed_at = timestamp
+            .parse::<i64>()
+            .map_err(|_| webhook_error("webhook timestamp isn't a number"))?;
+        if (now - signed_at).abs() > MAX_WEBHOOK_AGE {
+            return Err(webhook_error("webhook timestamp is too old"));
         }
+
+        let signature = base64::decode(signature)
+            .map_err(|_| webhook_error("webhook signature isn't base64"))?;
+        let mut message = timestamp.as_bytes().to_vec();
+        message.extend_from_slice(body);
+
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &self.public_key)
+            .verify(&message, &signature)
+            .map_err(|_| webhook_error("webhook signature doesn't match"))
+    }
+
+    // verifies a delivery and marks every address that bounced,
+    // was dropped or reported us as spam, returns how many were marked
+    pub async fn receive(
+        &self,
+        db: &Store,
+        signature: &str,
+        timestamp: &str,
+        body: &[u8],
+    ) -> Result<usize, AuthError> {
+        self.verify(signature, timestamp, body, Utc::now().timestamp())?;
+
+        let events: Vec<Event> = serde_json::from_slice(body).map_err(|err| {
+            AuthError::new("webhook", "Invalid webhook request.", &err.to_string(), 400)
+        })?;
+
+        let mut marked = 0;
+        for event in events
+            .iter()
+            .filter(|event| UNDELIVERABLE_EVENTS.contains(&event.event.as_str()))
+        {
+            db.suppress_email(&event.email, &event.event, event.reason.as_deref())
+                .await?;
+            marked += 1;
+        }
+        Ok(marked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{FakeHttpClient, HttpResponse};
+    use crate::mailer::FROM_ADDRESS;
+    use reqwest::header::AUTHORIZATION;
+    use reqwest::Method;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    fn email() -> Email {
+        Email {
+            to: "a@a.com".to_owned(),
+            subject: "Subject".to_owned(),
+            html: "<p>reset-token</p>".to_owned(),
+            text: "reset-token".to_owned(),
+        }
+    }
+
+    #[test]
+    fn send_posts_to_send_grid() {
+        let http = Arc::new(FakeHttpClient::new());
+        http.respond(Method::POST, SEND_GRID_URL, HttpResponse::new(202, ""));
+
+        let send_grid = SendGrid::new(http.clone(), "key", FROM_ADDRESS);
+        send_grid.send(&email()).unwrap();
+
+        let requests = http.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers[AUTHORIZATION], "Bearer key");
+        assert!(requests[0].text().contains("a@a.com"));
+        assert!(requests[0].text().contains("reset-token"));
+    }
+
+    #[test]
+    fn send_fails_on_error_status() {
+        let http = Arc::new(FakeHttpClient::new());
+        http.respond(Method::POST, SEND_GRID_URL, HttpResponse::new(429, ""));
+
+        let send_grid = SendGrid::new(http, "key", FROM_ADDRESS);
+        assert!(send_grid.send(&email()).is_err());
+    }
+
+    #[test]
+    fn event_webhook_checks_signature_and_age() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        let mut spki = P256_SPKI_PREFIX.to_vec();
+        spki.extend_from_slice(key_pair.public_key().as_ref());
+        let webhook = EventWebhook::new(&base64::encode(&spki)).unwrap();
+
+        let body = br#"[{"email":"a@a.com","event":"bounce"}]"#;
+        let timestamp = "1600000000";
+        let mut message = timestamp.as_bytes().to_vec();
+        message.extend_from_slice(body);
+        let signature = base64::encode(key_pair.sign(&rng, &message).unwrap().as_ref());
+
+        assert!(webhook
+            .verify(&signature, timestamp, body, 1600000060)
+            .is_ok());
+        assert!(webhook
+            .verify(&signature, timestamp, b"[]", 1600000060)
+            .is_err());
+        assert!(webhook
+            .verify(
+                &signature,
+                timestamp,
+                body,
+                1600000000 + MAX_WEBHOOK_AGE + 1
+            )
+            .is_err());
     }
 }
@@ -0,0 +1,429 @@
+use crate::auth::Auth;
+use crate::crypto;
+use crate::error::AuthError;
+use crate::jwks::JwkSet;
+use crate::oauth_server::{
+    client_credentials as credentials, parse_scopes, OAuthError, TokenRequest, TokenResponse,
+};
+use crate::oidc_issuer::OidcIssuer;
+use crate::store::Store;
+use chrono::Utc;
+use jsonwebtoken as jwt;
+use jwt::{Algorithm, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// scopes that make sense without a user behind the token
+pub static SERVICE_SCOPES: [&str; 1] = ["users:read"];
+
+// the rfc 7523 grant type for signed jwt assertions
+pub static JWT_BEARER: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+// tells service accounts apart from oauth clients
+static CLIENT_ID_PREFIX: &str = "svc-";
+
+// assertions have to be short lived, their jtis are remembered until they expire
+static MAX_ASSERTION_SECONDS: i64 = 5 * 60;
+
+// a backend job or service that gets tokens for itself
+#[derive(Clone, Debug)]
+pub struct ServiceAccount {
+    pub client_id: String,
+    pub name: String,
+    // set for accounts that authenticate with a secret
+    pub secret_hash: Option<String>,
+    // set for accounts that sign jwt assertions with their own keys
+    pub jwks: Option<JwkSet>,
+    pub scopes: Vec<String>,
+    // a disabled account can't get tokens and the ones it has stop working
+    pub disabled: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewServiceAccount {
+    pub name: String,
+    pub scopes: Vec<String>,
+    // public keys for the jwt bearer grant, no secret is issued when they're given
+    #[serde(default)]
+    pub jwks: Option<JwkSet>,
+}
+
+#[derive(Serialize)]
+pub struct RegisteredServiceAccount {
+    pub client_id: String,
+    // only ever shown here, we just keep its hash
+    pub client_secret: Option<String>,
+}
+
+fn invalid_request(message: &str) -> AuthError {
+    AuthError::new_general(message, "", 400)
+}
+
+pub async fn register(
+    db: &Store,
+    owner_id: i32,
+    account: &NewServiceAccount,
+) -> Result<RegisteredServiceAccount, AuthError> {
+    if account.name.trim().is_empty() {
+        return Err(invalid_request("Please give the service account a name."));
+    }
+    for scope in &account.scopes {
+        if !SERVICE_SCOPES.contains(&scope.as_str()) {
+            return Err(invalid_request(&format!(
+                "{} isn't a scope service accounts can have.",
+                scope
+            )));
+        }
+    }
+    if let Some(ref jwks) = account.jwks {
+        if jwks.keys.iter().all(|key| key.kty != "RSA") {
+            return Err(invalid_request("Please add at least one RSA public key."));
+        }
+    }
+
+    let client_secret = match account.jwks {
+        Some(_) => None,
+        None => Some(crypto::random_token(32)),
+    };
+
+    let registered = ServiceAccount {
+        client_id: format!("{}{}", CLIENT_ID_PREFIX, crypto::random_token(16)),
+        name: account.name.trim().to_owned(),
+        secret_hash: client_secret
+            .as_ref()
+            .map(|secret| crypto::hash_token(secret)),
+        jwks: account.jwks.clone(),
+        scopes: account.scopes.clone(),
+        disabled: false,
+    };
+    db.add_service_account(&registered, owner_id).await?;
+
+    Ok(RegisteredServiceAccount {
+        client_id: registered.client_id,
+        client_secret,
+    })
+}
+
+fn invalid_client() -> OAuthError {
+    OAuthError::new("invalid_client", "Client authentication failed.")
+}
+
+// the client_credentials grant, for accounts with a secret
+pub async fn client_credentials(
+    db: &Store,
+    auth: &Auth,
+    req: &TokenRequest,
+    basic: Option<(String, String)>,
+) -> Result<TokenResponse, OAuthError> {
+    let (client_id, secret) = credentials(
+        req.client_id.as_deref(),
+        req.client_secret.as_deref(),
+        basic,
+    )
+    .ok_or_else(invalid_client)?;
+    let account = authenticate(db, &client_id, secret.as_deref()).await?;
+
+    issue_token(auth, &account, req.scope.as_deref())
+}
+
+pub fn is_service_account(client_id: &str) -> bool {
+    client_id.starts_with(CLIENT_ID_PREFIX)
+}
+
+// checks the secret of an account that has one
+pub async fn authenticate(
+    db: &Store,
+    client_id: &str,
+    secret: Option<&str>,
+) -> Result<ServiceAccount, OAuthError> {
+    let account = db
+        .get_service_account(client_id)
+        .await?
+        .filter(|account| !account.disabled)
+        .ok_or_else(invalid_client)?;
+    match (&account.secret_hash, secret) {
+        (Some(hash), Some(secret)) if crypto::hash_token(secret) == *hash => Ok(account),
+        _ => Err(invalid_client()),
+    }
+}
+
+// the jwt bearer grant, for accounts that sign an assertion
+// with one of their registered keys (rfc 7523)
+pub async fn jwt_bearer(
+    db: &Store,
+    auth: &Auth,
+    issuer: &OidcIssuer,
+    req: &TokenRequest,
+) -> Result<TokenResponse, OAuthError> {
+    let invalid_grant = || OAuthError::new("invalid_grant", "The assertion is invalid.");
+
+    let assertion = req.assertion.as_ref().ok_or_else(invalid_grant)?;
+    let header = jwt::decode_header(assertion).map_err(|_| invalid_grant())?;
+    let unverified = jwt::dangerous_unsafe_decode::<Map<String, Value>>(assertion)
+        .map_err(|_| invalid_grant())?
+        .claims;
+    let client_id = unverified
+        .get("iss")
+        .and_then(Value::as_str)
+        .ok_or_else(invalid_grant)?;
+
+    let account = db
+        .get_service_account(client_id)
+        .await?
+        .filter(|account| !account.disabled)
+        .ok_or_else(invalid_grant)?;
+    let jwks = account.jwks.as_ref().ok_or_else(invalid_grant)?;
+
+    // the kid can be left out when the account only has one key
+    let key = match header.kid {
+        Some(ref kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.find(&jwks.keys[0].kid),
+        None => None,
+    }
+    .ok_or_else(invalid_grant)?;
+
+    let mut validation = Validation {
+        leeway: 10,
+        iss: Some(account.client_id.clone()),
+        sub: Some(account.client_id.clone()),
+        ..Validation::new(Algorithm::RS256)
+    };
+    validation.set_audience(&[issuer.token_endpoint(), issuer.issuer().to_owned()]);
+
+    let claims =
+        jwt::decode_rsa_components::<Map<String, Value>>(assertion, &key.n, &key.e, &validation)
+            .map_err(|_| invalid_grant())?
+            .claims;
+
+    let exp = claims.get("exp").and_then(Value::as_i64).unwrap_or(0);
+    if exp > Utc::now().timestamp() + MAX_ASSERTION_SECONDS {
+        return Err(OAuthError::new(
+            "invalid_grant",
+            "The assertion expires too far in the future.",
+        ));
+    }
+
+    // an assertion can only be used once. its jti is kept with the revoked
+    // tokens, under the account so accounts can't use up each other's
+    let jti = claims
+        .get("jti")
+        .and_then(Value::as_str)
+        .filter(|jti| !jti.is_empty())
+        .ok_or_else(|| OAuthError::new("invalid_grant", "The assertion has no jti."))?;
+    if !db
+        .revoke_token(&format!("{}:{}", account.client_id, jti), exp)
+        .await?
+    {
+        return Err(OAuthError::new(
+            "invalid_grant",
+            "The assertion has already been used.",
+        ));
+    }
+
+    issue_token(auth, &account, req.scope.as_deref())
+}
+
+// service tokens get the requested scopes, or all of the account's
+fn issue_token(
+    auth: &Auth,
+    account: &ServiceAccount,
+    scope: Option<&str>,
+) -> Result<TokenResponse, OAuthError> {
+    let scopes = match scope {
+        Some(scope) => {
+            let requested = parse_scopes(scope);
+            if !requested.iter().all(|s| account.scopes.contains(s)) {
+                return Err(OAuthError::new(
+                    "invalid_scope",
+                    "This service account can't have the requested scope.",
+                ));
+            }
+            requested
+        }
+        None => account.scopes.clone(),
+    };
+    let scope = scopes.join(" ");
+
+    let duration = auth.lifetimes.access;
+    let expires_in = duration.seconds();
+    let access_token = auth.create_service_token(&account.client_id, &scope, duration)?;
+
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_owned(),
+        expires_in,
+        refresh_token: None,
+        scope,
+        id_token: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+    use crate::session;
+    use jwt::Header;
+    use serde_json::json;
+
+    static TOKEN_ENDPOINT: &str = "https://auth.example/oauth/token";
+
+    fn auth() -> Auth {
+        Auth::new(
+            "secretsecretsecret".to_owned(),
+            "0123456789abcdef".to_owned(),
+        )
+    }
+
+    fn issuer() -> OidcIssuer {
+        OidcIssuer::new("https://auth.example", "https://auth.example", Vec::new())
+    }
+
+    async fn register_account(db: &Store, jwks: Option<JwkSet>) -> RegisteredServiceAccount {
+        let account = NewServiceAccount {
+            name: "nightly export".to_owned(),
+            scopes: vec!["users:read".to_owned()],
+            jwks,
+        };
+        register(db, 1, &account).await.unwrap()
+    }
+
+    fn request(grant_type: &str) -> TokenRequest {
+        TokenRequest {
+            grant_type: grant_type.to_owned(),
+            code: None,
+            redirect_uri: None,
+            code_verifier: None,
+            refresh_token: None,
+            scope: None,
+            client_id: None,
+            client_secret: None,
+            assertion: None,
+        }
+    }
+
+    fn secret_request(client_id: &str, secret: &str) -> TokenRequest {
+        TokenRequest {
+            client_id: Some(client_id.to_owned()),
+            client_secret: Some(secret.to_owned()),
+            ..request("client_credentials")
+        }
+    }
+
+    // an assertion signed with the test key, which the account registers
+    fn assertion(claims: Value) -> TokenRequest {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key-1".to_owned());
+        let assertion = jwt::encode(
+            &header,
+            &claims,
+            include_bytes!("../test_data/rsa_private.pem"),
+        )
+        .unwrap();
+        TokenRequest {
+            assertion: Some(assertion),
+            ..request(JWT_BEARER)
+        }
+    }
+
+    fn claims(client_id: &str, jti: &str) -> Value {
+        json!({
+            "iss": client_id,
+            "sub": client_id,
+            "aud": TOKEN_ENDPOINT,
+            "exp": Utc::now().timestamp() + 60,
+            "jti": jti,
+        })
+    }
+
+    fn error_of(res: Result<TokenResponse, OAuthError>) -> String {
+        match res {
+            Ok(_) => panic!("the token request should have failed"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn client_credentials_need_the_secret_and_an_enabled_account() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let (db, auth) = (Store::memory(), auth());
+            let account = register_account(&db, None).await;
+            let client_id = account.client_id.as_str();
+            let secret = account.client_secret.unwrap();
+
+            let tokens = client_credentials(&db, &auth, &secret_request(client_id, &secret), None)
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(tokens.scope, "users:read");
+            let claims = auth.decode_token(&tokens.access_token).unwrap();
+            assert!(claims.is_service());
+            assert_eq!(claims.sub, client_id);
+
+            let wrong = secret_request(client_id, "not-the-secret");
+            let res = client_credentials(&db, &auth, &wrong, None).await;
+            assert!(error_of(res).starts_with("invalid_client"));
+
+            db.set_service_account_disabled(client_id, true)
+                .await
+                .unwrap();
+            let res =
+                client_credentials(&db, &auth, &secret_request(client_id, &secret), None).await;
+            assert!(error_of(res).starts_with("invalid_client"));
+            // and the token it already had stops working
+            assert!(!session::is_active(&db, &claims).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn assertions_are_checked_and_only_used_once() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let (db, auth, issuer) = (Store::memory(), auth(), issuer());
+            let jwks: JwkSet =
+                serde_json::from_str(include_str!("../test_data/jwks.json")).unwrap();
+            let account = register_account(&db, Some(jwks)).await;
+            let client_id = account.client_id.as_str();
+            assert!(account.client_secret.is_none());
+
+            let first = assertion(claims(client_id, "1"));
+            let tokens = jwt_bearer(&db, &auth, &issuer, &first).await.ok().unwrap();
+            assert_eq!(tokens.scope, "users:read");
+
+            let replayed = jwt_bearer(&db, &auth, &issuer, &first).await;
+            assert_eq!(
+                error_of(replayed),
+                "invalid_grant: The assertion has already been used."
+            );
+
+            let mut wrong_aud = claims(client_id, "2");
+            wrong_aud["aud"] = json!("https://elsewhere.example/token");
+            let mut wrong_sub = claims(client_id, "3");
+            wrong_sub["sub"] = json!("svc-someone-else");
+            let mut expired = claims(client_id, "4");
+            expired["exp"] = json!(Utc::now().timestamp() - 60);
+            let mut too_long = claims(client_id, "5");
+            too_long["exp"] = json!(Utc::now().timestamp() + 60 * 60);
+            let mut no_jti = claims(client_id, "6");
+            no_jti.as_object_mut().unwrap().remove("jti");
+            for bad in [
+                wrong_aud,
+                wrong_sub,
+                claims("svc-unknown", "7"),
+                expired,
+                too_long,
+                no_jti,
+            ] {
+                let res = jwt_bearer(&db, &auth, &issuer, &assertion(bad)).await;
+                assert!(error_of(res).starts_with("invalid_grant"));
+            }
+
+            db.set_service_account_disabled(client_id, true)
+                .await
+                .unwrap();
+            let res = jwt_bearer(&db, &auth, &issuer, &assertion(claims(client_id, "8"))).await;
+            assert!(error_of(res).starts_with("invalid_grant"));
+        });
+    }
+}
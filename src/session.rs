@@ -0,0 +1,221 @@
+use crate::api_key;
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::auth::{Auth, Claims, User};
+use crate::error::AuthError;
+use crate::store::{self, Store};
+
+// what we need to know about a user to accept their tokens
+pub struct UserStatus {
+    pub disabled: bool,
+    // tokens issued before this unix time were signed out
+    pub tokens_valid_after: Option<i64>,
+}
+
+// a password user as signing in sees them
+pub struct Credentials {
+    pub username: String,
+    pub password_hash: String,
+    pub disabled: bool,
+}
+
+// checks what a signature can't: that the token wasn't revoked, that it was
+// issued after the user last signed out everywhere, and that its subject still
+// exists and isn't disabled
+pub async fn is_active(db: &Store, claims: &Claims) -> Result<bool, AuthError> {
+    if let Some(jti) = claims.jti() {
+        if db.is_token_revoked(jti).await? {
+            return Ok(false);
+        }
+    }
+
+    if claims.is_service() {
+        let account = db.get_service_account(&claims.sub).await?;
+        return Ok(account.is_some_and(|account| !account.disabled));
+    }
+
+    is_user_active(db, &claims.sub, claims.issued_at()).await
+}
+
+// whether the user still exists, isn't disabled and hasn't signed out
+// everywhere since issued_at. refresh tokens are checked against this too
+pub async fn is_user_active(db: &Store, username: &str, issued_at: i64) -> Result<bool, AuthError> {
+    match db.get_user_status(username).await? {
+        Some(status) => Ok(!status.disabled
+            && status
+                .tokens_valid_after
+                .is_none_or(|valid_after| issued_at >= valid_after)),
+        None => Ok(false),
+    }
+}
+
+// the claims for a bearer token, which can be one of our jwts or an api key.
+// every request made as a user goes through here
+pub async fn authenticate(auth: &Auth, db: &Store, token: &str) -> Result<Claims, AuthError> {
+    let claims = if api_key::is_api_key(token) {
+        api_key::authenticate(db, token).await?
+    } else {
+        auth.decode_token(token)?
+    };
+
+    if !is_active(db, &claims).await? {
+        return Err(AuthError::new(
+            "auth",
+            "Please log in or sign up to access this resource.",
+            "token was revoked or its user is disabled",
+            401,
+        ));
+    }
+    Ok(claims)
+}
+
+// revokes the token until it would have expired anyway
+pub async fn sign_out(
+    db: &Store,
+    claims: &Claims,
+    ip: &str,
+    user_agent: &str,
+) -> Result<(), AuthError> {
+    if let Some(jti) = claims.jti() {
+        db.revoke_token(jti, claims.expires_at()).await?;
+        let event = AuditEvent::new(AuditAction::SessionRevoked, &claims.sub)
+            .by(&claims.sub)
+            .from(ip, user_agent);
+        audit::record(db, event).await;
+    }
+    Ok(())
+}
+
+// checks an email and password, returning the username to issue a token for.
+// the attempt goes in the audit log either way, against the account when the
+// email has one
+pub async fn verify_user(
+    auth: &Auth,
+    db: &Store,
+    user: &User,
+    ip: &str,
+    user_agent: &str,
+) -> Result<String, AuthError> {
+    let credentials = db.get_credentials(&user.email).await?;
+    let (username, hashed_password, disabled) = match credentials {
+        Some(c) => (c.username, c.password_hash, c.disabled),
+        None => ("".to_owned(), "".to_owned(), false),
+    };
+
+    let verified = auth
+        .verify_password(hashed_password, user.password.clone())
+        .await?;
+    let subject = if username.is_empty() {
+        &user.email
+    } else {
+        &username
+    };
+    let event = AuditEvent::new(AuditAction::SignIn, subject).from(ip, user_agent);
+    if verified {
+        if disabled {
+            audit::record(db, event.failed("account disabled")).await;
+            return Err(store::account_disabled(&username));
+        }
+        audit::record(db, event.by(&username)).await;
+        Ok(username)
+    } else {
+        let reason = if username.is_empty() {
+            "no account has that email"
+        } else {
+            "wrong password"
+        };
+        audit::record(db, event.failed(reason)).await;
+        Err(AuthError::new(
+            "signin",
+            "Email and password combo not found.",
+            "Token hash wasn't verified.",
+            400,
+        ))
+    }
+}
+
+// who a password was reset for, and the session they're signed in with
+pub struct PasswordReset {
+    pub username: String,
+    pub token: String,
+}
+
+// sets a new password for whoever the reset token is for and signs them in.
+// the username in the request is ignored, the token's subject is the only
+// one we've verified
+pub async fn reset_password(
+    auth: &Auth,
+    db: &Store,
+    token: &str,
+    user: &User,
+    ip: &str,
+    user_agent: &str,
+) -> Result<PasswordReset, AuthError> {
+    user.is_valid_password("resetPassword")?;
+    let claims = authenticate(auth, db, token).await?;
+    if !claims.is_first_party() {
+        return Err(AuthError::new(
+            "auth",
+            "You don't have access to this resource.",
+            "token is missing the password scope",
+            403,
+        ));
+    }
+
+    let hashed_password = auth.hash_password(&user.password).await?;
+    if db
+        .update_user_password(&claims.sub, &hashed_password)
+        .await?
+        == 0
+    {
+        return Err(AuthError::internal_error(
+            "No rows modified for updating password.",
+        ));
+    }
+    let event = AuditEvent::new(AuditAction::PasswordReset, &claims.sub)
+        .by(&claims.sub)
+        .from(ip, user_agent);
+    audit::record(db, event).await;
+    Ok(PasswordReset {
+        token: auth.create_token(&claims.sub, auth.lifetimes.session)?,
+        username: claims.sub,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn a_reset_signs_in_the_user_the_token_is_for() {
+        let rt = Runtime::new();
+        rt.block_on(async {
+            let auth = Auth::new(
+                "secretsecretsecret".to_owned(),
+                "0123456789abcdef".to_owned(),
+            );
+            let db = Store::memory();
+            for name in &["ann", "admin"] {
+                let email = format!("{}@example.com", name);
+                db.add_user(&User::new(&email, name, "hash")).await.unwrap();
+            }
+            db.set_user_admin("admin", true).await.unwrap();
+
+            // ann's reset link, with someone else's name in the body
+            let token = auth.create_token("ann", auth.lifetimes.reset).unwrap();
+            let body = User::new("", "admin", "new password");
+            let reset = reset_password(&auth, &db, &token, &body, "", "")
+                .await
+                .unwrap();
+            assert_eq!(reset.username, "ann");
+            assert_eq!(auth.decode_token(&reset.token).unwrap().sub, "ann");
+
+            let admin = db
+                .get_credentials("admin@example.com")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(admin.password_hash, "hash");
+        });
+    }
+}
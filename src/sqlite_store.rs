@@ -0,0 +1,1337 @@
+use crate::admin::Account;
+use crate::api_key::{ApiKey, ApiKeyGrant};
+use crate::audit::{AuditEvent, AuditQuery, AuditRecord};
+use crate::auth::{ExternalIdentity, ExternalSignIn, User};
+use crate::directory::{DirectoryUser, SqlParam, UserQuery};
+use crate::error::AuthError;
+use crate::logging;
+use crate::mailer::Email;
+use crate::migrations::SQLITE_MIGRATIONS;
+use crate::oauth_client::OAuthLogin;
+use crate::oauth_server::{AuthorizationCode, AuthorizeRequest, Grant, OAuthClient};
+use crate::oidc_issuer::UserInfo;
+use crate::outbox::{DeadMessage, OutboxMessage};
+use crate::profile::{ExternalProfile, Profile, ProfileUpdate};
+use crate::runtime;
+use crate::service_account::ServiceAccount;
+use crate::session::{Credentials, UserStatus};
+use crate::store::{
+    self, AuditStore, ClientStore, OutboxStore, SessionStore, TokenStore, UserStore,
+};
+use async_trait::async_trait;
+use rusqlite::types::Type;
+use rusqlite::{ffi, params, Connection, OptionalExtension, Row, ToSql};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// a single sqlite file, for deployments too small to want a postgres server.
+// the connection is shared and used from the runtime's blocking pool
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+// lists are kept as json arrays
+fn to_list(list: &[String]) -> String {
+    serde_json::to_string(list).unwrap_or_else(|_| "[]".to_owned())
+}
+
+fn list(row: &Row, idx: usize) -> rusqlite::Result<Vec<String>> {
+    let text: String = row.get(idx)?;
+    serde_json::from_str(&text)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(err)))
+}
+
+fn user_info(row: &Row) -> rusqlite::Result<UserInfo> {
+    Ok(UserInfo {
+        id: row.get(0)?,
+        email: row.get(1)?,
+        username: row.get(2)?,
+    })
+}
+
+// brings the schema up to date, the version is kept in sqlite's user_version
+fn migrate(conn: &mut Connection) -> Result<(), AuthError> {
+    let applied: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for migration in SQLITE_MIGRATIONS.iter().filter(|m| m.version > applied) {
+        let trans = conn.transaction()?;
+        trans.execute_batch(migration.sql).map_err(|err| {
+            AuthError::internal_error(&format!(
+                "migration {} ({}) failed: {}",
+                migration.version, migration.name, err
+            ))
+        })?;
+        trans.pragma_update(None, "user_version", migration.version)?;
+        trans.commit()?;
+    }
+    Ok(())
+}
+
+impl SqliteStore {
+    // opens the database at path, making it if it doesn't exist yet
+    pub fn open(path: &str) -> Result<SqliteStore, AuthError> {
+        let mut conn = Connection::open(path)?;
+        // another process, like auth-admin, may be writing
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        migrate(&mut conn)?;
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn call<F, T>(&self, f: F) -> Result<T, AuthError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, AuthError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let span = tracing::debug_span!("db");
+        runtime::blocking(move || {
+            let _span = span.enter();
+            let started = Instant::now();
+            let mut conn = conn.lock().unwrap_or_else(|err| err.into_inner());
+            let res = f(&mut conn);
+            tracing::debug!(elapsed_ms = logging::elapsed_ms(started), "query finished");
+            res
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn add_user(&self, user: &User) -> Result<u64, AuthError> {
+        let user = user.clone();
+        self.call(move |conn| {
+            let res = conn.execute(
+                "INSERT INTO users (email, username, password) VALUES (?1, ?2, ?3)",
+                params![user.email, user.username, user.password],
+            );
+            match res {
+                Ok(num) => Ok(num as u64),
+                Err(rusqlite::Error::SqliteFailure(err, Some(message)))
+                    if err.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE =>
+                {
+                    if message.contains("users.email") {
+                        Err(store::email_taken())
+                    } else if message.contains("users.username") {
+                        Err(store::username_taken())
+                    } else {
+                        Err(AuthError::internal_error(&message))
+                    }
+                }
+                Err(err) => Err(err.into()),
+            }
+        })
+        .await
+    }
+
+    async fn user_exists(&self, username: &str) -> Result<bool, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT 1 FROM users WHERE username=?1 OR email=?1",
+                    params![username],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(found.is_some())
+        })
+        .await
+    }
+
+    async fn list_users(
+        &self,
+        query: &UserQuery,
+        limit: i64,
+    ) -> Result<(Vec<DirectoryUser>, i64), AuthError> {
+        let sql = query.to_sql("created_at", |n| format!("?{}", n));
+        self.call(move |conn| {
+            let mut params: Vec<&dyn ToSql> = sql
+                .params
+                .iter()
+                .map(|param| match param {
+                    SqlParam::Text(value) => value as &dyn ToSql,
+                    SqlParam::Int(value) => value,
+                    SqlParam::Bool(value) => value,
+                    SqlParam::Id(value) => value,
+                })
+                .collect();
+
+            let total: i64 = conn.query_row(
+                &format!("SELECT count(*) FROM users {}", sql.filter),
+                &params[..sql.filter_params],
+                |row| row.get(0),
+            )?;
+
+            params.push(&limit);
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT id, username, email, admin, disabled, email_verified, created_at
+                FROM users {} {} LIMIT ?{}",
+                sql.page,
+                sql.order,
+                params.len()
+            ))?;
+            let users = stmt
+                .query_map(&params[..], |row| {
+                    Ok(DirectoryUser {
+                        id: row.get(0)?,
+                        username: row.get(1)?,
+                        email: row.get(2)?,
+                        admin: row.get(3)?,
+                        disabled: row.get(4)?,
+                        email_verified: row.get(5)?,
+                        created_at: row.get(6)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<DirectoryUser>>>()?;
+            Ok((users, total))
+        })
+        .await
+    }
+
+    async fn get_credentials(&self, email: &str) -> Result<Option<Credentials>, AuthError> {
+        let email = email.to_owned();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT username, password, disabled FROM users WHERE email=?1",
+                    params![email],
+                    |row| {
+                        Ok(Credentials {
+                            username: row.get(0)?,
+                            password_hash: row.get(1)?,
+                            disabled: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<String, AuthError> {
+        let email = email.to_owned();
+        self.call(move |conn| {
+            let username = conn
+                .query_row(
+                    "SELECT username FROM users WHERE email=?1",
+                    params![email],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(username.unwrap_or_default())
+        })
+        .await
+    }
+
+    async fn update_user_password(&self, username: &str, password: &str) -> Result<u64, AuthError> {
+        let (username, password) = (username.to_owned(), password.to_owned());
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE users SET password = ?1, tokens_valid_after = unixepoch() WHERE username=?2",
+                params![password, username],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn sign_in_external(
+        &self,
+        identity: &ExternalIdentity,
+    ) -> Result<ExternalSignIn, AuthError> {
+        let identity = identity.clone();
+        self.call(move |conn| {
+            let trans = conn.transaction()?;
+
+            let linked: Option<(String, bool)> = trans
+                .query_row(
+                    "SELECT u.username, u.disabled FROM user_identities i
+                    JOIN users u ON u.id = i.user_id WHERE i.provider=?1 AND i.subject=?2",
+                    params![identity.provider, identity.subject],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            if let Some((username, disabled)) = linked {
+                if disabled {
+                    return Err(store::account_disabled(&username));
+                }
+                return Ok(ExternalSignIn {
+                    username,
+                    linked_existing: false,
+                });
+            }
+
+            store::check_external_email(&identity)?;
+
+            let existing: Option<(i32, String, bool)> = trans
+                .query_row(
+                    "SELECT id, username, disabled FROM users WHERE email=?1",
+                    params![identity.email],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .optional()?;
+
+            let profile = ExternalProfile::from_identity(&identity);
+            let linked_existing = existing.is_some();
+            let (user_id, username) = match existing {
+                Some((id, username, disabled)) => {
+                    store::check_external_link(&identity)?;
+                    if disabled {
+                        return Err(store::account_disabled(&username));
+                    }
+                    // the provider vouched for the email, and fills in what the profile is missing
+                    trans.execute(
+                        "UPDATE users SET email_verified=1, display_name=coalesce(display_name, ?2),
+                        avatar_url=coalesce(avatar_url, ?3), locale=coalesce(locale, ?4) WHERE id=?1",
+                        params![
+                            id,
+                            profile.display_name,
+                            profile.avatar_url,
+                            profile.locale
+                        ],
+                    )?;
+                    (id, username)
+                }
+                None => {
+                    let mut created = None;
+                    for candidate in store::external_usernames(&identity) {
+                        let id: Option<i32> = trans
+                            .query_row(
+                                "INSERT INTO users (email, username, password, email_verified,
+                                display_name, avatar_url, locale)
+                                VALUES (?1, ?2, '', ?3, ?4, ?5, ?6)
+                                ON CONFLICT (username) DO NOTHING RETURNING id",
+                                params![
+                                    identity.email,
+                                    candidate,
+                                    identity.email_verified,
+                                    profile.display_name,
+                                    profile.avatar_url,
+                                    profile.locale
+                                ],
+                                |row| row.get(0),
+                            )
+                            .optional()?;
+                        if let Some(id) = id {
+                            created = Some((id, candidate));
+                            break;
+                        }
+                    }
+                    created.ok_or_else(|| store::no_free_username(&identity))?
+                }
+            };
+
+            trans.execute(
+                "INSERT INTO user_identities (user_id, provider, subject, email) VALUES (?1, ?2, ?3, ?4)",
+                params![user_id, identity.provider, identity.subject, identity.email],
+            )?;
+            trans.commit()?;
+
+            Ok(ExternalSignIn {
+                username,
+                linked_existing,
+            })
+        })
+        .await
+    }
+
+    async fn get_user_id(&self, username: &str) -> Result<i32, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            let id = conn
+                .query_row(
+                    "SELECT id FROM users WHERE username=?1",
+                    params![username],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            id.ok_or_else(|| store::unknown_user(&username))
+        })
+        .await
+    }
+
+    async fn get_user_info(&self, username: &str) -> Result<Option<UserInfo>, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT id, email, username FROM users WHERE username=?1",
+                    params![username],
+                    user_info,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT disabled, tokens_valid_after FROM users WHERE username=?1",
+                    params![username],
+                    |row| {
+                        Ok(UserStatus {
+                            disabled: row.get(0)?,
+                            tokens_valid_after: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn is_admin(&self, username: &str) -> Result<bool, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT 1 FROM users WHERE username=?1 AND admin AND NOT disabled",
+                    params![username],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(found.is_some())
+        })
+        .await
+    }
+
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            let account = conn
+                .query_row(
+                    "SELECT id, email, username, disabled, admin, tokens_valid_after
+                    FROM users WHERE username=?1",
+                    params![username],
+                    |row| {
+                        Ok(Account {
+                            id: row.get(0)?,
+                            email: row.get(1)?,
+                            username: row.get(2)?,
+                            disabled: row.get(3)?,
+                            admin: row.get(4)?,
+                            tokens_valid_after: row.get(5)?,
+                            identities: Vec::new(),
+                        })
+                    },
+                )
+                .optional()?;
+            let mut account = match account {
+                Some(account) => account,
+                None => return Ok(None),
+            };
+
+            let mut stmt = conn.prepare_cached(
+                "SELECT provider FROM user_identities WHERE user_id=?1 ORDER BY provider",
+            )?;
+            account.identities = stmt
+                .query_map(params![account.id], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(Some(account))
+        })
+        .await
+    }
+
+    async fn set_user_disabled(&self, username: &str, disabled: bool) -> Result<u64, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE users SET disabled=?2 WHERE username=?1",
+                params![username, disabled],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn set_user_admin(&self, username: &str, admin: bool) -> Result<u64, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE users SET admin=?2 WHERE username=?1",
+                params![username, admin],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT username, email, display_name, avatar_url, locale, timezone,
+                    created_at, coalesce(updated_at, created_at), last_login_at
+                    FROM users WHERE username=?1",
+                    params![username],
+                    |row| {
+                        Ok(Profile {
+                            username: row.get(0)?,
+                            email: row.get(1)?,
+                            display_name: row.get(2)?,
+                            avatar_url: row.get(3)?,
+                            locale: row.get(4)?,
+                            timezone: row.get(5)?,
+                            created_at: row.get(6)?,
+                            updated_at: row.get(7)?,
+                            last_login_at: row.get(8)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn update_profile(
+        &self,
+        username: &str,
+        update: &ProfileUpdate,
+    ) -> Result<u64, AuthError> {
+        let username = username.to_owned();
+        let update = update.clone();
+        self.call(move |conn| {
+            let mut sets = vec!["updated_at=unixepoch()".to_owned()];
+            let mut params: Vec<&dyn ToSql> = vec![&username];
+            for (column, value) in [
+                ("display_name", &update.display_name),
+                ("avatar_url", &update.avatar_url),
+                ("locale", &update.locale),
+                ("timezone", &update.timezone),
+            ] {
+                if let Some(value) = value {
+                    params.push(value);
+                    sets.push(format!("{}=?{}", column, params.len()));
+                }
+            }
+
+            let num = conn.execute(
+                &format!("UPDATE users SET {} WHERE username=?1", sets.join(", ")),
+                &params[..],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn record_sign_in(&self, username: &str) -> Result<(), AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE users SET last_login_at=unixepoch() WHERE username=?1",
+                params![username],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn revoke_token(&self, jti: &str, expires_at: i64) -> Result<bool, AuthError> {
+        let jti = jti.to_owned();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM revoked_tokens WHERE expires_at < unixepoch()",
+                [],
+            )?;
+            let num = conn.execute(
+                "INSERT INTO revoked_tokens (jti, expires_at) VALUES (?1, ?2)
+                ON CONFLICT (jti) DO NOTHING",
+                params![jti, expires_at],
+            )?;
+            Ok(num == 1)
+        })
+        .await
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        let jti = jti.to_owned();
+        self.call(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT 1 FROM revoked_tokens WHERE jti=?1",
+                    params![jti],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(found.is_some())
+        })
+        .await
+    }
+
+    async fn revoke_sessions(&self, username: &str) -> Result<u64, AuthError> {
+        let username = username.to_owned();
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE users SET tokens_valid_after = unixepoch() WHERE username=?1",
+                params![username],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn remember_device(&self, user_id: i32, device_hash: &str) -> Result<bool, AuthError> {
+        let device_hash = device_hash.to_owned();
+        self.call(move |conn| {
+            let trans = conn.transaction()?;
+            let num = trans.execute(
+                "UPDATE known_devices SET last_seen_at = unixepoch() WHERE user_id=?1 AND device_hash=?2",
+                params![user_id, device_hash],
+            )?;
+            if num != 0 {
+                return Ok(false);
+            }
+
+            let seen_before = trans
+                .query_row(
+                    "SELECT 1 FROM known_devices WHERE user_id=?1 LIMIT 1",
+                    params![user_id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            trans.execute(
+                "INSERT INTO known_devices (user_id, device_hash) VALUES (?1, ?2)
+                ON CONFLICT (user_id, device_hash) DO NOTHING",
+                params![user_id, device_hash],
+            )?;
+            trans.commit()?;
+            Ok(seen_before)
+        })
+        .await
+    }
+
+    async fn add_oauth_login(&self, login: &OAuthLogin) -> Result<(), AuthError> {
+        let login = login.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM oauth_logins WHERE created_at < unixepoch() - 60 * 60",
+                [],
+            )?;
+            conn.execute(
+                "INSERT INTO oauth_logins (state, provider, nonce, code_verifier) VALUES (?1, ?2, ?3, ?4)",
+                params![login.state, login.provider, login.nonce, login.code_verifier],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take_oauth_login(&self, state: &str) -> Result<Option<OAuthLogin>, AuthError> {
+        let state = state.to_owned();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "DELETE FROM oauth_logins WHERE state=?1 AND created_at > unixepoch() - 10 * 60
+                    RETURNING provider, nonce, code_verifier",
+                    params![state],
+                    |row| {
+                        Ok(OAuthLogin {
+                            state: state.clone(),
+                            provider: row.get(0)?,
+                            nonce: row.get(1)?,
+                            code_verifier: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl TokenStore for SqliteStore {
+    async fn add_api_key(
+        &self,
+        user_id: i32,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        expires_at: Option<i64>,
+    ) -> Result<i32, AuthError> {
+        let (name, prefix, key_hash) = (name.to_owned(), prefix.to_owned(), key_hash.to_owned());
+        let scopes = to_list(scopes);
+        self.call(move |conn| {
+            Ok(conn.query_row(
+                "INSERT INTO api_keys (user_id, name, prefix, key_hash, scopes, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id",
+                params![user_id, name, prefix, key_hash, scopes, expires_at],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, AuthError> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, name, prefix, scopes, created_at, last_used_at, expires_at
+                FROM api_keys WHERE user_id=?1 AND revoked_at IS NULL ORDER BY id",
+            )?;
+            let keys = stmt
+                .query_map(params![user_id], |row| {
+                    Ok(ApiKey {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        prefix: row.get(2)?,
+                        scopes: list(row, 3)?,
+                        created_at: row.get(4)?,
+                        last_used_at: row.get(5)?,
+                        expires_at: row.get(6)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<ApiKey>>>()?;
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn revoke_api_key(&self, user_id: i32, id: i32) -> Result<u64, AuthError> {
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE api_keys SET revoked_at = unixepoch()
+                WHERE id=?1 AND user_id=?2 AND revoked_at IS NULL",
+                params![id, user_id],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn use_api_key(
+        &self,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<Option<ApiKeyGrant>, AuthError> {
+        let (prefix, key_hash) = (prefix.to_owned(), key_hash.to_owned());
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE api_keys SET last_used_at = unixepoch()
+                WHERE prefix=?1 AND key_hash=?2 AND revoked_at IS NULL
+                    AND (expires_at IS NULL OR expires_at > unixepoch())",
+                params![prefix, key_hash],
+            )?;
+            if num == 0 {
+                return Ok(None);
+            }
+
+            Ok(conn
+                .query_row(
+                    "SELECT users.username, api_keys.prefix, api_keys.scopes, api_keys.created_at
+                    FROM api_keys JOIN users ON users.id = api_keys.user_id
+                    WHERE api_keys.prefix=?1",
+                    params![prefix],
+                    |row| {
+                        Ok(ApiKeyGrant {
+                            username: row.get(0)?,
+                            prefix: row.get(1)?,
+                            scopes: list(row, 2)?,
+                            created_at: row.get(3)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn add_oauth_code(
+        &self,
+        code_hash: &str,
+        user_id: i32,
+        req: &AuthorizeRequest,
+        scope: &str,
+        auth_time: i64,
+    ) -> Result<(), AuthError> {
+        let (code_hash, scope, req) = (code_hash.to_owned(), scope.to_owned(), req.clone());
+        self.call(move |conn| {
+            conn.execute("DELETE FROM oauth_codes WHERE expires_at < unixepoch()", [])?;
+            conn.execute(
+                "INSERT INTO oauth_codes (code_hash, client_id, user_id, redirect_uri, scope, code_challenge, nonce, auth_time, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, unixepoch() + 10 * 60)",
+                params![
+                    code_hash,
+                    req.client_id,
+                    user_id,
+                    req.redirect_uri,
+                    scope,
+                    req.code_challenge,
+                    req.nonce,
+                    auth_time,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take_oauth_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<AuthorizationCode>, AuthError> {
+        let code_hash = code_hash.to_owned();
+        self.call(move |conn| {
+            let trans = conn.transaction()?;
+            let code = trans
+                .query_row(
+                    "SELECT oauth_codes.client_id, oauth_codes.user_id, users.username, oauth_codes.scope,
+                        oauth_codes.auth_time, oauth_codes.redirect_uri, oauth_codes.code_challenge, oauth_codes.nonce
+                    FROM oauth_codes JOIN users ON users.id = oauth_codes.user_id
+                    WHERE oauth_codes.code_hash=?1 AND oauth_codes.expires_at > unixepoch()",
+                    params![code_hash],
+                    |row| {
+                        Ok(AuthorizationCode {
+                            grant: Grant {
+                                client_id: row.get(0)?,
+                                user_id: row.get(1)?,
+                                username: row.get(2)?,
+                                scope: row.get(3)?,
+                                auth_time: row.get(4)?,
+                            },
+                            redirect_uri: row.get(5)?,
+                            code_challenge: row.get(6)?,
+                            nonce: row.get(7)?,
+                        })
+                    },
+                )
+                .optional()?;
+            trans.execute("DELETE FROM oauth_codes WHERE code_hash=?1", params![code_hash])?;
+            trans.commit()?;
+            Ok(code)
+        })
+        .await
+    }
+
+    async fn add_refresh_token(&self, token_hash: &str, grant: &Grant) -> Result<(), AuthError> {
+        let (token_hash, grant) = (token_hash.to_owned(), grant.clone());
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO oauth_refresh_tokens (token_hash, client_id, user_id, scope, auth_time, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, unixepoch() + 30 * 24 * 60 * 60)",
+                params![
+                    token_hash,
+                    grant.client_id,
+                    grant.user_id,
+                    grant.scope,
+                    grant.auth_time,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take_refresh_token(&self, token_hash: &str) -> Result<Option<Grant>, AuthError> {
+        let token_hash = token_hash.to_owned();
+        self.call(move |conn| {
+            let trans = conn.transaction()?;
+            let num = trans.execute(
+                "UPDATE oauth_refresh_tokens SET revoked_at = unixepoch()
+                WHERE token_hash=?1 AND revoked_at IS NULL AND expires_at > unixepoch()",
+                params![token_hash],
+            )?;
+            if num == 0 {
+                return Ok(None);
+            }
+
+            let grant = trans
+                .query_row(
+                    "SELECT token.client_id, token.user_id, users.username, token.scope, token.auth_time
+                    FROM oauth_refresh_tokens token JOIN users ON users.id = token.user_id
+                    WHERE token.token_hash=?1",
+                    params![token_hash],
+                    |row| {
+                        Ok(Grant {
+                            client_id: row.get(0)?,
+                            user_id: row.get(1)?,
+                            username: row.get(2)?,
+                            scope: row.get(3)?,
+                            auth_time: row.get(4)?,
+                        })
+                    },
+                )
+                .optional()?;
+            trans.commit()?;
+            Ok(grant)
+        })
+        .await
+    }
+
+    async fn add_not_me_token(
+        &self,
+        token_hash: &str,
+        user_id: i32,
+        days: i64,
+    ) -> Result<(), AuthError> {
+        let token_hash = token_hash.to_owned();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM not_me_tokens WHERE expires_at < unixepoch()",
+                [],
+            )?;
+            conn.execute(
+                "INSERT INTO not_me_tokens (token_hash, user_id, expires_at)
+                VALUES (?1, ?2, unixepoch() + ?3 * 24 * 60 * 60)",
+                params![token_hash, user_id, days],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take_not_me_token(&self, token_hash: &str) -> Result<Option<UserInfo>, AuthError> {
+        let token_hash = token_hash.to_owned();
+        self.call(move |conn| {
+            let trans = conn.transaction()?;
+            let user = trans
+                .query_row(
+                    "SELECT users.id, users.email, users.username
+                    FROM not_me_tokens token JOIN users ON users.id = token.user_id
+                    WHERE token.token_hash=?1 AND token.expires_at > unixepoch()",
+                    params![token_hash],
+                    user_info,
+                )
+                .optional()?;
+            trans.execute(
+                "DELETE FROM not_me_tokens WHERE token_hash=?1",
+                params![token_hash],
+            )?;
+            trans.commit()?;
+            Ok(user)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ClientStore for SqliteStore {
+    async fn add_oauth_client(&self, client: &OAuthClient, owner_id: i32) -> Result<(), AuthError> {
+        let client = client.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO oauth_clients (client_id, secret_hash, name, redirect_uris, scopes, first_party, owner_id)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    client.client_id,
+                    client.secret_hash,
+                    client.name,
+                    to_list(&client.redirect_uris),
+                    to_list(&client.scopes),
+                    client.first_party,
+                    owner_id,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>, AuthError> {
+        let client_id = client_id.to_owned();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT client_id, secret_hash, name, redirect_uris, scopes, first_party
+                    FROM oauth_clients WHERE client_id=?1",
+                    params![client_id],
+                    |row| {
+                        Ok(OAuthClient {
+                            client_id: row.get(0)?,
+                            secret_hash: row.get(1)?,
+                            name: row.get(2)?,
+                            redirect_uris: list(row, 3)?,
+                            scopes: list(row, 4)?,
+                            first_party: row.get(5)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn get_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+    ) -> Result<Vec<String>, AuthError> {
+        let client_id = client_id.to_owned();
+        self.call(move |conn| {
+            let scopes = conn
+                .query_row(
+                    "SELECT scopes FROM oauth_consents WHERE user_id=?1 AND client_id=?2",
+                    params![user_id, client_id],
+                    |row| list(row, 0),
+                )
+                .optional()?;
+            Ok(scopes.unwrap_or_default())
+        })
+        .await
+    }
+
+    async fn add_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+        scopes: &[String],
+    ) -> Result<(), AuthError> {
+        let (client_id, scopes) = (client_id.to_owned(), scopes.to_vec());
+        self.call(move |conn| {
+            let trans = conn.transaction()?;
+            let mut granted = trans
+                .query_row(
+                    "SELECT scopes FROM oauth_consents WHERE user_id=?1 AND client_id=?2",
+                    params![user_id, client_id],
+                    |row| list(row, 0),
+                )
+                .optional()?
+                .unwrap_or_default();
+            for scope in scopes {
+                if !granted.contains(&scope) {
+                    granted.push(scope);
+                }
+            }
+            trans.execute(
+                "INSERT INTO oauth_consents (user_id, client_id, scopes) VALUES (?1, ?2, ?3)
+                ON CONFLICT (user_id, client_id) DO UPDATE SET scopes = excluded.scopes",
+                params![user_id, client_id, to_list(&granted)],
+            )?;
+            trans.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_service_account(
+        &self,
+        account: &ServiceAccount,
+        owner_id: i32,
+    ) -> Result<(), AuthError> {
+        let jwks = match account.jwks {
+            Some(ref jwks) => Some(
+                serde_json::to_string(jwks)
+                    .map_err(|err| AuthError::internal_error(&err.to_string()))?,
+            ),
+            None => None,
+        };
+        let account = account.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO service_accounts (client_id, name, secret_hash, jwks, scopes, owner_id)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    account.client_id,
+                    account.name,
+                    account.secret_hash,
+                    jwks,
+                    to_list(&account.scopes),
+                    owner_id,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_service_account(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<ServiceAccount>, AuthError> {
+        let client_id = client_id.to_owned();
+        self.call(move |conn| {
+            let row = conn
+                .query_row(
+                    "SELECT client_id, name, secret_hash, jwks, scopes, disabled
+                    FROM service_accounts WHERE client_id=?1",
+                    params![client_id],
+                    |row| {
+                        let jwks: Option<String> = row.get(3)?;
+                        let account = ServiceAccount {
+                            client_id: row.get(0)?,
+                            name: row.get(1)?,
+                            secret_hash: row.get(2)?,
+                            jwks: None,
+                            scopes: list(row, 4)?,
+                            disabled: row.get(5)?,
+                        };
+                        Ok((account, jwks))
+                    },
+                )
+                .optional()?;
+
+            let (mut account, jwks) = match row {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            if let Some(jwks) = jwks {
+                account.jwks = Some(
+                    serde_json::from_str(&jwks)
+                        .map_err(|err| AuthError::internal_error(&err.to_string()))?,
+                );
+            }
+            Ok(Some(account))
+        })
+        .await
+    }
+
+    async fn set_service_account_disabled(
+        &self,
+        client_id: &str,
+        disabled: bool,
+    ) -> Result<u64, AuthError> {
+        let client_id = client_id.to_owned();
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE service_accounts SET disabled=?2 WHERE client_id=?1",
+                params![client_id, disabled],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl OutboxStore for SqliteStore {
+    async fn enqueue_email(&self, email: &Email, expires_at: Option<i64>) -> Result<(), AuthError> {
+        let email = email.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO email_outbox (to_address, subject, html, text, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![email.to, email.subject, email.html, email.text, expires_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn drop_expired_emails(&self) -> Result<u64, AuthError> {
+        self.call(|conn| {
+            let num = conn.execute(
+                "DELETE FROM email_outbox WHERE status <> 'sent' AND expires_at <= unixepoch()",
+                [],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn claim_emails(
+        &self,
+        limit: i64,
+        lease_seconds: i64,
+    ) -> Result<Vec<OutboxMessage>, AuthError> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "UPDATE email_outbox SET next_attempt_at = unixepoch() + ?2
+                WHERE id IN (
+                    SELECT id FROM email_outbox
+                    WHERE status = 'pending' AND next_attempt_at <= unixepoch()
+                        AND (expires_at IS NULL OR expires_at > unixepoch())
+                    ORDER BY next_attempt_at LIMIT ?1
+                )
+                RETURNING id, to_address, subject, html, text, attempts",
+            )?;
+            let messages = stmt
+                .query_map(params![limit, lease_seconds], |row| {
+                    Ok(OutboxMessage {
+                        id: row.get(0)?,
+                        email: Email {
+                            to: row.get(1)?,
+                            subject: row.get(2)?,
+                            html: row.get(3)?,
+                            text: row.get(4)?,
+                        },
+                        attempts: row.get(5)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<OutboxMessage>>>()?;
+            Ok(messages)
+        })
+        .await
+    }
+
+    async fn mark_email_sent(&self, id: i32) -> Result<(), AuthError> {
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE email_outbox SET status = 'sent', sent_at = unixepoch(), html = '', text = ''
+                WHERE id=?1",
+                params![id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn mark_email_failed(
+        &self,
+        id: i32,
+        error: &str,
+        retry_in: Option<i64>,
+    ) -> Result<(), AuthError> {
+        let error = error.to_owned();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE email_outbox SET attempts = attempts + 1, last_error = ?2,
+                    status = CASE WHEN ?3 IS NULL THEN 'dead' ELSE 'pending' END,
+                    next_attempt_at = unixepoch() + coalesce(?3, 0)
+                WHERE id=?1",
+                params![id, error, retry_in],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_dead_emails(&self) -> Result<Vec<DeadMessage>, AuthError> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, to_address, subject, attempts, last_error, created_at
+                FROM email_outbox WHERE status = 'dead' ORDER BY id",
+            )?;
+            let messages = stmt
+                .query_map([], |row| {
+                    Ok(DeadMessage {
+                        id: row.get(0)?,
+                        to: row.get(1)?,
+                        subject: row.get(2)?,
+                        attempts: row.get(3)?,
+                        last_error: row.get(4)?,
+                        created_at: row.get(5)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<DeadMessage>>>()?;
+            Ok(messages)
+        })
+        .await
+    }
+
+    async fn replay_email(&self, id: i32) -> Result<u64, AuthError> {
+        self.call(move |conn| {
+            let num = conn.execute(
+                "UPDATE email_outbox SET status = 'pending', attempts = 0, next_attempt_at = unixepoch()
+                WHERE id=?1 AND status = 'dead' AND (expires_at IS NULL OR expires_at > unixepoch())",
+                params![id],
+            )?;
+            Ok(num as u64)
+        })
+        .await
+    }
+
+    async fn suppress_email(
+        &self,
+        email: &str,
+        event: &str,
+        reason: Option<&str>,
+    ) -> Result<(), AuthError> {
+        let (email, event) = (email.to_owned(), event.to_owned());
+        let reason = reason.map(str::to_owned);
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO email_suppressions (email, event, reason) VALUES (lower(?1), ?2, ?3)
+                ON CONFLICT (email) DO UPDATE SET event = ?2, reason = ?3, updated_at = unixepoch()",
+                params![email, event, reason],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_email_suppressed(&self, email: &str) -> Result<bool, AuthError> {
+        let email = email.to_owned();
+        self.call(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT 1 FROM email_suppressions WHERE email = lower(?1)",
+                    params![email],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            Ok(found.is_some())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl AuditStore for SqliteStore {
+    async fn add_audit_event(&self, event: &AuditEvent) -> Result<(), AuthError> {
+        let event = event.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO audit_events (action, outcome, actor, subject, ip, user_agent, detail)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    event.action.as_str(),
+                    event.outcome.as_str(),
+                    event.actor,
+                    event.subject,
+                    event.ip,
+                    event.user_agent,
+                    event.detail
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_audit_events(
+        &self,
+        query: &AuditQuery,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, AuthError> {
+        let (filter, sql_params) = query.to_sql("occurred_at", |n| format!("?{}", n));
+        self.call(move |conn| {
+            let mut params: Vec<&dyn ToSql> = sql_params
+                .iter()
+                .map(|param| match param {
+                    SqlParam::Text(value) => value as &dyn ToSql,
+                    SqlParam::Int(value) => value,
+                    SqlParam::Bool(value) => value,
+                    SqlParam::Id(value) => value,
+                })
+                .collect();
+            params.push(&limit);
+
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT id, occurred_at, action, outcome, actor, subject, ip, user_agent, detail
+                FROM audit_events {} ORDER BY id DESC LIMIT ?{}",
+                filter,
+                params.len()
+            ))?;
+            let events = stmt
+                .query_map(&params[..], |row| {
+                    Ok(AuditRecord {
+                        id: row.get(0)?,
+                        occurred_at: row.get(1)?,
+                        action: row.get(2)?,
+                        outcome: row.get(3)?,
+                        actor: row.get(4)?,
+                        subject: row.get(5)?,
+                        ip: row.get(6)?,
+                        user_agent: row.get(7)?,
+                        detail: row.get(8)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<AuditRecord>>>()?;
+            Ok(events)
+        })
+        .await
+    }
+}
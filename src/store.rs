@@ -0,0 +1,678 @@
+use crate::admin::Account;
+use crate::api_key::{ApiKey, ApiKeyGrant};
+use crate::audit::{AuditEvent, AuditQuery, AuditRecord};
+use crate::auth::{ExternalIdentity, ExternalSignIn, User};
+use crate::directory::{DirectoryUser, UserQuery};
+use crate::error::AuthError;
+use crate::mailer::Email;
+use crate::memory_store::MemoryStore;
+use crate::oauth_client::OAuthLogin;
+use crate::oauth_server::{AuthorizationCode, AuthorizeRequest, Grant, OAuthClient};
+use crate::oidc_issuer::UserInfo;
+use crate::outbox::{DeadMessage, OutboxMessage};
+use crate::profile::{Profile, ProfileUpdate};
+use crate::service_account::ServiceAccount;
+use crate::session::{Credentials, UserStatus};
+use async_trait::async_trait;
+use std::ops::Deref;
+use std::sync::Arc;
+
+// where the server keeps its data. postgres is the default, sqlite is enough
+// for a small deployment and the in-memory store is for tests and trying
+// things out locally. each backend implements every trait below
+
+// users, their external identities and what admins can change about them
+#[async_trait]
+pub trait UserStore {
+    async fn add_user(&self, user: &User) -> Result<u64, AuthError>;
+    // true when the name is taken as a username or an email
+    async fn user_exists(&self, username: &str) -> Result<bool, AuthError>;
+    // up to limit users in the query's order, and how many match it in all
+    async fn list_users(
+        &self,
+        query: &UserQuery,
+        limit: i64,
+    ) -> Result<(Vec<DirectoryUser>, i64), AuthError>;
+    // what signing in with a password is checked against
+    async fn get_credentials(&self, email: &str) -> Result<Option<Credentials>, AuthError>;
+    // the username for an email, empty when there's no such user
+    async fn get_user_by_email(&self, email: &str) -> Result<String, AuthError>;
+    // changing the password signs the user out everywhere
+    async fn update_user_password(&self, username: &str, password: &str) -> Result<u64, AuthError>;
+    // finds the user linked to an external identity, linking or creating one if needed
+    async fn sign_in_external(
+        &self,
+        identity: &ExternalIdentity,
+    ) -> Result<ExternalSignIn, AuthError>;
+    async fn get_user_id(&self, username: &str) -> Result<i32, AuthError>;
+    async fn get_user_info(&self, username: &str) -> Result<Option<UserInfo>, AuthError>;
+    async fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>, AuthError>;
+    // only enabled admins count
+    async fn is_admin(&self, username: &str) -> Result<bool, AuthError>;
+    async fn get_account(&self, username: &str) -> Result<Option<Account>, AuthError>;
+    async fn set_user_disabled(&self, username: &str, disabled: bool) -> Result<u64, AuthError>;
+    async fn set_user_admin(&self, username: &str, admin: bool) -> Result<u64, AuthError>;
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, AuthError>;
+    // changes the fields the update has
+    async fn update_profile(
+        &self,
+        username: &str,
+        update: &ProfileUpdate,
+    ) -> Result<u64, AuthError>;
+    async fn record_sign_in(&self, username: &str) -> Result<(), AuthError>;
+}
+
+// signed in sessions: revoked tokens, the devices users sign in from
+// and social logins that are in progress
+#[async_trait]
+pub trait SessionStore {
+    // the token is remembered until it would have expired anyway. false when
+    // it had already been revoked
+    async fn revoke_token(&self, jti: &str, expires_at: i64) -> Result<bool, AuthError>;
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AuthError>;
+    // signs the user out everywhere, tokens issued before now stop working
+    async fn revoke_sessions(&self, username: &str) -> Result<u64, AuthError>;
+    // records that the user signed in from a device. true when the device is new
+    // and they had signed in from somewhere else before
+    async fn remember_device(&self, user_id: i32, device_hash: &str) -> Result<bool, AuthError>;
+    async fn add_oauth_login(&self, login: &OAuthLogin) -> Result<(), AuthError>;
+    // a login can only be finished once, and only within 10 minutes of starting it
+    async fn take_oauth_login(&self, state: &str) -> Result<Option<OAuthLogin>, AuthError>;
+}
+
+// long lived and single use tokens, kept as hashes
+#[async_trait]
+pub trait TokenStore {
+    // expires_at is a unix time, keys without one last until they're revoked
+    async fn add_api_key(
+        &self,
+        user_id: i32,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        expires_at: Option<i64>,
+    ) -> Result<i32, AuthError>;
+    // the keys that haven't been revoked
+    async fn get_api_keys(&self, user_id: i32) -> Result<Vec<ApiKey>, AuthError>;
+    async fn revoke_api_key(&self, user_id: i32, id: i32) -> Result<u64, AuthError>;
+    // looks up a key that hasn't been revoked or expired by its prefix and
+    // hash, and records that it was used
+    async fn use_api_key(
+        &self,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<Option<ApiKeyGrant>, AuthError>;
+    // codes expire after 10 minutes
+    async fn add_oauth_code(
+        &self,
+        code_hash: &str,
+        user_id: i32,
+        req: &AuthorizeRequest,
+        scope: &str,
+        auth_time: i64,
+    ) -> Result<(), AuthError>;
+    // codes can only be exchanged once
+    async fn take_oauth_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<AuthorizationCode>, AuthError>;
+    // refresh tokens expire after 30 days
+    async fn add_refresh_token(&self, token_hash: &str, grant: &Grant) -> Result<(), AuthError>;
+    // marks the refresh token used and returns what it was granted for
+    async fn take_refresh_token(&self, token_hash: &str) -> Result<Option<Grant>, AuthError>;
+    async fn add_not_me_token(
+        &self,
+        token_hash: &str,
+        user_id: i32,
+        days: i64,
+    ) -> Result<(), AuthError>;
+    // uses up a "this wasn't me" token, returning the user it was for
+    async fn take_not_me_token(&self, token_hash: &str) -> Result<Option<UserInfo>, AuthError>;
+}
+
+// the apps and services we issue tokens to, and what users let them do
+#[async_trait]
+pub trait ClientStore {
+    async fn add_oauth_client(&self, client: &OAuthClient, owner_id: i32) -> Result<(), AuthError>;
+    async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>, AuthError>;
+    // the scopes a user has already agreed to give a client
+    async fn get_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+    ) -> Result<Vec<String>, AuthError>;
+    // adds to the scopes already agreed to
+    async fn add_oauth_consent(
+        &self,
+        user_id: i32,
+        client_id: &str,
+        scopes: &[String],
+    ) -> Result<(), AuthError>;
+    async fn add_service_account(
+        &self,
+        account: &ServiceAccount,
+        owner_id: i32,
+    ) -> Result<(), AuthError>;
+    async fn get_service_account(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<ServiceAccount>, AuthError>;
+    async fn set_service_account_disabled(
+        &self,
+        client_id: &str,
+        disabled: bool,
+    ) -> Result<u64, AuthError>;
+}
+
+// mail waiting to go out, and the addresses we don't send to
+#[async_trait]
+pub trait OutboxStore {
+    // a message with expires_at, a unix time, is dropped unsent once it passes
+    async fn enqueue_email(&self, email: &Email, expires_at: Option<i64>) -> Result<(), AuthError>;
+    // drops the unsent messages that have expired, returning how many
+    async fn drop_expired_emails(&self) -> Result<u64, AuthError>;
+    // takes the messages that are due and pushes them back by lease_seconds,
+    // so other workers skip them while they're being sent
+    async fn claim_emails(
+        &self,
+        limit: i64,
+        lease_seconds: i64,
+    ) -> Result<Vec<OutboxMessage>, AuthError>;
+    // the bodies aren't kept, the links in them may still work
+    async fn mark_email_sent(&self, id: i32) -> Result<(), AuthError>;
+    // without retry_in the message is dead lettered
+    async fn mark_email_failed(
+        &self,
+        id: i32,
+        error: &str,
+        retry_in: Option<i64>,
+    ) -> Result<(), AuthError>;
+    async fn get_dead_emails(&self) -> Result<Vec<DeadMessage>, AuthError>;
+    // expired messages can't be replayed
+    async fn replay_email(&self, id: i32) -> Result<u64, AuthError>;
+    // stops mail going to an address, event is what sendgrid told us about it
+    async fn suppress_email(
+        &self,
+        email: &str,
+        event: &str,
+        reason: Option<&str>,
+    ) -> Result<(), AuthError>;
+    async fn is_email_suppressed(&self, email: &str) -> Result<bool, AuthError>;
+}
+
+// the security audit log, which is only ever added to
+#[async_trait]
+pub trait AuditStore {
+    async fn add_audit_event(&self, event: &AuditEvent) -> Result<(), AuthError>;
+    // up to limit events matching the query, newest first
+    async fn list_audit_events(
+        &self,
+        query: &AuditQuery,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, AuthError>;
+}
+
+pub trait Backend:
+    UserStore + SessionStore + TokenStore + ClientStore + OutboxStore + AuditStore + Send + Sync
+{
+}
+
+impl<T> Backend for T where
+    T: UserStore + SessionStore + TokenStore + ClientStore + OutboxStore + AuditStore + Send + Sync
+{
+}
+
+// the backend picked by the config, cheap to clone and share between workers
+#[derive(Clone)]
+pub struct Store {
+    backend: Arc<dyn Backend>,
+}
+
+impl Store {
+    pub fn new<B: Backend + 'static>(backend: B) -> Store {
+        Store {
+            backend: Arc::new(backend),
+        }
+    }
+
+    // an empty store that's gone when it's dropped
+    pub fn memory() -> Store {
+        Store::new(MemoryStore::new())
+    }
+}
+
+impl Deref for Store {
+    type Target = dyn Backend;
+
+    fn deref(&self) -> &(dyn Backend + 'static) {
+        &*self.backend
+    }
+}
+
+// the errors every backend gives for the same problems
+
+pub fn email_taken() -> AuthError {
+    AuthError::new(
+        "signupEmail",
+        "This email has already been registered.",
+        "",
+        500,
+    )
+}
+
+pub fn username_taken() -> AuthError {
+    AuthError::new("username", "This username has already been taken.", "", 500)
+}
+
+// what signing in as a disabled user gets, however they sign in
+pub fn account_disabled(username: &str) -> AuthError {
+    AuthError::new(
+        "signin",
+        "This account has been disabled.",
+        &format!("user {} is disabled", username),
+        403,
+    )
+}
+
+pub fn unknown_user(username: &str) -> AuthError {
+    AuthError::new(
+        "auth",
+        "Please log in or sign up to access this resource.",
+        &format!("user {} doesn't exist", username),
+        401,
+    )
+}
+
+// an identity has to share an email to be linked to or create an account
+pub fn check_external_email(identity: &ExternalIdentity) -> Result<(), AuthError> {
+    if identity.email.is_empty() {
+        return Err(AuthError::new_general(
+            "Your account didn't share an email address with us.",
+            "external identity has no email",
+            400,
+        ));
+    }
+    Ok(())
+}
+
+// only link to an existing account when the provider vouches for the email,
+// otherwise anyone could claim an account by signing up elsewhere with its email
+pub fn check_external_link(identity: &ExternalIdentity) -> Result<(), AuthError> {
+    if !identity.email_verified {
+        return Err(AuthError::new_general(
+            "An account with this email already exists. Please sign in with your password.",
+            "external identity email is not verified",
+            409,
+        ));
+    }
+    Ok(())
+}
+
+// usernames to try, in order, for a user created from an external identity
+pub fn external_usernames(identity: &ExternalIdentity) -> Vec<String> {
+    let base = if identity.username.is_empty() {
+        identity
+            .email
+            .split('@')
+            .next()
+            .unwrap_or("user")
+            .to_owned()
+    } else {
+        identity.username.clone()
+    };
+    (0..10)
+        .map(|attempt| match attempt {
+            0 => base.clone(),
+            _ => format!("{}{}", base, attempt + 1),
+        })
+        .collect()
+}
+
+pub fn no_free_username(identity: &ExternalIdentity) -> AuthError {
+    AuthError::internal_error(&format!(
+        "couldn't find a free username for {}",
+        external_usernames(identity)[0]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory;
+    use crate::runtime::Runtime;
+    use crate::sqlite_store::SqliteStore;
+    use chrono::Utc;
+
+    // every backend should behave the same, so the same checks run against each
+    fn backends() -> Vec<Store> {
+        vec![
+            Store::memory(),
+            Store::new(SqliteStore::open(":memory:").unwrap()),
+        ]
+    }
+
+    fn identity(subject: &str, email: &str, verified: bool) -> ExternalIdentity {
+        ExternalIdentity {
+            provider: "google".to_owned(),
+            subject: subject.to_owned(),
+            email: email.to_owned(),
+            email_verified: verified,
+            username: String::new(),
+            name: Some("Someone".to_owned()),
+            picture: Some("https://example.com/someone.png".to_owned()),
+            locale: Some("not a locale".to_owned()),
+        }
+    }
+
+    async fn users(db: &Store) {
+        // add_user returns the rows it added, not the new id
+        let added = db
+            .add_user(&User::new("ann@example.com", "ann", "hash"))
+            .await
+            .unwrap();
+        assert_eq!(added, 1);
+        db.add_user(&User::new("dan@example.com", "dan", "hash"))
+            .await
+            .unwrap();
+        assert_eq!(db.get_user_id("ann").await.unwrap(), 1);
+        assert_eq!(db.get_user_id("dan").await.unwrap(), 2);
+        assert!(db.user_exists("ann@example.com").await.unwrap());
+        assert!(db
+            .add_user(&User::new("ann@example.com", "other", "hash"))
+            .await
+            .is_err());
+        assert!(db
+            .add_user(&User::new("other@example.com", "ann", "hash"))
+            .await
+            .is_err());
+
+        let credentials = db
+            .get_credentials("ann@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(credentials.username, "ann");
+        assert!(!credentials.disabled);
+        assert!(!db.is_admin("ann").await.unwrap());
+        db.set_user_admin("ann", true).await.unwrap();
+        assert!(db.is_admin("ann").await.unwrap());
+        db.set_user_disabled("ann", true).await.unwrap();
+        assert!(!db.is_admin("ann").await.unwrap());
+        assert!(db.get_user_status("ann").await.unwrap().unwrap().disabled);
+        assert!(db.get_user_status("nobody").await.unwrap().is_none());
+    }
+
+    async fn external_sign_in(db: &Store) {
+        db.add_user(&User::new("bob@example.com", "bob", "hash"))
+            .await
+            .unwrap();
+
+        // an unverified email can't take over the account that has it
+        assert!(db
+            .sign_in_external(&identity("1", "bob@example.com", false))
+            .await
+            .is_err());
+
+        let linked = db
+            .sign_in_external(&identity("1", "bob@example.com", true))
+            .await
+            .unwrap();
+        assert_eq!(linked.username, "bob");
+        assert!(linked.linked_existing);
+
+        let again = db
+            .sign_in_external(&identity("1", "bob@example.com", true))
+            .await
+            .unwrap();
+        assert_eq!(again.username, "bob");
+        assert!(!again.linked_existing);
+
+        // a disabled user can't sign in through a linked identity, or link a new one
+        db.set_user_disabled("bob", true).await.unwrap();
+        let res = db
+            .sign_in_external(&identity("1", "bob@example.com", true))
+            .await;
+        assert!(matches!(res, Err(err) if err.status() == 403));
+        let res = db
+            .sign_in_external(&identity("3", "bob@example.com", true))
+            .await;
+        assert!(matches!(res, Err(err) if err.status() == 403));
+        db.set_user_disabled("bob", false).await.unwrap();
+
+        // a new account gets a free username based on the email
+        let created = db
+            .sign_in_external(&identity("2", "bob@elsewhere.example", false))
+            .await
+            .unwrap();
+        assert_eq!(created.username, "bob2");
+
+        // the profile starts out with what the provider sent that passes our checks
+        let profile = db.get_profile("bob2").await.unwrap().unwrap();
+        assert_eq!(profile.display_name.as_deref(), Some("Someone"));
+        assert!(profile.avatar_url.is_some());
+        assert!(profile.locale.is_none());
+        assert!(profile.last_login_at.is_none());
+
+        let update = ProfileUpdate {
+            display_name: Some(None),
+            timezone: Some(Some("Europe/Paris".to_owned())),
+            ..ProfileUpdate::default()
+        };
+        assert_eq!(db.update_profile("bob2", &update).await.unwrap(), 1);
+        db.record_sign_in("bob2").await.unwrap();
+        let profile = db.get_profile("bob2").await.unwrap().unwrap();
+        assert!(profile.display_name.is_none());
+        assert!(profile.avatar_url.is_some());
+        assert_eq!(profile.timezone.as_deref(), Some("Europe/Paris"));
+        assert!(profile.last_login_at.is_some());
+        assert_eq!(db.update_profile("nobody", &update).await.unwrap(), 0);
+    }
+
+    async fn tokens(db: &Store) {
+        db.add_user(&User::new("cat@example.com", "cat", "hash"))
+            .await
+            .unwrap();
+        let user_id = db.get_user_id("cat").await.unwrap();
+
+        let scopes = vec!["read".to_owned()];
+        let key = db
+            .add_api_key(user_id, "ci", "abc", "hash", &scopes, None)
+            .await
+            .unwrap();
+        let grant = db.use_api_key("abc", "hash").await.unwrap().unwrap();
+        assert_eq!(grant.username, "cat");
+        assert_eq!(grant.scopes, scopes);
+        assert!(db.use_api_key("abc", "wrong").await.unwrap().is_none());
+        assert_eq!(db.revoke_api_key(user_id, key).await.unwrap(), 1);
+        assert!(db.use_api_key("abc", "hash").await.unwrap().is_none());
+        assert!(db.get_api_keys(user_id).await.unwrap().is_empty());
+
+        db.add_api_key(user_id, "old", "def", "hash", &scopes, Some(1))
+            .await
+            .unwrap();
+        assert!(db.use_api_key("def", "hash").await.unwrap().is_none());
+        assert_eq!(
+            db.get_api_keys(user_id).await.unwrap()[0].expires_at,
+            Some(1)
+        );
+
+        assert!(!db.is_token_revoked("jti").await.unwrap());
+        assert!(db.revoke_token("jti", i64::MAX / 2).await.unwrap());
+        assert!(db.is_token_revoked("jti").await.unwrap());
+        assert!(!db.revoke_token("jti", i64::MAX / 2).await.unwrap());
+
+        assert!(!db.remember_device(user_id, "laptop").await.unwrap());
+        assert!(db.remember_device(user_id, "phone").await.unwrap());
+        assert!(!db.remember_device(user_id, "phone").await.unwrap());
+
+        db.add_not_me_token("not-me", user_id, 7).await.unwrap();
+        assert!(db.take_not_me_token("not-me").await.unwrap().is_some());
+        assert!(db.take_not_me_token("not-me").await.unwrap().is_none());
+    }
+
+    async fn outbox(db: &Store) {
+        let email = Email {
+            to: "dan@example.com".to_owned(),
+            subject: "hi".to_owned(),
+            html: "<p>hi</p>".to_owned(),
+            text: "hi".to_owned(),
+        };
+        db.enqueue_email(&email, None).await.unwrap();
+        // mail whose links have expired isn't sent, and is dropped
+        let expired = Utc::now().timestamp() - 1;
+        db.enqueue_email(&email, Some(expired)).await.unwrap();
+
+        let claimed = db.claim_emails(10, 60).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(db.drop_expired_emails().await.unwrap(), 1);
+        // leased messages aren't handed out twice
+        assert!(db.claim_emails(10, 60).await.unwrap().is_empty());
+
+        db.mark_email_failed(claimed[0].id, "bounced", None)
+            .await
+            .unwrap();
+        assert_eq!(db.get_dead_emails().await.unwrap().len(), 1);
+        assert_eq!(db.replay_email(claimed[0].id).await.unwrap(), 1);
+        assert_eq!(db.claim_emails(10, 60).await.unwrap().len(), 1);
+
+        assert!(!db.is_email_suppressed("dan@example.com").await.unwrap());
+        db.suppress_email("dan@example.com", "bounce", None)
+            .await
+            .unwrap();
+        assert!(db.is_email_suppressed("dan@example.com").await.unwrap());
+    }
+
+    async fn directory(db: &Store) {
+        for name in &["dee", "Dan", "eve", "dora", "fay"] {
+            let email = format!("{}@example.com", name.to_lowercase());
+            db.add_user(&User::new(&email, name, "hash")).await.unwrap();
+        }
+        db.set_user_disabled("eve", true).await.unwrap();
+
+        // the d names, two at a time in reverse email order
+        let mut params: directory::UserListParams =
+            serde_urlencoded::from_str("q=D&sort=-email&limit=2").unwrap();
+        let mut seen = Vec::new();
+        loop {
+            let page = directory::list_users(db, &params, "/users").await.unwrap();
+            assert_eq!(page.total, 3);
+            seen.extend(page.users.into_iter().map(|user| user.username));
+            match page.next_cursor {
+                Some(cursor) => params.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec!["dora", "dee", "Dan"]);
+
+        let params = serde_urlencoded::from_str("status=disabled").unwrap();
+        let page = directory::list_users(db, &params, "/users").await.unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.users[0].username, "eve");
+        assert!(page.next.is_none());
+
+        // fay is an admin and sees everything, dee only usernames
+        db.add_user(&User::new("hal@example.com", "gus", "hash"))
+            .await
+            .unwrap();
+        db.set_user_admin("fay", true).await.unwrap();
+        let params = serde_urlencoded::from_str("q=h").unwrap();
+        match directory::browse(db, "fay", &params, "/users")
+            .await
+            .unwrap()
+        {
+            directory::Listing::Users(page) => assert_eq!(page.users[0].email, "hal@example.com"),
+            directory::Listing::Usernames(_) => panic!("admins should see the whole user"),
+        }
+        // nobody's email can be guessed a prefix at a time
+        match directory::browse(db, "dee", &params, "/users")
+            .await
+            .unwrap()
+        {
+            directory::Listing::Usernames(page) => assert_eq!(page.total, 0),
+            directory::Listing::Users(_) => panic!("only admins should see emails"),
+        }
+
+        let params = serde_urlencoded::from_str("q=d&limit=2").unwrap();
+        let listing = directory::browse(db, "dee", &params, "/users")
+            .await
+            .unwrap();
+        assert!(!serde_json::to_string(&listing)
+            .unwrap()
+            .contains("example.com"));
+        match listing {
+            directory::Listing::Usernames(page) => {
+                let names: Vec<String> = page.users.into_iter().map(|user| user.username).collect();
+                assert_eq!(names, vec!["Dan", "dee"]);
+                assert_eq!(page.total, 3);
+                assert!(page.next.is_some());
+            }
+            directory::Listing::Users(_) => panic!("only admins should see emails"),
+        }
+        for query in &[
+            "status=disabled",
+            "role=admin",
+            "verified=true",
+            "sort=email",
+        ] {
+            let params = serde_urlencoded::from_str(query).unwrap();
+            let err = directory::browse(db, "dee", &params, "/users")
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.status(), 403, "{}", query);
+        }
+    }
+
+    async fn audit_log(db: &Store) {
+        use crate::audit::{self, AuditAction, AuditParams};
+
+        let ann = AuditEvent::new(AuditAction::SignIn, "ann").from("203.0.113.7", "Firefox");
+        audit::record(db, ann.clone().failed("wrong password")).await;
+        audit::record(db, ann.by("ann")).await;
+        audit::record(db, AuditEvent::new(AuditAction::SignUp, "bob").by("bob")).await;
+
+        let activity = audit::recent_activity(db, "ann").await.unwrap();
+        let outcomes: Vec<&str> = activity.iter().map(|e| e.outcome.as_str()).collect();
+        assert_eq!(outcomes, vec!["success", "failure"]);
+        assert_eq!(activity[1].detail.as_deref(), Some("wrong password"));
+        assert_eq!(activity[0].ip.as_deref(), Some("203.0.113.7"));
+
+        let mut params: AuditParams = serde_urlencoded::from_str("action=sign_in&limit=1").unwrap();
+        let page = audit::search(db, &params, "/audit").await.unwrap();
+        assert_eq!(page.events[0].actor.as_deref(), Some("ann"));
+        params.before = Some(page.events[0].id);
+        let page = audit::search(db, &params, "/audit").await.unwrap();
+        assert_eq!(page.events[0].outcome, "failure");
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn audit_logs_agree() {
+        let rt = Runtime::new();
+        for db in backends() {
+            rt.block_on(audit_log(&db));
+        }
+    }
+
+    #[test]
+    fn directories_agree() {
+        let rt = Runtime::new();
+        for db in backends() {
+            rt.block_on(directory(&db));
+        }
+    }
+
+    #[test]
+    fn backends_agree() {
+        let rt = Runtime::new();
+        for db in backends() {
+            rt.block_on(async {
+                users(&db).await;
+                external_sign_in(&db).await;
+                tokens(&db).await;
+                outbox(&db).await;
+            });
+        }
+    }
+}
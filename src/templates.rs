@@ -0,0 +1,427 @@
+use crate::error::AuthError;
+use crate::mailer::Email;
+use failure;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// every locale has to fall back to this one
+pub static DEFAULT_LOCALE: &str = "en";
+
+pub static APP_NAME: &str = "Auth App";
+
+// the emails we send. each one has <name>.<locale>.html and <name>.<locale>.txt
+// in the template directory, and the text one starts with a "Subject: " line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Template {
+    ResetPassword,
+    VerifyEmail,
+    MagicLink,
+    SecurityNotice,
+}
+
+impl Template {
+    pub fn all() -> [Template; 4] {
+        [
+            Template::ResetPassword,
+            Template::VerifyEmail,
+            Template::MagicLink,
+            Template::SecurityNotice,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Template::ResetPassword => "reset_password",
+            Template::VerifyEmail => "verify_email",
+            Template::MagicLink => "magic_link",
+            Template::SecurityNotice => "security_notice",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Variant {
+    subject: String,
+    html: String,
+    text: String,
+}
+
+// the email templates, all read once at startup so a broken one stops us
+// from starting instead of failing the first time someone needs it
+#[derive(Clone)]
+pub struct Templates {
+    base_url: String,
+    // wraps every html body, with {{body}} where it goes
+    layout: String,
+    variants: HashMap<(String, String), Variant>,
+    // the sentences security notices are made of, by name and locale
+    notices: HashMap<(String, String), String>,
+}
+
+fn template_error(message: String) -> failure::Error {
+    failure::err_msg(format!("templates: {}", message))
+}
+
+// "&", "<", ">" and both quotes, so values can go in text and attributes
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// replaces every {{name}} with its value. a name without a value is an error,
+// so a typo in a template can't send an email with a hole in it
+pub fn render(
+    template: &str,
+    vars: &HashMap<&str, String>,
+    escape: bool,
+) -> Result<String, failure::Error> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| template_error("unclosed {{".to_owned()))?;
+        let name = after[..end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| template_error(format!("no value for {{{{{}}}}}", name)))?;
+        if escape {
+            out.push_str(&escape_html(value));
+        } else {
+            out.push_str(value);
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// the first language in an accept-language header, like "pt-br" for
+// "pt-BR,pt;q=0.9,en;q=0.8". browsers list them most preferred first
+pub fn locale_from_accept_language(header: &str) -> String {
+    header
+        .split(',')
+        .next()
+        .and_then(|tag| tag.split(';').next())
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty() && tag != "*")
+        .unwrap_or_else(|| DEFAULT_LOCALE.to_owned())
+}
+
+// security_notices.<locale>.txt has one "name: sentence" per line
+fn read_notices(
+    path: &Path,
+    locale: &str,
+    notices: &mut HashMap<(String, String), String>,
+) -> Result<(), failure::Error> {
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(sentence)) => {
+                notices.insert(
+                    (name.trim().to_owned(), locale.to_owned()),
+                    sentence.trim().to_owned(),
+                );
+            }
+            _ => {
+                return Err(template_error(format!(
+                    "{} has a line without a name: {}",
+                    path.display(),
+                    line
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+// the locales to try for locale, most specific first
+fn fallbacks(locale: &str) -> [String; 3] {
+    let locale = locale.to_lowercase();
+    let language = locale.split('-').next().unwrap_or("").to_owned();
+    [locale, language, DEFAULT_LOCALE.to_owned()]
+}
+
+fn read_variant(dir: &Path, name: &str, locale: &str) -> Result<Variant, failure::Error> {
+    let html = fs::read_to_string(dir.join(format!("{}.{}.html", name, locale)))?;
+    let text = fs::read_to_string(dir.join(format!("{}.{}.txt", name, locale)))?;
+
+    let mut lines = text.splitn(2, '\n');
+    let subject = lines
+        .next()
+        .and_then(|line| line.strip_prefix("Subject:"))
+        .map(|subject| subject.trim().to_owned())
+        .ok_or_else(|| {
+            template_error(format!(
+                "{}.{}.txt has to start with a Subject: line",
+                name, locale
+            ))
+        })?;
+    let text = lines
+        .next()
+        .unwrap_or("")
+        .trim_start_matches('\n')
+        .to_owned();
+
+    Ok(Variant {
+        subject,
+        html,
+        text,
+    })
+}
+
+impl Templates {
+    // loads every template in dir. base_url is where the react app is served,
+    // links in the emails point there
+    pub fn load(dir: &str, base_url: &str) -> Result<Templates, failure::Error> {
+        let dir = Path::new(dir);
+        let layout = fs::read_to_string(dir.join("layout.html"))?;
+
+        let mut variants = HashMap::new();
+        let mut notices = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            let parts: Vec<&str> = file_name.split('.').collect();
+            if parts.len() == 3 && parts[0] == "security_notices" && parts[2] == "txt" {
+                read_notices(
+                    &dir.join(&*file_name),
+                    &parts[1].to_lowercase(),
+                    &mut notices,
+                )?;
+                continue;
+            }
+            if parts.len() != 3 || parts[2] != "html" {
+                continue;
+            }
+            let (name, locale) = (parts[0], parts[1]);
+            if !Template::all().iter().any(|t| t.name() == name) {
+                continue;
+            }
+            let variant = read_variant(dir, name, locale)?;
+            variants.insert((name.to_owned(), locale.to_lowercase()), variant);
+        }
+
+        for template in Template::all().iter() {
+            let key = (template.name().to_owned(), DEFAULT_LOCALE.to_owned());
+            if !variants.contains_key(&key) {
+                return Err(template_error(format!(
+                    "{} has no {} version",
+                    template.name(),
+                    DEFAULT_LOCALE
+                )));
+            }
+        }
+
+        Ok(Templates {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            layout,
+            variants,
+            notices,
+        })
+    }
+
+    // the closest variant, "pt-br" falls back to "pt" and then to the default
+    fn variant(&self, template: Template, locale: &str) -> Option<&Variant> {
+        fallbacks(locale).iter().find_map(|locale| {
+            self.variants
+                .get(&(template.name().to_owned(), locale.to_owned()))
+        })
+    }
+
+    pub fn has_notice(&self, name: &str) -> bool {
+        self.notices
+            .contains_key(&(name.to_owned(), DEFAULT_LOCALE.to_owned()))
+    }
+
+    // the sentence for a security notice, in the closest locale
+    pub fn notice(
+        &self,
+        name: &str,
+        locale: &str,
+        vars: &[(&str, &str)],
+    ) -> Result<String, AuthError> {
+        let sentence = fallbacks(locale)
+            .iter()
+            .find_map(|locale| self.notices.get(&(name.to_owned(), locale.to_owned())))
+            .ok_or_else(|| AuthError::internal_error(&format!("templates: no {} notice", name)))?;
+        let values = vars
+            .iter()
+            .map(|(name, value)| (*name, (*value).to_owned()))
+            .collect();
+        render(sentence, &values, false).map_err(|err| AuthError::internal_error(&err.to_string()))
+    }
+
+    pub fn link(&self, path: &str, token: &str) -> String {
+        format!("{}{}?token={}", self.base_url, path, token)
+    }
+
+    // renders an email for to. app_name and base_url can be used in every template
+    pub fn render(
+        &self,
+        template: Template,
+        locale: &str,
+        to: &str,
+        vars: &[(&str, &str)],
+    ) -> Result<Email, AuthError> {
+        let variant = self.variant(template, locale).ok_or_else(|| {
+            AuthError::internal_error(&format!("templates: {} is missing", template.name()))
+        })?;
+
+        let mut values: HashMap<&str, String> = vars
+            .iter()
+            .map(|(name, value)| (*name, (*value).to_owned()))
+            .collect();
+        values.insert("app_name", APP_NAME.to_owned());
+        values.insert("base_url", self.base_url.clone());
+
+        let rendered = || -> Result<Email, failure::Error> {
+            // the body is escaped as it's rendered, the layout just wraps it
+            let mut layout = HashMap::new();
+            layout.insert("body", render(&variant.html, &values, true)?);
+            layout.insert("app_name", escape_html(APP_NAME));
+
+            Ok(Email {
+                to: to.to_owned(),
+                subject: render(&variant.subject, &values, false)?,
+                html: render(&self.layout, &layout, false)?,
+                text: render(&variant.text, &values, false)?,
+            })
+        };
+        rendered().map_err(|err| AuthError::internal_error(&err.to_string()))
+    }
+
+    pub fn reset_password(
+        &self,
+        locale: &str,
+        to: &str,
+        username: &str,
+        token: &str,
+    ) -> Result<Email, AuthError> {
+        let link = self.link("/reset-password", token);
+        self.render(
+            Template::ResetPassword,
+            locale,
+            to,
+            &[("username", username), ("link", &link)],
+        )
+    }
+
+    pub fn verify_email(
+        &self,
+        locale: &str,
+        to: &str,
+        username: &str,
+        token: &str,
+    ) -> Result<Email, AuthError> {
+        let link = self.link("/verify-email", token);
+        self.render(
+            Template::VerifyEmail,
+            locale,
+            to,
+            &[("username", username), ("link", &link)],
+        )
+    }
+
+    pub fn magic_link(
+        &self,
+        locale: &str,
+        to: &str,
+        username: &str,
+        token: &str,
+    ) -> Result<Email, AuthError> {
+        let link = self.link("/magic-link", token);
+        self.render(
+            Template::MagicLink,
+            locale,
+            to,
+            &[("username", username), ("link", &link)],
+        )
+    }
+
+    // tells the user something happened to their account, notice says what.
+    // the token is for the link that signs them out everywhere if it wasn't them
+    pub fn security_notice(
+        &self,
+        locale: &str,
+        to: &str,
+        username: &str,
+        notice: &str,
+        token: &str,
+    ) -> Result<Email, AuthError> {
+        let link = self.link("/not-me", token);
+        self.render(
+            Template::SecurityNotice,
+            locale,
+            to,
+            &[("username", username), ("notice", notice), ("link", &link)],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates() -> Templates {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/templates/email");
+        Templates::load(dir, "https://auth.example/").unwrap()
+    }
+
+    #[test]
+    fn render_escapes_html_but_not_text() {
+        let email = templates()
+            .reset_password("en-US", "a@a.com", "<b>a</b>", "reset-token")
+            .unwrap();
+
+        assert_eq!(email.subject, "Auth App: Password Reset");
+        assert!(email.html.contains("&lt;b&gt;a&lt;/b&gt;"));
+        assert!(!email.html.contains("<b>a</b>"));
+        assert!(email.text.contains("Hi <b>a</b>,"));
+        assert!(email
+            .text
+            .contains("https://auth.example/reset-password?token=reset-token"));
+        assert!(!email.text.starts_with("Subject:"));
+    }
+
+    #[test]
+    fn render_falls_back_to_language_then_default() {
+        let templates = templates();
+        let spanish = templates
+            .reset_password("es-MX", "a@a.com", "a", "t")
+            .unwrap();
+        assert!(spanish.subject.contains("contraseña"));
+
+        let unknown = templates.reset_password("xx", "a@a.com", "a", "t").unwrap();
+        assert_eq!(unknown.subject, "Auth App: Password Reset");
+
+        assert_eq!(
+            locale_from_accept_language("es-MX,es;q=0.9,en;q=0.8"),
+            "es-mx"
+        );
+        assert_eq!(locale_from_accept_language(""), DEFAULT_LOCALE);
+    }
+
+    #[test]
+    fn render_rejects_unknown_placeholders() {
+        let vars = HashMap::new();
+        assert!(render("Hi {{ username }}", &vars, true).is_err());
+    }
+}